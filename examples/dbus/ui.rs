@@ -20,6 +20,9 @@ pub fn rt() -> &'static tokio::runtime::Runtime {
     })
 }
 
+/// The app id of the demo, see `ngn::protocol::service`.
+const APP_ID: &str = "io.crisal.ngndemo";
+
 static SESSION: OnceLock<Arc<ngn::platform::dbus::Session>> = OnceLock::new();
 fn start_session(
     device_name: &str,
@@ -40,6 +43,16 @@ fn start_session(
                         device_name,
                         identity,
                         go_intent: 1,
+                        limits: Default::default(),
+                        padding: Default::default(),
+                        resumption_window: ngn::protocol::resumption::DEFAULT_WINDOW,
+                        // Same as the Android demo, so that they see each other.
+                        app_id: APP_ID,
+                        discovery_filter: ngn::protocol::service::DiscoveryFilter::Apps(vec![
+                            APP_ID.into(),
+                        ]),
+                        name_matching: ngn::protocol::NameMatching::Unique,
+                        identity_beacon: true,
                     },
                     listener,
                 )
@@ -104,6 +117,12 @@ impl<S: P2PSession> P2PSessionListener<S> for Listener {
             .unwrap();
     }
 
+    fn invitation_received(&self, sess: &S, peer_id: PeerId) -> bool {
+        self.logger.invitation_received(sess, peer_id);
+        // The demo joins any group it's invited to, like it connects to any peer it's asked to.
+        true
+    }
+
     fn peer_discovered(&self, sess: &S, peer_id: PeerId) {
         self.logger.peer_discovered(sess, peer_id);
         let Some(identity) = sess.peer_identity(peer_id) else {
@@ -142,6 +161,26 @@ fn start_discovery(peer_list_box: &gtk::ListBox, refresh_button: &gtk::Button) {
     rt().spawn(async move { session.discover_peers().await });
 }
 
+/// Starts a group right away and waits for peers to join it, like a kiosk would.
+fn start_group_owner() {
+    let Some(session) = SESSION.get() else { return };
+    let session = session.clone();
+    rt().spawn(async move {
+        session
+            .create_group(ngn::platform::dbus::GroupOptions {
+                persistent: true,
+                ..Default::default()
+            })
+            .await?;
+        session
+            .set_extended_listen(Some(ngn::platform::dbus::ExtendedListen {
+                period: std::time::Duration::from_millis(500),
+                interval: std::time::Duration::from_secs(1),
+            }))
+            .await
+    });
+}
+
 pub fn build(app: &adw::Application, device_name: &str, interface_name: Option<&str>) {
     let content = adw::ToolbarView::new();
     let refresh_button = gtk::Button::from_icon_name("view-refresh");
@@ -197,7 +236,11 @@ pub fn build(app: &adw::Application, device_name: &str, interface_name: Option<&
             if let Some(sess) = SESSION.get() {
                 entry.set_text(&sess.own_identity().to_string());
             }
-            start_discovery(&peer_list_box, &refresh_button);
+            if std::env::var_os("GROUP_OWNER").is_some() {
+                start_group_owner();
+            } else {
+                start_discovery(&peer_list_box, &refresh_button);
+            }
         });
 
         identity_box.append(&identity_entry);
@@ -232,7 +275,14 @@ pub fn build(app: &adw::Application, device_name: &str, interface_name: Option<&
             match event {
                 Event::PeerDiscovered { id, identity } => {
                     let row = adw::ActionRow::builder().activatable(true).build();
-                    row.set_title(&identity.physical.to_string());
+                    match identity.logical {
+                        // Only advertised in its beacon so far, so it could be anyone replaying it.
+                        Some(ref logical_id) => {
+                            row.set_title(&format!("{logical_id}?"));
+                            row.set_subtitle(&identity.physical.to_string());
+                        }
+                        None => row.set_title(&identity.physical.to_string()),
+                    }
                     unsafe {
                         row.set_data::<PeerId>("peer-id", id);
                     }
@@ -16,6 +16,31 @@ pub struct PeerId(pub(crate) handy::Handle);
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct GroupId(pub(crate) handy::Handle);
 
+/// How a message got to us.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Delivery {
+    /// Over a connection to the peer.
+    Direct,
+    /// Through the group owner, see `protocol::relay`.
+    Relayed,
+    /// Through other groups, see `protocol::routing`. The group is the one the message arrived
+    /// through, which the peer might not be in.
+    Routed,
+}
+
+/// How to authenticate a Wi-Fi Direct connection to a peer, i.e. the WPS configuration method.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Provisioning {
+    /// Push-button: the connection is accepted on both ends without any secret.
+    #[default]
+    PushButton,
+    /// We display a PIN for the user to enter on the peer. `None` generates a random one, which
+    /// is passed to `P2PSessionListener::display_pin`.
+    DisplayPin(Option<String>),
+    /// The user enters here the PIN the peer displays.
+    Keypad(String),
+}
+
 pub trait P2PSessionListener<S: P2PSession>: Debug + Send + Sync {
     fn peer_discovered(&self, sess: &S, peer_id: PeerId) {
         trace!(
@@ -51,9 +76,60 @@ pub trait P2PSessionListener<S: P2PSession>: Debug + Send + Sync {
         trace!("Listener::peer_left_group({group_id:?}, {peer_id:?})");
     }
 
+    /// Called when the user needs to enter `pin` on a peer to connect to it, either because we
+    /// connect with `Provisioning::DisplayPin`, or because the peer asked to connect entering our
+    /// PIN.
+    fn display_pin(&self, _: &S, peer_id: PeerId, pin: &str) {
+        trace!("Listener::display_pin({peer_id:?}, {pin})");
+    }
+
+    /// Called when a peer wants to connect displaying a PIN, which the user needs to enter here.
+    /// Pass it to `P2PSession::connect_to_peer_with_provisioning` as `Provisioning::Keypad` to
+    /// accept the connection.
+    fn pin_requested(&self, _: &S, peer_id: PeerId) {
+        trace!("Listener::pin_requested({peer_id:?})");
+    }
+
+    /// Called when a peer invites us to a group, either one it's in, or a persistent group we were
+    /// in together. Returns whether to join it, which we don't by default.
+    fn invitation_received(&self, _: &S, peer_id: PeerId) -> bool {
+        trace!("Listener::invitation_received({peer_id:?})");
+        false
+    }
+
     fn peer_messaged(&self, _: &S, peer_id: PeerId, group_id: GroupId, message: &[u8]) {
         trace!("Listener::peer_messaged({peer_id:?}, {group_id:?}, {message:?})");
     }
+
+    /// Like `peer_messaged`, but also tells whether the message came directly from the peer, or
+    /// through the group owner or other groups. By default this just calls `peer_messaged`.
+    fn peer_messaged_via(
+        &self,
+        sess: &S,
+        peer_id: PeerId,
+        group_id: GroupId,
+        message: &[u8],
+        _delivery: Delivery,
+    ) {
+        self.peer_messaged(sess, peer_id, group_id, message)
+    }
+
+    /// Called for messages routed to us from peers we haven't discovered ourselves, see
+    /// `P2PSession::message_remote_peer`. Messages from discovered peers go to
+    /// `peer_messaged_via` instead.
+    fn remote_peer_messaged(
+        &self,
+        _: &S,
+        from: &protocol::identity::LogicalPeerIdentity,
+        message: &[u8],
+    ) {
+        trace!("Listener::remote_peer_messaged({from:?}, {message:?})");
+    }
+
+    /// Called for each datagram received from a peer, see `P2PSession::send_datagram`.
+    fn peer_datagram(&self, _: &S, peer_id: PeerId, group_id: GroupId, datagram: &[u8]) {
+        trace!("Listener::peer_datagram({peer_id:?}, {group_id:?}, {datagram:?})");
+    }
 }
 
 /// A listener implementation that logs.
@@ -92,16 +168,12 @@ pub trait P2PSession: Sized + Debug + Send + Sync + 'static {
 
     /// Explicitly start peer discovery.
     ///
-    /// TODO(emilio): For now this doesn't do any filtering. In practice, eventually, it'd be good
-    /// to set up pre-association discovery, via something like Bonjour[1][2] or UPnP[3][4].
+    /// This also queries the devices around for the service they advertise, via DNS-SD[1], so
+    /// that only the ones matching the session's `DiscoveryFilter` are reported through
+    /// `peer_discovered` (see the `service` module). With `DiscoveryFilter::All`, every device is
+    /// reported right away, even if it doesn't know our protocol.
     ///
     /// [1]: http://dns-sd.org/ServiceTypes.html
-    /// [2]: https://www.iana.org/form/ports-services
-    /// [3]: http://upnp.org
-    /// [4]: https://docs.macchina.io/edge/00200-UPnPSSDPTutorialAndUserGuide.html
-    ///
-    /// For now we just use post-association discovery (i.e., this discovers peers but those peers
-    /// might not know our protocol or anything).
     ///
     /// TODO(emilio): You might want to configure how persistent this really is etc.
     async fn discover_peers(&self) -> GenericResult<()>;
@@ -116,8 +188,81 @@ pub trait P2PSession: Sized + Debug + Send + Sync + 'static {
     /// Returns the current device's identity
     fn own_identity(&self) -> &protocol::identity::OwnIdentity;
 
-    async fn connect_to_peer(&self, id: PeerId) -> GenericResult<()>;
+    /// Connects to a peer with `Provisioning::PushButton`.
+    async fn connect_to_peer(&self, id: PeerId) -> GenericResult<()> {
+        self.connect_to_peer_with_provisioning(id, Provisioning::PushButton)
+            .await
+    }
+
+    /// Connects to a peer, authenticating the connection as given.
+    async fn connect_to_peer_with_provisioning(
+        &self,
+        id: PeerId,
+        provisioning: Provisioning,
+    ) -> GenericResult<()>;
+
+    /// Try to send a message to a given peer, with `Priority::Interactive`.
+    async fn message_peer(&self, id: PeerId, message: &[u8]) -> GenericResult<()> {
+        self.message_peer_with_priority(id, message, protocol::priority::Priority::Interactive)
+            .await
+    }
+
+    /// Like `message_peer`, but fails with an `io::ErrorKind::WouldBlock` error instead of waiting
+    /// if the send queue to the peer is full.
+    async fn try_message_peer(&self, id: PeerId, message: &[u8]) -> GenericResult<()> {
+        self.try_message_peer_with_priority(id, message, protocol::priority::Priority::Interactive)
+            .await
+    }
+
+    /// Try to send a message to a given peer with a given priority. More urgent messages are sent
+    /// first, both to this peer and to others. Messages to other clients of a group we're a
+    /// client of are relayed through the group owner if needed, see the `relay` module, and
+    /// messages to peers in other groups are routed through them, see the `routing` module (in
+    /// both cases the priority is ignored). If too many messages with this priority are already
+    /// waiting to be sent to the peer (see `Limits::max_send_queue_size`), or the peer is slow to
+    /// consume them, this waits.
+    async fn message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: protocol::priority::Priority,
+    ) -> GenericResult<()>;
 
-    /// Try to send a message to a given peer.
-    async fn message_peer(&self, id: PeerId, message: &[u8]) -> GenericResult<()>;
+    /// Like `message_peer_with_priority`, but fails with an `io::ErrorKind::WouldBlock` error
+    /// instead of waiting if the send queue to the peer is full.
+    async fn try_message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: protocol::priority::Priority,
+    ) -> GenericResult<()>;
+
+    /// Returns the peers we can reach through other groups, along with their distance in hops, see
+    /// the `routing` module. They don't need to have been discovered.
+    fn reachable_peers(&self) -> Vec<(protocol::identity::LogicalPeerIdentity, u8)>;
+
+    /// Sends a message to a peer several groups away given its logical identity, see
+    /// `reachable_peers`.
+    async fn message_remote_peer(
+        &self,
+        to: &protocol::identity::LogicalPeerIdentity,
+        message: &[u8],
+    ) -> GenericResult<()>;
+
+    /// Returns the features agreed upon with a given peer, or `None` if it hasn't associated with
+    /// us yet. Apps can use this to avoid features older ngn builds don't support.
+    fn peer_capabilities(&self, id: PeerId) -> Option<protocol::capabilities::Capabilities>;
+
+    /// Returns details about how we're talking to a given peer (e.g. the cipher suite in use), or
+    /// `None` if it hasn't associated with us yet.
+    fn peer_diagnostics(&self, id: PeerId) -> Option<protocol::PeerDiagnostics>;
+
+    /// Send an unreliable datagram to a given peer. Datagrams might be lost, duplicated or
+    /// reordered, and can't be longer than `max_datagram_size()`.
+    async fn send_datagram(&self, id: PeerId, datagram: &[u8]) -> GenericResult<()>;
+
+    /// The maximum size of the datagrams that can be sent via `send_datagram`.
+    fn max_datagram_size(&self) -> usize {
+        protocol::datagram::MAX_PAYLOAD_LEN
+    }
 }
@@ -5,7 +5,7 @@
 //! like `slaac private`, it will fail to connect to the GO. Other neighbor discovery approaches
 //! could be used in the future.
 
-use handy::HandleMap;
+use handy::{Handle, HandleMap};
 use jni::{
     objects::{GlobalRef, JByteArray, JClass, JObject, JObjectArray, JString},
     JNIEnv, JavaVM,
@@ -14,12 +14,28 @@ use jni_sys::{jboolean, jlong};
 
 use crate::{
     protocol::{
-        self, identity::OwnIdentity, key_exchange::KeyExchange, ControlMessage, P2pPorts,
-        PeerAddress, PeerGroupInfo, PeerIdentity, PeerOwnIdentifier, PhysiscalPeerIdentity,
+        self,
+        capabilities::Capabilities,
+        connection::{ConnectionDelegate, ConnectionManager, ConnectionTarget, QueuedMessage},
+        datagram,
+        encryption::Keys,
+        identity::{LogicalPeerIdentity, OwnIdentity},
+        key_exchange::KeyExchange,
+        limits::{ConnectionLimiter, KeyedRateLimiter, Limits},
+        padding::PaddingPolicy,
+        priority::Priority,
+        relay,
+        resumption::ResumptionCache,
+        roster,
+        routing::{self, RoutingTable},
+        service::{DiscoveryFilter, ServiceRecord},
+        signing::MaybeInvalidPublicKey,
+        wire, ControlMessage, GroupDelegate, NameMatching, P2pPorts, PeerAddress, PeerDiagnostics,
+        PeerGroupInfo, PeerIdentity, PeerOwnIdentifier, PhysiscalPeerIdentity, Store,
         GO_CONTROL_PORT,
     },
     utils::{self, trivial_error},
-    GenericResult, GroupId, P2PSession, P2PSessionListener, PeerId,
+    Delivery, GenericResult, GroupId, P2PSession, P2PSessionListener, PeerId, Provisioning,
 };
 use macaddr::MacAddr;
 
@@ -33,7 +49,10 @@ use std::{
     sync::{Arc, OnceLock},
     time::Duration,
 };
-use tokio::{self, net::TcpListener};
+use tokio::{
+    self,
+    net::{TcpListener, UdpSocket},
+};
 use tokio::{sync::mpsc, task::JoinHandle};
 
 // const WPS_METHOD: &'static str = "pbc";
@@ -49,11 +68,20 @@ fn rt() -> &'static tokio::runtime::Runtime {
     })
 }
 
+/// `WpsInfo` setups, see `Session::connect_to_peer_with_provisioning`.
+const WPS_SETUP_PBC: i32 = 0;
+const WPS_SETUP_DISPLAY: i32 = 1;
+const WPS_SETUP_KEYPAD: i32 = 2;
+
 /// Representation of system messages that we need to handle
 #[derive(Debug)]
 enum JavaNotification {
     // FindStopped,
     UpdateDevices(Vec<PhysiscalPeerIdentity>),
+    ServiceFound {
+        dev_addr: MacAddr,
+        record: ServiceRecord,
+    },
     // InvitationReceived,
     // InvitationResult,
     // WpsFailed,
@@ -72,9 +100,13 @@ enum JavaNotification {
     // PersistentGroupRemoved(..),
     // PersistentGroupsChanged(..),
     // ProvisionDiscoveryFailure(..),
-    // ProvisionDiscoveryRequestDisplayPin(..),
-    // ProvisionDiscoveryResponseDisplayPin(..),
-    // ProvisionDiscoveryRequestEnterPin(..),
+    /// A peer asked to connect entering the given PIN, which we need to display.
+    DisplayPin {
+        dev_addr: MacAddr,
+        pin: String,
+    },
+    /// A peer asked to connect displaying a PIN, which we need to enter.
+    PinRequested(MacAddr),
     // ProvisionDiscoveryPbcRequest(..),
     // ProvisionDiscoveryPbcResponse(..),
     // PeersChanged(..),
@@ -82,26 +114,53 @@ enum JavaNotification {
 }
 
 #[derive(Debug)]
-struct AndroidPeerData;
+pub struct AndroidPeerData;
 type Peer = protocol::PeerInfo<AndroidPeerData>;
 
 #[derive(Debug)]
-struct AndroidGroupData {
+pub struct AndroidGroupData {
     go_device_address: MacAddr,
 }
 type Group = protocol::GroupInfo<AndroidGroupData>;
 
 #[derive(Debug, Default)]
-struct PeerStore {
+pub struct PeerStore {
     map: HandleMap<Peer>,
     /// This is only used to speed up device updates, we could also track name to id.
     mac_to_id: HashMap<MacAddr, PeerId>,
+    /// Services advertised by devices that weren't in the peer list yet.
+    pending_services: HashMap<MacAddr, ServiceRecord>,
 }
 
 impl PeerStore {
     fn clear(&mut self) {
         self.map.clear();
         self.mac_to_id.clear();
+        self.pending_services.clear();
+    }
+}
+
+impl Store<Peer> for PeerStore {
+    fn get(&self, handle: Handle) -> Option<&Peer> {
+        self.map.get(handle)
+    }
+
+    fn get_mut(&mut self, handle: Handle) -> Option<&mut Peer> {
+        self.map.get_mut(handle)
+    }
+
+    fn iter_with_handles<'a>(&'a self) -> impl Iterator<Item = (Handle, &'a Peer)>
+    where
+        Peer: 'a,
+    {
+        self.map.iter_with_handles()
+    }
+
+    fn iter_mut_with_handles<'a>(&'a mut self) -> impl Iterator<Item = (Handle, &'a mut Peer)>
+    where
+        Peer: 'a,
+    {
+        self.map.iter_mut_with_handles()
     }
 }
 
@@ -120,6 +179,18 @@ fn peer_identity_to_jni<'local>(
     ))
 }
 
+fn byte_array_to_vec(env: &mut JNIEnv<'_>, array: &JByteArray<'_>) -> Vec<u8> {
+    let len = env.get_array_length(array).unwrap();
+    let mut buf = vec![0u8; len as usize];
+    {
+        // SAFETY: u8 and i8 share representation, it's just more convenient for us to use u8.
+        let signed_buf =
+            unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut i8, buf.len()) };
+        env.get_byte_array_region(array, 0, signed_buf).unwrap();
+    }
+    buf
+}
+
 /// Global state for a P2P session.
 #[derive(Debug)]
 pub struct Session {
@@ -135,6 +206,26 @@ pub struct Session {
     /// The name we expose to our P2P peers. We store it instead of the device address because the
     /// P2P device address is not exposed to non-privileged apps.
     name: String,
+    /// Resource limits for incoming connections and messages.
+    limits: Limits,
+    /// How to pad outgoing peer records.
+    padding: PaddingPolicy,
+    /// Tickets to resume our sessions with recently seen peers.
+    resumption: ResumptionCache,
+    /// Our routes to peers in other groups, see the `routing` module.
+    routing: RwLock<RoutingTable>,
+    /// The service we advertise, see the `service` module.
+    service: ServiceRecord,
+    /// Which discovered devices we report as peers.
+    discovery_filter: DiscoveryFilter,
+    /// How to match peers that identify themselves by name.
+    name_matching: NameMatching,
+    /// Currently open incoming connections to our control ports, by source address.
+    control_connections: ConnectionLimiter<IpAddr>,
+    /// Currently open incoming connections to our p2p ports, by peer.
+    peer_connections: ConnectionLimiter<PeerId>,
+    /// Open connections to our peers.
+    connections: ConnectionManager,
 }
 
 impl Drop for Session {
@@ -155,6 +246,19 @@ pub struct SessionInit<'a> {
     pub p2p_name: String,
     /// Identity for message signing and verification.
     pub identity: OwnIdentity,
+    /// Resource limits for incoming connections and messages.
+    pub limits: Limits,
+    /// How to pad outgoing peer records, to hide their length. See the `padding` module.
+    pub padding: PaddingPolicy,
+    /// How long after losing a peer we can bind a new session with it to the previous one, see the
+    /// `resumption` module.
+    pub resumption_window: Duration,
+    /// The app we advertise ourselves for, see the `service` module.
+    pub app_id: String,
+    /// Which discovered devices to report as peers.
+    pub discovery_filter: DiscoveryFilter,
+    /// How to match peers that identify themselves by name, which Android peers always do.
+    pub name_matching: NameMatching,
     pub _phantom: std::marker::PhantomData<&'a ()>,
 }
 
@@ -179,7 +283,7 @@ impl P2PSession for Session {
         init: SessionInit<'_>,
         listener: Arc<dyn P2PSessionListener<Self>>,
     ) -> GenericResult<Arc<Self>> {
-        Ok(Self::new_sync(init, listener))
+        Self::new_sync(init, listener)
     }
 
     async fn wait(&self) -> GenericResult<()> {
@@ -194,6 +298,7 @@ impl P2PSession for Session {
     async fn stop(&self) -> GenericResult<()> {
         trace!("Session::stop");
         // TODO: More graceful termination.
+        self.connections.clear();
         self.groups.write().clear();
         self.peers.write().clear();
         if let Some(ref t) = *self.run_loop_task.read() {
@@ -204,13 +309,22 @@ impl P2PSession for Session {
 
     async fn discover_peers(&self) -> GenericResult<()> {
         trace!("Session::discover_peers");
-        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (tx, rx) = tokio::sync::oneshot::channel::<GenericResult<()>>();
+        let (services_tx, services_rx) = tokio::sync::oneshot::channel();
         {
             let tx_long = Box::leak(Box::new(tx)) as *mut _ as jlong;
+            let services_tx_long = Box::leak(Box::new(services_tx)) as *mut _ as jlong;
             let mut env = self.vm.attach_current_thread()?;
             self.call_proxy(&mut env, "(J)V", "discoverPeers", &[tx_long.into()])?;
+            self.call_proxy(
+                &mut env,
+                "(J)V",
+                "discoverServices",
+                &[services_tx_long.into()],
+            )?;
         }
-        rx.await?
+        rx.await??;
+        services_rx.await?
     }
 
     fn peer_identity(&self, id: PeerId) -> Option<PeerIdentity> {
@@ -222,6 +336,7 @@ impl P2PSession for Session {
             .read()
             .map
             .iter_with_handles()
+            .filter(|(_, info)| self.is_discovered(info))
             .map(|(id, info)| (PeerId(id), info.identity.clone()))
             .collect()
     }
@@ -230,8 +345,12 @@ impl P2PSession for Session {
         &self.identity
     }
 
-    async fn connect_to_peer(&self, id: PeerId) -> GenericResult<()> {
-        trace!("Session::connect_to_peer({id:?})");
+    async fn connect_to_peer_with_provisioning(
+        &self,
+        id: PeerId,
+        provisioning: Provisioning,
+    ) -> GenericResult<()> {
+        trace!("Session::connect_to_peer_with_provisioning({id:?}, {provisioning:?})");
         let device_address = {
             let peers = self.peers.read();
             let Some(peer) = peers.map.get(id.0) else {
@@ -239,51 +358,232 @@ impl P2PSession for Session {
             };
             peer.identity.physical.dev_addr
         };
+        // The WpsInfo setup and PIN. Android doesn't tell us the PINs it generates, so we generate
+        // them ourselves to pass them to the listener.
+        let (setup, pin) = match provisioning {
+            Provisioning::PushButton => (WPS_SETUP_PBC, None),
+            Provisioning::DisplayPin(pin) => {
+                let pin = match pin {
+                    Some(pin) => pin,
+                    None => {
+                        let pin = utils::new_wps_pin()?;
+                        self.display_pin(id, &pin);
+                        pin
+                    }
+                };
+                (WPS_SETUP_DISPLAY, Some(pin))
+            }
+            Provisioning::Keypad(pin) => (WPS_SETUP_KEYPAD, Some(pin)),
+        };
+        if pin
+            .as_deref()
+            .is_some_and(|pin| !utils::is_valid_wps_pin(pin))
+        {
+            return Err(trivial_error!("Invalid WPS PIN"));
+        }
         let (tx, rx) = tokio::sync::oneshot::channel();
         {
             let mut env = self.vm.attach_current_thread()?;
             let tx_long = Box::leak(Box::new(tx)) as *mut _ as jlong;
             let peer_address = env.new_string(device_address.to_string())?;
-            env.call_method(
-                self.proxy.as_obj(),
-                "(Ljava/lang/String;J)V",
+            let pin = match pin {
+                Some(pin) => env.new_string(pin)?.into(),
+                None => JObject::null(),
+            };
+            self.call_proxy(
+                &mut env,
+                "(Ljava/lang/String;ILjava/lang/String;J)V",
                 "connectToPeer",
-                &[(&peer_address).into(), tx_long.into()],
+                &[
+                    (&peer_address).into(),
+                    setup.into(),
+                    (&pin).into(),
+                    tx_long.into(),
+                ],
             )?;
         }
         rx.await?
     }
 
-    async fn message_peer(&self, id: PeerId, message: &[u8]) -> GenericResult<()> {
-        let (peer_address, encryption_keys, scope_id) = {
+    async fn message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let target = match self.message_target(id) {
+            Ok(target) => target,
+            Err(e) => return routing::message_indirectly(self, id, message, e).await,
+        };
+        let session = self.to_strong();
+        let message = self
+            .connections
+            .queue_message(&session, &target, message, priority)
+            .await?;
+        self.send_queued_message(id, message).await
+    }
+
+    async fn try_message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let target = match self.message_target(id) {
+            Ok(target) => target,
+            Err(e) => return routing::message_indirectly(self, id, message, e).await,
+        };
+        let session = self.to_strong();
+        let message = self
+            .connections
+            .try_queue_message(&session, &target, message, priority)?;
+        self.send_queued_message(id, message).await
+    }
+
+    fn reachable_peers(&self) -> Vec<(LogicalPeerIdentity, u8)> {
+        self.routing
+            .read()
+            .routes()
+            .map(|route| (route.destination.clone(), route.hops))
+            .collect()
+    }
+
+    async fn message_remote_peer(
+        &self,
+        to: &LogicalPeerIdentity,
+        message: &[u8],
+    ) -> GenericResult<()> {
+        routing::send(self, &to.key, message).await
+    }
+
+    fn peer_capabilities(&self, id: PeerId) -> Option<Capabilities> {
+        self.peers.read().map.get(id.0)?.capabilities
+    }
+
+    fn peer_diagnostics(&self, id: PeerId) -> Option<PeerDiagnostics> {
+        self.peers.read().map.get(id.0)?.diagnostics()
+    }
+
+    async fn send_datagram(&self, id: PeerId, datagram: &[u8]) -> GenericResult<()> {
+        let (socket, address, keys) = {
             let peers = self.peers.read();
             let groups = self.groups.read();
             let Some(peer) = peers.map.get(id.0) else {
                 return Err(trivial_error!("Peer was lost (stale handle?)"));
             };
             let Some(keys) = peer.key_exchange.encryption_keys() else {
-                return Err(trivial_error!("Key exchange hasn't completed (yet?)"));
+                return Err(trivial_error!("Key exchange hasn't finished yet?"));
             };
-            // Choose one arbitrary group to connect to it.
+            if !peer.capabilities.is_some_and(|c| c.datagrams) {
+                return Err(trivial_error!("Peer doesn't support datagrams"));
+            }
             let Some(group_id) = peer.groups.first() else {
-                // TODO: Maybe we want to call connect_to_peer automatically?
                 return Err(trivial_error!("Peer is not connected to any group"));
             };
             let Some(group) = groups.get(group_id.0) else {
-                // TODO: Maybe we want to call connect_to_peer automatically?
                 return Err(trivial_error!("Group not found"));
             };
-            let Some(address) = group.peers.get(&id).map(|info| info.address.clone()) else {
+            let Some(info) = group.peers.get(&id) else {
                 return Err(trivial_error!(
                     "Peer doesn't have a link local address (yet?)"
                 ));
             };
-            (address, Arc::clone(keys), group.scope_id)
+            let Some(socket) = group.datagram_socket.get() else {
+                return Err(trivial_error!("Group isn't ready for datagrams yet"));
+            };
+            (
+                Arc::clone(socket),
+                protocol::peer_to_socket_addr(
+                    info.address.address,
+                    group.scope_id,
+                    info.address.ports.datagram,
+                ),
+                Arc::clone(keys),
+            )
         };
-        let socket_addr =
-            protocol::peer_to_socket_addr(peer_address.address, scope_id, peer_address.ports.p2p);
+        datagram::send(&socket, &keys, &address, datagram).await
+    }
+}
+
+impl ConnectionDelegate for Session {
+    fn identity(&self) -> &OwnIdentity {
+        &self.identity
+    }
+
+    fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    fn padding(&self) -> PaddingPolicy {
+        self.padding
+    }
+
+    fn connections(&self) -> &ConnectionManager {
+        &self.connections
+    }
+
+    fn connection_message(&self, peer_id: PeerId, group_id: GroupId, message: &[u8]) {
+        trace!(
+            "Got message from {peer_id:?}: {:?}",
+            String::from_utf8_lossy(message)
+        );
+        self.peer_messaged(peer_id, group_id, message, Delivery::Direct);
+    }
+}
+
+impl GroupDelegate for Session {
+    type PeerData = AndroidPeerData;
+    type GroupData = AndroidGroupData;
+    type Peers = PeerStore;
+    type Groups = HandleMap<Group>;
+
+    fn peers(&self) -> &RwLock<PeerStore> {
+        &self.peers
+    }
+
+    fn groups(&self) -> &RwLock<HandleMap<Group>> {
+        &self.groups
+    }
+
+    fn name_matching(&self) -> NameMatching {
+        self.name_matching
+    }
+
+    fn group_owner<'a>(peers: &'a PeerStore, group: &Group) -> Option<&'a Peer> {
+        if group.is_go {
+            return None;
+        }
+        let go_id = peers.mac_to_id.get(&group.data.go_device_address)?;
+        peers.map.get(go_id.0)
+    }
+
+    fn routing(&self) -> &RwLock<RoutingTable> {
+        &self.routing
+    }
+
+    fn peer_messaged(&self, peer_id: PeerId, group_id: GroupId, buf: &[u8], delivery: Delivery) {
+        if let Err(e) = self.peer_messaged_internal(peer_id, group_id, buf, delivery) {
+            error!("Failed to broadcast peer message to java: {e}");
+        }
+    }
+
+    fn remote_peer_messaged(&self, from: &LogicalPeerIdentity, message: &[u8]) {
+        self.listener.remote_peer_messaged(self, from, message);
+    }
+
+    async fn send_control_message(
+        &self,
+        address: IpAddr,
+        control_port: u16,
+        scope_id: u32,
+        version: u16,
+        message: ControlMessage,
+    ) -> GenericResult<()> {
+        trace!("Session::send_control_message({address:?}, {scope_id}, {version}, {message:?})");
+        let socket_addr = protocol::peer_to_socket_addr(address, scope_id, control_port);
+        let msg = wire::encode(version, &message)?;
         utils::retry_timeout(Duration::from_secs(2), 5, || {
-            protocol::send_message(Some(&self.identity), Some(&*encryption_keys), &socket_addr, message)
+            protocol::send_message(None, None, &socket_addr, version, &msg)
         })
         .await
     }
@@ -294,8 +594,13 @@ impl Session {
         PeerOwnIdentifier::Name(self.name.clone())
     }
 
-    fn new_sync(init: SessionInit<'_>, listener: Arc<dyn P2PSessionListener<Self>>) -> Arc<Self> {
+    fn new_sync(
+        init: SessionInit<'_>,
+        listener: Arc<dyn P2PSessionListener<Self>>,
+    ) -> GenericResult<Arc<Self>> {
+        init.limits.validate()?;
         let (tx, rx) = mpsc::unbounded_channel();
+        let service = ServiceRecord::ours(&init.identity, &init.app_id)?;
         let session = Arc::new(Self {
             peers: Default::default(),
             groups: Default::default(),
@@ -306,44 +611,273 @@ impl Session {
             listener,
             name: init.p2p_name,
             run_loop_task: RwLock::new(None),
+            control_connections: ConnectionLimiter::new(
+                init.limits.max_incoming_connections,
+                init.limits.max_incoming_connections_per_peer,
+            ),
+            peer_connections: ConnectionLimiter::new(
+                init.limits.max_incoming_connections,
+                init.limits.max_incoming_connections_per_peer,
+            ),
+            limits: init.limits,
+            padding: init.padding,
+            resumption: ResumptionCache::new(init.resumption_window),
+            routing: Default::default(),
+            service,
+            discovery_filter: init.discovery_filter,
+            name_matching: init.name_matching,
+            connections: Default::default(),
         });
 
         let handle = rt().spawn(Session::run_loop(Arc::clone(&session), rx));
         *session.run_loop_task.write() = Some(handle);
 
-        session
+        session.advertise_service()?;
+        Ok(session)
     }
 
-    fn peer_id_from_address(&self, group_id: GroupId, address: &SocketAddr) -> Option<PeerId> {
-        // TODO: This lookup could be faster, really.
+    /// Whether we report a peer as discovered, given what it advertises.
+    fn is_discovered(&self, peer: &Peer) -> bool {
+        self.discovery_filter
+            .accepts(peer.identity.service.as_ref())
+    }
+
+    /// Registers our service with the system, see the `service` module. Android builds the
+    /// Bonjour records itself from the TXT record.
+    fn advertise_service(&self) -> GenericResult<()> {
+        trace!("Advertising {:?}", self.service);
+        let mut env = self.vm.attach_current_thread()?;
+        let txt = self.service.to_txt();
+        let arr = env.new_object_array(txt.len() as i32 * 2, "java/lang/String", unsafe {
+            JString::from_raw(ptr::null_mut())
+        })?;
+        for (i, (key, value)) in txt.iter().enumerate() {
+            let key = env.new_string(key)?;
+            let value = env.new_string(value)?;
+            env.set_object_array_element(&arr, i as i32 * 2, key)?;
+            env.set_object_array_element(&arr, i as i32 * 2 + 1, value)?;
+        }
+        let instance_name = env.new_string(self.service.instance_name())?;
+        self.call_proxy(
+            &mut env,
+            "(Ljava/lang/String;[Ljava/lang/String;)V",
+            "advertiseService",
+            &[(&instance_name).into(), (&arr).into()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns what we need to connect to a peer to message it.
+    fn message_target(&self, id: PeerId) -> GenericResult<ConnectionTarget> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let Some(peer) = peers.map.get(id.0) else {
+            return Err(trivial_error!("Peer was lost (stale handle?)"));
+        };
+        let Some(keys) = peer.key_exchange.encryption_keys() else {
+            return Err(trivial_error!("Key exchange hasn't completed (yet?)"));
+        };
+        let Some(identity) = peer.identity.verified_logical() else {
+            return Err(trivial_error!(
+                "Peer doesn't have a logical identity (yet?)"
+            ));
+        };
+        let Some(capabilities) = peer.capabilities else {
+            return Err(trivial_error!("Peer hasn't associated (yet?)"));
+        };
+        // Choose one arbitrary group to connect to it.
+        let Some(group_id) = peer.groups.first() else {
+            // TODO: Maybe we want to call connect_to_peer automatically?
+            return Err(trivial_error!("Peer is not connected to any group"));
+        };
+        let Some(group) = groups.get(group_id.0) else {
+            // TODO: Maybe we want to call connect_to_peer automatically?
+            return Err(trivial_error!("Group not found"));
+        };
+        let Some(address) = group.peers.get(&id).map(|info| info.address.clone()) else {
+            return Err(trivial_error!(
+                "Peer doesn't have a link local address (yet?)"
+            ));
+        };
+        Ok(ConnectionTarget {
+            peer_id: id,
+            group_id: *group_id,
+            address: protocol::peer_to_socket_addr(
+                address.address,
+                group.scope_id,
+                address.ports.p2p,
+            ),
+            identity: identity.clone(),
+            keys: Arc::clone(keys),
+            capabilities,
+            wire_version: peer.wire_version,
+        })
+    }
+
+    async fn send_queued_message(&self, id: PeerId, message: QueuedMessage) -> GenericResult<()> {
+        let session = self.to_strong();
+        utils::retry_timeout(Duration::from_secs(2), 5, || async {
+            // Look the peer up again every time, so that if it comes back after its group is
+            // re-formed, the message goes to its new address.
+            let target = self.message_target(id)?;
+            self.connections.send(&session, &target, &message).await
+        })
+        .await
+    }
+
+    /// Finds the peer in the given group with the given logical key, returning the keys we share
+    /// with it, used to authenticate incoming connections from `address`.
+    fn resolve_group_peer(
+        &self,
+        group_id: GroupId,
+        address: SocketAddr,
+        key: &MaybeInvalidPublicKey,
+    ) -> Option<(ConnectionTarget, Arc<Keys>)> {
+        let peers = self.peers.read();
         let groups = self.groups.read();
         let Some(group) = groups.get(group_id.0) else {
             error!("Group {group_id:?} lost?");
             return None;
         };
-        for (peer_id, info) in &group.peers {
-            if info.address.address == address.ip() {
-                return Some(*peer_id);
+        for peer_id in group.peers.keys() {
+            let Some(peer) = peers.map.get(peer_id.0) else {
+                continue;
+            };
+            let Some(logical) = peer.identity.verified_logical() else {
+                continue;
+            };
+            if logical.key != *key {
+                continue;
             }
+            let Some(keys) = peer.key_exchange.encryption_keys() else {
+                warn!("Peer {peer_id:?} connected but key exchange hasn't finished yet?");
+                return None;
+            };
+            let Some(capabilities) = peer.capabilities else {
+                warn!("Peer {peer_id:?} connected but hasn't associated yet?");
+                return None;
+            };
+            let target = ConnectionTarget {
+                peer_id: *peer_id,
+                group_id,
+                address,
+                identity: logical.clone(),
+                keys: Arc::clone(keys),
+                capabilities,
+                wire_version: peer.wire_version,
+            };
+            return Some((target, Arc::clone(keys)));
         }
-        error!("Address {address:?} couldn't be mapped to a known peer in the group!");
+        error!("Key {key:?} couldn't be mapped to a known peer in the group!");
         None
     }
 
-    async fn send_control_message(
+    /// Finds the peer in the given group with the given address, returning the keys we share
+    /// with it.
+    fn resolve_group_peer_by_address(
         &self,
+        group_id: GroupId,
         address: IpAddr,
-        control_port: u16,
+    ) -> Option<(PeerId, Arc<Keys>)> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let group = groups.get(group_id.0)?;
+        let (peer_id, _) = group
+            .peers
+            .iter()
+            .find(|(_, info)| info.address.address == address)?;
+        let keys = peers.map.get(peer_id.0)?.key_exchange.encryption_keys()?;
+        Some((*peer_id, Arc::clone(keys)))
+    }
+
+    /// Finds the peer in the given group with the address of an incoming connection if it
+    /// associated using wire version 1, in which connections aren't authenticated (see
+    /// `handshake::initiate`).
+    fn resolve_legacy_group_peer(
+        &self,
+        group_id: GroupId,
+        address: SocketAddr,
+    ) -> Option<ConnectionTarget> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let group = groups.get(group_id.0)?;
+        let (peer_id, _) = group
+            .peers
+            .iter()
+            .find(|(_, info)| info.address.address == address.ip())?;
+        let peer = peers.map.get(peer_id.0)?;
+        if peer.wire_version != 1 {
+            return None;
+        }
+        Some(ConnectionTarget {
+            peer_id: *peer_id,
+            group_id,
+            address,
+            identity: peer.identity.verified_logical()?.clone(),
+            keys: Arc::clone(peer.key_exchange.encryption_keys()?),
+            capabilities: peer.capabilities?,
+            wire_version: peer.wire_version,
+        })
+    }
+
+    /// Returns the wire version negotiated with the peer at the given address in a group, if any.
+    fn wire_version_for_address(&self, group_id: GroupId, address: IpAddr) -> u16 {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        groups
+            .get(group_id.0)
+            .and_then(|group| {
+                let (peer_id, _) = group
+                    .peers
+                    .iter()
+                    .find(|(_, info)| info.address.address == address)?;
+                Some(peers.map.get(peer_id.0)?.wire_version)
+            })
+            .unwrap_or(wire::MIN_VERSION)
+    }
+
+    /// Waits for the GO of a group we joined to associate back with us, returning whether it did
+    /// before the timeout.
+    async fn wait_for_association(&self, group_id: GroupId, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let associated = self
+                    .groups
+                    .read()
+                    .get(group_id.0)
+                    .is_some_and(|g| !g.peers.is_empty());
+                if associated {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Sends our association message to the GO of a group we joined, falling back to older wire
+    /// versions if the GO doesn't associate back (see the `wire` module).
+    async fn associate_with_go(
+        &self,
+        group_id: GroupId,
+        go_ip: IpAddr,
         scope_id: u32,
         message: ControlMessage,
     ) -> GenericResult<()> {
-        trace!("Session::send_control_message({address:?}, {scope_id}, {message:?})");
-        let socket_addr = protocol::peer_to_socket_addr(address, scope_id, control_port);
-        let msg = bincode::encode_to_vec(message, bincode::config::standard())?;
-        utils::retry_timeout(Duration::from_secs(2), 5, || {
-            protocol::send_message(None, None, &socket_addr, &msg)
-        })
-        .await
+        for version in [wire::CURRENT_VERSION, wire::MIN_VERSION] {
+            trace!("Trying to send control message to {go_ip:?} (version {version})");
+            self.send_control_message(go_ip, GO_CONTROL_PORT, scope_id, version, message.clone())
+                .await?;
+            if self
+                .wait_for_association(group_id, wire::ASSOCIATION_TIMEOUT)
+                .await
+            {
+                return Ok(());
+            }
+            warn!("GO didn't associate back with wire version {version}");
+        }
+        Ok(())
     }
 
     async fn establish_control_channel(
@@ -357,57 +891,127 @@ impl Session {
         trace!(
             "Session::establish_control_channel({group_id:?}, {scope_id}, {own_ports:?}, {is_go})"
         );
+        let mut rate_limiter = KeyedRateLimiter::new(
+            session.limits.control_connection_burst,
+            session.limits.control_connections_per_second,
+            session.limits.max_control_connection_sources,
+        );
         loop {
             // TODO: Use a buffered reader.
             // TODO: Keep a single stream around for faster bi-lateral communication maybe?
             let (mut stream, address) = control_listener.accept().await?;
+            // Known peers use the control port for relayed messages, rosters and routes, so only
+            // unknown addresses are rate limited.
+            let known = session
+                .groups
+                .read()
+                .get(group_id.0)
+                .is_some_and(|g| g.has_peer_at(address.ip()));
+            if !known && !rate_limiter.try_acquire(address.ip()) {
+                warn!("Rate limiting control connection from {address:?} in group {group_id:?}");
+                continue;
+            }
+            let Some(mut permit) = session.control_connections.try_acquire() else {
+                warn!("Too many control connections, dropping connection from {address:?}");
+                continue;
+            };
+            if !permit.attribute(address.ip()) {
+                warn!("Too many control connections from {address:?}, dropping connection");
+                continue;
+            }
             let session = Arc::clone(&session);
             tokio::spawn(async move {
+                let _permit = permit;
                 trace!("Incoming connection from {address:?}");
-                while let Ok(control_message) =
-                    protocol::read_control_message(&mut stream, &address).await
+                while let Ok((version, control_message)) =
+                    protocol::read_control_message(&mut stream, &address, &session.limits).await
                 {
-                    trace!("Got control message {control_message:?} on group {group_id:?}");
+                    trace!(
+                        "Got control message {control_message:?} (version {version}) on group {group_id:?}"
+                    );
                     match control_message {
                         ControlMessage::Associate {
                             physical_id,
                             logical_id,
                             ports,
                             key_exchange_public_key,
+                            capabilities,
+                            post_quantum_share,
+                            resumption_ticket,
                         } => {
-                            let (peer_id, key_exchange_public_key) = {
+                            let (peer_id, key_exchange_public_key, post_quantum_share, resumption) = {
                                 let mut peers = session.peers.write();
-                                let mut result = None;
-                                for (id, peer) in peers.map.iter_mut_with_handles() {
+                                let found = physical_id.find_peer(
+                                    peers.map.iter_mut_with_handles(),
+                                    |(_, p)| &p.identity.physical,
+                                    session.name_matching,
+                                );
+                                let found = match found {
+                                    Ok(found) => found,
+                                    Err(e) => {
+                                        error!("Refusing to associate {physical_id:?}: {e}");
+                                        continue;
+                                    }
+                                };
+                                let result = 'associate: {
+                                    let Some((id, peer)) = found else {
+                                        break 'associate None;
+                                    };
                                     trace!(" {:?} -> {:?}", id, peer.identity.logical);
-                                    if peer.identity.physical.matches(&physical_id) {
-                                        if peer
-                                            .identity
-                                            .logical
-                                            .as_ref()
-                                            .is_some_and(|i| *i != logical_id)
-                                        {
-                                            error!("Refusing to associate {id:?} with different logical {logical_id:?}");
-                                            break;
-                                        }
-                                        if peer.groups.contains(&group_id) {
-                                            error!("Refusing to associate {id:?} with {group_id:?} again");
-                                            break;
-                                        }
-                                        peer.groups.push(group_id);
-                                        if let Err(e) =
-                                            peer.key_exchange.finish(&key_exchange_public_key)
-                                        {
+                                    if peer
+                                        .identity
+                                        .verified_logical()
+                                        .is_some_and(|i| *i != logical_id)
+                                    {
+                                        error!("Refusing to associate {id:?} with different logical {logical_id:?}");
+                                        break 'associate None;
+                                    }
+                                    if peer.groups.contains(&group_id) {
+                                        error!(
+                                            "Refusing to associate {id:?} with {group_id:?} again"
+                                        );
+                                        break 'associate None;
+                                    }
+                                    peer.groups.push(group_id);
+                                    // The peer offers a ticket if we're the GO, and echoes
+                                    // ours if we're the client and the GO accepted it.
+                                    let resumption = resumption_ticket
+                                        .and_then(|id| session.resumption.redeem(&id, &logical_id));
+                                    let capabilities = Capabilities::ours(&session.limits)
+                                        .negotiate(&capabilities);
+                                    peer.capabilities = Some(capabilities);
+                                    peer.wire_version = version;
+                                    let post_quantum_share = match peer.key_exchange.finish(
+                                        &key_exchange_public_key,
+                                        post_quantum_share.as_ref(),
+                                        resumption.as_ref(),
+                                        capabilities.cipher_suite(),
+                                    ) {
+                                        Ok(reply) => reply,
+                                        Err(e) => {
                                             error!("Couldn't finish key exchange with {id:?}: {e}");
+                                            None
                                         }
-                                        peer.identity.logical = Some(logical_id);
-                                        result = Some((
-                                            PeerId(id),
-                                            peer.key_exchange.export_public_key(),
-                                        ));
-                                        break;
+                                    };
+                                    peer.identity.logical = Some(logical_id);
+                                    peer.identity.logical_verified = true;
+                                    if let (Some(keys), Some(logical)) = (
+                                        peer.key_exchange.encryption_keys(),
+                                        &peer.identity.logical,
+                                    ) {
+                                        session.resumption.store(
+                                            peer.identity.physical.dev_addr,
+                                            logical,
+                                            keys.resumption_ticket().clone(),
+                                        );
                                     }
-                                }
+                                    Some((
+                                        PeerId(id),
+                                        peer.key_exchange.export_public_key(),
+                                        post_quantum_share,
+                                        resumption.map(|ticket| ticket.id),
+                                    ))
+                                };
                                 match result {
                                     Some(r) => r,
                                     None => {
@@ -439,11 +1043,15 @@ impl Session {
                                         address,
                                         ports.control,
                                         scope_id,
+                                        version,
                                         ControlMessage::Associate {
                                             physical_id: session.own_physical_id(),
                                             logical_id: session.identity.to_public(),
                                             ports: own_ports,
                                             key_exchange_public_key,
+                                            capabilities: Capabilities::ours(&session.limits),
+                                            post_quantum_share,
+                                            resumption_ticket: resumption,
                                         },
                                     )
                                     .await;
@@ -456,6 +1064,98 @@ impl Session {
                                 .listener
                                 .peer_joined_group(&session, group_id, peer_id);
                             session.peers_changed();
+                            if is_go {
+                                tokio::spawn(roster::broadcast(Arc::clone(&session), group_id));
+                            }
+                            tokio::spawn(routing::announce(Arc::clone(&session), group_id));
+                        }
+                        ControlMessage::Roster { update, signature } => {
+                            // The roster might arrive before the association back from the GO.
+                            if !session
+                                .wait_for_association(group_id, wire::ASSOCIATION_TIMEOUT)
+                                .await
+                            {
+                                warn!("Got roster in {group_id:?} before associating with the GO");
+                                continue;
+                            }
+                            let (joined, left) =
+                                match roster::apply(&*session, group_id, update, &signature) {
+                                    Ok(r) => r,
+                                    Err(e) => {
+                                        error!(
+                                            "Dropping roster from {address:?} in {group_id:?}: {e}"
+                                        );
+                                        continue;
+                                    }
+                                };
+                            for peer_id in &joined {
+                                session
+                                    .listener
+                                    .peer_joined_group(&session, group_id, *peer_id);
+                            }
+                            for peer_id in &left {
+                                session.connections.close(*peer_id, group_id);
+                                session
+                                    .listener
+                                    .peer_left_group(&session, group_id, *peer_id);
+                            }
+                            if !joined.is_empty() || !left.is_empty() {
+                                session.peers_changed();
+                            }
+                        }
+                        ControlMessage::Relay {
+                            envelope,
+                            signature,
+                        } => {
+                            let result = if is_go {
+                                relay::forward(
+                                    &*session,
+                                    group_id,
+                                    address.ip(),
+                                    envelope,
+                                    signature,
+                                )
+                                .await
+                            } else {
+                                relay::receive(&*session, group_id, envelope, signature).await
+                            };
+                            if let Err(e) = result {
+                                warn!("Dropping relayed envelope from {address:?} in {group_id:?}: {e}");
+                            }
+                        }
+                        ControlMessage::Routes {
+                            announcement,
+                            signature,
+                        } => match routing::apply(
+                            &*session,
+                            group_id,
+                            address.ip(),
+                            &announcement,
+                            &signature,
+                        ) {
+                            Ok(true) => routing::routes_changed(&session),
+                            Ok(false) => {}
+                            Err(e) => {
+                                warn!("Dropping routes from {address:?} in {group_id:?}: {e}")
+                            }
+                        },
+                        ControlMessage::Routed {
+                            envelope,
+                            signature,
+                            ttl,
+                        } => {
+                            let result = routing::handle(
+                                &*session,
+                                group_id,
+                                address.ip(),
+                                envelope,
+                                signature,
+                                ttl,
+                            )
+                            .await;
+                            if let Err(e) = result {
+                                warn!("Dropping routed envelope from {address:?} in {group_id:?}: {e}");
+                            }
                         }
                     }
                 }
@@ -473,53 +1173,78 @@ impl Session {
     ) -> GenericResult<()> {
         trace!("Session::listen_to_peer_messages({group_id:?}, {scope_id})");
         loop {
-            // TODO: Use a buffered reader.
-            // TODO: Keep a single stream around for faster bi-lateral communication maybe?
             let (mut stream, address) = listener.accept().await?;
-            let Some(peer_id) = session.peer_id_from_address(group_id, &address) else {
-                warn!("Got message from {address:?} but couldn't map that to a peer in group {group_id:?}");
+            let Some(mut permit) = session.peer_connections.try_acquire() else {
+                warn!("Too many peer connections, dropping connection from {address:?}");
                 continue;
             };
-            let (peer_identity, encryption_keys) = {
-                let peers = session.peers.read();
-                let Some(peer) = peers.map.get(peer_id.0) else {
-                    warn!("Got message from {address:?} but peer is gone?");
-                    continue;
-                };
-                let Some(ref peer_identity) = peer.identity.logical else {
-                    warn!("Got message from {address:?} but peer doesn't yet have a logical id?");
-                    continue;
-                };
-                let Some(keys) = peer.key_exchange.encryption_keys() else {
-                    warn!("Got message from {address:?} but key exchange hasn't finished yet?");
-                    continue;
-                };
-                (peer_identity.clone(), Arc::clone(keys))
-            };
             let session = Arc::clone(&session);
             tokio::spawn(async move {
                 trace!("Incoming connection from {address:?}");
-                while let Ok(buf) = protocol::read_peer_message(
-                    &session.identity,
-                    &encryption_keys,
-                    &peer_identity,
-                    &mut stream,
-                    &address,
-                )
-                .await
-                {
-                    trace!(
-                        "Got message from socket: {:?}",
-                        String::from_utf8_lossy(&buf)
+                let accepted = match session.resolve_legacy_group_peer(group_id, address) {
+                    Some(target) => protocol::handshake::legacy_records(&target.keys)
+                        .map(|records| (target, records)),
+                    None => {
+                        let challenge_version =
+                            session.wire_version_for_address(group_id, address.ip());
+                        protocol::handshake::accept(
+                            &mut stream,
+                            &address,
+                            challenge_version,
+                            |key| session.resolve_group_peer(group_id, address, key),
+                        )
+                        .await
+                    }
+                };
+                let (target, records) = match accepted {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Dropping connection from {address:?} in group {group_id:?}: {e}");
+                        return;
+                    }
+                };
+                if !permit.attribute(target.peer_id) {
+                    warn!(
+                        "Too many connections from {:?}, dropping connection from {address:?}",
+                        target.peer_id
                     );
-                    session.peer_messaged(peer_id, group_id, &buf);
+                    return;
                 }
+                session
+                    .connections
+                    .adopt(&session, &target, stream, records, permit);
             });
         }
         #[allow(unreachable_code)]
         Ok(())
     }
 
+    async fn listen_to_datagrams(
+        session: Arc<Self>,
+        socket: Arc<UdpSocket>,
+        group_id: GroupId,
+    ) -> GenericResult<()> {
+        trace!("Session::listen_to_datagrams({group_id:?})");
+        let mut buf = vec![0u8; datagram::MAX_PACKET_LEN];
+        loop {
+            let (len, address) = socket.recv_from(&mut buf).await?;
+            let Some((peer_id, keys)) =
+                session.resolve_group_peer_by_address(group_id, address.ip())
+            else {
+                trace!("Dropping datagram from unknown address {address:?}");
+                continue;
+            };
+            let payload = match datagram::open(&keys, &mut buf[..len]) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    trace!("Dropping datagram from {address:?}: {e}");
+                    continue;
+                }
+            };
+            session.peer_datagram(peer_id, group_id, payload);
+        }
+    }
+
     async fn group_task(session: Arc<Self>, group_id: GroupId) -> GenericResult<()> {
         trace!("Session::group_task({group_id:?})");
 
@@ -550,28 +1275,52 @@ impl Session {
                 scope_id,
             )),
         )?;
+        let datagram_socket = Arc::new(
+            UdpSocket::bind(SocketAddrV6::new(
+                Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
+                0,
+                /* flowinfo = */ 0,
+                scope_id,
+            ))
+            .await?,
+        );
 
         let my_ports = P2pPorts {
             control: control_listener.local_addr()?.port(),
             p2p: p2p_listener.local_addr()?.port(),
+            datagram: datagram_socket.local_addr()?.port(),
         };
+        if let Some(group) = session.groups.read().get(group_id.0) {
+            let _ = group.datagram_socket.set(Arc::clone(&datagram_socket));
+        }
 
         trace!(" > go = {is_go}, ports = {my_ports:?}, go_ip = {go_ip:?}");
         if !is_go {
-            let key_exchange_public_key = {
-                let peers = session.peers.read();
+            let (key_exchange_public_key, post_quantum_share, resumption_ticket) = {
+                let mut peers = session.peers.write();
                 let id = match peers.mac_to_id.get(&go_dev_addr) {
-                    Some(i) => i,
+                    Some(i) => *i,
                     None => return Err(trivial_error!("Couldn't find GO in peer list?")),
                 };
-                let key = peers.map[id.0].key_exchange.export_public_key();
-                key
+                let go = &mut peers.map[id.0];
+                if go.groups.is_empty() {
+                    // We might have associated with this GO before, start over.
+                    go.key_exchange.restart_if_completed()?;
+                }
+                (
+                    go.key_exchange.export_public_key(),
+                    go.key_exchange.export_post_quantum_key(),
+                    session.resumption.offer(go_dev_addr),
+                )
             };
             let control_message = ControlMessage::Associate {
                 physical_id: session.own_physical_id(),
                 logical_id: session.identity.to_public(),
                 ports: my_ports,
                 key_exchange_public_key,
+                capabilities: Capabilities::ours(&session.limits),
+                post_quantum_share,
+                resumption_ticket,
             };
             tokio::try_join!(
                 Self::listen_to_peer_messages(
@@ -580,6 +1329,8 @@ impl Session {
                     group_id,
                     scope_id
                 ),
+                Self::listen_to_datagrams(Arc::clone(&session), datagram_socket, group_id),
+                routing::maintain(Arc::clone(&session), group_id),
                 Self::establish_control_channel(
                     Arc::clone(&session),
                     control_listener,
@@ -588,12 +1339,7 @@ impl Session {
                     my_ports,
                     is_go
                 ),
-                async move {
-                    trace!("Trying to send control message to {go_ip:?}");
-                    session
-                        .send_control_message(go_ip, GO_CONTROL_PORT, scope_id, control_message)
-                        .await
-                },
+                session.associate_with_go(group_id, go_ip, scope_id, control_message),
             )?;
             return Ok(());
         }
@@ -601,6 +1347,8 @@ impl Session {
         // TODO: peer_joined / peer_left? Also factor out with dbus code.
         tokio::try_join!(
             Self::listen_to_peer_messages(Arc::clone(&session), p2p_listener, group_id, scope_id),
+            Self::listen_to_datagrams(Arc::clone(&session), datagram_socket, group_id),
+            routing::maintain(Arc::clone(&session), group_id),
             Self::establish_control_channel(
                 Arc::clone(&session),
                 control_listener,
@@ -640,13 +1388,21 @@ impl Session {
                                 identity: PeerIdentity {
                                     physical: identity,
                                     logical: None,
+                                    logical_verified: false,
+                                    advertised_capabilities: None,
+                                    service: peers.pending_services.remove(&dev_addr),
                                 },
                                 key_exchange: KeyExchange::new().unwrap(),
+                                relay_key_exchange: None,
                                 groups: Vec::new(),
+                                capabilities: None,
+                                wire_version: wire::MIN_VERSION,
                                 data: AndroidPeerData,
                             }));
                             peers.mac_to_id.insert(dev_addr, id);
-                            peers_joined.push(id);
+                            if session.is_discovered(peers.map.get(id.0).unwrap()) {
+                                peers_joined.push(id);
+                            }
                         }
                         if seen_ids.len() != peers.mac_to_id.len() {
                             // Some device has been lost.
@@ -659,13 +1415,18 @@ impl Session {
                                     return false;
                                 };
                                 trace!("Peer lost: {peer:?}");
-                                peers_lost.push((*id, std::mem::take(&mut peer.groups)));
+                                let discovered = session.is_discovered(peer);
+                                peers_lost.push((
+                                    *id,
+                                    std::mem::take(&mut peer.groups),
+                                    discovered,
+                                ));
                                 false
                             });
                         }
                     }
                     let changed = !peers_lost.is_empty() || !peers_joined.is_empty();
-                    for (peer_id, groups_disconnected) in peers_lost {
+                    for (peer_id, groups_disconnected, discovered) in peers_lost {
                         // Remove the peer for any outstanding groups before notifying the listener
                         // of the peer being lost.
                         if !groups_disconnected.is_empty() {
@@ -685,9 +1446,16 @@ impl Session {
                                 session
                                     .listener
                                     .peer_left_group(&session, *group_id, peer_id);
+                                tokio::spawn(roster::broadcast(Arc::clone(&session), *group_id));
+                                if session.routing.write().remove_neighbour(peer_id, *group_id) {
+                                    routing::routes_changed(&session);
+                                }
                             }
                         }
-                        session.listener.peer_lost(&session, peer_id);
+                        session.connections.close_peer(peer_id);
+                        if discovered {
+                            session.listener.peer_lost(&session, peer_id);
+                        }
                         let removed = session.peers.write().map.remove(peer_id.0);
                         debug_assert!(removed.is_some(), "Found id but couldn't remove peer?");
                     }
@@ -698,6 +1466,43 @@ impl Session {
                         session.peers_changed();
                     }
                 }
+                JavaNotification::ServiceFound { dev_addr, record } => {
+                    trace!("{dev_addr} advertises {record:?}");
+                    let peer_id = {
+                        let mut peers = session.peers.write();
+                        let Some(id) = peers.mac_to_id.get(&dev_addr).copied() else {
+                            // We'll get to it once it's in the peer list.
+                            peers.pending_services.insert(dev_addr, record);
+                            continue;
+                        };
+                        let Some(peer) = peers.map.get_mut(id.0) else {
+                            error!("Store out of sync for {dev_addr:?}, {id:?}!");
+                            continue;
+                        };
+                        let was_discovered = session.is_discovered(peer);
+                        peer.identity.service = Some(record);
+                        if was_discovered || !session.is_discovered(peer) {
+                            continue;
+                        }
+                        id
+                    };
+                    session.listener.peer_discovered(&session, peer_id);
+                    session.peers_changed();
+                }
+                JavaNotification::DisplayPin { dev_addr, pin } => {
+                    let Some(id) = session.peers.read().mac_to_id.get(&dev_addr).copied() else {
+                        warn!("Can't display PIN for unknown device {dev_addr}");
+                        continue;
+                    };
+                    session.display_pin(id, &pin);
+                }
+                JavaNotification::PinRequested(dev_addr) => {
+                    let Some(id) = session.peers.read().mac_to_id.get(&dev_addr).copied() else {
+                        warn!("Ignoring PIN request from unknown device {dev_addr}");
+                        continue;
+                    };
+                    session.pin_requested(id);
+                }
                 JavaNotification::GroupStarted {
                     iface_name,
                     is_go,
@@ -718,7 +1523,9 @@ impl Session {
                             scope_id,
                             is_go,
                             peers: Default::default(),
+                            roster: Default::default(),
                             group_task: OnceLock::new(),
+                            datagram_socket: OnceLock::new(),
                             data: AndroidGroupData { go_device_address },
                         });
                         let id = GroupId(handle);
@@ -748,12 +1555,14 @@ impl Session {
         owner: JObject<'l>,
         device_name: JString<'l>,
         nickname: JString<'l>,
+        app_id: JString<'l>,
     ) -> jlong {
         let device_name = env.get_string(&device_name).unwrap();
         let device_name = device_name.to_string_lossy();
         let nickname = env.get_string(&nickname).unwrap();
         let nickname = nickname.to_string_lossy();
-        trace!("Session::init({device_name:?}, {nickname:?})");
+        let app_id: String = env.get_string(&app_id).unwrap().into();
+        trace!("Session::init({device_name:?}, {nickname:?}, {app_id:?})");
 
         // TODO(emilio): Get keys from caller.
         let identity = protocol::identity::new_own_id(nickname.into_owned()).unwrap();
@@ -763,11 +1572,23 @@ impl Session {
             proxy: env.new_global_ref(owner).unwrap(),
             p2p_name: device_name.into(),
             identity,
+            limits: Default::default(),
+            padding: Default::default(),
+            resumption_window: protocol::resumption::DEFAULT_WINDOW,
+            // Apps only care about other instances of themselves.
+            discovery_filter: DiscoveryFilter::Apps(vec![app_id.clone()]),
+            name_matching: Default::default(),
+            app_id,
             _phantom: std::marker::PhantomData,
         };
 
-        let session = Self::new_sync(init, Arc::new(crate::LoggerListener));
-        Arc::into_raw(session) as jlong
+        match Self::new_sync(init, Arc::new(crate::LoggerListener)) {
+            Ok(session) => Arc::into_raw(session) as jlong,
+            Err(e) => {
+                error!("Failed to create session: {e}");
+                0
+            }
+        }
     }
 
     fn call_proxy<'local>(
@@ -827,19 +1648,15 @@ impl Session {
         Ok(())
     }
 
-    fn peer_messaged(&self, peer_id: PeerId, group_id: GroupId, buf: &[u8]) {
-        if let Err(e) = self.peer_messaged_internal(peer_id, group_id, buf) {
-            error!("Failed to broadcast peer message to java: {e}");
-        }
-    }
-
     fn peer_messaged_internal(
         &self,
         peer_id: PeerId,
         group_id: GroupId,
         buf: &[u8],
+        delivery: Delivery,
     ) -> GenericResult<()> {
-        self.listener.peer_messaged(self, peer_id, group_id, buf);
+        self.listener
+            .peer_messaged_via(self, peer_id, group_id, buf, delivery);
         let mut env = self.vm.attach_current_thread()?;
         let (peer_name, peer_dev_addr, peer_logical_id) = {
             let peers = self.peers.read();
@@ -863,6 +1680,102 @@ impl Session {
         Ok(())
     }
 
+    fn peer_datagram(&self, peer_id: PeerId, group_id: GroupId, buf: &[u8]) {
+        if let Err(e) = self.peer_datagram_internal(peer_id, group_id, buf) {
+            error!("Failed to broadcast peer datagram to java: {e}");
+        }
+    }
+
+    fn peer_datagram_internal(
+        &self,
+        peer_id: PeerId,
+        group_id: GroupId,
+        buf: &[u8],
+    ) -> GenericResult<()> {
+        self.listener.peer_datagram(self, peer_id, group_id, buf);
+        let mut env = self.vm.attach_current_thread()?;
+        let (peer_name, peer_dev_addr, peer_logical_id) = {
+            let peers = self.peers.read();
+            let Some(peer) = peers.map.get(peer_id.0) else {
+                return Err(trivial_error!("peer_datagram from gone peer"));
+            };
+            peer_identity_to_jni(&mut env, &peer.identity)?
+        };
+        let byte_array = env.byte_array_from_slice(buf)?;
+        self.call_proxy(
+            &mut env,
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;[B)V",
+            "peerDatagram",
+            &[
+                (&peer_name).into(),
+                (&peer_dev_addr).into(),
+                (&peer_logical_id).into(),
+                (&byte_array).into(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn display_pin(&self, peer_id: PeerId, pin: &str) {
+        if let Err(e) = self.display_pin_internal(peer_id, pin) {
+            error!("Failed to broadcast PIN to java: {e}");
+        }
+    }
+
+    fn display_pin_internal(&self, peer_id: PeerId, pin: &str) -> GenericResult<()> {
+        self.listener.display_pin(self, peer_id, pin);
+        let mut env = self.vm.attach_current_thread()?;
+        let (peer_name, peer_dev_addr, peer_logical_id) = {
+            let peers = self.peers.read();
+            let Some(peer) = peers.map.get(peer_id.0) else {
+                return Err(trivial_error!("display_pin for gone peer"));
+            };
+            peer_identity_to_jni(&mut env, &peer.identity)?
+        };
+        let pin = env.new_string(pin)?;
+        self.call_proxy(
+            &mut env,
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+            "displayPin",
+            &[
+                (&peer_name).into(),
+                (&peer_dev_addr).into(),
+                (&peer_logical_id).into(),
+                (&pin).into(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn pin_requested(&self, peer_id: PeerId) {
+        if let Err(e) = self.pin_requested_internal(peer_id) {
+            error!("Failed to broadcast PIN request to java: {e}");
+        }
+    }
+
+    fn pin_requested_internal(&self, peer_id: PeerId) -> GenericResult<()> {
+        self.listener.pin_requested(self, peer_id);
+        let mut env = self.vm.attach_current_thread()?;
+        let (peer_name, peer_dev_addr, peer_logical_id) = {
+            let peers = self.peers.read();
+            let Some(peer) = peers.map.get(peer_id.0) else {
+                return Err(trivial_error!("pin_requested from gone peer"));
+            };
+            peer_identity_to_jni(&mut env, &peer.identity)?
+        };
+        self.call_proxy(
+            &mut env,
+            "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;)V",
+            "pinRequested",
+            &[
+                (&peer_name).into(),
+                (&peer_dev_addr).into(),
+                (&peer_logical_id).into(),
+            ],
+        )?;
+        Ok(())
+    }
+
     /// Breaks the cyclic owner <-> native listener.
     #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1drop"]
     extern "C" fn drop<'l>(_env: JNIEnv<'l>, _class: JClass<'l>, raw: jlong) {
@@ -893,7 +1806,11 @@ impl Session {
         for i in (0..len).step_by(STEP) {
             let name = get_string(i);
             let dev_addr = MacAddr::from_str(&get_string(i + 1)).unwrap();
-            identities.push(PhysiscalPeerIdentity { name, dev_addr });
+            identities.push(PhysiscalPeerIdentity {
+                name,
+                dev_addr,
+                iface_addr: None,
+            });
         }
         trace!(" > identities: {:?}", identities);
         session
@@ -902,6 +1819,48 @@ impl Session {
             .unwrap();
     }
 
+    /// Records the service a device advertises. Expects the key-value pairs of its TXT record.
+    #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1service_1found"]
+    extern "C" fn service_found<'l>(
+        mut env: JNIEnv<'l>,
+        _class: JClass<'l>,
+        raw: jlong,
+        device_address: JString<'l>,
+        txt: JObjectArray<'l>,
+    ) {
+        const STEP: usize = 2;
+        trace!("Session::service_found({raw:?})");
+        let session = unsafe { &*(raw as *const Self) };
+        let device_address = env.get_string(&device_address).unwrap();
+        let dev_addr = try_void!(
+            MacAddr::from_str(&device_address.to_string_lossy()),
+            "Invalid device address"
+        );
+        let len = env.get_array_length(&txt).unwrap();
+        assert!(
+            (len as usize).is_multiple_of(STEP),
+            "Should have the right step"
+        );
+        let mut get_string = |i| {
+            let string = env.get_object_array_element(&txt, i).unwrap();
+            let string = unsafe { JString::from_raw(string.as_raw()) };
+            let string = env.get_string(&string).unwrap();
+            string.to_string_lossy().into_owned()
+        };
+        let entries: Vec<_> = (0..len)
+            .step_by(STEP)
+            .map(|i| (get_string(i), get_string(i + 1)))
+            .collect();
+        let record = try_void!(
+            ServiceRecord::from_txt(entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))),
+            "Invalid service record"
+        );
+        session
+            .java_notification
+            .send(JavaNotification::ServiceFound { dev_addr, record })
+            .unwrap();
+    }
+
     /// Signals the group start operation. Android only supports one physical group at a time.
     #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1group_1joined"]
     extern "C" fn group_joined<'l>(
@@ -938,6 +1897,50 @@ impl Session {
             .unwrap();
     }
 
+    /// Signals that a device asked to connect entering the given PIN, which needs to be displayed.
+    #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1display_1pin"]
+    extern "C" fn display_pin_requested<'l>(
+        mut env: JNIEnv<'l>,
+        _class: JClass<'l>,
+        raw: jlong,
+        device_address: JString<'l>,
+        pin: JString<'l>,
+    ) {
+        trace!("Session::display_pin_requested({raw:?})");
+        let session = unsafe { &*(raw as *const Self) };
+        let device_address = env.get_string(&device_address).unwrap();
+        let dev_addr = try_void!(
+            MacAddr::from_str(&device_address.to_string_lossy()),
+            "Invalid device address"
+        );
+        let pin: String = env.get_string(&pin).unwrap().into();
+        session
+            .java_notification
+            .send(JavaNotification::DisplayPin { dev_addr, pin })
+            .unwrap();
+    }
+
+    /// Signals that a device asked to connect displaying a PIN, which needs to be entered here.
+    #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1pin_1requested"]
+    extern "C" fn pin_requested_by<'l>(
+        mut env: JNIEnv<'l>,
+        _class: JClass<'l>,
+        raw: jlong,
+        device_address: JString<'l>,
+    ) {
+        trace!("Session::pin_requested_by({raw:?})");
+        let session = unsafe { &*(raw as *const Self) };
+        let device_address = env.get_string(&device_address).unwrap();
+        let dev_addr = try_void!(
+            MacAddr::from_str(&device_address.to_string_lossy()),
+            "Invalid device address"
+        );
+        session
+            .java_notification
+            .send(JavaNotification::PinRequested(dev_addr))
+            .unwrap();
+    }
+
     #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1message_1peer"]
     extern "C" fn message_peer<'l>(
         mut env: JNIEnv<'l>,
@@ -951,19 +1954,7 @@ impl Session {
         let peer_physical_address = env.get_string(&peer_physical_address).unwrap();
         let peer_physical_address =
             MacAddr::from_str(&peer_physical_address.to_str().unwrap()).unwrap();
-        let message = {
-            let msg_len = env.get_array_length(&message).unwrap();
-            let mut buf = vec![0u8; msg_len as usize];
-            {
-                // SAFETY: u8 and i8 share representation, it's just more convenient for us to use
-                // u8.
-                let signed_buf = unsafe {
-                    std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut i8, buf.len())
-                };
-                env.get_byte_array_region(&message, 0, signed_buf).unwrap();
-            }
-            buf
-        };
+        let message = byte_array_to_vec(&mut env, &message);
         let peer_id = session
             .peers
             .read()
@@ -992,6 +1983,37 @@ impl Session {
             );
         });
     }
+
+    #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1session_1send_1datagram"]
+    extern "C" fn send_datagram<'l>(
+        mut env: JNIEnv<'l>,
+        _class: JClass<'l>,
+        raw: jlong,
+        peer_physical_address: JString<'l>,
+        datagram: JByteArray<'l>,
+    ) {
+        let session = unsafe { &*(raw as *const Self) };
+        let peer_physical_address = env.get_string(&peer_physical_address).unwrap();
+        let peer_physical_address =
+            MacAddr::from_str(peer_physical_address.to_str().unwrap()).unwrap();
+        let datagram = byte_array_to_vec(&mut env, &datagram);
+        let peer_id = session
+            .peers
+            .read()
+            .mac_to_id
+            .get(&peer_physical_address)
+            .copied();
+        let Some(peer_id) = peer_id else {
+            error!("Failed to find peer {peer_physical_address} to send datagram");
+            return;
+        };
+        let session = session.to_strong();
+        rt().spawn(async move {
+            if let Err(e) = session.send_datagram(peer_id, &datagram).await {
+                error!("Failed to send datagram to {peer_id:?}: {e}");
+            }
+        });
+    }
 }
 
 #[export_name = "Java_io_crisal_ngn_NgnSessionProxy_ngn_1init"]
@@ -23,4 +23,18 @@ open class NgnListener {
     open fun messageReceived(from: Peer, content: ByteArray) {
         Log.d(TAG, "messageReceived($from, $content)")
     }
+
+    open fun datagramReceived(from: Peer, content: ByteArray) {
+        Log.d(TAG, "datagramReceived($from, $content)")
+    }
+
+    /** The user needs to enter [pin] on [peer] to connect to it. */
+    open fun displayPin(peer: Peer, pin: String) {
+        Log.d(TAG, "displayPin($peer, $pin)")
+    }
+
+    /** [peer] wants to connect displaying a PIN, connect to it with the PIN the user enters. */
+    open fun pinRequested(peer: Peer) {
+        Log.d(TAG, "pinRequested($peer)")
+    }
 }
@@ -10,12 +10,15 @@ import android.content.Intent;
 import android.content.IntentFilter;
 import android.net.MacAddress;
 import android.net.NetworkInfo;
+import android.net.wifi.WpsInfo;
 import android.net.wifi.p2p.WifiP2pConfig;
 import android.net.wifi.p2p.WifiP2pDevice;
 import android.net.wifi.p2p.WifiP2pDeviceList;
 import android.net.wifi.p2p.WifiP2pGroup;
 import android.net.wifi.p2p.WifiP2pInfo;
 import android.net.wifi.p2p.WifiP2pManager;
+import android.net.wifi.p2p.nsd.WifiP2pDnsSdServiceInfo;
+import android.net.wifi.p2p.nsd.WifiP2pDnsSdServiceRequest;
 import android.os.Build;
 import android.os.Looper;
 import android.util.Log;
@@ -28,6 +31,9 @@ import androidx.core.content.ContextCompat;
 
 import java.util.ArrayList;
 import java.util.Collection;
+import java.util.HashMap;
+import java.util.HashSet;
+import java.util.Map;
 import java.util.Objects;
 import java.util.function.Function;
 
@@ -87,18 +93,32 @@ class ActionListenerFunctionAdapter implements WifiP2pManager.ActionListener {
 public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager.ChannelListener, WifiP2pManager.GroupInfoListener, WifiP2pManager.ConnectionInfoListener, WifiP2pManager.PeerListListener, WifiP2pManager.DeviceInfoListener {
     public static String TAG = "NgnSessionProxy";
 
-    private static native long ngn_session_init(NgnSessionProxy session, String device_name, String nick_name);
+    // Keep in sync with protocol::datagram::MAX_PAYLOAD_LEN.
+    public static final int MAX_DATAGRAM_SIZE = 1200;
+
+    // Keep in sync with protocol::service::SERVICE_TYPE.
+    public static final String SERVICE_TYPE = "_ngn._udp";
+
+    private static native long ngn_session_init(NgnSessionProxy session, String device_name, String nick_name, String app_id);
 
     private static native long ngn_session_update_peers(long native_session, String[] peer_details);
 
     private static native void ngn_session_message_peer(long native_session, String destination_address, byte[] message, Object on_result);
 
+    private static native void ngn_session_send_datagram(long native_session, String destination_address, byte[] datagram);
+
     private static native void ngn_session_drop(long native_session);
 
+    private static native void ngn_session_service_found(long native_session, String device_address, String[] txt_record);
+
     private static native void ngn_session_group_lost(long native_session);
 
     private static native void ngn_session_group_joined(long native_session, boolean is_go, String go_device_address, String interface_name, String owner_ip_address);
 
+    private static native void ngn_session_display_pin(long native_session, String device_address, String pin);
+
+    private static native void ngn_session_pin_requested(long native_session, String device_address);
+
     private static native void ngn_init();
 
     // BroadcastReceiver
@@ -159,6 +179,9 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         m_channel = null;
         // TODO: Notify the native object / maybe recreate the native session?
         initChannel();
+        if (m_localService != null) {
+            addLocalService();
+        }
     }
 
     // ConnectionInfoListener
@@ -221,7 +244,7 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         Log.d(TAG, "onDeviceInfoAvailable(" + wifiP2pDevice.deviceName + "): " + wifiP2pDevice);
         assert m_native == 0;
 
-        m_native = ngn_session_init(this, wifiP2pDevice.deviceName, m_nickName);
+        m_native = ngn_session_init(this, wifiP2pDevice.deviceName, m_nickName, m_appId);
         Log.d(TAG, "onDeviceInfoAvailable got session: " + m_native);
 
         if (m_onInit != null) {
@@ -247,14 +270,24 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         m_listener = aListener;
     }
 
+    /**
+     * Like init(nickname, appId, onInit), using the package name of the app as the app id.
+     */
+    @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
+    public boolean init(String nickname, Runnable onInit) {
+        return init(nickname, m_context.getPackageName(), onInit);
+    }
+
     /**
      * Initializes the P2P session. This needs to be outside the constructor so that the app can
      * make sure to obtain the right permissions.
      *
+     * @param appId String, the app we advertise ourselves for. Only peers advertising the same
+     *              app id are reported.
      * @param onInit Runnable, what to run once we're initialized.
      */
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
-    public boolean init(String nickname, Runnable onInit) {
+    public boolean init(String nickname, String appId, Runnable onInit) {
         if (m_manager != null) {
             // Already initialized or initializing, try to refresh the peer list.
             discoverPeers();
@@ -263,6 +296,7 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         m_manager = m_context.getSystemService(WifiP2pManager.class);
         m_onInit = onInit;
         m_nickName = nickname;
+        m_appId = appId;
         assert m_manager != null;
         initChannel();
         onResume();
@@ -338,8 +372,120 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         m_listener.messageReceived(new Peer(name, mac_addr, logicalId), message);
     }
 
+    @Keep
+    private void peerDatagram(String name, String mac_addr, String logicalId, byte[] datagram) {
+        m_listener.datagramReceived(new Peer(name, mac_addr, logicalId), datagram);
+    }
+
+    @Keep
+    private void displayPin(String name, String mac_addr, String logicalId, String pin) {
+        m_listener.displayPin(new Peer(name, mac_addr, logicalId), pin);
+    }
+
+    @Keep
+    private void pinRequested(String name, String mac_addr, String logicalId) {
+        m_listener.pinRequested(new Peer(name, mac_addr, logicalId));
+    }
+
+    @Keep
+    private void advertiseService(String instanceName, String[] txtRecord) {
+        final Map<String, String> record = new HashMap<>();
+        for (int i = 0; i < txtRecord.length; i += 2) {
+            record.put(txtRecord[i], txtRecord[i + 1]);
+        }
+        m_localService = WifiP2pDnsSdServiceInfo.newInstance(instanceName, SERVICE_TYPE, record);
+        addLocalService();
+    }
+
+    private void addLocalService() {
+        m_manager.addLocalService(m_channel, m_localService, new ActionListenerFunctionAdapter(success -> {
+            Log.d(TAG, "addLocalService(" + m_localService + "): " + success);
+            return null;
+        }));
+    }
+
+    // DnsSdTxtRecordListener
+    private void onTxtRecordAvailable(String fullDomainName, Map<String, String> txtRecord, WifiP2pDevice device) {
+        Log.d(TAG, "onTxtRecordAvailable(" + fullDomainName + ", " + device.deviceAddress + "): " + txtRecord);
+        if (m_native == 0 || !fullDomainName.endsWith("." + SERVICE_TYPE + ".local.")) {
+            return;
+        }
+        final String[] array = new String[txtRecord.size() * 2];
+        int i = 0;
+        for (Map.Entry<String, String> entry : txtRecord.entrySet()) {
+            array[i++] = entry.getKey();
+            array[i++] = entry.getValue();
+        }
+        ngn_session_service_found(m_native, device.deviceAddress, array);
+    }
+
     private void initChannel() {
         m_channel = m_manager.initialize(m_context, Looper.getMainLooper(), this);
+        // Service requests and listeners are per channel.
+        m_serviceRequest = null;
+        addExternalApprover();
+    }
+
+    /**
+     * Approves incoming connections ourselves, so that PIN-based ones reach the listener rather
+     * than the system dialog. This needs MANAGE_WIFI_NETWORK_SELECTION, without it the system
+     * keeps handling them.
+     */
+    private void addExternalApprover() {
+        m_pinRequests.clear();
+        if (Build.VERSION.SDK_INT < Build.VERSION_CODES.TIRAMISU) {
+            return;
+        }
+        try {
+            m_manager.addExternalApprover(m_channel, MacAddress.BROADCAST_ADDRESS, new WifiP2pManager.ExternalApproverRequestListener() {
+                @Override
+                public void onAttached(MacAddress deviceAddress) {
+                    Log.d(TAG, "External approver attached for " + deviceAddress);
+                }
+
+                @Override
+                public void onDetached(MacAddress deviceAddress, int reason) {
+                    Log.d(TAG, "External approver detached for " + deviceAddress + ": " + reason);
+                }
+
+                @Override
+                public void onConnectionRequested(int requestType, WifiP2pConfig config, WifiP2pDevice device) {
+                    onIncomingConnection(config, device);
+                }
+
+                @Override
+                public void onPinGenerated(MacAddress deviceAddress, String pin) {
+                    Log.d(TAG, "onPinGenerated(" + deviceAddress + ")");
+                    if (m_native != 0) {
+                        ngn_session_display_pin(m_native, deviceAddress.toString(), pin);
+                    }
+                }
+            });
+        } catch (SecurityException e) {
+            Log.w(TAG, "Can't approve connections, the system will ask for PINs: " + e);
+        }
+    }
+
+    // ExternalApproverRequestListener
+    private void onIncomingConnection(WifiP2pConfig config, WifiP2pDevice device) {
+        Log.d(TAG, "onIncomingConnection(" + device.deviceAddress + "): " + config.wps);
+        final MacAddress address = MacAddress.fromString(device.deviceAddress);
+        switch (config.wps.setup) {
+            case WpsInfo.DISPLAY:
+                // The framework generates the PIN, see onPinGenerated.
+                m_manager.setConnectionRequestResult(m_channel, address, WifiP2pManager.CONNECTION_REQUEST_ACCEPT, null);
+                break;
+            case WpsInfo.KEYPAD:
+                // Answered by connecting with the PIN, see connectToPeer.
+                m_pinRequests.add(address);
+                if (m_native != 0) {
+                    ngn_session_pin_requested(m_native, device.deviceAddress);
+                }
+                break;
+            default:
+                m_manager.setConnectionRequestResult(m_channel, address, WifiP2pManager.CONNECTION_REQUEST_DEFER_TO_SERVICE, null);
+                break;
+        }
     }
 
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
@@ -363,6 +509,27 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         discoverPeers((WifiP2pManager.ActionListener) null);
     }
 
+    /**
+     * Queries the devices around for the service they advertise, so that only the ones running
+     * ngn for our app are reported. See the `service` module on the Rust side.
+     */
+    @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
+    public void discoverServices(WifiP2pManager.ActionListener listener) {
+        if (m_serviceRequest == null) {
+            m_manager.setDnsSdResponseListeners(m_channel, null, this::onTxtRecordAvailable);
+            // We don't know the instance names in advance, so ask for all the Bonjour services.
+            m_serviceRequest = WifiP2pDnsSdServiceRequest.newInstance();
+            m_manager.addServiceRequest(m_channel, m_serviceRequest, null);
+        }
+        m_manager.discoverServices(m_channel, listener);
+    }
+
+    @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
+    @Keep
+    public void discoverServices(long aNativePromise) {
+        discoverServices(new ActionListenerNativeAdapter(aNativePromise));
+    }
+
     // TODO(emilio): Maybe use byte[] rather than string to pass around mac addresses.
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
     public void connectToPeer(String aMacAddress, Function<Boolean, Void> onConnect) {
@@ -374,6 +541,15 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         connectToPeer(aMacAddress, new ActionListenerNativeAdapter(aNativePromise));
     }
 
+    /**
+     * Connects to a peer authenticating with the given WpsInfo setup, and PIN if needed.
+     */
+    @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
+    @Keep
+    public void connectToPeer(String aMacAddress, int aWpsSetup, @Nullable String aPin, long aNativePromise) {
+        connectToPeer(aMacAddress, aWpsSetup, aPin, new ActionListenerNativeAdapter(aNativePromise));
+    }
+
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
     public void connectToPeer(String aMacAddress) {
         connectToPeer(aMacAddress, (WifiP2pManager.ActionListener) null);
@@ -381,14 +557,31 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
 
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
     public void connectToPeer(String aMacAddress, WifiP2pManager.ActionListener aListener) {
+        connectToPeer(aMacAddress, WpsInfo.PBC, null, aListener);
+    }
+
+    @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
+    public void connectToPeer(String aMacAddress, int aWpsSetup, @Nullable String aPin, WifiP2pManager.ActionListener aListener) {
+        final MacAddress address = MacAddress.fromString(aMacAddress);
+        if (aWpsSetup == WpsInfo.KEYPAD && m_pinRequests.remove(address) && Build.VERSION.SDK_INT >= Build.VERSION_CODES.TIRAMISU) {
+            // The peer is already waiting for us to enter the PIN it displays.
+            m_manager.setConnectionRequestResult(m_channel, address, WifiP2pManager.CONNECTION_REQUEST_ACCEPT, aPin, aListener);
+            return;
+        }
         final WifiP2pConfig.Builder builder = new WifiP2pConfig.Builder();
-        builder.setDeviceAddress(MacAddress.fromString(aMacAddress));
+        builder.setDeviceAddress(address);
         if (Build.VERSION.SDK_INT >= Build.VERSION_CODES.UPSIDE_DOWN_CAKE) {
             builder.setGroupClientIpProvisioningMode(WifiP2pConfig.GROUP_CLIENT_IP_PROVISIONING_MODE_IPV6_LINK_LOCAL);
         } else {
             Log.w(TAG, "Client IP provisioning might not use IPv6 link-local addressing!");
         }
-        m_manager.connect(m_channel, builder.build(), aListener);
+        final WifiP2pConfig config = builder.build();
+        // The builder only does push-button configuration, but the WPS info is still honored.
+        config.wps.setup = aWpsSetup;
+        if (aPin != null) {
+            config.wps.pin = aPin;
+        }
+        m_manager.connect(m_channel, config, aListener);
     }
 
     @RequiresPermission(allOf = {Manifest.permission.ACCESS_FINE_LOCATION, Manifest.permission.NEARBY_WIFI_DEVICES})
@@ -404,6 +597,18 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
         ngn_session_message_peer(m_native, aMacAddress, aMessage, onResult);
     }
 
+    /**
+     * Sends an unreliable datagram to the given peer. Datagrams might be lost, duplicated or
+     * reordered, and can't be longer than MAX_DATAGRAM_SIZE bytes.
+     */
+    public void sendDatagram(String aMacAddress, byte[] aDatagram) {
+        if (m_native == 0) {
+            Log.w(TAG, "Tried to send datagram to " + aMacAddress + " without an active session");
+            return;
+        }
+        ngn_session_send_datagram(m_native, aMacAddress, aDatagram);
+    }
+
     Context m_context;
     WifiP2pManager m_manager;
     WifiP2pManager.Channel m_channel;
@@ -415,4 +620,9 @@ public class NgnSessionProxy extends BroadcastReceiver implements WifiP2pManager
     long m_native = 0;
     NgnListener m_listener;
     String m_nickName;
+    String m_appId;
+    WifiP2pDnsSdServiceInfo m_localService;
+    WifiP2pDnsSdServiceRequest m_serviceRequest;
+    // Devices waiting for us to enter the PIN they display.
+    final HashSet<MacAddress> m_pinRequests = new HashSet<>();
 }
@@ -10,12 +10,28 @@ pub mod wpa_supplicant;
 
 use crate::{
     protocol::{
-        self, identity::OwnIdentity, ControlMessage, GroupInfo, P2pPorts, PeerAddress,
-        PeerGroupInfo, PeerIdentity, PeerInfo, PeerOwnIdentifier, PhysiscalPeerIdentity,
-        GO_CONTROL_PORT,
+        self,
+        beacon::Beacon,
+        capabilities::Capabilities,
+        connection::{ConnectionDelegate, ConnectionManager, ConnectionTarget, QueuedMessage},
+        datagram,
+        encryption::Keys,
+        identity::{LogicalPeerIdentity, OwnIdentity},
+        limits::{ConnectionLimiter, KeyedRateLimiter, Limits},
+        padding::PaddingPolicy,
+        priority::Priority,
+        relay,
+        resumption::ResumptionCache,
+        roster,
+        routing::{self, RoutingTable},
+        service::{self, DiscoveryFilter, ServiceRecord},
+        signing::MaybeInvalidPublicKey,
+        wire, ControlMessage, GroupDelegate, GroupInfo, NameMatching, P2pPorts, PeerAddress,
+        PeerDiagnostics, PeerGroupInfo, PeerIdentity, PeerInfo, PeerOwnIdentifier,
+        PhysiscalPeerIdentity, GO_CONTROL_PORT,
     },
     utils::{self, trivial_error},
-    GenericResult, GroupId, P2PSession, P2PSessionListener, PeerId,
+    Delivery, GenericResult, GroupId, P2PSession, P2PSessionListener, PeerId, Provisioning,
 };
 
 use futures_lite::StreamExt;
@@ -29,15 +45,58 @@ use std::{
     time::Duration,
 };
 use store::{DbusPath, DbusStore};
-use tokio::{self, net::TcpListener, task::JoinHandle};
+use tokio::{
+    self,
+    net::{TcpListener, UdpSocket},
+    task::JoinHandle,
+};
 use wpa_supplicant::{p2pdevice::P2PDeviceProxy, wpa_supplicant::WpaSupplicantProxy};
 use zbus::zvariant::{OwnedObjectPath, Value};
 
-const WPS_METHOD: &str = "pbc";
+/// WPS device password id of peers that display a PIN, see `Session::go_negotiation_request`.
+const DEV_PW_REGISTRAR_SPECIFIED: u16 = 5;
+
+/// WPS device password id of peers that enter our PIN.
+const DEV_PW_USER_SPECIFIED: u16 = 1;
+
+/// The `wps_method` argument of `Connect` for a provisioning method.
+fn wps_method(provisioning: &Provisioning) -> &'static str {
+    match *provisioning {
+        Provisioning::PushButton => "pbc",
+        Provisioning::DisplayPin(..) => "display",
+        Provisioning::Keypad(..) => "keypad",
+    }
+}
+
+/// The PIN to connect with, if we know it already.
+fn wps_pin(provisioning: &Provisioning) -> Option<&str> {
+    match *provisioning {
+        Provisioning::PushButton => None,
+        Provisioning::DisplayPin(ref pin) => pin.as_deref(),
+        Provisioning::Keypad(ref pin) => Some(pin),
+    }
+}
+
+/// wpa_supplicant's network mode for 802.11s meshes.
+const MESH_MODE: u32 = 5;
+
+/// How long we wait for wpa_supplicant to join or start a mesh.
+const MESH_JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times we try to associate with a mesh peer before giving up.
+const MESH_ASSOCIATION_ATTEMPTS: usize = 3;
+
+/// The frames wpa_supplicant adds our identity beacon to: P2P probe requests, P2P probe responses,
+/// and probe responses of the groups we own.
+const BEACON_FRAME_IDS: [i32; 3] = [0, 1, 2];
 
 #[derive(Debug)]
-struct DbusPeerData {
-    proxy: wpa_supplicant::peer::PeerProxy<'static>,
+pub struct DbusPeerData {
+    /// Proxy to the peer object. `None` for mesh peers, which wpa_supplicant doesn't expose as
+    /// objects.
+    proxy: Option<wpa_supplicant::peer::PeerProxy<'static>>,
+    /// Path of the peer object. Mesh peers get a made up one under the path of the mesh, see
+    /// `mesh_peer_path`.
     path: OwnedObjectPath,
 }
 
@@ -49,18 +108,50 @@ impl DbusPath for Peer {
     }
 }
 
+#[derive(Debug, Clone)]
+enum DbusGroupKind {
+    /// A Wi-Fi Direct group.
+    P2p {
+        /// Proxy to the group object.
+        proxy: wpa_supplicant::group::GroupProxy<'static>,
+        /// Dev address of the GO
+        go_dev_addr: MacAddr,
+        /// The address of our group interface, if wpa_supplicant exposes it, see
+        /// `Session::own_phy_id_in`.
+        own_iface_addr: Option<MacAddr>,
+    },
+    /// An 802.11s mesh, see `Session::join_mesh`.
+    Mesh {
+        /// Proxy to the mesh interface object.
+        proxy: wpa_supplicant::mesh::MeshProxy<'static>,
+        /// The address of our mesh interface.
+        own_addr: MacAddr,
+    },
+}
+
 #[derive(Debug)]
-struct DbusGroupData {
-    /// Proxy to the group object.
-    proxy: wpa_supplicant::group::GroupProxy<'static>,
+pub struct DbusGroupData {
+    kind: DbusGroupKind,
     /// Proxy to the interface object that connects the nodes in this group.
     iface: wpa_supplicant::interface::InterfaceProxy<'static>,
     /// DBUS Path of the interface.
     iface_path: OwnedObjectPath,
-    /// Path of the group.
+    /// Path of the group, or of the network object for meshes.
     path: OwnedObjectPath,
-    /// Dev address of the GO
-    go_dev_addr: MacAddr,
+}
+
+impl DbusGroupData {
+    /// Dev address of the GO, for Wi-Fi Direct groups.
+    fn go_dev_addr(&self) -> Option<MacAddr> {
+        match self.kind {
+            DbusGroupKind::P2p { go_dev_addr, .. } => Some(go_dev_addr),
+            DbusGroupKind::Mesh { .. } => None,
+        }
+    }
+
+    fn is_mesh(&self) -> bool {
+        matches!(self.kind, DbusGroupKind::Mesh { .. })
+    }
 }
 
 type Group = GroupInfo<DbusGroupData>;
@@ -71,6 +162,34 @@ impl DbusPath for Group {
     }
 }
 
+/// Returns the path we store a mesh peer under, see `DbusPeerData::path`.
+fn mesh_peer_path(mesh_path: &OwnedObjectPath, addr: &MacAddr) -> OwnedObjectPath {
+    let addr: String = addr.as_bytes().iter().map(|b| format!("{b:02x}")).collect();
+    OwnedObjectPath::try_from(format!("{}/MeshPeers/{addr}", mesh_path.as_str()))
+        .expect("Mesh peer path should be valid")
+}
+
+/// Returns the peer address in the arguments of a `MeshPeerConnected` or `MeshPeerDisconnected`
+/// signal.
+fn mesh_peer_address(args: &HashMap<&str, Value<'_>>) -> Option<MacAddr> {
+    mac_addr_arg(args, "PeerAddress")
+}
+
+/// Returns an address passed as a byte array in the arguments of a signal.
+fn mac_addr_arg(args: &HashMap<&str, Value<'_>>, key: &str) -> Option<MacAddr> {
+    let Some(Value::Array(addr)) = args.get(key) else {
+        return None;
+    };
+    let addr: Vec<u8> = addr
+        .iter()
+        .map(|b| match b {
+            Value::U8(b) => Some(*b),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    utils::to_mac_addr(&addr)
+}
+
 /// Global state for a P2P session.
 #[derive(Debug)]
 pub struct Session {
@@ -85,8 +204,36 @@ pub struct Session {
     run_loop_task: RwLock<Option<JoinHandle<GenericResult<()>>>>,
     /// Our own logical identity.
     identity: OwnIdentity,
-    /// Our own P2P identifier address.
-    own_phy_id: PeerOwnIdentifier,
+    /// What we identify ourselves as when associating: our P2P device address once we know it,
+    /// our device name until then.
+    own_phy_id: RwLock<PeerOwnIdentifier>,
+    /// How to match peers that identify themselves by name.
+    name_matching: NameMatching,
+    /// Resource limits for incoming connections and messages.
+    limits: Limits,
+    /// How to pad outgoing peer records.
+    padding: PaddingPolicy,
+    /// Tickets to resume our sessions with recently seen peers.
+    resumption: ResumptionCache,
+    /// Our routes to peers in other groups, see the `routing` module.
+    routing: RwLock<RoutingTable>,
+    /// The service we advertise, see the `service` module.
+    service: ServiceRecord,
+    /// Which discovered devices we report as peers.
+    discovery_filter: DiscoveryFilter,
+    /// Our outstanding service discovery request, if any.
+    service_request: RwLock<Option<u64>>,
+    /// How to authenticate connections with peers, by peer path, if not with push-button.
+    provisioning: RwLock<HashMap<String, Provisioning>>,
+    /// The interface we registered our identity beacon IE with, and the IE, if enabled. See the
+    /// `beacon` module.
+    beacon: Option<(wpa_supplicant::interface::InterfaceProxy<'static>, Vec<u8>)>,
+    /// Currently open incoming connections to our control ports, by source address.
+    control_connections: ConnectionLimiter<IpAddr>,
+    /// Currently open incoming connections to our p2p ports, by peer.
+    peer_connections: ConnectionLimiter<PeerId>,
+    /// Open connections to our peers.
+    connections: ConnectionManager,
 }
 
 impl Drop for Session {
@@ -106,6 +253,124 @@ pub struct SessionInit<'a> {
     pub identity: OwnIdentity,
     /// Our group owner intent, from 0 to 15.
     pub go_intent: u32,
+    /// Resource limits for incoming connections and messages.
+    pub limits: Limits,
+    /// How to pad outgoing peer records, to hide their length. See the `padding` module.
+    pub padding: PaddingPolicy,
+    /// How long after losing a peer we can bind a new session with it to the previous one, see the
+    /// `resumption` module.
+    pub resumption_window: Duration,
+    /// The app we advertise ourselves for, see the `service` module.
+    pub app_id: &'a str,
+    /// Which discovered devices to report as peers.
+    pub discovery_filter: DiscoveryFilter,
+    /// How to match peers that identify themselves by name rather than by device address, e.g.
+    /// older builds, or ones running on older wpa_supplicant versions.
+    pub name_matching: NameMatching,
+    /// Whether to advertise a signed beacon with our logical identity in probe frames, and read
+    /// the ones of the peers we discover, see the `beacon` module.
+    pub identity_beacon: bool,
+}
+
+/// A group wpa_supplicant remembers credentials for, which can be reinvoked with
+/// `Session::reinvoke_persistent_group` without going through negotiation again.
+#[derive(Debug, Clone)]
+pub struct PersistentGroup {
+    /// The wpa_supplicant object of the group.
+    pub path: OwnedObjectPath,
+    /// The SSID of the group.
+    pub ssid: String,
+    /// The device address of the GO, if known.
+    pub go_dev_addr: Option<MacAddr>,
+    /// Whether we were the GO.
+    pub is_go: bool,
+    /// The device addresses of the clients we had, if we were the GO.
+    pub clients: Vec<MacAddr>,
+}
+
+impl PersistentGroup {
+    /// Parses the network block properties of a persistent group. wpa_supplicant exposes them as
+    /// strings, as in its configuration file.
+    fn from_properties(path: OwnedObjectPath, props: &HashMap<String, Value<'_>>) -> Self {
+        let get = |key: &str| match props.get(key) {
+            Some(Value::Str(s)) => Some(s.as_str()),
+            _ => None,
+        };
+        Self {
+            path,
+            ssid: get("ssid").unwrap_or_default().trim_matches('"').to_owned(),
+            go_dev_addr: get("bssid").and_then(|a| a.parse().ok()),
+            // WPAS_MODE_P2P_GO
+            is_go: get("mode") == Some("3"),
+            clients: get("p2p_client_list")
+                .unwrap_or_default()
+                .split_whitespace()
+                .filter_map(|a| a.parse().ok())
+                .collect(),
+        }
+    }
+}
+
+/// Where to start a group with `Session::create_group`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GroupFrequency {
+    /// Let wpa_supplicant pick a channel.
+    #[default]
+    Any,
+    /// Any channel in the 2.4GHz band, which all devices support.
+    Band2_4Ghz,
+    /// Any channel in the 5GHz band, which is usually less crowded.
+    Band5Ghz,
+    /// A specific channel, by frequency in MHz, e.g. 2412 for channel 1.
+    Mhz(u32),
+}
+
+impl GroupFrequency {
+    /// The value of the `frequency` argument of `GroupAdd`, if any. wpa_supplicant treats 2 and 5
+    /// as band preferences.
+    fn to_dbus(self) -> GenericResult<Option<i32>> {
+        Ok(match self {
+            Self::Any => None,
+            Self::Band2_4Ghz => Some(2),
+            Self::Band5Ghz => Some(5),
+            Self::Mhz(mhz) => Some(i32::try_from(mhz)?),
+        })
+    }
+}
+
+/// The group to start with `Session::create_group`.
+#[derive(Debug, Clone, Default)]
+pub struct GroupOptions {
+    /// Whether wpa_supplicant remembers the group, so that peers can rejoin it without going
+    /// through WPS again, see `PersistentGroup`.
+    pub persistent: bool,
+    /// A persistent group we owned to start again, keeping its SSID and credentials, instead of a
+    /// new one.
+    pub persistent_group: Option<OwnedObjectPath>,
+    /// Where to start the group. Ignored when starting a persistent group again.
+    pub frequency: GroupFrequency,
+}
+
+/// How often to listen for probe requests with `Session::set_extended_listen`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedListen {
+    /// How long to stay on the listen channel each time.
+    pub period: Duration,
+    /// How often to go to the listen channel, must be at least `period`.
+    pub interval: Duration,
+}
+
+/// The mesh to join with `Session::join_mesh`.
+pub struct MeshConfig<'a> {
+    /// The interface to run the mesh on. It needs to support mesh point mode, and can't be the one
+    /// we use for Wi-Fi Direct.
+    pub interface_name: &'a str,
+    /// The mesh ID, shared by all the members of the mesh.
+    pub mesh_id: &'a str,
+    /// The frequency of the mesh in MHz, e.g. 2412 for channel 1.
+    pub frequency: u32,
+    /// The password of the mesh, if it's secured with SAE.
+    pub password: Option<&'a str>,
 }
 
 #[async_trait::async_trait]
@@ -117,6 +382,7 @@ impl P2PSession for Session {
         init: SessionInit<'_>,
         listener: Arc<dyn P2PSessionListener<Self>>,
     ) -> GenericResult<Arc<Self>> {
+        init.limits.validate()?;
         trace!("Trying to connect to system bus");
         let system_bus = zbus::Connection::system().await?;
 
@@ -198,19 +464,47 @@ impl P2PSession for Session {
             })
             .await?;
 
-        // TODO(emilio): use device_address() if available.
-        let own_phy_id = PeerOwnIdentifier::Name(init.device_name.into());
-
-        /*
-                let dev_addr = p2pdevice.device_address().await?;
-                trace!("Own P2P device address: {dev_addr:?}");
-                let Some(dev_addr) = utils::to_mac_addr(&dev_addr) else {
-                    return Err(trivial_error!(
-                        "Expected a valid mac address from
-        P2PDevice::device_address()"
-                    ));
-                };
-                */
+        let service = ServiceRecord::ours(&init.identity, init.app_id)?;
+        for (query, response) in service.bonjour_records()? {
+            let service_type = Value::new("bonjour");
+            let query = Value::new(query);
+            let response = Value::new(response);
+            let mut args = HashMap::new();
+            args.insert("service_type", &service_type);
+            args.insert("query", &query);
+            args.insert("response", &response);
+            p2pdevice.add_service(args).await?;
+        }
+        trace!("Advertising {service:?}");
+
+        let beacon = if init.identity_beacon {
+            let iface = wpa_supplicant::interface::InterfaceProxy::new(
+                &system_bus,
+                p2pdevice.inner().path().to_owned(),
+            )
+            .await?;
+            let ie = Beacon::ours_ie(&init.identity, Capabilities::ours(&init.limits))?;
+            for frame_id in BEACON_FRAME_IDS {
+                iface.vendor_elem_add(frame_id, &ie).await?;
+            }
+            trace!("Advertising identity beacon {ie:?}");
+            Some((iface, ie))
+        } else {
+            None
+        };
+
+        // Older wpa_supplicant versions don't expose our device address, in which case we use our
+        // name until we learn it from a group we own, see `Session::learn_own_dev_addr`, or by the
+        // address of our interface in groups we join, see `Session::own_phy_id_in`.
+        let dev_addr = p2pdevice.device_address().await;
+        trace!("Own P2P device address: {dev_addr:?}");
+        let own_phy_id = match dev_addr.ok().and_then(|a| utils::to_mac_addr(&a)) {
+            Some(dev_addr) => PeerOwnIdentifier::DevAddr(dev_addr.into()),
+            None => {
+                warn!("Couldn't get our device address, identifying by name");
+                PeerOwnIdentifier::Name(init.device_name.into())
+            }
+        };
 
         trace!("Successfully initialized P2P session");
         let session = Arc::new(Self {
@@ -221,9 +515,28 @@ impl P2PSession for Session {
             identity: init.identity,
             peers: Default::default(),
             groups: Default::default(),
-            own_phy_id,
+            own_phy_id: RwLock::new(own_phy_id),
+            name_matching: init.name_matching,
             listener,
             run_loop_task: RwLock::new(None),
+            control_connections: ConnectionLimiter::new(
+                init.limits.max_incoming_connections,
+                init.limits.max_incoming_connections_per_peer,
+            ),
+            peer_connections: ConnectionLimiter::new(
+                init.limits.max_incoming_connections,
+                init.limits.max_incoming_connections_per_peer,
+            ),
+            limits: init.limits,
+            padding: init.padding,
+            resumption: ResumptionCache::new(init.resumption_window),
+            routing: Default::default(),
+            service,
+            discovery_filter: init.discovery_filter,
+            service_request: RwLock::new(None),
+            provisioning: Default::default(),
+            beacon,
+            connections: Default::default(),
         });
 
         let handle = tokio::spawn(Session::run_loop(Arc::clone(&session)));
@@ -244,8 +557,13 @@ impl P2PSession for Session {
     async fn stop(&self) -> GenericResult<()> {
         trace!("Session::stop");
         // TODO: More graceful termination.
+        if let Err(e) = self.stop_advertising().await {
+            warn!("Failed to stop advertising our service: {e}");
+        }
+        self.connections.clear();
         self.groups.write().clear();
         self.peers.write().clear();
+        self.provisioning.write().clear();
         if let Some(ref t) = *self.run_loop_task.read() {
             t.abort();
         }
@@ -254,6 +572,15 @@ impl P2PSession for Session {
 
     async fn discover_peers(&self) -> GenericResult<()> {
         trace!("Session::discover_peers");
+        let old_request = self.service_request.write().take();
+        if let Some(id) = old_request {
+            let _ = self.p2pdevice.service_discovery_cancel_request(id).await;
+        }
+        let tlv = Value::new(service::bonjour_request());
+        let mut args = HashMap::new();
+        args.insert("tlv", &tlv);
+        let id = self.p2pdevice.service_discovery_request(args).await?;
+        *self.service_request.write() = Some(id);
         self.p2pdevice.find(HashMap::default()).await?;
         Ok(())
     }
@@ -266,6 +593,7 @@ impl P2PSession for Session {
         self.peers
             .read()
             .iter_with_handles()
+            .filter(|(_, info)| self.is_discovered(info))
             .map(|(id, info)| (PeerId(id), info.identity.clone()))
             .collect()
     }
@@ -274,8 +602,15 @@ impl P2PSession for Session {
         &self.identity
     }
 
-    async fn connect_to_peer(&self, id: PeerId) -> GenericResult<()> {
-        trace!("Session::connect_to_peer({id:?})");
+    async fn connect_to_peer_with_provisioning(
+        &self,
+        id: PeerId,
+        provisioning: Provisioning,
+    ) -> GenericResult<()> {
+        trace!("Session::connect_to_peer_with_provisioning({id:?}, {provisioning:?})");
+        if wps_pin(&provisioning).is_some_and(|pin| !utils::is_valid_wps_pin(pin)) {
+            return Err(trivial_error!("Invalid WPS PIN"));
+        }
         let peer_path = {
             let guard = self.peers.read();
             match guard.get(id.0) {
@@ -283,12 +618,78 @@ impl P2PSession for Session {
                 None => return Err(trivial_error!("Can't locate peer")),
             }
         };
+        {
+            let mut provisionings = self.provisioning.write();
+            match provisioning {
+                Provisioning::PushButton => provisionings.remove(peer_path.as_str()),
+                _ => provisionings.insert(peer_path.as_str().to_owned(), provisioning),
+            };
+        }
         self.connect_to_peer_by_path(peer_path).await?;
         Ok(())
     }
 
-    async fn message_peer(&self, id: PeerId, message: &[u8]) -> GenericResult<()> {
-        let (peer_address, encryption_keys, scope_id) = {
+    async fn message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let target = match self.message_target(id) {
+            Ok(target) => target,
+            Err(e) => return routing::message_indirectly(self, id, message, e).await,
+        };
+        let session = self.to_strong();
+        let message = self
+            .connections
+            .queue_message(&session, &target, message, priority)
+            .await?;
+        self.send_queued_message(id, message).await
+    }
+
+    async fn try_message_peer_with_priority(
+        &self,
+        id: PeerId,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let target = match self.message_target(id) {
+            Ok(target) => target,
+            Err(e) => return routing::message_indirectly(self, id, message, e).await,
+        };
+        let session = self.to_strong();
+        let message = self
+            .connections
+            .try_queue_message(&session, &target, message, priority)?;
+        self.send_queued_message(id, message).await
+    }
+
+    fn reachable_peers(&self) -> Vec<(LogicalPeerIdentity, u8)> {
+        self.routing
+            .read()
+            .routes()
+            .map(|route| (route.destination.clone(), route.hops))
+            .collect()
+    }
+
+    async fn message_remote_peer(
+        &self,
+        to: &LogicalPeerIdentity,
+        message: &[u8],
+    ) -> GenericResult<()> {
+        routing::send(self, &to.key, message).await
+    }
+
+    fn peer_capabilities(&self, id: PeerId) -> Option<Capabilities> {
+        self.peers.read().get(id.0)?.capabilities
+    }
+
+    fn peer_diagnostics(&self, id: PeerId) -> Option<PeerDiagnostics> {
+        self.peers.read().get(id.0)?.diagnostics()
+    }
+
+    async fn send_datagram(&self, id: PeerId, datagram: &[u8]) -> GenericResult<()> {
+        let (socket, address, keys) = {
             let peers = self.peers.read();
             let groups = self.groups.read();
             let Some(peer) = peers.get(id.0) else {
@@ -297,13 +698,13 @@ impl P2PSession for Session {
             let Some(keys) = peer.key_exchange.encryption_keys() else {
                 return Err(trivial_error!("Key exchange hasn't finished yet?"));
             };
-            // Choose one arbitrary group to connect to it.
+            if !peer.capabilities.is_some_and(|c| c.datagrams) {
+                return Err(trivial_error!("Peer doesn't support datagrams"));
+            }
             let Some(group_id) = peer.groups.first() else {
-                // TODO: Maybe we want to call connect_to_peer automatically?
                 return Err(trivial_error!("Peer is not connected to any group"));
             };
             let Some(group) = groups.get(group_id.0) else {
-                // TODO: Maybe we want to call connect_to_peer automatically?
                 return Err(trivial_error!("Group not found"));
             };
             let Some(info) = group.peers.get(&id) else {
@@ -311,17 +712,110 @@ impl P2PSession for Session {
                     "Peer doesn't have a link local address (yet?)"
                 ));
             };
-            (info.address.clone(), Arc::clone(keys), group.scope_id)
+            let Some(socket) = group.datagram_socket.get() else {
+                return Err(trivial_error!("Group isn't ready for datagrams yet"));
+            };
+            (
+                Arc::clone(socket),
+                protocol::peer_to_socket_addr(
+                    info.address.address,
+                    group.scope_id,
+                    info.address.ports.datagram,
+                ),
+                Arc::clone(keys),
+            )
         };
-        let socket_addr =
-            protocol::peer_to_socket_addr(peer_address.address, scope_id, peer_address.ports.p2p);
+        datagram::send(&socket, &keys, &address, datagram).await
+    }
+}
+
+impl ConnectionDelegate for Session {
+    fn identity(&self) -> &OwnIdentity {
+        &self.identity
+    }
+
+    fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    fn padding(&self) -> PaddingPolicy {
+        self.padding
+    }
+
+    fn connections(&self) -> &ConnectionManager {
+        &self.connections
+    }
+
+    fn connection_message(&self, peer_id: PeerId, group_id: GroupId, message: &[u8]) {
+        trace!(
+            "Got message from {peer_id:?}: {:?}",
+            String::from_utf8_lossy(message)
+        );
+        self.listener
+            .peer_messaged_via(self, peer_id, group_id, message, Delivery::Direct);
+    }
+}
+
+impl GroupDelegate for Session {
+    type PeerData = DbusPeerData;
+    type GroupData = DbusGroupData;
+    type Peers = DbusStore<Peer>;
+    type Groups = DbusStore<Group>;
+
+    fn peers(&self) -> &RwLock<DbusStore<Peer>> {
+        &self.peers
+    }
+
+    fn groups(&self) -> &RwLock<DbusStore<Group>> {
+        &self.groups
+    }
+
+    fn name_matching(&self) -> NameMatching {
+        self.name_matching
+    }
+
+    fn group_owner<'a>(peers: &'a DbusStore<Peer>, group: &Group) -> Option<&'a Peer> {
+        if group.is_go {
+            return None;
+        }
+        let go_dev_addr = group.data.go_dev_addr()?;
+        peers
+            .iter()
+            .find(|p| p.identity.physical.dev_addr == go_dev_addr)
+    }
+
+    fn routing(&self) -> &RwLock<RoutingTable> {
+        &self.routing
+    }
+
+    fn peer_messaged(
+        &self,
+        peer_id: PeerId,
+        group_id: GroupId,
+        message: &[u8],
+        delivery: Delivery,
+    ) {
+        self.listener
+            .peer_messaged_via(self, peer_id, group_id, message, delivery);
+    }
+
+    fn remote_peer_messaged(&self, from: &LogicalPeerIdentity, message: &[u8]) {
+        self.listener.remote_peer_messaged(self, from, message);
+    }
+
+    async fn send_control_message(
+        &self,
+        ip: IpAddr,
+        control_port: u16,
+        scope_id: u32,
+        version: u16,
+        message: ControlMessage,
+    ) -> GenericResult<()> {
+        trace!("Session::send_control_message({ip:?}, {scope_id}, {version}, {message:?})");
+        let addr = protocol::peer_to_socket_addr(ip, scope_id, control_port);
+        let msg = wire::encode(version, &message)?;
         utils::retry_timeout(Duration::from_secs(2), 5, || {
-            protocol::send_message(
-                Some(&self.identity),
-                Some(&encryption_keys),
-                &socket_addr,
-                message,
-            )
+            protocol::send_message(None, None, &addr, version, &msg)
         })
         .await
     }
@@ -332,44 +826,775 @@ impl Session {
         &self.system_bus
     }
 
-    pub fn wpa_s(&self) -> &WpaSupplicantProxy<'static> {
-        &self.wpa_supplicant
+    pub fn wpa_s(&self) -> &WpaSupplicantProxy<'static> {
+        &self.wpa_supplicant
+    }
+
+    pub fn p2pdevice(&self) -> &P2PDeviceProxy<'static> {
+        &self.p2pdevice
+    }
+
+    /// Switches to identifying ourselves by device address if we didn't know it yet. The device
+    /// address of the GO of a group we own is ours.
+    fn learn_own_dev_addr(&self, dev_addr: MacAddr) {
+        let mut own_phy_id = self.own_phy_id.write();
+        if let PeerOwnIdentifier::Name(..) = *own_phy_id {
+            trace!("Learned our own P2P device address: {dev_addr}");
+            *own_phy_id = PeerOwnIdentifier::DevAddr(dev_addr.into());
+        }
+    }
+
+    /// Returns how we identify ourselves to the members of a group. Members of a mesh know us by
+    /// the address of our mesh interface, see `Session::join_mesh`. If we don't know our device
+    /// address, Wi-Fi Direct peers we negotiated the group with know us by the address of our
+    /// group interface, see `PhysiscalPeerIdentity::iface_addr`.
+    fn own_phy_id_in(&self, kind: &DbusGroupKind) -> PeerOwnIdentifier {
+        let own_phy_id = self.own_phy_id.read();
+        match (kind, &*own_phy_id) {
+            (DbusGroupKind::Mesh { own_addr, .. }, _) => {
+                PeerOwnIdentifier::DevAddr((*own_addr).into())
+            }
+            (
+                DbusGroupKind::P2p {
+                    own_iface_addr: Some(addr),
+                    ..
+                },
+                PeerOwnIdentifier::Name(..),
+            ) => PeerOwnIdentifier::DevAddr((*addr).into()),
+            _ => own_phy_id.clone(),
+        }
+    }
+
+    /// Whether we report a peer as discovered, given what it advertises.
+    fn is_discovered(&self, peer: &Peer) -> bool {
+        self.discovery_filter
+            .accepts(peer.identity.service.as_ref())
+    }
+
+    /// Removes the service records and beacon we registered with wpa_supplicant, and our
+    /// outstanding service discovery request.
+    async fn stop_advertising(&self) -> GenericResult<()> {
+        if let Some((ref iface, ref ie)) = self.beacon {
+            for frame_id in BEACON_FRAME_IDS {
+                iface.vendor_elem_rem(frame_id, ie).await?;
+            }
+        }
+        let request = self.service_request.write().take();
+        if let Some(id) = request {
+            self.p2pdevice.service_discovery_cancel_request(id).await?;
+        }
+        for (query, _) in self.service.bonjour_records()? {
+            let service_type = Value::new("bonjour");
+            let query = Value::new(query);
+            let mut args = HashMap::new();
+            args.insert("service_type", &service_type);
+            args.insert("query", &query);
+            self.p2pdevice.delete_service(args).await?;
+        }
+        Ok(())
+    }
+
+    /// Joins an 802.11s mesh, starting it if no other member is around, and returns it as a group.
+    ///
+    /// There's no group owner in a mesh: every member listens on `GO_CONTROL_PORT`, and associates
+    /// with the members it links with directly, which are reported as discovered peers. Members
+    /// further away can be reached through the `routing` module.
+    pub async fn join_mesh(self: &Arc<Self>, config: MeshConfig<'_>) -> GenericResult<GroupId> {
+        trace!(
+            "Session::join_mesh({:?}, {:?}, {})",
+            config.interface_name,
+            config.mesh_id,
+            config.frequency
+        );
+        // Before creating the interface, so that we don't leave it behind.
+        let frequency = i32::try_from(config.frequency)?;
+        let iface_path = match self
+            .wpa_supplicant
+            .get_interface(config.interface_name)
+            .await
+        {
+            Ok(path) => path,
+            Err(e) => {
+                trace!(
+                    "Couldn't get interface for {}, creating: {e}",
+                    config.interface_name
+                );
+                let name = Value::new(config.interface_name);
+                let mut args = HashMap::new();
+                args.insert("Ifname", &name);
+                self.wpa_supplicant.create_interface(args).await?
+            }
+        };
+        let iface =
+            wpa_supplicant::interface::InterfaceProxy::new(&self.system_bus, iface_path.clone())
+                .await?;
+        let mesh =
+            wpa_supplicant::mesh::MeshProxy::new(&self.system_bus, iface_path.clone()).await?;
+        let iface_name = iface.ifname().await?;
+        let own_addr: MacAddr =
+            std::fs::read_to_string(format!("/sys/class/net/{iface_name}/address"))?
+                .trim()
+                .parse()?;
+        let scope_id = unsafe {
+            libc::if_nametoindex(std::ffi::CString::new(iface_name.clone()).unwrap().as_ptr())
+        };
+        trace!("Mesh interface is {iface_name:?} ({own_addr}), scope id {scope_id}");
+
+        let mut mesh_started = mesh.receive_mesh_group_started().await?;
+        let ssid = Value::new(config.mesh_id);
+        let mode = Value::new(MESH_MODE);
+        let frequency = Value::new(frequency);
+        let key_mgmt = Value::new(if config.password.is_some() {
+            "SAE"
+        } else {
+            "NONE"
+        });
+        let psk = config.password.map(Value::new);
+        let mut args = HashMap::new();
+        args.insert("ssid", &ssid);
+        args.insert("mode", &mode);
+        args.insert("frequency", &frequency);
+        args.insert("key_mgmt", &key_mgmt);
+        if let Some(ref psk) = psk {
+            args.insert("psk", psk);
+        }
+        let network_path = iface.add_network(args).await?;
+        iface.select_network(&network_path).await?;
+        let started = tokio::time::timeout(MESH_JOIN_TIMEOUT, mesh_started.next()).await;
+        if !matches!(started, Ok(Some(..))) {
+            let _ = iface.remove_network(&network_path).await;
+            return Err(trivial_error!("Timed out joining the mesh"));
+        }
+
+        let id = Self::insert_group(
+            self,
+            Group {
+                go_ip_address: IpAddr::V6(utils::mac_addr_to_local_link_address(&own_addr)),
+                iface_name,
+                scope_id,
+                is_go: false,
+                peers: Default::default(),
+                roster: Default::default(),
+                group_task: OnceLock::new(),
+                datagram_socket: OnceLock::new(),
+                data: DbusGroupData {
+                    kind: DbusGroupKind::Mesh {
+                        proxy: mesh,
+                        own_addr,
+                    },
+                    iface,
+                    iface_path,
+                    path: network_path,
+                },
+            },
+        );
+        self.listener.joined_group(self, id, false);
+        Ok(id)
+    }
+
+    /// Leaves a mesh joined with `join_mesh`.
+    pub async fn leave_mesh(self: &Arc<Self>, group_id: GroupId) -> GenericResult<()> {
+        trace!("Session::leave_mesh({group_id:?})");
+        let (iface, network_path) = {
+            let groups = self.groups.read();
+            let Some(group) = groups.get(group_id.0) else {
+                return Err(trivial_error!("Group not found (stale handle?)"));
+            };
+            if !group.data.is_mesh() {
+                return Err(trivial_error!("Group is not a mesh"));
+            }
+            (group.data.iface.clone(), group.data.path.clone())
+        };
+        iface.remove_network(&network_path).await?;
+
+        // Our mesh peers only exist in the mesh, so they're all lost.
+        let mesh_peers: Vec<_> = {
+            let prefix = format!("{}/MeshPeers/", network_path.as_str());
+            self.peers
+                .read()
+                .iter()
+                .filter(|peer| peer.data.path.as_str().starts_with(&prefix))
+                .map(|peer| peer.identity.physical.dev_addr)
+                .collect()
+        };
+        for addr in mesh_peers {
+            Self::mesh_peer_disconnected(self, group_id, addr);
+        }
+        self.connections.close_group(group_id);
+        if self.routing.write().remove_group(group_id) {
+            routing::routes_changed(self);
+        }
+        self.listener.left_group(self, group_id, false);
+        if let Some(group) = self.groups.write().remove(group_id.0) {
+            if let Some(task) = group.group_task.get() {
+                task.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a group with ourselves as the GO, without negotiating with any peer, so that peers
+    /// can join it right away by connecting to us. `P2PSessionListener::joined_group` is called
+    /// once it's up, and it then works like any group we negotiated to own.
+    pub async fn create_group(&self, options: GroupOptions) -> GenericResult<()> {
+        trace!("Session::create_group({options:?})");
+        let persistent = Value::from(options.persistent);
+        let persistent_group = options.persistent_group.map(Value::from);
+        let frequency = options.frequency.to_dbus()?.map(Value::from);
+        let mut args = HashMap::new();
+        args.insert("persistent", &persistent);
+        if let Some(ref path) = persistent_group {
+            args.insert("persistent_group_object", path);
+        } else if let Some(ref frequency) = frequency {
+            args.insert("frequency", frequency);
+        }
+        self.p2pdevice.group_add(args).await?;
+        Ok(())
+    }
+
+    /// Periodically listens for probe requests, so that other devices can discover us while we
+    /// don't scan ourselves, e.g. on a device that only ever owns groups with `create_group`.
+    /// `None` stops listening.
+    pub async fn set_extended_listen(&self, listen: Option<ExtendedListen>) -> GenericResult<()> {
+        trace!("Session::set_extended_listen({listen:?})");
+        let (period, interval) = match listen {
+            Some(listen) => {
+                if listen.interval < listen.period {
+                    return Err(trivial_error!("Listen interval shorter than its period"));
+                }
+                (
+                    i32::try_from(listen.period.as_millis())?,
+                    i32::try_from(listen.interval.as_millis())?,
+                )
+            }
+            None => (0, 0),
+        };
+        let period = Value::from(period);
+        let interval = Value::from(interval);
+        let mut args = HashMap::new();
+        args.insert("period", &period);
+        args.insert("interval", &interval);
+        self.p2pdevice.extended_listen(args).await?;
+        Ok(())
+    }
+
+    /// Returns the groups wpa_supplicant remembers, see `PersistentGroup`.
+    pub async fn persistent_groups(&self) -> GenericResult<Vec<PersistentGroup>> {
+        let mut groups = vec![];
+        for path in self.p2pdevice.persistent_groups().await? {
+            let proxy = wpa_supplicant::persistent_group::PersistentGroupProxy::new(
+                &self.system_bus,
+                path.clone(),
+            )
+            .await?;
+            let props: HashMap<String, Value<'_>> = proxy
+                .properties()
+                .await?
+                .into_iter()
+                .map(|(k, v)| (k, v.into()))
+                .collect();
+            groups.push(PersistentGroup::from_properties(path, &props));
+        }
+        Ok(groups)
+    }
+
+    /// Restarts a persistent group with a peer that was in it, skipping group negotiation. If we
+    /// were the GO we start the group again once the peer accepts, otherwise the peer does.
+    pub async fn reinvoke_persistent_group(
+        &self,
+        group: &PersistentGroup,
+        peer: PeerId,
+    ) -> GenericResult<()> {
+        trace!(
+            "Session::reinvoke_persistent_group({:?}, {peer:?})",
+            group.path
+        );
+        let peer_path = Value::from(self.p2p_peer_path(peer)?);
+        let group_path = Value::from(group.path.clone());
+        let mut args = HashMap::new();
+        args.insert("peer", &peer_path);
+        args.insert("persistent_group_object", &group_path);
+        self.p2pdevice.invite(args).await?;
+        Ok(())
+    }
+
+    /// Invites a peer to a Wi-Fi Direct group we're in. The peer is told about it through
+    /// `P2PSessionListener::invitation_received`, and joins the group if it accepts.
+    pub async fn invite_to_group(&self, group_id: GroupId, peer: PeerId) -> GenericResult<()> {
+        trace!("Session::invite_to_group({group_id:?}, {peer:?})");
+        let iface_path = {
+            let groups = self.groups.read();
+            let Some(group) = groups.get(group_id.0) else {
+                return Err(trivial_error!("Group not found (stale handle?)"));
+            };
+            if group.data.is_mesh() {
+                return Err(trivial_error!("Can't invite peers to a mesh"));
+            }
+            group.data.iface_path.clone()
+        };
+        // Invitations to running groups go through the interface of the group.
+        let group_device = P2PDeviceProxy::new(&self.system_bus, iface_path).await?;
+        let peer_path = Value::from(self.p2p_peer_path(peer)?);
+        let mut args = HashMap::new();
+        args.insert("peer", &peer_path);
+        group_device.invite(args).await?;
+        Ok(())
+    }
+
+    /// Returns the wpa_supplicant object of a Wi-Fi Direct peer.
+    fn p2p_peer_path(&self, id: PeerId) -> GenericResult<OwnedObjectPath> {
+        let peers = self.peers.read();
+        let Some(peer) = peers.get(id.0) else {
+            return Err(trivial_error!("Can't locate peer"));
+        };
+        if peer.data.proxy.is_none() {
+            return Err(trivial_error!("Peer is only reachable through a mesh"));
+        }
+        Ok(peer.data.path.clone())
+    }
+
+    /// Handles an invitation from a peer, joining the group if the listener accepts it.
+    async fn invitation_received(
+        session: &Arc<Self>,
+        props: &HashMap<&str, Value<'_>>,
+    ) -> GenericResult<()> {
+        let addr = |key: &str| mac_addr_arg(props, key);
+        let Some(sender) = addr("sa") else {
+            return Err(trivial_error!("Invitation without a source address"));
+        };
+        let find_peer = |addr: MacAddr| {
+            session
+                .peers
+                .read()
+                .iter_with_handles()
+                .find(|(_, p)| p.identity.physical.dev_addr == addr && p.data.proxy.is_some())
+                .map(|(id, p)| (PeerId(id), p.data.path.clone()))
+        };
+        let Some((peer_id, _)) = find_peer(sender) else {
+            trace!("Ignoring invitation from unknown device {sender}");
+            return Ok(());
+        };
+        if !session.listener.invitation_received(session, peer_id) {
+            trace!("Listener declined invitation from {peer_id:?}");
+            return Ok(());
+        }
+        if let Some(Value::I32(id)) = props.get("persistent_id") {
+            // A group we were in together, which we can start again from our side.
+            let path = OwnedObjectPath::try_from(format!(
+                "{}/PersistentGroups/{id}",
+                session.p2pdevice.inner().path().as_str()
+            ))?;
+            let path = Value::from(path);
+            let mut args = HashMap::new();
+            args.insert("persistent_group_object", &path);
+            session.p2pdevice.group_add(args).await?;
+            return Ok(());
+        }
+        let go_dev_addr = addr("go_dev_addr").unwrap_or(sender);
+        let Some((_, go_path)) = find_peer(go_dev_addr) else {
+            return Err(trivial_error!("Invited to the group of an unknown GO"));
+        };
+        let mut args = HashMap::default();
+        let method = Value::from(wps_method(&Provisioning::PushButton));
+        let join = Value::from(true);
+        let go_path = Value::from(go_path);
+        args.insert("peer", &go_path);
+        args.insert("join", &join);
+        args.insert("wps_method", &method);
+        session.p2pdevice.connect(args).await?;
+        Ok(())
+    }
+
+    /// Stores a group we joined and spawns its task, returning its id.
+    fn insert_group(session: &Arc<Self>, group: Group) -> GroupId {
+        let mut groups = session.groups.write();
+        let handle = groups.insert(group);
+        let id = GroupId(handle);
+        groups.get_mut(handle).unwrap().group_task.get_or_init(|| {
+            let session = session.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Session::group_task(session, id).await {
+                    error!("Group task for {id:?} failed with {e}");
+                    return Err(e);
+                }
+                Ok(())
+            })
+        });
+        id
+    }
+
+    /// Returns what we need to connect to a peer to message it.
+    fn message_target(&self, id: PeerId) -> GenericResult<ConnectionTarget> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let Some(peer) = peers.get(id.0) else {
+            return Err(trivial_error!("Peer was lost (stale handle?)"));
+        };
+        let Some(keys) = peer.key_exchange.encryption_keys() else {
+            return Err(trivial_error!("Key exchange hasn't finished yet?"));
+        };
+        let Some(identity) = peer.identity.verified_logical() else {
+            return Err(trivial_error!("Peer doesn't have a logical identity yet?"));
+        };
+        let Some(capabilities) = peer.capabilities else {
+            return Err(trivial_error!("Peer hasn't associated yet?"));
+        };
+        // Choose one arbitrary group to connect to it.
+        let Some(group_id) = peer.groups.first() else {
+            // TODO: Maybe we want to call connect_to_peer automatically?
+            return Err(trivial_error!("Peer is not connected to any group"));
+        };
+        let Some(group) = groups.get(group_id.0) else {
+            // TODO: Maybe we want to call connect_to_peer automatically?
+            return Err(trivial_error!("Group not found"));
+        };
+        let Some(info) = group.peers.get(&id) else {
+            return Err(trivial_error!(
+                "Peer doesn't have a link local address (yet?)"
+            ));
+        };
+        Ok(ConnectionTarget {
+            peer_id: id,
+            group_id: *group_id,
+            address: protocol::peer_to_socket_addr(
+                info.address.address,
+                group.scope_id,
+                info.address.ports.p2p,
+            ),
+            identity: identity.clone(),
+            keys: Arc::clone(keys),
+            capabilities,
+            wire_version: peer.wire_version,
+        })
+    }
+
+    async fn send_queued_message(&self, id: PeerId, message: QueuedMessage) -> GenericResult<()> {
+        let session = self.to_strong();
+        utils::retry_timeout(Duration::from_secs(2), 5, || async {
+            // Look the peer up again every time, so that if it comes back after its group is
+            // re-formed, the message goes to its new address.
+            let target = self.message_target(id)?;
+            self.connections.send(&session, &target, &message).await
+        })
+        .await
+    }
+
+    /// Finds the peer in the given group with the given logical key, returning the keys we share
+    /// with it, used to authenticate incoming connections from `address`.
+    fn resolve_group_peer(
+        &self,
+        group_id: GroupId,
+        address: SocketAddr,
+        key: &MaybeInvalidPublicKey,
+    ) -> Option<(ConnectionTarget, Arc<Keys>)> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let Some(group) = groups.get(group_id.0) else {
+            error!("Group {group_id:?} lost?");
+            return None;
+        };
+        for peer_id in group.peers.keys() {
+            let Some(peer) = peers.get(peer_id.0) else {
+                continue;
+            };
+            let Some(logical) = peer.identity.verified_logical() else {
+                continue;
+            };
+            if logical.key != *key {
+                continue;
+            }
+            let Some(keys) = peer.key_exchange.encryption_keys() else {
+                warn!("Peer {peer_id:?} connected but key exchange hasn't finished yet?");
+                return None;
+            };
+            let Some(capabilities) = peer.capabilities else {
+                warn!("Peer {peer_id:?} connected but hasn't associated yet?");
+                return None;
+            };
+            let target = ConnectionTarget {
+                peer_id: *peer_id,
+                group_id,
+                address,
+                identity: logical.clone(),
+                keys: Arc::clone(keys),
+                capabilities,
+                wire_version: peer.wire_version,
+            };
+            return Some((target, Arc::clone(keys)));
+        }
+        error!("Key {key:?} couldn't be mapped to a known peer in the group!");
+        None
+    }
+
+    /// Finds the peer in the given group with the given address, returning the keys we share
+    /// with it.
+    fn resolve_group_peer_by_address(
+        &self,
+        group_id: GroupId,
+        address: IpAddr,
+    ) -> Option<(PeerId, Arc<Keys>)> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let group = groups.get(group_id.0)?;
+        let (peer_id, _) = group
+            .peers
+            .iter()
+            .find(|(_, info)| info.address.address == address)?;
+        let keys = peers.get(peer_id.0)?.key_exchange.encryption_keys()?;
+        Some((*peer_id, Arc::clone(keys)))
+    }
+
+    /// Finds the peer in the given group with the address of an incoming connection if it
+    /// associated using wire version 1, in which connections aren't authenticated (see
+    /// `handshake::initiate`).
+    fn resolve_legacy_group_peer(
+        &self,
+        group_id: GroupId,
+        address: SocketAddr,
+    ) -> Option<ConnectionTarget> {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        let group = groups.get(group_id.0)?;
+        let (peer_id, _) = group
+            .peers
+            .iter()
+            .find(|(_, info)| info.address.address == address.ip())?;
+        let peer = peers.get(peer_id.0)?;
+        if peer.wire_version != 1 {
+            return None;
+        }
+        Some(ConnectionTarget {
+            peer_id: *peer_id,
+            group_id,
+            address,
+            identity: peer.identity.verified_logical()?.clone(),
+            keys: Arc::clone(peer.key_exchange.encryption_keys()?),
+            capabilities: peer.capabilities?,
+            wire_version: peer.wire_version,
+        })
+    }
+
+    /// Returns the wire version negotiated with the peer at the given address in a group, if any.
+    fn wire_version_for_address(&self, group_id: GroupId, address: IpAddr) -> u16 {
+        let peers = self.peers.read();
+        let groups = self.groups.read();
+        groups
+            .get(group_id.0)
+            .and_then(|group| {
+                let (peer_id, _) = group
+                    .peers
+                    .iter()
+                    .find(|(_, info)| info.address.address == address)?;
+                Some(peers.get(peer_id.0)?.wire_version)
+            })
+            .unwrap_or(wire::MIN_VERSION)
+    }
+
+    /// Waits for the GO of a group we joined to associate back with us, returning whether it did
+    /// before the timeout.
+    async fn wait_for_association(&self, group_id: GroupId, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let associated = self
+                    .groups
+                    .read()
+                    .get(group_id.0)
+                    .is_some_and(|g| !g.peers.is_empty());
+                if associated {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Starts tracking a peer we linked with in a mesh, and associates with it.
+    async fn mesh_peer_connected(
+        session: Arc<Self>,
+        group_id: GroupId,
+        addr: MacAddr,
+        own_ports: P2pPorts,
+    ) {
+        if let Err(e) = session
+            .associate_with_mesh_peer(group_id, addr, own_ports)
+            .await
+        {
+            error!("Failed to associate with mesh peer {addr} in {group_id:?}: {e}");
+        }
+    }
+
+    /// Sends our association message to a peer in a mesh until it associates back. Both sides do
+    /// this, and the one that gets there first wins, see `establish_control_channel`.
+    async fn associate_with_mesh_peer(
+        &self,
+        group_id: GroupId,
+        addr: MacAddr,
+        own_ports: P2pPorts,
+    ) -> GenericResult<()> {
+        let (peer_id, discovered, scope_id, message) = {
+            let mut peers = self.peers.write();
+            let groups = self.groups.read();
+            let Some(group) = groups.get(group_id.0) else {
+                return Err(trivial_error!("Mesh was torn down"));
+            };
+            let DbusGroupKind::Mesh { own_addr, .. } = group.data.kind else {
+                return Err(trivial_error!("Group is not a mesh"));
+            };
+            let path = mesh_peer_path(&group.data.path, &addr);
+            let (handle, discovered) = match peers.id_by_path(&path) {
+                Some(handle) => (handle, false),
+                None => {
+                    let handle = peers.insert(Peer {
+                        identity: PeerIdentity {
+                            physical: PhysiscalPeerIdentity {
+                                name: addr.to_string(),
+                                dev_addr: addr,
+                                iface_addr: None,
+                            },
+                            logical: None,
+                            logical_verified: false,
+                            advertised_capabilities: None,
+                            service: None,
+                        },
+                        key_exchange: protocol::key_exchange::KeyExchange::new()?,
+                        relay_key_exchange: None,
+                        groups: Vec::new(),
+                        capabilities: None,
+                        wire_version: wire::MIN_VERSION,
+                        data: DbusPeerData { proxy: None, path },
+                    });
+                    (handle, true)
+                }
+            };
+            let peer = peers.get_mut(handle).unwrap();
+            if peer.groups.contains(&group_id) {
+                // It associated with us first.
+                return Ok(());
+            }
+            if peer.groups.is_empty() {
+                // We might have associated with this peer before, start over.
+                peer.key_exchange.restart_if_completed()?;
+            }
+            let message = ControlMessage::Associate {
+                physical_id: PeerOwnIdentifier::DevAddr(own_addr.into()),
+                logical_id: self.identity.to_public(),
+                ports: own_ports,
+                key_exchange_public_key: peer.key_exchange.export_public_key(),
+                capabilities: Capabilities::ours(&self.limits),
+                // Hybrid key exchanges and resumption are only between a GO and its clients.
+                post_quantum_share: None,
+                resumption_ticket: None,
+            };
+            (PeerId(handle), discovered, group.scope_id, message)
+        };
+        if discovered {
+            self.listener.peer_discovered(self, peer_id);
+        }
+
+        // The peer might not have seen the link yet, in which case it drops our message.
+        let address = IpAddr::V6(utils::mac_addr_to_local_link_address(&addr));
+        for _ in 0..MESH_ASSOCIATION_ATTEMPTS {
+            let result = self
+                .send_control_message(
+                    address,
+                    GO_CONTROL_PORT,
+                    scope_id,
+                    wire::CURRENT_VERSION,
+                    message.clone(),
+                )
+                .await;
+            if let Err(e) = result {
+                warn!("Failed to send associate message to mesh peer {addr}: {e}");
+            }
+            if self
+                .wait_for_peer_association(group_id, peer_id, wire::ASSOCIATION_TIMEOUT)
+                .await
+            {
+                return Ok(());
+            }
+        }
+        Err(trivial_error!("Mesh peer didn't associate back"))
     }
 
-    pub fn p2pdevice(&self) -> &P2PDeviceProxy<'static> {
-        &self.p2pdevice
+    /// Waits for a peer to associate with us in a group, returning whether it did before the
+    /// timeout.
+    async fn wait_for_peer_association(
+        &self,
+        group_id: GroupId,
+        peer_id: PeerId,
+        timeout: Duration,
+    ) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let associated = self
+                    .groups
+                    .read()
+                    .get(group_id.0)
+                    .is_some_and(|g| g.peers.contains_key(&peer_id));
+                if associated {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok()
     }
 
-    fn peer_id_from_address(&self, group_id: GroupId, address: &SocketAddr) -> Option<PeerId> {
-        // TODO: This lookup could be faster, really.
-        let groups = self.groups.read();
-        let Some(group) = groups.get(group_id.0) else {
-            error!("Group {group_id:?} lost?");
-            return None;
+    /// Forgets a peer we lost the link with in a mesh. Mesh peers only exist in their mesh, so
+    /// they're lost altogether.
+    fn mesh_peer_disconnected(session: &Arc<Self>, group_id: GroupId, addr: MacAddr) {
+        let (peer_id, was_associated) = {
+            let mut peers = session.peers.write();
+            let mut groups = session.groups.write();
+            let Some(group) = groups.get_mut(group_id.0) else {
+                return;
+            };
+            let path = mesh_peer_path(&group.data.path, &addr);
+            let Some(handle) = peers.id_by_path(&path) else {
+                trace!("Lost unknown mesh peer {addr}");
+                return;
+            };
+            let peer_id = PeerId(handle);
+            peers.get_mut(handle).unwrap().groups.clear();
+            (peer_id, group.peers.remove(&peer_id).is_some())
         };
-        for (peer_id, info) in &group.peers {
-            if info.address.address == address.ip() {
-                return Some(*peer_id);
+        if was_associated {
+            session.connections.close(peer_id, group_id);
+            session.listener.peer_left_group(session, group_id, peer_id);
+            if session.routing.write().remove_neighbour(peer_id, group_id) {
+                routing::routes_changed(session);
             }
         }
-        error!("Address {address:?} couldn't be mapped to a known peer in the group!");
-        None
+        session.connections.close_peer(peer_id);
+        session.listener.peer_lost(session, peer_id);
+        session.peers.write().remove(peer_id.0);
     }
 
-    async fn send_control_message(
+    /// Sends our association message to the GO of a group we joined, falling back to older wire
+    /// versions if the GO doesn't associate back (see the `wire` module).
+    async fn associate_with_go(
         &self,
-        ip: IpAddr,
-        control_port: u16,
+        group_id: GroupId,
+        go_ip: IpAddr,
         scope_id: u32,
         message: ControlMessage,
     ) -> GenericResult<()> {
-        trace!("Session::send_control_message({ip:?}, {scope_id}, {message:?})");
-        let addr = protocol::peer_to_socket_addr(ip, scope_id, control_port);
-        let msg = bincode::encode_to_vec(message, bincode::config::standard())?;
-        utils::retry_timeout(Duration::from_secs(2), 5, || {
-            protocol::send_message(None, None, &addr, &msg)
-        })
-        .await
+        for version in [wire::CURRENT_VERSION, wire::MIN_VERSION] {
+            trace!("Trying to send control message to {go_ip:?} (version {version})");
+            self.send_control_message(go_ip, GO_CONTROL_PORT, scope_id, version, message.clone())
+                .await?;
+            if self
+                .wait_for_association(group_id, wire::ASSOCIATION_TIMEOUT)
+                .await
+            {
+                return Ok(());
+            }
+            warn!("GO didn't associate back with wire version {version}");
+        }
+        Ok(())
     }
 
     async fn establish_control_channel(
@@ -383,57 +1608,134 @@ impl Session {
         trace!(
             "Session::establish_control_channel({group_id:?}, {scope_id}, {own_ports:?}, {is_go})"
         );
+        // Members of a mesh all reply to each other's association.
+        let (own_phy_id, is_mesh) = match session.groups.read().get(group_id.0) {
+            Some(g) => (session.own_phy_id_in(&g.data.kind), g.data.is_mesh()),
+            None => (session.own_phy_id.read().clone(), false),
+        };
+        let replies_to_associate = is_go || is_mesh;
+        let mut rate_limiter = KeyedRateLimiter::new(
+            session.limits.control_connection_burst,
+            session.limits.control_connections_per_second,
+            session.limits.max_control_connection_sources,
+        );
         loop {
             // TODO: Use a buffered reader.
             // TODO: Keep a single stream around for faster bi-lateral communication maybe?
             let (mut stream, address) = control_listener.accept().await?;
+            // Known peers use the control port for relayed messages, rosters and routes, so only
+            // unknown addresses are rate limited.
+            let known = session
+                .groups
+                .read()
+                .get(group_id.0)
+                .is_some_and(|g| g.has_peer_at(address.ip()));
+            if !known && !rate_limiter.try_acquire(address.ip()) {
+                warn!("Rate limiting control connection from {address:?} in group {group_id:?}");
+                continue;
+            }
+            let Some(mut permit) = session.control_connections.try_acquire() else {
+                warn!("Too many control connections, dropping connection from {address:?}");
+                continue;
+            };
+            if !permit.attribute(address.ip()) {
+                warn!("Too many control connections from {address:?}, dropping connection");
+                continue;
+            }
             let session = Arc::clone(&session);
+            let own_phy_id = own_phy_id.clone();
             tokio::spawn(async move {
+                let _permit = permit;
                 trace!("Incoming connection from {address:?}");
-                while let Ok(control_message) =
-                    protocol::read_control_message(&mut stream, &address).await
+                while let Ok((version, control_message)) =
+                    protocol::read_control_message(&mut stream, &address, &session.limits).await
                 {
-                    trace!("Got control message {control_message:?} on group {group_id:?}");
+                    trace!(
+                        "Got control message {control_message:?} (version {version}) on group {group_id:?}"
+                    );
                     match control_message {
                         ControlMessage::Associate {
                             physical_id,
                             logical_id,
                             key_exchange_public_key,
                             ports,
+                            capabilities,
+                            post_quantum_share,
+                            resumption_ticket,
                         } => {
-                            let (peer_id, key_exchange_public_key) = {
+                            let (peer_id, key_exchange_public_key, post_quantum_share, resumption) = {
                                 let mut peers = session.peers.write();
-                                let mut result = None;
-                                for (id, peer) in peers.iter_mut_with_handles() {
+                                let found = physical_id.find_peer(
+                                    peers.iter_mut_with_handles(),
+                                    |(_, p)| &p.identity.physical,
+                                    session.name_matching,
+                                );
+                                let found = match found {
+                                    Ok(found) => found,
+                                    Err(e) => {
+                                        error!("Refusing to associate {physical_id:?}: {e}");
+                                        continue;
+                                    }
+                                };
+                                let result = 'associate: {
+                                    let Some((id, peer)) = found else {
+                                        break 'associate None;
+                                    };
                                     trace!(" {:?} -> {:?}", id, peer.identity.physical);
-                                    if peer.identity.physical.matches(&physical_id) {
-                                        if peer
-                                            .identity
-                                            .logical
-                                            .as_ref()
-                                            .is_some_and(|i| *i != logical_id)
-                                        {
-                                            error!("Refusing to associate {id:?} with different logical {logical_id:?}");
-                                            break;
-                                        }
-                                        if peer.groups.contains(&group_id) {
-                                            error!("Refusing to associate {id:?} with {group_id:?} again");
-                                            break;
-                                        }
-                                        peer.groups.push(group_id);
-                                        peer.identity.logical = Some(logical_id);
-                                        if let Err(e) =
-                                            peer.key_exchange.finish(&key_exchange_public_key)
-                                        {
+                                    if peer
+                                        .identity
+                                        .verified_logical()
+                                        .is_some_and(|i| *i != logical_id)
+                                    {
+                                        error!("Refusing to associate {id:?} with different logical {logical_id:?}");
+                                        break 'associate None;
+                                    }
+                                    if peer.groups.contains(&group_id) {
+                                        error!(
+                                            "Refusing to associate {id:?} with {group_id:?} again"
+                                        );
+                                        break 'associate None;
+                                    }
+                                    peer.groups.push(group_id);
+                                    // The peer offers a ticket if we're the GO, and echoes
+                                    // ours if we're the client and the GO accepted it.
+                                    let resumption = resumption_ticket
+                                        .and_then(|id| session.resumption.redeem(&id, &logical_id));
+                                    let capabilities = Capabilities::ours(&session.limits)
+                                        .negotiate(&capabilities);
+                                    peer.capabilities = Some(capabilities);
+                                    peer.wire_version = version;
+                                    peer.identity.logical = Some(logical_id);
+                                    peer.identity.logical_verified = true;
+                                    let post_quantum_share = match peer.key_exchange.finish(
+                                        &key_exchange_public_key,
+                                        post_quantum_share.as_ref(),
+                                        resumption.as_ref(),
+                                        capabilities.cipher_suite(),
+                                    ) {
+                                        Ok(reply) => reply,
+                                        Err(e) => {
                                             error!("Failed to finish key exchange ({:?})", e);
+                                            None
                                         }
-                                        result = Some((
-                                            PeerId(id),
-                                            peer.key_exchange.export_public_key(),
-                                        ));
-                                        break;
+                                    };
+                                    if let (Some(keys), Some(logical)) = (
+                                        peer.key_exchange.encryption_keys(),
+                                        &peer.identity.logical,
+                                    ) {
+                                        session.resumption.store(
+                                            peer.identity.physical.dev_addr,
+                                            logical,
+                                            keys.resumption_ticket().clone(),
+                                        );
                                     }
-                                }
+                                    Some((
+                                        PeerId(id),
+                                        peer.key_exchange.export_public_key(),
+                                        post_quantum_share,
+                                        resumption.map(|ticket| ticket.id),
+                                    ))
+                                };
                                 match result {
                                     Some(r) => r,
                                     None => {
@@ -452,7 +1754,7 @@ impl Session {
                                 let address = PeerAddress { address, ports };
                                 group.peers.insert(peer_id, PeerGroupInfo { address });
                             };
-                            if is_go {
+                            if replies_to_associate {
                                 // Try to send the association request back to the peer. This
                                 // ensures that the peer notifies of the connection (via the
                                 // is_new_connection code-path).
@@ -465,22 +1767,117 @@ impl Session {
                                         address,
                                         ports.control,
                                         scope_id,
+                                        version,
                                         ControlMessage::Associate {
-                                            physical_id: session.own_phy_id.clone(),
+                                            physical_id: own_phy_id.clone(),
                                             logical_id: session.identity.to_public(),
                                             key_exchange_public_key,
                                             ports: own_ports,
+                                            capabilities: Capabilities::ours(&session.limits),
+                                            post_quantum_share,
+                                            resumption_ticket: resumption,
                                         },
                                     )
                                     .await;
                                 if let Err(e) = result {
-                                    error!("Failed to send associate message from GO to {peer_id:?}: {e}");
+                                    error!(
+                                        "Failed to send associate message back to {peer_id:?}: {e}"
+                                    );
                                 }
                             }
                             trace!("Notifying of new association of {peer_id:?} to {group_id:?}");
                             session
                                 .listener
                                 .peer_joined_group(&session, group_id, peer_id);
+                            if is_go {
+                                tokio::spawn(roster::broadcast(Arc::clone(&session), group_id));
+                            }
+                            tokio::spawn(routing::announce(Arc::clone(&session), group_id));
+                        }
+                        ControlMessage::Roster { update, signature } => {
+                            // The roster might arrive before the association back from the GO.
+                            if !session
+                                .wait_for_association(group_id, wire::ASSOCIATION_TIMEOUT)
+                                .await
+                            {
+                                warn!("Got roster in {group_id:?} before associating with the GO");
+                                continue;
+                            }
+                            let (joined, left) =
+                                match roster::apply(&*session, group_id, update, &signature) {
+                                    Ok(r) => r,
+                                    Err(e) => {
+                                        error!(
+                                            "Dropping roster from {address:?} in {group_id:?}: {e}"
+                                        );
+                                        continue;
+                                    }
+                                };
+                            for peer_id in &joined {
+                                session
+                                    .listener
+                                    .peer_joined_group(&session, group_id, *peer_id);
+                            }
+                            for peer_id in &left {
+                                session.connections.close(*peer_id, group_id);
+                                session
+                                    .listener
+                                    .peer_left_group(&session, group_id, *peer_id);
+                            }
+                        }
+                        ControlMessage::Relay {
+                            envelope,
+                            signature,
+                        } => {
+                            let result = if is_go {
+                                relay::forward(
+                                    &*session,
+                                    group_id,
+                                    address.ip(),
+                                    envelope,
+                                    signature,
+                                )
+                                .await
+                            } else {
+                                relay::receive(&*session, group_id, envelope, signature).await
+                            };
+                            if let Err(e) = result {
+                                warn!("Dropping relayed envelope from {address:?} in {group_id:?}: {e}");
+                            }
+                        }
+                        ControlMessage::Routes {
+                            announcement,
+                            signature,
+                        } => match routing::apply(
+                            &*session,
+                            group_id,
+                            address.ip(),
+                            &announcement,
+                            &signature,
+                        ) {
+                            Ok(true) => routing::routes_changed(&session),
+                            Ok(false) => {}
+                            Err(e) => {
+                                warn!("Dropping routes from {address:?} in {group_id:?}: {e}")
+                            }
+                        },
+                        ControlMessage::Routed {
+                            envelope,
+                            signature,
+                            ttl,
+                        } => {
+                            let result = routing::handle(
+                                &*session,
+                                group_id,
+                                address.ip(),
+                                envelope,
+                                signature,
+                                ttl,
+                            )
+                            .await;
+                            if let Err(e) = result {
+                                warn!("Dropping routed envelope from {address:?} in {group_id:?}: {e}");
+                            }
                         }
                     }
                 }
@@ -498,77 +1895,100 @@ impl Session {
     ) -> GenericResult<()> {
         trace!("Session::listen_to_peer_messages({group_id:?}, {scope_id})");
         loop {
-            // TODO: Use a buffered reader.
-            // TODO: Keep a single stream around for faster bi-lateral communication maybe?
             let (mut stream, address) = listener.accept().await?;
-            let Some(peer_id) = session.peer_id_from_address(group_id, &address) else {
-                warn!("Got message from {address:?} but couldn't map that to a peer in group {group_id:?}");
+            let Some(mut permit) = session.peer_connections.try_acquire() else {
+                warn!("Too many peer connections, dropping connection from {address:?}");
                 continue;
             };
-            let (peer_identity, encryption_keys) = {
-                let peers = session.peers.read();
-                let Some(peer) = peers.get(peer_id.0) else {
-                    warn!("Got message from {address:?} but peer is gone?");
-                    continue;
-                };
-                let Some(ref peer_identity) = peer.identity.logical else {
-                    warn!("Got message from {address:?} but peer doesn't yet have a logical id?");
-                    continue;
-                };
-                let Some(keys) = peer.key_exchange.encryption_keys() else {
-                    warn!("Got message from {address:?} but key exchange hasn't finished yet?");
-                    continue;
-                };
-                (peer_identity.clone(), Arc::clone(keys))
-            };
             let session = Arc::clone(&session);
             tokio::spawn(async move {
                 trace!("Incoming connection from {address:?}");
-                while let Ok(buf) = protocol::read_peer_message(
-                    &session.identity,
-                    &encryption_keys,
-                    &peer_identity,
-                    &mut stream,
-                    &address,
-                )
-                .await
-                {
-                    trace!(
-                        "Got message from socket: {:?}",
-                        String::from_utf8_lossy(&buf)
+                let accepted = match session.resolve_legacy_group_peer(group_id, address) {
+                    Some(target) => protocol::handshake::legacy_records(&target.keys)
+                        .map(|records| (target, records)),
+                    None => {
+                        let challenge_version =
+                            session.wire_version_for_address(group_id, address.ip());
+                        protocol::handshake::accept(
+                            &mut stream,
+                            &address,
+                            challenge_version,
+                            |key| session.resolve_group_peer(group_id, address, key),
+                        )
+                        .await
+                    }
+                };
+                let (target, records) = match accepted {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Dropping connection from {address:?} in group {group_id:?}: {e}");
+                        return;
+                    }
+                };
+                if !permit.attribute(target.peer_id) {
+                    warn!(
+                        "Too many connections from {:?}, dropping connection from {address:?}",
+                        target.peer_id
                     );
-                    session
-                        .listener
-                        .peer_messaged(&session, peer_id, group_id, &buf);
+                    return;
                 }
+                session
+                    .connections
+                    .adopt(&session, &target, stream, records, permit);
             });
         }
         #[allow(unreachable_code)]
         Ok(())
     }
 
+    async fn listen_to_datagrams(
+        session: Arc<Self>,
+        socket: Arc<UdpSocket>,
+        group_id: GroupId,
+    ) -> GenericResult<()> {
+        trace!("Session::listen_to_datagrams({group_id:?})");
+        let mut buf = vec![0u8; datagram::MAX_PACKET_LEN];
+        loop {
+            let (len, address) = socket.recv_from(&mut buf).await?;
+            let Some((peer_id, keys)) =
+                session.resolve_group_peer_by_address(group_id, address.ip())
+            else {
+                trace!("Dropping datagram from unknown address {address:?}");
+                continue;
+            };
+            let payload = match datagram::open(&keys, &mut buf[..len]) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    trace!("Dropping datagram from {address:?}: {e}");
+                    continue;
+                }
+            };
+            session
+                .listener
+                .peer_datagram(&session, peer_id, group_id, payload);
+        }
+    }
+
     async fn group_task(session: Arc<Self>, group_id: GroupId) -> GenericResult<()> {
         trace!("Session::group_task({group_id:?})");
 
-        let (is_go, go_ip, go_dev_addr, scope_id, proxy) =
-            match session.groups.read().get(group_id.0) {
-                Some(g) => (
-                    g.is_go,
-                    g.go_ip_address,
-                    g.data.go_dev_addr,
-                    g.scope_id,
-                    g.data.proxy.clone(),
-                ),
-                None => {
-                    error!("Didn't find {group_id:?} on group_task start!");
-                    return Err(trivial_error!("Didn't find group on group_task start!"));
-                }
-            };
+        let (is_go, go_ip, kind, scope_id) = match session.groups.read().get(group_id.0) {
+            Some(g) => (g.is_go, g.go_ip_address, g.data.kind.clone(), g.scope_id),
+            None => {
+                error!("Didn't find {group_id:?} on group_task start!");
+                return Err(trivial_error!("Didn't find group on group_task start!"));
+            }
+        };
 
         let (control_listener, p2p_listener) = tokio::try_join!(
             TcpListener::bind(SocketAddrV6::new(
                 Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
-                if is_go { GO_CONTROL_PORT } else { 0 },
+                // Every member of a mesh listens like a GO, see `Session::join_mesh`.
+                if is_go || matches!(kind, DbusGroupKind::Mesh { .. }) {
+                    GO_CONTROL_PORT
+                } else {
+                    0
+                },
                 /* flowinfo = */ 0,
                 scope_id,
             )),
@@ -579,35 +1999,127 @@ impl Session {
                 scope_id,
             )),
         )?;
+        let datagram_socket = Arc::new(
+            UdpSocket::bind(SocketAddrV6::new(
+                Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0),
+                0,
+                /* flowinfo = */ 0,
+                scope_id,
+            ))
+            .await?,
+        );
 
         let my_ports = P2pPorts {
             control: control_listener.local_addr()?.port(),
             p2p: p2p_listener.local_addr()?.port(),
+            datagram: datagram_socket.local_addr()?.port(),
         };
+        if let Some(group) = session.groups.read().get(group_id.0) {
+            let _ = group.datagram_socket.set(Arc::clone(&datagram_socket));
+        }
 
         trace!(" > go = {is_go}, ports = {my_ports:?}, go_ip = {go_ip:?}");
+        let (go_dev_addr, proxy) = match kind {
+            DbusGroupKind::P2p {
+                go_dev_addr,
+                ref proxy,
+                ..
+            } => (go_dev_addr, proxy.clone()),
+            DbusGroupKind::Mesh { proxy, .. } => {
+                let mut peer_connected = proxy.receive_mesh_peer_connected().await?;
+                let mut peer_disconnected = proxy.receive_mesh_peer_disconnected().await?;
+                tokio::try_join!(
+                    async {
+                        // Peers we linked with before we started listening.
+                        for addr in proxy.mesh_peers().await? {
+                            let Some(addr) = utils::to_mac_addr(&addr) else {
+                                error!("Expected a valid mac address, got {addr:?}");
+                                continue;
+                            };
+                            tokio::spawn(Self::mesh_peer_connected(
+                                Arc::clone(&session),
+                                group_id,
+                                addr,
+                                my_ports,
+                            ));
+                        }
+                        while let Some(msg) = peer_connected.next().await {
+                            let args = msg.args()?;
+                            let Some(addr) = mesh_peer_address(args.args()) else {
+                                error!("Expected a mesh peer address, got {:?}", args.args());
+                                continue;
+                            };
+                            trace!("Mesh peer connected to {group_id:?}: {addr}");
+                            tokio::spawn(Self::mesh_peer_connected(
+                                Arc::clone(&session),
+                                group_id,
+                                addr,
+                                my_ports,
+                            ));
+                        }
+                        Ok(())
+                    },
+                    async {
+                        while let Some(msg) = peer_disconnected.next().await {
+                            let args = msg.args()?;
+                            let Some(addr) = mesh_peer_address(args.args()) else {
+                                error!("Expected a mesh peer address, got {:?}", args.args());
+                                continue;
+                            };
+                            trace!("Mesh peer disconnected from {group_id:?}: {addr}");
+                            Self::mesh_peer_disconnected(&session, group_id, addr);
+                        }
+                        Ok(())
+                    },
+                    Self::listen_to_peer_messages(
+                        Arc::clone(&session),
+                        p2p_listener,
+                        group_id,
+                        scope_id
+                    ),
+                    Self::listen_to_datagrams(Arc::clone(&session), datagram_socket, group_id),
+                    routing::maintain(Arc::clone(&session), group_id),
+                    Self::establish_control_channel(
+                        Arc::clone(&session),
+                        control_listener,
+                        group_id,
+                        scope_id,
+                        my_ports,
+                        is_go,
+                    ),
+                )?;
+                return Ok(());
+            }
+        };
         if !is_go {
-            let key_exchange_public_key = {
+            let (key_exchange_public_key, post_quantum_share, resumption_ticket) = {
                 trace!(" > GO dev addr is {}", go_dev_addr);
-                let peers = session.peers.read();
-                let id = PeerOwnIdentifier::DevAddr(go_dev_addr.into());
-                let key = peers.iter().find_map(|p| {
-                    p.identity
-                        .physical
-                        .matches(&id)
-                        .then(|| p.key_exchange.export_public_key())
-                });
-                match key {
-                    Some(key) => key,
-                    None => return Err(trivial_error!("Couldn't find GO by dev addr")),
+                let mut peers = session.peers.write();
+                let Some((_, go)) = peers
+                    .iter_mut_with_handles()
+                    .find(|(_, p)| p.identity.physical.dev_addr == go_dev_addr)
+                else {
+                    return Err(trivial_error!("Couldn't find GO by dev addr"));
+                };
+                if go.groups.is_empty() {
+                    // We might have associated with this GO before, start over.
+                    go.key_exchange.restart_if_completed()?;
                 }
+                (
+                    go.key_exchange.export_public_key(),
+                    go.key_exchange.export_post_quantum_key(),
+                    session.resumption.offer(go_dev_addr),
+                )
             };
 
             let control_message = ControlMessage::Associate {
-                physical_id: session.own_phy_id.clone(),
+                physical_id: session.own_phy_id_in(&kind),
                 logical_id: session.identity.to_public(),
                 ports: my_ports,
                 key_exchange_public_key,
+                capabilities: Capabilities::ours(&session.limits),
+                post_quantum_share,
+                resumption_ticket,
             };
             tokio::try_join!(
                 Self::listen_to_peer_messages(
@@ -616,6 +2128,8 @@ impl Session {
                     group_id,
                     scope_id
                 ),
+                Self::listen_to_datagrams(Arc::clone(&session), datagram_socket, group_id),
+                routing::maintain(Arc::clone(&session), group_id),
                 Self::establish_control_channel(
                     Arc::clone(&session),
                     control_listener,
@@ -624,12 +2138,7 @@ impl Session {
                     my_ports,
                     is_go,
                 ),
-                async move {
-                    trace!("Trying to send control message to {go_ip:?}");
-                    session
-                        .send_control_message(go_ip, GO_CONTROL_PORT, scope_id, control_message)
-                        .await
-                },
+                session.associate_with_go(group_id, go_ip, scope_id, control_message),
             )?;
             return Ok(());
         }
@@ -674,14 +2183,20 @@ impl Session {
                         this_group.peers.remove(&peer_id);
                         peer_id
                     };
-                    // TODO: Broadcast to non-GOs?
+                    session.connections.close(peer_id, group_id);
                     session
                         .listener
                         .peer_left_group(&session, group_id, peer_id);
+                    tokio::spawn(roster::broadcast(Arc::clone(&session), group_id));
+                    if session.routing.write().remove_neighbour(peer_id, group_id) {
+                        routing::routes_changed(&session);
+                    }
                 }
                 Ok(())
             },
             Self::listen_to_peer_messages(Arc::clone(&session), p2p_listener, group_id, scope_id),
+            Self::listen_to_datagrams(Arc::clone(&session), datagram_socket, group_id),
+            routing::maintain(Arc::clone(&session), group_id),
             Self::establish_control_channel(
                 Arc::clone(&session),
                 control_listener,
@@ -703,6 +2218,11 @@ impl Session {
 
         let mut device_lost = session.p2pdevice.receive_device_lost().await?;
 
+        let mut service_discovery_response = session
+            .p2pdevice
+            .receive_service_discovery_response()
+            .await?;
+
         let mut invitation_received = session.p2pdevice.receive_invitation_received().await?;
         let mut invitation_result = session.p2pdevice.receive_invitation_result().await?;
 
@@ -794,13 +2314,36 @@ impl Session {
                         continue;
                     };
 
-                    let physical_identity = PhysiscalPeerIdentity { name, dev_addr };
+                    let physical_identity = PhysiscalPeerIdentity {
+                        name,
+                        dev_addr,
+                        iface_addr: None,
+                    };
+                    let beacon = if session.beacon.is_some() {
+                        match proxy
+                            .vsie()
+                            .await
+                            .map_err(Into::into)
+                            .and_then(|ies| Beacon::find_in_ies(&ies))
+                        {
+                            Ok(beacon) => beacon,
+                            Err(e) => {
+                                warn!("Ignoring invalid beacon from {physical_identity}: {e}");
+                                None
+                            }
+                        }
+                    } else {
+                        None
+                    };
 
-                    let handle = {
+                    let (handle, discovered) = {
                         let mut peers = session.peers.write();
-                        if let Some(id) = peers.id_by_path(&path) {
+                        let id = if let Some(id) = peers.id_by_path(&path) {
                             let existing = peers.get_mut(id).expect("DBUS store out of sync");
                             trace!("Peer was already registered (from previous scan?) with identity {:?}", existing.identity);
+                            if let Some(ref beacon) = beacon {
+                                existing.identity.set_beacon(beacon);
+                            }
                             // TODO(emilio): Consider not notifying? Kinda puts the burden of
                             // preserving peer list to the parent.
                             id
@@ -808,19 +2351,30 @@ impl Session {
                             peers.insert(Peer {
                                 identity: PeerIdentity {
                                     physical: physical_identity,
-                                    logical: None,
+                                    logical: beacon.as_ref().map(|b| b.identity.clone()),
+                                    logical_verified: false,
+                                    advertised_capabilities: beacon.map(|b| b.capabilities),
+                                    service: None,
                                 },
                                 key_exchange: protocol::key_exchange::KeyExchange::new().unwrap(),
+                                relay_key_exchange: None,
                                 groups: Vec::new(),
+                                capabilities: None,
+                                wire_version: wire::MIN_VERSION,
                                 data: DbusPeerData {
-                                    proxy,
+                                    proxy: Some(proxy),
                                     path: path.into(),
                                 },
                             })
-                        }
+                        };
+                        (id, session.is_discovered(peers.get(id).unwrap()))
                     };
 
-                    session.listener.peer_discovered(&session, PeerId(handle));
+                    if discovered {
+                        session.listener.peer_discovered(&session, PeerId(handle));
+                    } else {
+                        trace!("Not reporting {handle:?} until it advertises a matching service");
+                    }
                 }
                 Ok(())
             },
@@ -829,8 +2383,9 @@ impl Session {
                     let args = msg.args()?;
                     let peer_path = args.path();
                     trace!("Lost device at {peer_path}");
+                    session.provisioning.write().remove(peer_path.as_str());
 
-                    let (peer_id, groups_disconnected) = {
+                    let (peer_id, groups_disconnected, discovered) = {
                         let mut peers = session.peers.write();
                         let id = match peers.id_by_path(peer_path) {
                             Some(id) => id,
@@ -849,7 +2404,8 @@ impl Session {
                         };
 
                         trace!("Peer lost: {peer:?}");
-                        (PeerId(id), std::mem::take(&mut peer.groups))
+                        let discovered = session.is_discovered(peer);
+                        (PeerId(id), std::mem::take(&mut peer.groups), discovered)
                     };
 
                     // Remove the peer for any outstanding groups before notifying the
@@ -874,20 +2430,74 @@ impl Session {
                             session
                                 .listener
                                 .peer_left_group(&session, *group_id, peer_id);
+                            tokio::spawn(roster::broadcast(Arc::clone(&session), *group_id));
+                            if session.routing.write().remove_neighbour(peer_id, *group_id) {
+                                routing::routes_changed(&session);
+                            }
                         }
                     }
 
-                    session.listener.peer_lost(&session, peer_id);
+                    session.connections.close_peer(peer_id);
+                    if discovered {
+                        session.listener.peer_lost(&session, peer_id);
+                    }
                     let removed = session.peers.write().remove(peer_id.0);
                     debug_assert!(removed.is_some(), "Found id but couldn't remove peer?");
                 }
                 Ok(())
             },
+            async {
+                while let Some(msg) = service_discovery_response.next().await {
+                    let args = msg.args()?;
+                    let response = args.sd_response();
+                    trace!("Service discovery response: {response:?}");
+                    let Some(Value::ObjectPath(peer_path)) = response.get("peer_object") else {
+                        error!("Expected a peer object path, got {response:?}");
+                        continue;
+                    };
+                    let Some(Value::Array(tlvs)) = response.get("tlvs") else {
+                        error!("Expected service discovery TLVs, got {response:?}");
+                        continue;
+                    };
+                    let tlvs: Option<Vec<u8>> = tlvs
+                        .iter()
+                        .map(|b| match b {
+                            Value::U8(b) => Some(*b),
+                            _ => None,
+                        })
+                        .collect();
+                    let Some(record) = tlvs.as_deref().and_then(service::parse_bonjour_response)
+                    else {
+                        trace!("{peer_path} doesn't advertise our service");
+                        continue;
+                    };
+                    let peer_id = {
+                        let mut peers = session.peers.write();
+                        let Some(id) = peers.id_by_path(peer_path) else {
+                            error!("Got service discovery response from unknown {peer_path}");
+                            continue;
+                        };
+                        let peer = peers.get_mut(id).unwrap();
+                        let was_discovered = session.is_discovered(peer);
+                        trace!("{peer_path} advertises {record:?}");
+                        peer.identity.service = Some(record);
+                        if was_discovered || !session.is_discovered(peer) {
+                            continue;
+                        }
+                        PeerId(id)
+                    };
+                    session.listener.peer_discovered(&session, peer_id);
+                }
+                Ok(())
+            },
             async {
                 while let Some(msg) = invitation_received.next().await {
                     let args = msg.args()?;
                     let props = args.properties();
                     trace!("Got invitation: {props:?}");
+                    if let Err(e) = Self::invitation_received(&session, props).await {
+                        error!("Failed to handle invitation: {e}");
+                    }
                 }
                 Ok(())
             },
@@ -896,6 +2506,10 @@ impl Session {
                     let args = msg.args()?;
                     let props = args.invite_result();
                     trace!("Got invitation result: {props:?}");
+                    match props.get("status") {
+                        Some(Value::I32(0)) => {}
+                        status => warn!("Invitation failed with status {status:?}"),
+                    }
                 }
                 Ok(())
             },
@@ -958,16 +2572,30 @@ impl Session {
                     trace!("Group GO dev address is {go_dev_addr:?}");
 
                     let is_go = props.get("role") == Some(&Value::from("GO"));
-                    let id = {
-                        let mut groups = session.groups.write();
-                        let data = DbusGroupData {
+                    if is_go {
+                        session.learn_own_dev_addr(go_dev_addr);
+                    }
+                    let own_iface_addr = match iface.macaddress().await {
+                        Ok(addr) => utils::to_mac_addr(&addr),
+                        Err(e) => {
+                            trace!("Couldn't get our group interface address: {e}");
+                            None
+                        }
+                    };
+                    trace!("Own group interface address is {own_iface_addr:?}");
+                    let data = DbusGroupData {
+                        kind: DbusGroupKind::P2p {
                             proxy: group,
-                            iface,
-                            iface_path: iface_path.into(),
-                            path: group_path.into(),
                             go_dev_addr,
-                        };
-                        let handle = groups.insert(Group {
+                            own_iface_addr,
+                        },
+                        iface,
+                        iface_path: iface_path.into(),
+                        path: group_path.into(),
+                    };
+                    let id = Self::insert_group(
+                        &session,
+                        Group {
                             go_ip_address: IpAddr::V6(utils::mac_addr_to_local_link_address(
                                 &go_iface_addr,
                             )),
@@ -975,22 +2603,12 @@ impl Session {
                             scope_id,
                             is_go,
                             peers: Default::default(),
+                            roster: Default::default(),
                             group_task: OnceLock::new(),
+                            datagram_socket: OnceLock::new(),
                             data,
-                        });
-                        let id = GroupId(handle);
-                        groups.get_mut(handle).unwrap().group_task.get_or_init(|| {
-                            let session = session.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = Session::group_task(session, id).await {
-                                    error!("Group task for {id:?} failed with {e}");
-                                    return Err(e);
-                                }
-                                Ok(())
-                            })
-                        });
-                        id
-                    };
+                        },
+                    );
 
                     session.listener.joined_group(&session, id, is_go);
                 }
@@ -1031,6 +2649,10 @@ impl Session {
                         trace!("Group finished: {group:?}");
                         (GroupId(id), group.is_go, std::mem::take(&mut group.peers))
                     };
+                    session.connections.close_group(group_id);
+                    if session.routing.write().remove_group(group_id) {
+                        routing::routes_changed(&session);
+                    }
 
                     if !peers_lost.is_empty() {
                         let mut peers = session.peers.write();
@@ -1088,18 +2710,9 @@ impl Session {
                     };
 
                     let session = Arc::clone(&session);
-                    let peer = Value::from(peer_path);
-                    let method = Value::from(WPS_METHOD);
-                    let go_intent = Value::from(session.go_intent as i32);
-                    let auto_join = Value::from(true);
-                    tokio::spawn(async move {
-                        let mut connect_args = HashMap::new();
-                        connect_args.insert("peer", &peer);
-                        connect_args.insert("wps_method", &method);
-                        connect_args.insert("auto_join", &auto_join);
-                        connect_args.insert("go_intent", &go_intent);
-                        session.p2pdevice.connect(connect_args).await
-                    });
+                    tokio::spawn(
+                        async move { session.connect_to_peer_by_path(peer_path.into()).await },
+                    );
                 }
                 Ok(())
             },
@@ -1110,6 +2723,27 @@ impl Session {
                     let passwd_id = args.dev_passwd_id();
                     let go_intent = args.device_go_intent();
                     trace!("GO negotiation request from {path} ({passwd_id} / {go_intent})");
+                    let known = session.provisioning.read().contains_key(path.as_str());
+                    if !known {
+                        // The peer didn't go through provision discovery first, so go by what it
+                        // tells it uses.
+                        match *passwd_id {
+                            DEV_PW_REGISTRAR_SPECIFIED => {
+                                // The user needs to enter the PIN, which connects to the peer.
+                                if let Some(peer_id) = session.peer_id_by_path(path) {
+                                    session.listener.pin_requested(&session, peer_id);
+                                }
+                                continue;
+                            }
+                            DEV_PW_USER_SPECIFIED => {
+                                session.provisioning.write().insert(
+                                    path.as_str().to_owned(),
+                                    Provisioning::DisplayPin(None),
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
                     // Let's try to connect to the peer directly.
                     // TODO(emilio): Maybe confirm?
                     session
@@ -1123,6 +2757,22 @@ impl Session {
                     let args = msg.args()?;
                     let props = args.properties();
                     trace!("GO negotiation succeeded: {props:?}");
+                    // Remember the interface address the peer is going to use in the group, in
+                    // case it identifies itself by it, see `Session::own_phy_id_in`.
+                    let Some(Value::ObjectPath(peer_path)) = props.get("peer_object") else {
+                        continue;
+                    };
+                    let Some(iface_addr) = mac_addr_arg(props, "peer_interface_addr") else {
+                        continue;
+                    };
+                    let mut peers = session.peers.write();
+                    let peer_path = OwnedObjectPath::from(peer_path.to_owned());
+                    if let Some(peer) = peers
+                        .id_by_path(&peer_path)
+                        .and_then(|id| peers.get_mut(id))
+                    {
+                        peer.identity.physical.iface_addr = Some(iface_addr);
+                    }
                 }
                 Ok(())
             },
@@ -1172,6 +2822,10 @@ impl Session {
                     let peer_object = args.peer_object();
                     let pin = args.pin();
                     trace!("PD Request display pin: {peer_object} ({pin})");
+                    let provisioning = Provisioning::DisplayPin(Some(pin.to_string()));
+                    Self::pin_generated(&session, peer_object, pin);
+                    // The peer might be joining a group we own rather than negotiating a new one.
+                    Self::authorize_wps(&session, peer_object, &provisioning).await;
                 }
                 Ok(())
             },
@@ -1181,6 +2835,7 @@ impl Session {
                     let peer_object = args.peer_object();
                     let pin = args.pin();
                     trace!("PD Response display pin: {peer_object} ({pin})");
+                    Self::pin_generated(&session, peer_object, pin);
                 }
                 Ok(())
             },
@@ -1189,6 +2844,9 @@ impl Session {
                     let args = msg.args()?;
                     let peer_object = args.peer_object();
                     trace!("PD Request enter pin: {peer_object}");
+                    if let Some(peer_id) = session.peer_id_by_path(peer_object) {
+                        session.listener.pin_requested(&session, peer_id);
+                    }
                 }
                 Ok(())
             },
@@ -1197,6 +2855,9 @@ impl Session {
                     let args = msg.args()?;
                     let peer_object = args.peer_object();
                     trace!("PD Response enter pin: {peer_object}");
+                    if let Some(peer_id) = session.peer_id_by_path(peer_object) {
+                        session.listener.pin_requested(&session, peer_id);
+                    }
                 }
                 Ok(())
             },
@@ -1205,50 +2866,7 @@ impl Session {
                     let args = msg.args()?;
                     let peer_object = args.peer_object();
                     trace!("PD PBC Request: {peer_object}, trying to authorize");
-
-                    let peer_dev_addr = {
-                        let peers = session.peers.read();
-                        let Some(peer) = peers.get_by_path(peer_object) else {
-                            error!("Can't found {peer_object} in peers map");
-                            continue;
-                        };
-                        peer.identity.physical.dev_addr
-                    };
-
-                    let go_groups = {
-                        let mut go_groups = vec![];
-                        let groups = session.groups.read();
-                        for group in groups.iter() {
-                            go_groups.push(group.data.iface_path.clone());
-                        }
-                        go_groups
-                    };
-
-                    for group_iface_path in go_groups {
-                        let Ok(wps) = wpa_supplicant::wps::WPSProxy::new(
-                            &session.system_bus,
-                            &group_iface_path,
-                        )
-                        .await
-                        else {
-                            error!(
-                                "Couldn't find WPS interface for group iface {:?}",
-                                group_iface_path
-                            );
-                            continue;
-                        };
-                        let mut params = HashMap::new();
-                        let dev_addr = Value::from(peer_dev_addr.as_bytes());
-                        let role = Value::from("registrar");
-                        let ty = Value::from(WPS_METHOD);
-                        params.insert("Role", &role);
-                        params.insert("P2PDeviceAddress", &dev_addr);
-                        params.insert("Type", &ty);
-                        if let Err(e) = wps.start(params).await {
-                            error!("Can't start wps authorization for {group_iface_path}: {e}");
-                            continue;
-                        }
-                    }
+                    Self::authorize_wps(&session, peer_object, &Provisioning::PushButton).await;
                 }
                 Ok(())
             },
@@ -1265,18 +2883,112 @@ impl Session {
         Ok(())
     }
 
+    /// Lets a peer join the Wi-Fi Direct groups we're in, authenticating as given. Only does
+    /// anything in the groups we own.
+    async fn authorize_wps(
+        session: &Arc<Self>,
+        peer_object: &zbus::zvariant::ObjectPath<'_>,
+        provisioning: &Provisioning,
+    ) {
+        let peer_dev_addr = {
+            let peers = session.peers.read();
+            let Some(peer) = peers.get_by_path(peer_object) else {
+                error!("Can't found {peer_object} in peers map");
+                return;
+            };
+            peer.identity.physical.dev_addr
+        };
+
+        let go_groups = {
+            let mut go_groups = vec![];
+            let groups = session.groups.read();
+            for group in groups.iter().filter(|g| g.is_go && !g.data.is_mesh()) {
+                go_groups.push(group.data.iface_path.clone());
+            }
+            go_groups
+        };
+
+        for group_iface_path in go_groups {
+            let Ok(wps) =
+                wpa_supplicant::wps::WPSProxy::new(&session.system_bus, &group_iface_path).await
+            else {
+                error!(
+                    "Couldn't find WPS interface for group iface {:?}",
+                    group_iface_path
+                );
+                continue;
+            };
+            let mut params = HashMap::new();
+            let dev_addr = Value::from(peer_dev_addr.as_bytes());
+            let role = Value::from("registrar");
+            let (ty, pin) = match wps_pin(provisioning) {
+                Some(pin) => (Value::from("pin"), Some(Value::from(pin))),
+                None => (Value::from("pbc"), None),
+            };
+            params.insert("Role", &role);
+            params.insert("P2PDeviceAddress", &dev_addr);
+            params.insert("Type", &ty);
+            if let Some(ref pin) = pin {
+                params.insert("Pin", pin);
+            }
+            if let Err(e) = wps.start(params).await {
+                error!("Can't start wps authorization for {group_iface_path}: {e}");
+                continue;
+            }
+        }
+    }
+
+    fn peer_id_by_path(&self, path: &zbus::zvariant::ObjectPath<'_>) -> Option<PeerId> {
+        self.peers.read().id_by_path(path).map(PeerId)
+    }
+
+    /// Remembers the PIN wpa_supplicant generated for a peer to enter, and asks the listener to
+    /// display it.
+    fn pin_generated(session: &Arc<Self>, peer_object: &zbus::zvariant::ObjectPath<'_>, pin: &str) {
+        session.provisioning.write().insert(
+            peer_object.as_str().to_owned(),
+            Provisioning::DisplayPin(Some(pin.to_owned())),
+        );
+        if let Some(peer_id) = session.peer_id_by_path(peer_object) {
+            session.listener.display_pin(session, peer_id, pin);
+        }
+    }
+
+    /// Connects to a peer, authenticating as last requested for it, or with push-button.
     async fn connect_to_peer_by_path(&self, peer_path: OwnedObjectPath) -> Result<(), zbus::Error> {
+        let provisioning = self
+            .provisioning
+            .read()
+            .get(peer_path.as_str())
+            .cloned()
+            .unwrap_or_default();
         let mut args = HashMap::default();
-        let method = Value::from(WPS_METHOD);
+        let method = Value::from(wps_method(&provisioning));
+        let pin = wps_pin(&provisioning).map(Value::from);
         let go_intent = Value::from(self.go_intent as i32);
         let auto_join = Value::from(true);
-        let peer_path = Value::from(peer_path);
-        args.insert("peer", &peer_path);
+        let peer = Value::from(peer_path.clone());
+        args.insert("peer", &peer);
         args.insert("auto_join", &auto_join);
         args.insert("wps_method", &method);
         args.insert("go_intent", &go_intent);
+        if let Some(ref pin) = pin {
+            args.insert("pin", pin);
+        }
         match self.p2pdevice.connect(args).await {
-            Ok(pin) => trace!("Connected with pin: {pin}"),
+            Ok(pin) => {
+                trace!("Connected with pin: {pin}");
+                if provisioning == Provisioning::DisplayPin(None) && !pin.is_empty() {
+                    // Use the same PIN if we need to retry.
+                    self.provisioning.write().insert(
+                        peer_path.as_str().to_owned(),
+                        Provisioning::DisplayPin(Some(pin.clone())),
+                    );
+                    if let Some(peer_id) = self.peer_id_by_path(&peer_path) {
+                        self.listener.display_pin(self, peer_id, &pin);
+                    }
+                }
+            }
             Err(e) => {
                 error!("Failed to connect to peer: {e:?}");
                 return Err(e);
@@ -1285,3 +2997,52 @@ impl Session {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persistent_group_from_properties() {
+        // As returned by wpa_supplicant for a group we owned.
+        let props = HashMap::from([
+            ("ssid".to_owned(), Value::from("\"DIRECT-Xy-ngn\"")),
+            ("bssid".to_owned(), Value::from("02:00:00:00:01:00")),
+            ("mode".to_owned(), Value::from("3")),
+            ("disabled".to_owned(), Value::from("2")),
+            (
+                "p2p_client_list".to_owned(),
+                Value::from("02:00:00:00:02:00 02:00:00:00:03:00"),
+            ),
+        ]);
+        let path =
+            OwnedObjectPath::try_from("/fi/w1/wpa_supplicant1/Interfaces/0/PersistentGroups/0")
+                .unwrap();
+        let group = PersistentGroup::from_properties(path.clone(), &props);
+        assert_eq!(group.path, path);
+        assert_eq!(group.ssid, "DIRECT-Xy-ngn");
+        assert_eq!(
+            group.go_dev_addr,
+            Some("02:00:00:00:01:00".parse().unwrap())
+        );
+        assert!(group.is_go);
+        assert_eq!(
+            group.clients,
+            [
+                "02:00:00:00:02:00".parse::<MacAddr>().unwrap(),
+                "02:00:00:00:03:00".parse().unwrap(),
+            ]
+        );
+
+        // A group we were a client of.
+        let props = HashMap::from([
+            ("ssid".to_owned(), Value::from("\"DIRECT-ab\"")),
+            ("mode".to_owned(), Value::from("0")),
+        ]);
+        let group = PersistentGroup::from_properties(path, &props);
+        assert_eq!(group.ssid, "DIRECT-ab");
+        assert_eq!(group.go_dev_addr, None);
+        assert!(!group.is_go);
+        assert!(group.clients.is_empty());
+    }
+}
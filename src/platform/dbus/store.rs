@@ -1,3 +1,4 @@
+use crate::protocol::Store;
 use handy::{Handle, HandleMap};
 use std::collections::HashMap;
 use zbus::zvariant::{ObjectPath, OwnedObjectPath};
@@ -77,6 +78,30 @@ impl<T: DbusPath> DbusStore<T> {
     }
 }
 
+impl<T: DbusPath> Store<T> for DbusStore<T> {
+    fn get(&self, handle: Handle) -> Option<&T> {
+        DbusStore::get(self, handle)
+    }
+
+    fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        DbusStore::get_mut(self, handle)
+    }
+
+    fn iter_with_handles<'a>(&'a self) -> impl Iterator<Item = (Handle, &'a T)>
+    where
+        T: 'a,
+    {
+        DbusStore::iter_with_handles(self)
+    }
+
+    fn iter_mut_with_handles<'a>(&'a mut self) -> impl Iterator<Item = (Handle, &'a mut T)>
+    where
+        T: 'a,
+    {
+        DbusStore::iter_mut_with_handles(self)
+    }
+}
+
 impl<T> Default for DbusStore<T> {
     fn default() -> Self {
         Self {
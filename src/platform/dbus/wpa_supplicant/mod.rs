@@ -7,5 +7,6 @@ pub mod mesh;
 pub mod network;
 pub mod p2pdevice;
 pub mod peer;
+pub mod persistent_group;
 pub mod wpa_supplicant;
 pub mod wps;
@@ -327,9 +327,9 @@ pub trait P2PDevice {
     #[zbus(property)]
     fn persistent_groups(&self) -> zbus::Result<Vec<zbus::zvariant::OwnedObjectPath>>;
 
-    /// DeviceAddress property
-    // #[zbus(property)]
-    // fn device_address(&self) -> zbus::Result<Vec<u8>>;
+    /// DeviceAddress property. Only exposed by wpa_supplicant 2.11 and newer.
+    #[zbus(property)]
+    fn device_address(&self) -> zbus::Result<Vec<u8>>;
 
     /// Role property
     #[zbus(property)]
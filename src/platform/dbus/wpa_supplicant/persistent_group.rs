@@ -0,0 +1,15 @@
+//! # D-Bus interface proxy for: `fi.w1.wpa_supplicant1.PersistentGroup`
+//!
+//! Hand-coded because zbus-xmlgen couldn't pick this one up, and it's trivial anyways.
+use zbus::proxy;
+#[proxy(
+    interface = "fi.w1.wpa_supplicant1.PersistentGroup",
+    default_service = "fi.w1.wpa_supplicant1"
+)]
+pub trait PersistentGroup {
+    /// Properties property
+    #[zbus(property)]
+    fn properties(
+        &self,
+    ) -> zbus::Result<std::collections::HashMap<String, zbus::zvariant::OwnedValue>>;
+}
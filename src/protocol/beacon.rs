@@ -0,0 +1,120 @@
+//! Identity beacons, advertised in vendor-specific information elements (IEs) of P2P probe
+//! requests and responses.
+//!
+//! Logical identities are normally only learned when a peer associates, so users need to connect
+//! to a device to find out who it is. When enabled, each device also embeds a beacon with its
+//! logical identity and capabilities, signed with its logical key, in the P2P probe frames it
+//! sends, and the devices that discover it show that identity right away.
+//!
+//! A valid signature only proves that the owner of the key signed the beacon at some point: the
+//! beacon itself can be replayed by anyone, from any device. So the identity it carries is only
+//! provisional (see `PeerIdentity::logical_verified`), and is replaced by the one the peer proves
+//! when it associates. It must not be relied upon for anything but display.
+//!
+//! The IE is laid out as:
+//!
+//! ```text
+//!   element id: u8 = 221 (vendor specific)
+//!   length: u8
+//!   oui: [u8; 3] = 02 6e 67
+//!   oui type: u8 = 1
+//!   body: wire fields (see the `wire` module)
+//! ```
+//!
+//! The OUI is a locally administered one (bit 1 of the first byte is set), so it can't clash with
+//! any assigned to a vendor. The body holds the logical identity, the capabilities, and an Ed25519
+//! signature over `SIGNATURE_CONTEXT` followed by the encoding of the first two. Nicknames are
+//! truncated as needed for the IE to fit in its 255 bytes.
+use super::{
+    capabilities::Capabilities,
+    identity::{LogicalPeerIdentity, OwnIdentity},
+    signing::{self, MaybeInvalidSignature},
+    wire,
+};
+use crate::{trivial_error, GenericResult};
+
+/// Domain separation for beacon signatures.
+const SIGNATURE_CONTEXT: &[u8] = b"ngn beacon";
+
+/// The element id of vendor-specific IEs.
+const VENDOR_SPECIFIC_ELEMENT_ID: u8 = 221;
+
+/// The OUI and OUI type that identify our IEs, see the module docs.
+const OUI: [u8; 4] = [0x02, 0x6e, 0x67, 0x01];
+
+/// The longest IE body, after the OUI.
+const MAX_BODY_LEN: usize = u8::MAX as usize - OUI.len();
+
+/// What a device advertises about itself in its beacon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub identity: LogicalPeerIdentity,
+    pub capabilities: Capabilities,
+}
+
+/// A beacon along with its signature, as sent in the IE.
+#[derive(Debug, Clone)]
+pub struct SignedBeacon {
+    pub beacon: Beacon,
+    pub signature: MaybeInvalidSignature,
+}
+
+impl Beacon {
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut payload = SIGNATURE_CONTEXT.to_vec();
+        payload.extend_from_slice(&wire::encode_fields(self));
+        payload
+    }
+
+    /// Returns the vendor-specific IE advertising our identity and capabilities.
+    pub fn ours_ie(identity: &OwnIdentity, capabilities: Capabilities) -> GenericResult<Vec<u8>> {
+        let mut beacon = Self {
+            identity: identity.to_public(),
+            capabilities,
+        };
+        loop {
+            let signature = signing::sign(&identity.key_pair, &beacon.signed_payload());
+            let body = wire::encode_fields(&SignedBeacon {
+                beacon: beacon.clone(),
+                signature: MaybeInvalidSignature(signature.as_ref().try_into().unwrap()),
+            });
+            if body.len() <= MAX_BODY_LEN {
+                let mut ie = vec![VENDOR_SPECIFIC_ELEMENT_ID, (OUI.len() + body.len()) as u8];
+                ie.extend_from_slice(&OUI);
+                ie.extend_from_slice(&body);
+                return Ok(ie);
+            }
+            // Drop a character at a time, since the encoded length isn't linear in the nickname
+            // length (varints), and nicknames are short anyway.
+            if beacon.identity.nickname.pop().is_none() {
+                return Err(trivial_error!("Beacon doesn't fit in an IE"));
+            }
+        }
+    }
+
+    /// Finds our beacon in a list of vendor-specific IEs, and checks its signature. Returns
+    /// `Ok(None)` if there's no beacon.
+    pub fn find_in_ies(mut ies: &[u8]) -> GenericResult<Option<Self>> {
+        while let Some((&[id, len], rest)) = ies.split_first_chunk::<2>() {
+            let Some(data) = rest.get(..len as usize) else {
+                return Err(trivial_error!("Truncated IE"));
+            };
+            ies = &rest[len as usize..];
+            if id != VENDOR_SPECIFIC_ELEMENT_ID {
+                continue;
+            }
+            let Some(body) = data.strip_prefix(&OUI) else {
+                continue;
+            };
+            let signed: SignedBeacon = wire::decode_fields(body)?;
+            let beacon = signed.beacon;
+            signing::verify(
+                &beacon.identity.key,
+                &signed.signature,
+                &beacon.signed_payload(),
+            )?;
+            return Ok(Some(beacon));
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,129 @@
+//! The optional features each peer supports, exchanged in `ControlMessage::Associate`.
+//!
+//! Both ends of an association advertise what they support, and the features in use with a peer
+//! are the intersection of both sets (see `Capabilities::negotiate`), so that newer builds can
+//! keep talking to older ones by just not using what the other end doesn't understand.
+
+use super::{
+    encryption::{self, CipherSuite},
+    limits::Limits,
+    wire,
+};
+
+/// A set of features supported by a peer, or agreed upon between two peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Stream multiplexing over peer connections, see the `mux` module.
+    pub multiplexing: bool,
+    /// Encrypted UDP datagrams, see the `datagram` module.
+    pub datagrams: bool,
+    /// Compression of peer messages, see the `compression` module.
+    pub compression: bool,
+    /// In-order, exactly-once delivery of peer messages, see the `delivery` module.
+    pub ordered_delivery: bool,
+    /// Padding of peer records, see the `padding` module.
+    pub padding: bool,
+    /// The ChaCha20-Poly1305 cipher suite. AES-256-GCM is always supported.
+    pub chacha20_poly1305: bool,
+    /// Whether the peer has AES instructions, see `Capabilities::cipher_suite`.
+    pub hardware_aes: bool,
+    /// Membership rosters from the group owner, see the `roster` module.
+    pub roster: bool,
+    /// Relaying messages between clients of a group through the group owner, see the `relay`
+    /// module.
+    pub relay: bool,
+    /// Multi-hop routing across groups, see the `routing` module.
+    pub routing: bool,
+    /// File transfers. Reserved for future use, ngn doesn't implement them yet.
+    pub file_transfer: bool,
+    /// The largest peer frame body the peer accepts, including encryption overhead.
+    pub max_frame_size: u32,
+    /// The oldest wire version the peer supports, see the `wire` module.
+    pub min_version: u16,
+    /// The newest wire version the peer supports.
+    pub max_version: u16,
+}
+
+impl Capabilities {
+    /// The capabilities of this build, given our session limits.
+    pub fn ours(limits: &Limits) -> Self {
+        Self {
+            multiplexing: true,
+            datagrams: true,
+            compression: true,
+            ordered_delivery: true,
+            padding: true,
+            chacha20_poly1305: true,
+            hardware_aes: encryption::has_hardware_aes(),
+            roster: true,
+            relay: true,
+            routing: true,
+            file_transfer: false,
+            max_frame_size: limits.max_peer_frame_size,
+            min_version: wire::MIN_VERSION,
+            max_version: wire::CURRENT_VERSION,
+        }
+    }
+
+    /// The capabilities of a peer that predates capability negotiation, and only told us whether
+    /// it supports multiplexing.
+    pub fn legacy(multiplexing: bool) -> Self {
+        Self {
+            multiplexing,
+            datagrams: true,
+            compression: false,
+            ordered_delivery: false,
+            padding: false,
+            chacha20_poly1305: false,
+            hardware_aes: false,
+            roster: false,
+            relay: false,
+            routing: false,
+            file_transfer: false,
+            max_frame_size: Limits::default().max_peer_frame_size,
+            min_version: wire::MIN_VERSION,
+            max_version: 2,
+        }
+    }
+
+    /// The capabilities of a peer that associated using wire version 1, which predates all the
+    /// optional features.
+    pub fn version_1() -> Self {
+        Self {
+            datagrams: false,
+            max_version: 1,
+            ..Self::legacy(/* multiplexing = */ false)
+        }
+    }
+
+    /// Returns the features both we and the peer support.
+    pub fn negotiate(&self, peer: &Self) -> Self {
+        Self {
+            multiplexing: self.multiplexing && peer.multiplexing,
+            datagrams: self.datagrams && peer.datagrams,
+            compression: self.compression && peer.compression,
+            ordered_delivery: self.ordered_delivery && peer.ordered_delivery,
+            padding: self.padding && peer.padding,
+            chacha20_poly1305: self.chacha20_poly1305 && peer.chacha20_poly1305,
+            hardware_aes: self.hardware_aes && peer.hardware_aes,
+            roster: self.roster && peer.roster,
+            relay: self.relay && peer.relay,
+            routing: self.routing && peer.routing,
+            file_transfer: self.file_transfer && peer.file_transfer,
+            max_frame_size: self.max_frame_size.min(peer.max_frame_size),
+            min_version: self.min_version.max(peer.min_version),
+            max_version: self.max_version.min(peer.max_version),
+        }
+    }
+
+    /// Returns the cipher suite to use with a peer, given the negotiated capabilities:
+    /// AES-256-GCM if both ends have AES instructions (or the peer doesn't support anything
+    /// else), ChaCha20-Poly1305 otherwise.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        if self.chacha20_poly1305 && !self.hardware_aes {
+            CipherSuite::ChaCha20Poly1305
+        } else {
+            CipherSuite::Aes256Gcm
+        }
+    }
+}
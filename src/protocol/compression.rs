@@ -0,0 +1,81 @@
+//! Optional compression of peer records, applied before encryption.
+//!
+//! When both peers support it (see `Capabilities::compression`), the plaintext of every peer
+//! record starts with a one byte header describing how the rest is encoded:
+//!
+//! ```text
+//!   0 | payload                              (uncompressed)
+//!   1 | uncompressed length: u32 | lz4 block (compressed)
+//! ```
+//!
+//! Records shorter than `THRESHOLD`, or that don't get any smaller, are sent uncompressed, and so
+//! are records we pad, see the `padding` module.
+//! Receivers refuse records whose uncompressed length would exceed the peer frame limit, so that a
+//! tiny record can't make us allocate an arbitrary amount of memory.
+use crate::{trivial_error, GenericResult};
+use log::error;
+
+/// Records shorter than this are never compressed, since the header would likely make them
+/// longer.
+pub const THRESHOLD: usize = 128;
+
+/// The most a record can grow when encoded.
+pub const MAX_OVERHEAD: usize = 1;
+
+const UNCOMPRESSED: u8 = 0;
+const LZ4: u8 = 1;
+const LZ4_HEADER_LEN: usize = 1 + 4;
+
+/// Encodes a record, compressing it if worth it.
+pub fn compress(payload: &[u8]) -> Vec<u8> {
+    if payload.len() >= THRESHOLD {
+        if let Ok(len) = u32::try_from(payload.len()) {
+            let compressed = lz4_flex::block::compress(payload);
+            if compressed.len() + LZ4_HEADER_LEN < payload.len() + 1 {
+                let mut record = Vec::with_capacity(LZ4_HEADER_LEN + compressed.len());
+                record.push(LZ4);
+                record.extend_from_slice(&len.to_be_bytes());
+                record.extend_from_slice(&compressed);
+                return record;
+            }
+        }
+    }
+    store(payload)
+}
+
+/// Encodes a record without compressing it. Used for padded records, see the `padding` module.
+pub fn store(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(1 + payload.len());
+    record.push(UNCOMPRESSED);
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Decodes a record, refusing to decompress it to more than `max_len` bytes.
+pub fn decompress(mut record: Vec<u8>, max_len: u32) -> GenericResult<Vec<u8>> {
+    let Some(&kind) = record.first() else {
+        return Err(trivial_error!("Empty record"));
+    };
+    match kind {
+        UNCOMPRESSED => {
+            record.remove(0);
+            Ok(record)
+        }
+        LZ4 => {
+            if record.len() < LZ4_HEADER_LEN {
+                return Err(trivial_error!("Truncated compressed record"));
+            }
+            let len = u32::from_be_bytes(record[1..LZ4_HEADER_LEN].try_into().unwrap());
+            if len > max_len {
+                error!("Refusing to decompress record of length {len} (max {max_len})");
+                return Err(trivial_error!("Decompressed record too long"));
+            }
+            let payload = lz4_flex::block::decompress(&record[LZ4_HEADER_LEN..], len as usize)?;
+            if payload.len() != len as usize {
+                return Err(trivial_error!("Wrong decompressed record length"));
+            }
+            Ok(payload)
+        }
+        _ => Err(trivial_error!("Unknown record compression")),
+    }
+}
@@ -0,0 +1,763 @@
+//! Persistent, authenticated connections to peers.
+//!
+//! We keep (at most) one buffered stream per peer per group, which is reused for both directions:
+//! whoever needs to send a message first connects and authenticates (see the `handshake` module),
+//! and the other end adopts the incoming stream for its own messages. Failed connections are
+//! dropped and transparently re-established on the next message.
+//!
+//! If both ends connect to each other at the same time, both agree to prefer the connection
+//! initiated by the peer with the lowest logical key, the other one just gets closed once the
+//! messages in flight are read.
+//!
+//! If both peers support it, connections are multiplexed (see the `mux` module), and besides
+//! regular messages can carry any number of `PeerStream`s. Regular messages are also delivered in
+//! order, see the `delivery` module.
+//!
+//! Every message has a priority class, and more urgent messages (and control frames) are written
+//! first, see the `priority` module.
+use super::{
+    capabilities::Capabilities,
+    compression,
+    delivery::{self, Header, Reorderer, Sequencer},
+    encryption::{self, Keys, RecordKeys},
+    handshake,
+    identity::{LogicalPeerIdentity, OwnIdentity},
+    limits::Limits,
+    mux::{self, Frame, IncomingMessage, Mux, MuxEvent, StreamId},
+    padding::{self, PaddingPolicy},
+    priority::{Priority, PriorityGate, WriteScheduler},
+    read_peer_message, wire, write_peer_message,
+};
+use crate::{trivial_error, GenericResult, GroupId, PeerId};
+use log::{error, trace};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncWriteExt, BufReader, BufWriter},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    task::JoinHandle,
+};
+
+/// How long we wait to establish a new connection to a peer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The session-side hooks the connection manager needs.
+pub trait ConnectionDelegate: Send + Sync + 'static {
+    /// Our own identity, used to authenticate and sign.
+    fn identity(&self) -> &OwnIdentity;
+    /// The limits for incoming messages.
+    fn limits(&self) -> &Limits;
+    /// How to pad outgoing records, if the peer supports it.
+    fn padding(&self) -> PaddingPolicy;
+    /// The connection manager of this session.
+    fn connections(&self) -> &ConnectionManager;
+    /// Called for each message read from a peer.
+    fn connection_message(&self, peer_id: PeerId, group_id: GroupId, message: &[u8]);
+    /// Called when a peer opens a new stream. Streams are rejected by default.
+    fn stream_opened(&self, peer_id: PeerId, group_id: GroupId, stream: PeerStream) {
+        trace!(
+            "Rejecting stream {} from {peer_id:?} in {group_id:?}",
+            stream.id()
+        );
+    }
+}
+
+/// What we need to know about a peer to talk to it.
+#[derive(Debug, Clone)]
+pub struct ConnectionTarget {
+    pub peer_id: PeerId,
+    pub group_id: GroupId,
+    /// The address of the peer: its p2p port for connections we initiate, or the source address
+    /// for incoming connections.
+    pub address: SocketAddr,
+    /// The logical identity of the peer, to verify its messages.
+    pub identity: LogicalPeerIdentity,
+    /// The keys we share with the peer.
+    pub keys: Arc<Keys>,
+    /// The features agreed upon with the peer.
+    pub capabilities: Capabilities,
+    /// The wire version negotiated with the peer.
+    pub wire_version: u16,
+}
+
+#[derive(Debug)]
+struct Connection {
+    /// Unique id of this connection, to avoid removing a newer connection from the map when an
+    /// older one fails.
+    id: u64,
+    /// Whether we initiated this connection.
+    initiated_by_us: bool,
+    target: ConnectionTarget,
+    /// The keys for the records of this connection, see `Keys::record_keys`.
+    records: Arc<RecordKeys>,
+    writer: tokio::sync::Mutex<BufWriter<OwnedWriteHalf>>,
+    /// Decides which pending write goes next.
+    scheduler: WriteScheduler,
+    padding: PaddingPolicy,
+    /// The multiplexing state, if negotiated.
+    mux: Option<Mux>,
+    /// The task reading from this connection. Note that this is not aborted when the connection
+    /// is just replaced by another one, so that messages in flight are still read.
+    reader_task: OnceLock<JoinHandle<()>>,
+}
+
+impl Connection {
+    async fn write_record(
+        &self,
+        identity: &OwnIdentity,
+        record: &[u8],
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let _turn = self.scheduler.acquire(priority).await;
+        let mut writer = self.writer.lock().await;
+        write_peer_message(
+            identity,
+            &self.records,
+            &mut *writer,
+            self.target.wire_version,
+            record,
+            &self.target.capabilities,
+            self.padding,
+        )
+        .await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    async fn write_frame(
+        &self,
+        identity: &OwnIdentity,
+        frame: Frame,
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let record = wire::encode(self.target.wire_version, &frame)?;
+        self.write_record(identity, &record, priority).await
+    }
+
+    /// Sends a full message on a given stream. With multiplexing, the message is split in chunks
+    /// and the writer is released between them, so that other streams can make progress.
+    async fn send(
+        &self,
+        identity: &OwnIdentity,
+        stream: StreamId,
+        message: &[u8],
+        priority: Priority,
+        gate: &PriorityGate,
+    ) -> GenericResult<()> {
+        let mut overhead = encryption::TAG_LEN + compression::MAX_OVERHEAD;
+        if self.target.capabilities.padding {
+            overhead += padding::HEADER_LEN;
+        }
+        if message.len() + overhead > self.target.capabilities.max_frame_size as usize {
+            return Err(trivial_error!("Message too long for the peer"));
+        }
+        let Some(ref mux) = self.mux else {
+            debug_assert!(mux::is_messages_stream(stream));
+            let _entry = gate.enter(priority);
+            gate.wait_turn(priority).await;
+            return self.write_record(identity, message, priority).await;
+        };
+        // Only enter the gate once the stream is ours, as we'd otherwise hold back the message
+        // we're waiting for.
+        let mut sender = mux.lock_stream(stream, priority).await?;
+        let _entry = gate.enter(priority);
+        sender
+            .send(message, move |frame| async move {
+                gate.wait_turn(priority).await;
+                self.write_frame(identity, frame, priority).await
+            })
+            .await
+    }
+
+    fn abort(&self) {
+        if let Some(task) = self.reader_task.get() {
+            task.abort();
+        }
+    }
+}
+
+/// A logical stream of messages multiplexed over a peer connection.
+///
+/// Dropping the stream without closing it resets it.
+pub struct PeerStream {
+    delegate: Arc<dyn ConnectionDelegate>,
+    connection: Arc<Connection>,
+    id: StreamId,
+    incoming: mpsc::UnboundedReceiver<IncomingMessage>,
+    priority: Priority,
+}
+
+impl std::fmt::Debug for PeerStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerStream")
+            .field("connection", &self.connection.id)
+            .field("id", &self.id)
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+impl PeerStream {
+    fn mux(&self) -> &Mux {
+        self.connection.mux.as_ref().unwrap()
+    }
+
+    /// The id of this stream within its connection.
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Sets the priority of the messages sent on this stream from now on, `Interactive` by
+    /// default.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Sends a message on this stream.
+    pub async fn send(&self, message: &[u8]) -> GenericResult<()> {
+        self.connection
+            .send(
+                self.delegate.identity(),
+                self.id,
+                message,
+                self.priority,
+                &self.delegate.connections().gate,
+            )
+            .await
+    }
+
+    /// Waits for the next message from the peer, or `None` if the peer closed or reset the
+    /// stream.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        let (message, credit) = self.incoming.recv().await?;
+        if let Some(frame) = self.mux().consumed(self.id, credit) {
+            let _ = self
+                .connection
+                .write_frame(self.delegate.identity(), frame, Priority::Control)
+                .await;
+        }
+        Some(message)
+    }
+
+    /// Closes our end of the stream. Messages from the peer can still be received.
+    pub async fn close(&self) -> GenericResult<()> {
+        if !self.mux().close(self.id) {
+            return Ok(());
+        }
+        self.connection
+            .write_frame(
+                self.delegate.identity(),
+                Frame::Close { stream: self.id },
+                Priority::Control,
+            )
+            .await
+    }
+}
+
+impl Drop for PeerStream {
+    fn drop(&mut self) {
+        if !self.mux().reset(self.id) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let delegate = Arc::clone(&self.delegate);
+        let connection = Arc::clone(&self.connection);
+        let stream = self.id;
+        runtime.spawn(async move {
+            let _ = connection
+                .write_frame(
+                    delegate.identity(),
+                    Frame::Reset { stream },
+                    Priority::Control,
+                )
+                .await;
+        });
+    }
+}
+
+/// A message waiting to be sent (and potentially retried) to a peer, see
+/// `ConnectionManager::queue_message`. It takes space in the send queue of the peer until dropped.
+///
+/// If it's dropped before being sent, the peer is told to skip it (see the `delivery` module).
+#[derive(Debug)]
+pub struct QueuedMessage {
+    bytes: Vec<u8>,
+    priority: Priority,
+    /// The peer and delivery header, if the message is sequenced.
+    sequenced: Option<(Arc<Sequencer>, PeerId, Header)>,
+    sent: AtomicBool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for QueuedMessage {
+    fn drop(&mut self) {
+        if let Some((sequencer, peer_id, header)) = &self.sequenced {
+            if !self.sent.load(Ordering::Relaxed) {
+                sequencer.abandon(*peer_id, &[*header]);
+            }
+        }
+    }
+}
+
+/// Manages the connections to all peers in all groups of a session.
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    connections: Mutex<HashMap<(PeerId, GroupId), Arc<Connection>>>,
+    next_id: AtomicU64,
+    sequencer: Arc<Sequencer>,
+    reorderer: Reorderer,
+    /// The send queue of each peer and priority, in bytes, so that bulk messages can't fill the
+    /// queue for more urgent ones.
+    send_queues: Mutex<HashMap<(PeerId, Priority), Arc<Semaphore>>>,
+    gate: PriorityGate,
+}
+
+impl ConnectionManager {
+    fn send_queue(
+        &self,
+        peer_id: PeerId,
+        priority: Priority,
+        limits: &Limits,
+    ) -> (Arc<Semaphore>, u32) {
+        let max = limits.max_send_queue_size;
+        let queue = Arc::clone(
+            self.send_queues
+                .lock()
+                .entry((peer_id, priority))
+                .or_insert_with(|| Arc::new(Semaphore::new(max as usize))),
+        );
+        (queue, max)
+    }
+
+    fn prepare_message(
+        &self,
+        target: &ConnectionTarget,
+        message: &[u8],
+        priority: Priority,
+        permit: OwnedSemaphorePermit,
+    ) -> QueuedMessage {
+        let (bytes, sequenced) = if target.capabilities.ordered_delivery {
+            let (header, bytes) = self.sequencer.wrap(target.peer_id, priority, message);
+            let sequencer = Arc::clone(&self.sequencer);
+            (bytes, Some((sequencer, target.peer_id, header)))
+        } else {
+            (message.to_vec(), None)
+        };
+        QueuedMessage {
+            bytes,
+            priority,
+            sequenced,
+            sent: AtomicBool::new(false),
+            _permit: permit,
+        }
+    }
+
+    /// Queues a message to a peer, waiting for space in its send queue for the given priority if
+    /// needed (see `Limits::max_send_queue_size`).
+    pub async fn queue_message<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<QueuedMessage> {
+        let (queue, max) = self.send_queue(target.peer_id, priority, delegate.limits());
+        // Messages larger than the whole queue just need it for themselves.
+        let len = message.len().clamp(1, max as usize) as u32;
+        let permit = queue.acquire_many_owned(len).await?;
+        Ok(self.prepare_message(target, message, priority, permit))
+    }
+
+    /// Like `queue_message`, but fails with an `io::ErrorKind::WouldBlock` error rather than
+    /// waiting if the send queue of the peer is full.
+    pub fn try_queue_message<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        message: &[u8],
+        priority: Priority,
+    ) -> GenericResult<QueuedMessage> {
+        let (queue, max) = self.send_queue(target.peer_id, priority, delegate.limits());
+        let len = message.len().clamp(1, max as usize) as u32;
+        let permit = match queue.try_acquire_many_owned(len) {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                return Err(
+                    io::Error::new(io::ErrorKind::WouldBlock, "Send queue to peer is full").into(),
+                )
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(self.prepare_message(target, message, priority, permit))
+    }
+
+    /// Sends a queued message to a peer, connecting to it first if needed.
+    pub async fn send<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        message: &QueuedMessage,
+    ) -> GenericResult<()> {
+        let key = (target.peer_id, target.group_id);
+        let existing = self.connections.lock().get(&key).cloned();
+        if let Some(connection) = existing {
+            match self.send_on(delegate, &connection, message).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    trace!("Connection to {key:?} failed ({e}), reconnecting");
+                    self.remove_if_current(key, connection.id, /* abort = */ true);
+                }
+            }
+        }
+        let connection = self.connect(delegate, target).await?;
+        self.send_on(delegate, &connection, message).await
+    }
+
+    /// Sends a queued message on a connection, preceded by the skip records for the messages with
+    /// the same priority that were given up on since the last one.
+    async fn send_on<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        connection: &Connection,
+        message: &QueuedMessage,
+    ) -> GenericResult<()> {
+        let identity = delegate.identity();
+        let priority = message.priority;
+        if let Some((sequencer, peer_id, _)) = &message.sequenced {
+            let abandoned = sequencer.take_abandoned(*peer_id, priority);
+            for (i, header) in abandoned.iter().enumerate() {
+                let record = header.skip_record();
+                let result = connection
+                    .send(
+                        identity,
+                        mux::messages_stream(priority),
+                        &record,
+                        priority,
+                        &self.gate,
+                    )
+                    .await;
+                if let Err(e) = result {
+                    sequencer.abandon(*peer_id, &abandoned[i..]);
+                    return Err(e);
+                }
+            }
+        }
+        connection
+            .send(
+                identity,
+                mux::messages_stream(priority),
+                &message.bytes,
+                priority,
+                &self.gate,
+            )
+            .await?;
+        message.sent.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Opens a new stream to a peer, connecting to it first if needed.
+    pub async fn open_stream<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+    ) -> GenericResult<PeerStream> {
+        if !target.capabilities.multiplexing {
+            return Err(trivial_error!("Peer doesn't support multiplexing"));
+        }
+        let key = (target.peer_id, target.group_id);
+        let existing = self.connections.lock().get(&key).cloned();
+        let connection = match existing {
+            Some(c) if c.mux.is_some() => c,
+            _ => self.connect(delegate, target).await?,
+        };
+        let (id, incoming) = connection.mux.as_ref().unwrap().open();
+        let stream = PeerStream {
+            delegate: Arc::clone(delegate) as Arc<dyn ConnectionDelegate>,
+            connection,
+            id,
+            incoming,
+            priority: Priority::default(),
+        };
+        stream
+            .connection
+            .write_frame(
+                delegate.identity(),
+                Frame::Open { stream: id },
+                Priority::Control,
+            )
+            .await?;
+        Ok(stream)
+    }
+
+    async fn connect<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+    ) -> GenericResult<Arc<Connection>> {
+        trace!("ConnectionManager::connect({:?})", target.address);
+        let mut stream = match tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect(target.address),
+        )
+        .await?
+        {
+            Ok(stream) => stream,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::TimedOut, e).into()),
+        };
+        let records = handshake::initiate(
+            &mut stream,
+            delegate.identity(),
+            &target.keys,
+            target.wire_version,
+        )
+        .await?;
+        Ok(self.register(
+            delegate,
+            target,
+            stream,
+            records,
+            /* initiated_by_us = */ true,
+            (),
+        ))
+    }
+
+    /// Adopts an incoming connection from a peer, already authenticated with the given record keys
+    /// (see `handshake::accept`). `guard` is kept alive for as long as the connection is open.
+    pub fn adopt<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        stream: TcpStream,
+        records: Arc<RecordKeys>,
+        guard: impl Send + 'static,
+    ) {
+        self.register(
+            delegate, target, stream, records, /* initiated_by_us = */ false, guard,
+        );
+    }
+
+    fn register<D: ConnectionDelegate>(
+        &self,
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        stream: TcpStream,
+        records: Arc<RecordKeys>,
+        initiated_by_us: bool,
+        guard: impl Send + 'static,
+    ) -> Arc<Connection> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let key = (target.peer_id, target.group_id);
+        let (reader, writer) = stream.into_split();
+        let connection = Arc::new(Connection {
+            id,
+            initiated_by_us,
+            target: target.clone(),
+            records,
+            writer: tokio::sync::Mutex::new(BufWriter::new(writer)),
+            scheduler: WriteScheduler::default(),
+            padding: delegate.padding(),
+            mux: target.capabilities.multiplexing.then(|| {
+                let limits = delegate.limits();
+                Mux::new(
+                    initiated_by_us,
+                    limits.max_peer_frame_size,
+                    limits.max_streams_per_connection,
+                )
+            }),
+            reader_task: OnceLock::new(),
+        });
+        let reader_task = {
+            let delegate = Arc::clone(delegate);
+            let connection = Arc::clone(&connection);
+            tokio::spawn(async move {
+                let _guard = guard;
+                let target = &connection.target;
+                let mut reader = BufReader::new(reader);
+                while let Ok((version, buf)) = read_peer_message(
+                    delegate.identity(),
+                    &connection.records,
+                    &target.identity,
+                    &mut reader,
+                    &target.address,
+                    delegate.limits(),
+                    &target.capabilities,
+                )
+                .await
+                {
+                    if connection.mux.is_none() {
+                        Self::deliver(&delegate, target, &buf);
+                        continue;
+                    }
+                    if let Err(e) = Self::handle_frame(&delegate, &connection, version, &buf) {
+                        error!("Multiplexing error in connection {id} to {key:?}: {e}");
+                        break;
+                    }
+                }
+                trace!("Connection {id} to {key:?} closed");
+                delegate
+                    .connections()
+                    .remove_if_current(key, id, /* abort = */ false);
+            })
+        };
+        let _ = connection.reader_task.set(reader_task);
+
+        let ours_preferred = delegate.identity().to_public().key.0 < target.identity.key.0;
+        let mut connections = self.connections.lock();
+        if let Some(existing) = connections.get(&key) {
+            // Both ends agree on keeping the connection initiated by the lowest key.
+            if existing.initiated_by_us == ours_preferred && initiated_by_us != ours_preferred {
+                trace!("Keeping existing connection {} to {key:?}", existing.id);
+                return connection;
+            }
+        }
+        trace!("Registering connection {id} to {key:?}");
+        connections.insert(key, Arc::clone(&connection));
+        connection
+    }
+
+    fn handle_frame<D: ConnectionDelegate>(
+        delegate: &Arc<D>,
+        connection: &Arc<Connection>,
+        version: u16,
+        buf: &[u8],
+    ) -> GenericResult<()> {
+        let frame = wire::decode::<Frame>(version, buf)?;
+        let target = &connection.target;
+        for event in connection.mux.as_ref().unwrap().handle_frame(frame)? {
+            match event {
+                MuxEvent::Message(stream, (message, credit)) => {
+                    Self::deliver(delegate, target, &message);
+                    let mux = connection.mux.as_ref().unwrap();
+                    if let Some(frame) = mux.consumed(stream, credit) {
+                        Self::spawn_write_frame(delegate, connection, frame);
+                    }
+                }
+                MuxEvent::Opened(id, incoming) => delegate.stream_opened(
+                    target.peer_id,
+                    target.group_id,
+                    PeerStream {
+                        delegate: Arc::clone(delegate) as Arc<dyn ConnectionDelegate>,
+                        connection: Arc::clone(connection),
+                        id,
+                        incoming,
+                        priority: Priority::default(),
+                    },
+                ),
+                MuxEvent::Reply(frame) => Self::spawn_write_frame(delegate, connection, frame),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a control frame from another task, so that the reader never blocks on the writer
+    /// (which might in turn be waiting on the peer to read).
+    fn spawn_write_frame<D: ConnectionDelegate>(
+        delegate: &Arc<D>,
+        connection: &Arc<Connection>,
+        frame: Frame,
+    ) {
+        let delegate = Arc::clone(delegate);
+        let connection = Arc::clone(connection);
+        tokio::spawn(async move {
+            let _ = connection
+                .write_frame(delegate.identity(), frame, Priority::Control)
+                .await;
+        });
+    }
+
+    /// Hands a message from a peer to the delegate, in order if negotiated.
+    fn deliver<D: ConnectionDelegate>(
+        delegate: &Arc<D>,
+        target: &ConnectionTarget,
+        message: &[u8],
+    ) {
+        let peer_id = target.peer_id;
+        if !target.capabilities.ordered_delivery {
+            return delegate.connection_message(peer_id, target.group_id, message);
+        }
+        let reorderer = &delegate.connections().reorderer;
+        let result = reorderer.receive(
+            peer_id,
+            target.group_id,
+            message,
+            delegate.limits().max_reorder_buffer_size,
+            |group_id, message| delegate.connection_message(peer_id, group_id, message),
+        );
+        match result {
+            Ok(None) => {}
+            Ok(Some(priority)) => {
+                let delegate = Arc::clone(delegate);
+                tokio::spawn(async move {
+                    let mut wait = Some(delivery::REORDER_TIMEOUT);
+                    while let Some(duration) = wait {
+                        tokio::time::sleep(duration).await;
+                        wait = delegate.connections().reorderer.expire(
+                            peer_id,
+                            priority,
+                            |group_id, message| {
+                                delegate.connection_message(peer_id, group_id, message)
+                            },
+                        );
+                    }
+                });
+            }
+            Err(e) => error!("Dropping message from {peer_id:?}: {e}"),
+        }
+    }
+
+    fn remove_if_current(&self, key: (PeerId, GroupId), id: u64, abort: bool) {
+        let mut connections = self.connections.lock();
+        if connections.get(&key).is_some_and(|c| c.id == id) {
+            let connection = connections.remove(&key).unwrap();
+            if abort {
+                connection.abort();
+            }
+        }
+    }
+
+    fn close_matching(&self, mut matches: impl FnMut(&(PeerId, GroupId)) -> bool) {
+        self.connections.lock().retain(|key, connection| {
+            if !matches(key) {
+                return true;
+            }
+            trace!("Closing connection {} to {key:?}", connection.id);
+            connection.abort();
+            false
+        });
+    }
+
+    /// Closes the connection to a peer in a given group, if any.
+    pub fn close(&self, peer_id: PeerId, group_id: GroupId) {
+        self.close_matching(|key| *key == (peer_id, group_id));
+    }
+
+    /// Closes all the connections to a given peer that is gone, and forgets about its delivery
+    /// state.
+    pub fn close_peer(&self, peer_id: PeerId) {
+        self.close_matching(|key| key.0 == peer_id);
+        self.sequencer.forget(peer_id);
+        self.reorderer.forget(peer_id);
+        self.send_queues.lock().retain(|key, _| key.0 != peer_id);
+    }
+
+    /// Closes all the connections in a given group.
+    pub fn close_group(&self, group_id: GroupId) {
+        self.close_matching(|key| key.1 == group_id);
+    }
+
+    /// Closes all connections.
+    pub fn clear(&self) {
+        self.close_matching(|_| true);
+    }
+}
@@ -0,0 +1,68 @@
+//! Unreliable, unordered datagrams between peers, for real-time data where a late message is
+//! worthless (e.g. position updates in a game).
+//!
+//! Datagrams are sent over UDP to the datagram port of the peer in a group, alongside the regular
+//! TCP connections. Each packet looks like:
+//!
+//! ```text
+//!   magic: u16 | sequence: u64 | ciphertext | tag
+//! ```
+//!
+//! The payload is encrypted and authenticated with a per-direction key derived from the
+//! association key exchange, using the sequence number as an explicit nonce, so that lost or
+//! reordered packets don't affect the rest. Packets that fail to authenticate or that were already
+//! received are dropped.
+use super::encryption::{Keys, TAG_LEN};
+use crate::{trivial_error, GenericResult};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// The maximum payload of a single datagram. This keeps packets below the minimum IPv6 MTU so
+/// that they're never fragmented.
+pub const MAX_PAYLOAD_LEN: usize = 1200;
+
+const MAGIC: u16 = 0xda7a;
+const HEADER_LEN: usize = 2 + 8;
+
+/// The maximum length of a datagram packet on the wire.
+pub const MAX_PACKET_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + TAG_LEN;
+
+/// Builds an encrypted datagram packet.
+pub fn seal(keys: &Keys, payload: &[u8]) -> GenericResult<Vec<u8>> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(trivial_error!("Datagram too long"));
+    }
+    let mut data = Vec::with_capacity(payload.len() + TAG_LEN);
+    data.extend_from_slice(payload);
+    let sequence = keys.seal_datagram(&mut data)?;
+    let mut packet = Vec::with_capacity(HEADER_LEN + data.len());
+    packet.extend_from_slice(&MAGIC.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&data);
+    Ok(packet)
+}
+
+/// Decrypts a datagram packet in place, returning its payload.
+pub fn open<'a>(keys: &Keys, packet: &'a mut [u8]) -> GenericResult<&'a [u8]> {
+    if packet.len() < HEADER_LEN + TAG_LEN || packet.len() > MAX_PACKET_LEN {
+        return Err(trivial_error!("Wrong datagram length"));
+    }
+    let (header, data) = packet.split_at_mut(HEADER_LEN);
+    if header[..2] != MAGIC.to_be_bytes() {
+        return Err(trivial_error!("Wrong datagram magic"));
+    }
+    let sequence = u64::from_be_bytes(header[2..].try_into().unwrap());
+    Ok(keys.open_datagram(sequence, data)?)
+}
+
+/// Sends an encrypted datagram to a peer.
+pub async fn send(
+    socket: &UdpSocket,
+    keys: &Keys,
+    to: &SocketAddr,
+    payload: &[u8],
+) -> GenericResult<()> {
+    let packet = seal(keys, payload)?;
+    socket.send_to(&packet, to).await?;
+    Ok(())
+}
@@ -0,0 +1,438 @@
+//! In-order, exactly-once delivery of peer messages.
+//!
+//! Messages to a peer can travel over different connections (e.g. if both ends connect to each
+//! other at the same time, or after a reconnection), and are read by different tasks, so they can
+//! arrive out of order. Sends can also be retried after a failure, so a message might arrive more
+//! than once.
+//!
+//! When both peers support it (see `Capabilities::ordered_delivery`), every message is prefixed
+//! with a header:
+//!
+//! ```text
+//!   epoch: u64 | skip: 1 bit, priority: 7 bits | sequence: u64
+//! ```
+//!
+//! Messages are ordered independently for each priority class (see the `priority` module), so
+//! that urgent messages don't wait for less urgent ones sent before them. The epoch is picked
+//! randomly by the sender the first time it messages a peer with a given priority, so that a
+//! restarted sender (or one that lost and rediscovered us) starts over, and the sequence number
+//! increases by one with each message to that peer (across all groups). Retries reuse the same
+//! header.
+//!
+//! If the sender gives up on a message, it sends a skip record in its place before the next
+//! message with that priority: a header with the skip bit set and no message, so that the receiver
+//! moves on right away.
+//!
+//! Receivers deliver messages strictly in sequence order, buffering the ones that arrive early and
+//! dropping the ones that were already delivered. If a gap isn't filled in `REORDER_TIMEOUT`
+//! (e.g. because the skip record didn't make it either), or the buffered messages exceed
+//! `Limits::max_reorder_buffer_size`, the receiver skips it. Messages that arrive after their gap
+//! was skipped are dropped, since they can't be delivered in order anymore.
+use super::priority::Priority;
+use crate::{trivial_error, GenericResult, GroupId, PeerId};
+use log::{trace, warn};
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The length of the header prepended to each message.
+pub const HEADER_LEN: usize = 8 + 1 + 8;
+
+/// The bit of the priority byte that marks skip records.
+const SKIP_FLAG: u8 = 0x80;
+
+/// How long we wait for a missing message before delivering the ones after it.
+pub const REORDER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages are sequenced independently for each of these.
+type Channel = (PeerId, Priority);
+
+/// The delivery header of an outgoing message.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    epoch: u64,
+    priority: Priority,
+    sequence: u64,
+}
+
+impl Header {
+    fn write(&self, skip: bool, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.epoch.to_be_bytes());
+        out.push(self.priority.index() | if skip { SKIP_FLAG } else { 0 });
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+    }
+
+    /// Returns the record telling the receiver that we gave up on this message.
+    pub fn skip_record(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(HEADER_LEN);
+        self.write(/* skip = */ true, &mut record);
+        record
+    }
+}
+
+/// Assigns sequence numbers to outgoing messages.
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    /// The epoch and next sequence number for each peer and priority.
+    next: Mutex<HashMap<Channel, (u64, u64)>>,
+    /// The messages we gave up on, to send skip records for.
+    abandoned: Mutex<HashMap<Channel, Vec<Header>>>,
+}
+
+impl Sequencer {
+    /// Prepends the header for the next message to the given peer with the given priority.
+    pub fn wrap(&self, peer_id: PeerId, priority: Priority, message: &[u8]) -> (Header, Vec<u8>) {
+        let header = {
+            let mut next = self.next.lock();
+            let (epoch, next) = next
+                .entry((peer_id, priority))
+                .or_insert_with(|| (rand::random(), 0));
+            let sequence = *next;
+            *next += 1;
+            Header {
+                epoch: *epoch,
+                priority,
+                sequence,
+            }
+        };
+        let mut wrapped = Vec::with_capacity(HEADER_LEN + message.len());
+        header.write(/* skip = */ false, &mut wrapped);
+        wrapped.extend_from_slice(message);
+        (header, wrapped)
+    }
+
+    /// Records that we gave up on sending some messages to a peer, so that `take_abandoned`
+    /// returns them.
+    pub fn abandon(&self, peer_id: PeerId, headers: &[Header]) {
+        let Some(first) = headers.first() else {
+            return;
+        };
+        let mut abandoned = self.abandoned.lock();
+        let channel = abandoned.entry((peer_id, first.priority)).or_default();
+        channel.extend_from_slice(headers);
+        channel.sort_by_key(|h| h.sequence);
+    }
+
+    /// Returns the messages to a peer we gave up on, to send skip records for before the next
+    /// message with the given priority.
+    pub fn take_abandoned(&self, peer_id: PeerId, priority: Priority) -> Vec<Header> {
+        self.abandoned
+            .lock()
+            .remove(&(peer_id, priority))
+            .unwrap_or_default()
+    }
+
+    /// Forgets about a peer.
+    pub fn forget(&self, peer_id: PeerId) {
+        self.next.lock().retain(|key, _| key.0 != peer_id);
+        self.abandoned.lock().retain(|key, _| key.0 != peer_id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    epoch: u64,
+    next_expected: u64,
+    /// Messages that arrived early, by sequence number. Skip records have no message.
+    pending: BTreeMap<u64, Option<(GroupId, Vec<u8>)>>,
+    pending_size: usize,
+    /// When we started waiting for `next_expected`, if there are pending messages.
+    gap_since: Option<Instant>,
+    /// Messages that are ready to be delivered, in order.
+    ready: VecDeque<(GroupId, Vec<u8>)>,
+    /// Whether a task is delivering the ready messages, see `Reorderer::deliver_ready`.
+    delivering: bool,
+}
+
+impl Queue {
+    /// Moves all the pending messages that are next in order to the ready ones.
+    fn drain(&mut self) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() != self.next_expected {
+                break;
+            }
+            self.next_expected += 1;
+            if let Some((group_id, message)) = entry.remove() {
+                self.pending_size -= message.len();
+                self.ready.push_back((group_id, message));
+            }
+        }
+        if self.pending.is_empty() {
+            self.gap_since = None;
+        }
+    }
+
+    /// Gives up on the current gap, readying everything up to the next one.
+    fn skip_gap(&mut self) {
+        let Some(&first) = self.pending.keys().next() else {
+            return;
+        };
+        warn!(
+            "Skipping messages {}..{first} which didn't arrive in time",
+            self.next_expected
+        );
+        self.next_expected = first;
+        self.gap_since = None;
+        self.drain();
+    }
+}
+
+/// Puts incoming messages back in order, per peer and priority.
+#[derive(Debug, Default)]
+pub struct Reorderer {
+    queues: Mutex<HashMap<Channel, Arc<Mutex<Queue>>>>,
+}
+
+impl Reorderer {
+    fn queue(&self, channel: Channel) -> Arc<Mutex<Queue>> {
+        Arc::clone(self.queues.lock().entry(channel).or_default())
+    }
+
+    /// Calls `deliver` with the ready messages of a queue, unless another task is already doing
+    /// it, in which case that task picks them up. The queue isn't locked while delivering, so
+    /// that the listener doesn't block the other readers of the peer, but messages are still
+    /// delivered one at a time and in order.
+    fn deliver_ready(queue: &Mutex<Queue>, mut deliver: impl FnMut(GroupId, &[u8])) {
+        {
+            let mut queue = queue.lock();
+            if queue.delivering || queue.ready.is_empty() {
+                return;
+            }
+            queue.delivering = true;
+        }
+        loop {
+            let ready = {
+                let mut queue = queue.lock();
+                if queue.ready.is_empty() {
+                    queue.delivering = false;
+                    return;
+                }
+                std::mem::take(&mut queue.ready)
+            };
+            for (group_id, message) in ready {
+                deliver(group_id, &message);
+            }
+        }
+    }
+
+    /// Handles an incoming message from a peer, calling `deliver` with every message that is
+    /// ready, in order. Messages from the same peer are never delivered concurrently.
+    ///
+    /// Returns the priority of the message if the peer now has a gap we're waiting on for it, in
+    /// which case `expire` should be called after `REORDER_TIMEOUT`.
+    pub fn receive(
+        &self,
+        peer_id: PeerId,
+        group_id: GroupId,
+        message: &[u8],
+        max_buffer_size: usize,
+        deliver: impl FnMut(GroupId, &[u8]),
+    ) -> GenericResult<Option<Priority>> {
+        if message.len() < HEADER_LEN {
+            return Err(trivial_error!("Message too short for delivery header"));
+        }
+        let epoch = u64::from_be_bytes(message[..8].try_into().unwrap());
+        let skip = message[8] & SKIP_FLAG != 0;
+        let Some(priority) = Priority::from_index(message[8] & !SKIP_FLAG) else {
+            return Err(trivial_error!("Unknown message priority"));
+        };
+        let sequence = u64::from_be_bytes(message[9..HEADER_LEN].try_into().unwrap());
+        let payload = &message[HEADER_LEN..];
+        if skip && !payload.is_empty() {
+            return Err(trivial_error!("Skip record with a message"));
+        }
+
+        let queue = self.queue((peer_id, priority));
+        let result = {
+            let mut queue = queue.lock();
+            if queue.epoch != epoch {
+                trace!("New delivery epoch for {peer_id:?}");
+                // Messages that are ready are still delivered, by whoever is delivering them.
+                *queue = Queue {
+                    epoch,
+                    ready: std::mem::take(&mut queue.ready),
+                    delivering: queue.delivering,
+                    ..Default::default()
+                };
+            }
+            Self::insert(
+                &mut queue,
+                peer_id,
+                group_id,
+                sequence,
+                skip,
+                payload,
+                max_buffer_size,
+            )
+        };
+        Self::deliver_ready(&queue, deliver);
+        Ok(result.then_some(priority))
+    }
+
+    /// Adds an incoming message to its queue. Returns whether the peer now has a gap we're
+    /// waiting on.
+    fn insert(
+        queue: &mut Queue,
+        peer_id: PeerId,
+        group_id: GroupId,
+        sequence: u64,
+        skip: bool,
+        payload: &[u8],
+        max_buffer_size: usize,
+    ) -> bool {
+        if sequence < queue.next_expected || queue.pending.contains_key(&sequence) {
+            trace!("Dropping duplicate message {sequence} from {peer_id:?}");
+            return false;
+        }
+        if skip {
+            trace!("Peer {peer_id:?} gave up on message {sequence}");
+        }
+        if sequence == queue.next_expected {
+            queue.next_expected += 1;
+            if !skip {
+                queue.ready.push_back((group_id, payload.to_vec()));
+            }
+            queue.drain();
+            if !queue.pending.is_empty() {
+                // We're now waiting on a different message.
+                queue.gap_since = Some(Instant::now());
+            }
+            return false;
+        }
+        trace!(
+            "Message {sequence} from {peer_id:?} arrived early, waiting for {}",
+            queue.next_expected
+        );
+        if skip {
+            queue.pending.insert(sequence, None);
+        } else {
+            queue.pending_size += payload.len();
+            queue
+                .pending
+                .insert(sequence, Some((group_id, payload.to_vec())));
+        }
+        if queue.pending_size > max_buffer_size {
+            queue.skip_gap();
+        }
+        if queue.pending.is_empty() || queue.gap_since.is_some() {
+            return false;
+        }
+        queue.gap_since = Some(Instant::now());
+        true
+    }
+
+    /// Skips the gap of a peer in a priority class if we've been waiting on it for too long.
+    /// Returns how long to wait before calling this again, if there's still a gap we're waiting
+    /// on.
+    pub fn expire(
+        &self,
+        peer_id: PeerId,
+        priority: Priority,
+        deliver: impl FnMut(GroupId, &[u8]),
+    ) -> Option<Duration> {
+        let queue = self.queues.lock().get(&(peer_id, priority)).cloned()?;
+        let wait = {
+            let mut queue = queue.lock();
+            let elapsed = queue.gap_since?.elapsed();
+            if elapsed < REORDER_TIMEOUT {
+                return Some(REORDER_TIMEOUT - elapsed);
+            }
+            queue.skip_gap();
+            if queue.pending.is_empty() {
+                None
+            } else {
+                queue.gap_since = Some(Instant::now());
+                Some(REORDER_TIMEOUT)
+            }
+        };
+        Self::deliver_ready(&queue, deliver);
+        wait
+    }
+
+    /// Forgets about a peer.
+    pub fn forget(&self, peer_id: PeerId) {
+        self.queues.lock().retain(|key, _| key.0 != peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+    fn ids() -> (PeerId, GroupId) {
+        let mut handles = handy::HandleMap::new();
+        (PeerId(handles.insert(())), GroupId(handles.insert(())))
+    }
+
+    fn receive(
+        reorderer: &Reorderer,
+        peer_id: PeerId,
+        group_id: GroupId,
+        message: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let mut delivered = Vec::new();
+        reorderer
+            .receive(peer_id, group_id, message, MAX_BUFFER_SIZE, |_, m| {
+                delivered.push(m.to_vec())
+            })
+            .unwrap();
+        delivered
+    }
+
+    #[test]
+    fn reorders_messages() {
+        let (peer_id, group_id) = ids();
+        let sequencer = Sequencer::default();
+        let reorderer = Reorderer::default();
+        let (_, first) = sequencer.wrap(peer_id, Priority::Interactive, b"first");
+        let (_, second) = sequencer.wrap(peer_id, Priority::Interactive, b"second");
+        let (_, urgent) = sequencer.wrap(peer_id, Priority::Realtime, b"urgent");
+
+        assert!(receive(&reorderer, peer_id, group_id, &second).is_empty());
+        // Other priorities don't wait.
+        assert_eq!(receive(&reorderer, peer_id, group_id, &urgent), [b"urgent"]);
+        assert_eq!(
+            receive(&reorderer, peer_id, group_id, &first),
+            [&b"first"[..], b"second"]
+        );
+        assert!(receive(&reorderer, peer_id, group_id, &first).is_empty());
+    }
+
+    #[test]
+    fn skips_abandoned_messages() {
+        let (peer_id, group_id) = ids();
+        let sequencer = Sequencer::default();
+        let reorderer = Reorderer::default();
+        let (lost, _) = sequencer.wrap(peer_id, Priority::Interactive, b"lost");
+        let (_, next) = sequencer.wrap(peer_id, Priority::Interactive, b"next");
+        sequencer.abandon(peer_id, &[lost]);
+        assert!(sequencer.take_abandoned(peer_id, Priority::Bulk).is_empty());
+        let abandoned = sequencer.take_abandoned(peer_id, Priority::Interactive);
+        assert_eq!(abandoned.len(), 1);
+        assert!(sequencer
+            .take_abandoned(peer_id, Priority::Interactive)
+            .is_empty());
+
+        assert!(receive(&reorderer, peer_id, group_id, &next).is_empty());
+        assert_eq!(
+            receive(&reorderer, peer_id, group_id, &abandoned[0].skip_record()),
+            [b"next"]
+        );
+    }
+
+    #[test]
+    fn rejects_skip_records_with_messages() {
+        let (peer_id, group_id) = ids();
+        let (header, _) = Sequencer::default().wrap(peer_id, Priority::Interactive, b"");
+        let mut record = header.skip_record();
+        record.push(0);
+        assert!(Reorderer::default()
+            .receive(peer_id, group_id, &record, MAX_BUFFER_SIZE, |_, _| {})
+            .is_err());
+    }
+}
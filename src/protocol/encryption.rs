@@ -1,23 +1,23 @@
 use parking_lot::Mutex;
-use ring::aead::{Aad, Nonce, UnboundKey, NONCE_LEN};
-use ring::aead::{BoundKey, AES_256_GCM};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::aead::{BoundKey, AES_256_GCM, CHACHA20_POLY1305};
 use ring::error::Unspecified;
+use ring::{hkdf, hmac};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
-use crate::protocol::key_exchange;
+use crate::protocol::{
+    key_exchange,
+    resumption::{self, Ticket},
+};
 
-// FIXME(emilio): Revisit this. Using a counter nonce works but seems like one lost message will
-// break all subsequent ones.
-//
-// Most AEAD algorithms fail in presence of repeated nonces, so probably worth combining randomness
-// with a counter or so:
-//
-//  * https://docs.rs/ring/0.17.14/src/ring/aead.rs.html#43-47
-//  * https://csrc.nist.gov/publications/detail/sp/800-38d/final
-//  * https://docs.rs/aead/0.5.2/src/aead/lib.rs.html#151
-//  * https://docs.rs/aead/0.5.2/src/aead/stream.rs.html#437
-//
-// It seems like the right way to do this would be to use LessSafeKey with a nonce given in the
-// message somehow? Or maybe the nonce is not even needed given the keys are ephemeral.
+// Using a counter nonce means one lost message breaks all subsequent ones, and most AEAD algorithms
+// fail in presence of repeated nonces. That's fine for peer records since every connection (and
+// direction) gets its own keys, see `Keys::record_keys`, and records in a stream can't get lost or
+// reordered. Datagrams use explicit nonces instead. Version 1 builds don't do any of that, see
+// `Keys::legacy_records`.
 //
 // See also the discussion in https://github.com/briansmith/ring/issues/899,
 // https://security.stackexchange.com/questions/272533/what-purpose-do-nonces-serve-in-the-tls-1-3-handshake
@@ -46,45 +46,503 @@ impl ring::aead::NonceSequence for NonceSequence {
     }
 }
 
-const AES_256_KEY_LEN: usize = 256 / 8;
+/// The AEAD used to encrypt peer records and datagrams, negotiated during association (see
+/// `Capabilities::cipher_suite`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    /// AES-256-GCM, the fastest choice on devices with AES instructions, and the only one older
+    /// builds support.
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, faster in software.
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    fn algorithm(self) -> &'static ring::aead::Algorithm {
+        match self {
+            Self::Aes256Gcm => &AES_256_GCM,
+            Self::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// Returns whether this device has AES instructions, and thus would rather use AES-256-GCM.
+pub fn has_hardware_aes() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
 pub type SealingKey = ring::aead::SealingKey<NonceSequence>;
 pub type OpeningKey = ring::aead::OpeningKey<NonceSequence>;
 
+/// The length of the authentication tag appended to every encrypted message.
+pub const TAG_LEN: usize = 16;
+
+/// The length of a proof of possession of the session keys, see `Keys::prove`.
+pub const PROOF_LEN: usize = 32;
+
+/// HKDF info used to derive the connection authentication key from the shared secret.
+const AUTH_KEY_INFO: &[u8] = b"ngn peer auth";
+
+/// HKDF salt used to combine the X25519 secret with the ML-KEM secret of a hybrid key exchange
+/// and / or the secret of a resumed session, see `combined_secrets`.
+const COMBINED_KEY_SCHEDULE_SALT: &[u8] = b"ngn combined key schedule";
+
+/// Labels of the secrets combined into the key schedule, see `combined_secrets`.
+const X25519_SECRET_LABEL: &[u8] = b"x25519";
+const POST_QUANTUM_SECRET_LABEL: &[u8] = b"mlkem768";
+const RESUMPTION_SECRET_LABEL: &[u8] = b"resumption";
+
+/// Encodes the secrets of a key schedule as the input keying material, each one prefixed by its
+/// label and length, so that schedules combining different secrets never share an input.
+fn combined_secrets(secrets: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+    let mut ikm = vec![];
+    for (label, secret) in secrets {
+        let Some(secret) = secret else {
+            continue;
+        };
+        ikm.push(label.len() as u8);
+        ikm.extend_from_slice(label);
+        ikm.extend_from_slice(&(secret.len() as u16).to_be_bytes());
+        ikm.extend_from_slice(secret);
+    }
+    ikm
+}
+
+/// HKDF info used to derive the record keys of a connection, one per direction, see
+/// `Keys::record_keys`.
+const RECORD_KEY_INFO_INITIATOR: &[u8] = b"ngn records initiator";
+const RECORD_KEY_INFO_ACCEPTOR: &[u8] = b"ngn records acceptor";
+
+/// HKDF info used to derive the resumption ticket of a session, see the `resumption` module.
+const TICKET_ID_INFO: &[u8] = b"ngn resumption ticket";
+const TICKET_SECRET_INFO: &[u8] = b"ngn resumption secret";
+
+/// An HKDF output length.
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF info used to derive the datagram keys, one per direction. Each peer seals with the key of
+/// the side its exchange public key sorts in, so that sequence numbers can be used as nonces
+/// without colliding.
+const DATAGRAM_KEY_INFO_LOW: &[u8] = b"ngn datagram low";
+const DATAGRAM_KEY_INFO_HIGH: &[u8] = b"ngn datagram high";
+
+/// How far behind the most recent datagram we still accept reordered ones.
+const REPLAY_WINDOW_LEN: u64 = 64;
+
+/// Tracks the datagram sequence numbers we've seen recently, to drop replayed datagrams.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// The highest sequence number seen so far, or zero.
+    highest: u64,
+    /// Bit `i` is set if we've seen `highest - i`.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Returns whether the datagram with the given sequence number should be accepted, and
+    /// records it as seen if so.
+    fn accept(&mut self, sequence: u64) -> bool {
+        if sequence == 0 {
+            return false;
+        }
+        if sequence > self.highest {
+            let shift = sequence - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW_LEN {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = sequence;
+            return true;
+        }
+        let offset = self.highest - sequence;
+        if offset >= REPLAY_WINDOW_LEN {
+            return false;
+        }
+        let bit = 1 << offset;
+        if self.seen & bit != 0 {
+            return false;
+        }
+        self.seen |= bit;
+        true
+    }
+}
+
+fn datagram_nonce(sequence: u64) -> Nonce {
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    nonce_bytes[NONCE_LEN - 8..].copy_from_slice(&sequence.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce_bytes)
+}
+
+/// Which end of a connection we are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Acceptor,
+}
+
+/// The keys for the records of a single peer connection, see `Keys::record_keys`.
+#[derive(Debug)]
+pub struct RecordKeys {
+    sealing: Mutex<SealingKey>,
+    opening: Mutex<OpeningKey>,
+}
+
+impl RecordKeys {
+    /// The keys version 1 builds use for all the records with a peer, in both directions: the
+    /// X25519 shared secret as is.
+    fn legacy(shared_secret: &[u8]) -> Result<Self, Unspecified> {
+        let key = || UnboundKey::new(&AES_256_GCM, shared_secret);
+        Ok(Self {
+            sealing: Mutex::new(SealingKey::new(key()?, NonceSequence::default())),
+            opening: Mutex::new(OpeningKey::new(key()?, NonceSequence::default())),
+        })
+    }
+
+    pub fn encrypt_in_place_append_tag(&self, data: &mut Vec<u8>) -> Result<(), Unspecified> {
+        self.sealing
+            .lock()
+            .seal_in_place_append_tag(Aad::from(b""), data)
+    }
+
+    pub fn decrypt_in_place<'a>(&self, data: &'a mut [u8]) -> Result<&'a mut [u8], Unspecified> {
+        self.opening.lock().open_in_place(Aad::from(b""), data)
+    }
+}
+
 #[derive(Debug)]
 pub struct Keys {
-    encryption: Mutex<SealingKey>,
-    decryption: Mutex<OpeningKey>,
+    cipher_suite: CipherSuite,
+    /// Whether these keys come from a hybrid post-quantum key exchange.
+    post_quantum: bool,
+    /// Whether these keys resume a previous session.
+    resumed: bool,
+    /// The ticket to resume this session later on.
+    resumption_ticket: Ticket,
+    /// The secret the record keys of each connection are derived from.
+    records: hkdf::Prk,
+    /// The keys for all the records with a peer that associated using wire version 1, if these
+    /// keys could be from such an association.
+    legacy_records: Option<Arc<RecordKeys>>,
+    /// Key used to prove possession of the shared secret when authenticating connections. This
+    /// is derived from the shared secret rather than used directly so that it's independent from
+    /// the encryption key.
+    authentication: hmac::Key,
+    /// Keys for datagrams, which can be lost or reordered, and thus use explicit nonces.
+    datagram_sealing: LessSafeKey,
+    datagram_opening: LessSafeKey,
+    /// The sequence number of the next datagram we send.
+    datagram_sequence: AtomicU64,
+    datagram_replay: Mutex<ReplayWindow>,
 }
 
 impl Keys {
+    /// Derives the keys from the X25519 exchange, combined with the ML-KEM shared secret if the
+    /// exchange was hybrid (see the `key_exchange` module), and the secret of the previous session
+    /// if resuming it (see the `resumption` module).
     pub fn from_shared_secret(
         exchange_private_key: key_exchange::PrivateKey,
         peer_public_key: key_exchange::UnparsedPublicKey<&[u8]>,
+        post_quantum_secret: Option<&[u8]>,
+        resumption_secret: Option<&[u8]>,
+        cipher_suite: CipherSuite,
     ) -> Result<Self, Unspecified> {
-        let key_bytes: [u8; AES_256_KEY_LEN] = ring::agreement::agree_ephemeral(
-            exchange_private_key,
-            &peer_public_key,
-            |shared_secret: &[u8]| shared_secret.try_into(),
-        )??;
+        let algorithm = cipher_suite.algorithm();
+        let we_are_low =
+            exchange_private_key.compute_public_key()?.as_ref() < *peer_public_key.bytes();
+        // Version 1 builds can't do anything else.
+        let maybe_legacy = cipher_suite == CipherSuite::Aes256Gcm
+            && post_quantum_secret.is_none()
+            && resumption_secret.is_none();
+        let (records, legacy, authentication, datagram_low, datagram_high, resumption_ticket) =
+            ring::agreement::agree_ephemeral(
+                exchange_private_key,
+                &peer_public_key,
+                |shared_secret: &[u8]| -> Result<_, Unspecified> {
+                    let prk = if post_quantum_secret.is_none() && resumption_secret.is_none() {
+                        hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(shared_secret)
+                    } else {
+                        let ikm = combined_secrets(&[
+                            (X25519_SECRET_LABEL, Some(shared_secret)),
+                            (POST_QUANTUM_SECRET_LABEL, post_quantum_secret),
+                            (RESUMPTION_SECRET_LABEL, resumption_secret),
+                        ]);
+                        hkdf::Salt::new(hkdf::HKDF_SHA256, COMBINED_KEY_SCHEDULE_SALT).extract(&ikm)
+                    };
+                    let authentication =
+                        hmac::Key::from(prk.expand(&[AUTH_KEY_INFO], hmac::HMAC_SHA256)?);
+                    let datagram_low =
+                        UnboundKey::from(prk.expand(&[DATAGRAM_KEY_INFO_LOW], algorithm)?);
+                    let datagram_high =
+                        UnboundKey::from(prk.expand(&[DATAGRAM_KEY_INFO_HIGH], algorithm)?);
+                    let mut resumption_ticket = Ticket {
+                        id: [0; resumption::TICKET_ID_LEN],
+                        secret: [0; resumption::SECRET_LEN],
+                    };
+                    prk.expand(&[TICKET_ID_INFO], Len(resumption::TICKET_ID_LEN))?
+                        .fill(&mut resumption_ticket.id)?;
+                    prk.expand(&[TICKET_SECRET_INFO], Len(resumption::SECRET_LEN))?
+                        .fill(&mut resumption_ticket.secret)?;
+                    let legacy = if maybe_legacy {
+                        Some(Arc::new(RecordKeys::legacy(shared_secret)?))
+                    } else {
+                        None
+                    };
+                    Ok((
+                        prk,
+                        legacy,
+                        authentication,
+                        datagram_low,
+                        datagram_high,
+                        resumption_ticket,
+                    ))
+                },
+            )??;
+        let (datagram_sealing, datagram_opening) = if we_are_low {
+            (datagram_low, datagram_high)
+        } else {
+            (datagram_high, datagram_low)
+        };
         Ok(Self {
-            encryption: Mutex::new(SealingKey::new(
-                UnboundKey::new(&AES_256_GCM, &key_bytes).unwrap(),
-                NonceSequence::default(),
-            )),
-            decryption: Mutex::new(OpeningKey::new(
-                UnboundKey::new(&AES_256_GCM, &key_bytes).unwrap(),
-                NonceSequence::default(),
-            )),
+            cipher_suite,
+            post_quantum: post_quantum_secret.is_some(),
+            resumed: resumption_secret.is_some(),
+            resumption_ticket,
+            records,
+            legacy_records: legacy,
+            authentication,
+            datagram_sealing: LessSafeKey::new(datagram_sealing),
+            datagram_opening: LessSafeKey::new(datagram_opening),
+            datagram_sequence: AtomicU64::new(1),
+            datagram_replay: Default::default(),
         })
     }
 
-    pub fn encrypt_in_place_append_tag(&self, data: &mut Vec<u8>) -> Result<(), Unspecified> {
-        self.encryption
-            .lock()
-            .seal_in_place_append_tag(Aad::from(b""), data)
+    /// The cipher suite these keys are for.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
     }
 
-    pub fn decrypt_in_place<'a>(&self, data: &'a mut [u8]) -> Result<&'a mut [u8], Unspecified> {
-        self.decryption.lock().open_in_place(Aad::from(b""), data)
+    /// Whether these keys come from a hybrid post-quantum key exchange.
+    pub fn post_quantum(&self) -> bool {
+        self.post_quantum
+    }
+
+    /// Whether these keys resume a previous session, see the `resumption` module.
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// The ticket to resume this session later on.
+    pub fn resumption_ticket(&self) -> &Ticket {
+        &self.resumption_ticket
+    }
+
+    /// Returns a proof that we know the shared secret, bound to the given context.
+    pub fn prove(&self, context: &[u8]) -> [u8; PROOF_LEN] {
+        hmac::sign(&self.authentication, context)
+            .as_ref()
+            .try_into()
+            .unwrap()
+    }
+
+    /// Verifies (in constant time) a proof generated by the peer via `prove` with the same
+    /// context.
+    pub fn verify_proof(&self, context: &[u8], proof: &[u8]) -> Result<(), Unspecified> {
+        hmac::verify(&self.authentication, context, proof)
+    }
+
+    /// Derives the record keys of a connection from the nonces exchanged in its handshake (see
+    /// the `handshake` module), so that no two connections or directions share a key, and thus
+    /// nonces.
+    pub fn record_keys(
+        &self,
+        role: Role,
+        acceptor_nonce: &[u8],
+        initiator_nonce: &[u8],
+    ) -> Result<RecordKeys, Unspecified> {
+        let algorithm = self.cipher_suite.algorithm();
+        let key = |label| -> Result<_, Unspecified> {
+            let info = [label, acceptor_nonce, initiator_nonce];
+            Ok(UnboundKey::from(self.records.expand(&info, algorithm)?))
+        };
+        let initiator = key(RECORD_KEY_INFO_INITIATOR)?;
+        let acceptor = key(RECORD_KEY_INFO_ACCEPTOR)?;
+        let (sealing, opening) = match role {
+            Role::Initiator => (initiator, acceptor),
+            Role::Acceptor => (acceptor, initiator),
+        };
+        Ok(RecordKeys {
+            sealing: Mutex::new(SealingKey::new(sealing, NonceSequence::default())),
+            opening: Mutex::new(OpeningKey::new(opening, NonceSequence::default())),
+        })
+    }
+
+    /// Returns the keys for all the records with a peer that associated using wire version 1, which
+    /// has neither handshakes nor per-connection keys. Like version 1 builds do, the nonces keep
+    /// counting across connections, so that they're never reused.
+    pub fn legacy_records(&self) -> Option<&Arc<RecordKeys>> {
+        self.legacy_records.as_ref()
+    }
+
+    /// Encrypts a datagram in place, returning the sequence number it needs to be sent with.
+    pub fn seal_datagram(&self, data: &mut Vec<u8>) -> Result<u64, Unspecified> {
+        let sequence = self.datagram_sequence.fetch_add(1, Ordering::Relaxed);
+        self.datagram_sealing.seal_in_place_append_tag(
+            datagram_nonce(sequence),
+            Aad::empty(),
+            data,
+        )?;
+        Ok(sequence)
+    }
+
+    /// Decrypts a datagram in place, rejecting it if it fails to authenticate or was already
+    /// received.
+    pub fn open_datagram<'a>(
+        &self,
+        sequence: u64,
+        data: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Unspecified> {
+        let plaintext =
+            self.datagram_opening
+                .open_in_place(datagram_nonce(sequence), Aad::empty(), data)?;
+        if !self.datagram_replay.lock().accept(sequence) {
+            return Err(Unspecified);
+        }
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::key_exchange::KeyExchange;
+    use std::sync::Arc;
+
+    /// Returns the keys both ends of an exchange end up with.
+    fn keys() -> (Arc<Keys>, Arc<Keys>) {
+        keys_with(CipherSuite::ChaCha20Poly1305)
+    }
+
+    fn keys_with(suite: CipherSuite) -> (Arc<Keys>, Arc<Keys>) {
+        let (mut a, mut b) = (KeyExchange::new().unwrap(), KeyExchange::new().unwrap());
+        let (a_public, b_public) = (a.export_public_key(), b.export_public_key());
+        a.finish(&b_public, None, None, suite).unwrap();
+        b.finish(&a_public, None, None, suite).unwrap();
+        (
+            Arc::clone(a.encryption_keys().unwrap()),
+            Arc::clone(b.encryption_keys().unwrap()),
+        )
+    }
+
+    fn seal(keys: &RecordKeys, message: &[u8]) -> Vec<u8> {
+        let mut record = message.to_vec();
+        keys.encrypt_in_place_append_tag(&mut record).unwrap();
+        record
+    }
+
+    fn open(keys: &RecordKeys, mut record: Vec<u8>) -> Option<Vec<u8>> {
+        Some(keys.decrypt_in_place(&mut record).ok()?.to_vec())
+    }
+
+    #[test]
+    fn record_keys_per_direction() {
+        let (a, b) = keys();
+        let initiator = a
+            .record_keys(Role::Initiator, b"acceptor", b"initiator")
+            .unwrap();
+        let acceptor = b
+            .record_keys(Role::Acceptor, b"acceptor", b"initiator")
+            .unwrap();
+
+        let record = seal(&initiator, b"ping");
+        assert_eq!(
+            open(&acceptor, record.clone()).as_deref(),
+            Some(&b"ping"[..])
+        );
+        let record = seal(&acceptor, b"pong");
+        assert_eq!(open(&initiator, record).as_deref(), Some(&b"pong"[..]));
+
+        // Records can't be reflected back to their sender.
+        let initiator = a
+            .record_keys(Role::Initiator, b"acceptor", b"initiator")
+            .unwrap();
+        let record = seal(&initiator, b"ping");
+        assert_eq!(open(&initiator, record), None);
+    }
+
+    #[test]
+    fn legacy_records_in_both_directions() {
+        let (a, b) = keys_with(CipherSuite::Aes256Gcm);
+        let (a, b) = (a.legacy_records().unwrap(), b.legacy_records().unwrap());
+        let record = seal(a, b"ping");
+        assert_eq!(open(b, record).as_deref(), Some(&b"ping"[..]));
+        let record = seal(b, b"pong");
+        assert_eq!(open(a, record).as_deref(), Some(&b"pong"[..]));
+        // Version 1 builds can't negotiate anything else.
+        assert!(keys().0.legacy_records().is_none());
+    }
+
+    #[test]
+    fn record_keys_per_connection() {
+        let (a, b) = keys();
+        let first = a
+            .record_keys(Role::Initiator, b"acceptor", b"first")
+            .unwrap();
+        let second = a
+            .record_keys(Role::Initiator, b"acceptor", b"second")
+            .unwrap();
+        let acceptor = b
+            .record_keys(Role::Acceptor, b"acceptor", b"second")
+            .unwrap();
+
+        // Both connections start from the same nonce, but with different keys.
+        let (first, second) = (seal(&first, b"hello"), seal(&second, b"hello"));
+        assert_ne!(first, second);
+        assert_eq!(open(&acceptor, first), None);
+        let acceptor = b
+            .record_keys(Role::Acceptor, b"acceptor", b"second")
+            .unwrap();
+        assert_eq!(open(&acceptor, second).as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn combined_secrets_are_domain_separated() {
+        let combine = |post_quantum: Option<&[u8]>, resumption: Option<&[u8]>| {
+            combined_secrets(&[
+                (X25519_SECRET_LABEL, Some(b"shared")),
+                (POST_QUANTUM_SECRET_LABEL, post_quantum),
+                (RESUMPTION_SECRET_LABEL, resumption),
+            ])
+        };
+        // The same secret as the ML-KEM or the resumption one.
+        assert_ne!(
+            combine(Some(b"secret"), None),
+            combine(None, Some(b"secret"))
+        );
+        // The same bytes split differently between the two.
+        assert_ne!(
+            combine(Some(b"sec"), Some(b"ret")),
+            combine(Some(b"se"), Some(b"cret"))
+        );
     }
 }
@@ -0,0 +1,252 @@
+//! Authentication of incoming peer connections.
+//!
+//! Every connection to the p2p port of a peer starts with a short handshake in which both ends
+//! prove that they know the session keys derived from the key exchange done during association:
+//!
+//! ```text
+//!   acceptor  -> initiator: Challenge { nonce }
+//!   initiator -> acceptor:  Authenticate { key, nonce, proof }
+//!   acceptor  -> initiator: Accept { proof }
+//! ```
+//!
+//! This allows the acceptor to bind the stream to a logical identity cryptographically rather than
+//! trusting the source address, and to drop connections that fail to authenticate before reading
+//! any payload.
+//!
+//! Both nonces also go into the keys the records of the connection are encrypted with (see
+//! `Keys::record_keys`), so that each connection, and each direction, has its own.
+use super::{
+    encryption::{Keys, RecordKeys, Role, PROOF_LEN},
+    identity::OwnIdentity,
+    read_binary_message,
+    signing::MaybeInvalidPublicKey,
+    wire::{self, Fields, WireMessage, Writer},
+    write_binary_message,
+};
+use crate::{trivial_error, GenericResult};
+use log::trace;
+use ring::rand::SecureRandom;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// How long we're willing to wait for the handshake to complete before dropping the connection.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NONCE_LEN: usize = 32;
+
+/// Handshake messages are tiny, so don't accept anything bigger than this.
+const MAX_HANDSHAKE_FRAME_SIZE: u32 = 256;
+
+/// Context labels, so that a proof from one side can't be reflected back as the other's.
+const INITIATOR_LABEL: &[u8] = b"ngn initiator";
+const ACCEPTOR_LABEL: &[u8] = b"ngn acceptor";
+
+#[derive(Debug)]
+enum HandshakeMessage {
+    /// Fresh random challenge from the acceptor.
+    Challenge { nonce: [u8; NONCE_LEN] },
+    /// The initiator's logical key, its own nonce, and its proof of the session key.
+    Authenticate {
+        key: MaybeInvalidPublicKey,
+        nonce: [u8; NONCE_LEN],
+        proof: [u8; PROOF_LEN],
+    },
+    /// The acceptor's proof of the session key.
+    Accept { proof: [u8; PROOF_LEN] },
+}
+
+impl WireMessage for HandshakeMessage {
+    type Legacy = wire::legacy::Unsupported;
+
+    fn message_type(&self) -> u64 {
+        match *self {
+            Self::Challenge { .. } => 16,
+            Self::Authenticate { .. } => 17,
+            Self::Accept { .. } => 18,
+        }
+    }
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        match *self {
+            Self::Challenge { ref nonce } => writer.bytes(1, nonce),
+            Self::Authenticate {
+                ref key,
+                ref nonce,
+                ref proof,
+            } => {
+                writer.bytes(1, &key.0);
+                writer.bytes(2, nonce);
+                writer.bytes(3, proof);
+            }
+            Self::Accept { ref proof } => writer.bytes(1, proof),
+        }
+    }
+
+    fn decode_fields(message_type: u64, fields: &Fields) -> GenericResult<Self> {
+        Ok(match message_type {
+            16 => Self::Challenge {
+                nonce: fields.array(1)?,
+            },
+            17 => Self::Authenticate {
+                key: MaybeInvalidPublicKey(fields.array(1)?),
+                nonce: fields.array(2)?,
+                proof: fields.array(3)?,
+            },
+            18 => Self::Accept {
+                proof: fields.array(1)?,
+            },
+            _ => return Err(trivial_error!("Unknown handshake message type")),
+        })
+    }
+
+    fn to_legacy(&self) -> GenericResult<Self::Legacy> {
+        Err(trivial_error!("No handshakes in wire version 1"))
+    }
+
+    fn from_legacy(_: Self::Legacy) -> GenericResult<Self> {
+        Err(trivial_error!("No handshakes in wire version 1"))
+    }
+}
+
+fn random_nonce() -> GenericResult<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    ring::rand::SystemRandom::new().fill(&mut nonce)?;
+    Ok(nonce)
+}
+
+fn proof_context(
+    label: &[u8],
+    acceptor_nonce: &[u8; NONCE_LEN],
+    initiator_nonce: &[u8; NONCE_LEN],
+) -> Vec<u8> {
+    let mut context = Vec::with_capacity(label.len() + NONCE_LEN * 2);
+    context.extend_from_slice(label);
+    context.extend_from_slice(acceptor_nonce);
+    context.extend_from_slice(initiator_nonce);
+    context
+}
+
+async fn read_handshake_message(stream: impl AsyncRead + Unpin) -> GenericResult<HandshakeMessage> {
+    let (version, buf) = read_binary_message(stream, None, MAX_HANDSHAKE_FRAME_SIZE).await?;
+    wire::decode(version, &buf)
+}
+
+async fn write_handshake_message(
+    stream: impl AsyncWrite + Unpin,
+    version: u16,
+    message: HandshakeMessage,
+) -> GenericResult<()> {
+    let buf = wire::encode(version, &message)?;
+    write_binary_message(stream, version, &buf, None, None).await
+}
+
+/// Returns the keys for the records of a connection with a peer that associated using wire version
+/// 1, see `initiate`.
+pub fn legacy_records(keys: &Keys) -> GenericResult<Arc<RecordKeys>> {
+    match keys.legacy_records() {
+        Some(records) => Ok(Arc::clone(records)),
+        None => Err(trivial_error!("No version 1 keys for the peer")),
+    }
+}
+
+/// Authenticates an outgoing connection to a peer with which we share `keys`, using the wire
+/// version negotiated with it. Returns the record keys of the connection.
+///
+/// Version 1 builds don't know about handshakes, so connections with peers that associated using
+/// it aren't authenticated, and use the same keys for all records (see `Keys::legacy_records`).
+pub async fn initiate(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    own_identity: &OwnIdentity,
+    keys: &Keys,
+    version: u16,
+) -> GenericResult<Arc<RecordKeys>> {
+    if version == 1 {
+        return legacy_records(keys);
+    }
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let HandshakeMessage::Challenge {
+            nonce: acceptor_nonce,
+        } = read_handshake_message(&mut stream).await?
+        else {
+            return Err(trivial_error!("Expected a handshake challenge"));
+        };
+        let nonce = random_nonce()?;
+        let proof = keys.prove(&proof_context(INITIATOR_LABEL, &acceptor_nonce, &nonce));
+        write_handshake_message(
+            &mut stream,
+            version,
+            HandshakeMessage::Authenticate {
+                key: own_identity.to_public().key,
+                nonce,
+                proof,
+            },
+        )
+        .await?;
+        let HandshakeMessage::Accept { proof } = read_handshake_message(&mut stream).await? else {
+            return Err(trivial_error!("Expected a handshake acceptance"));
+        };
+        keys.verify_proof(
+            &proof_context(ACCEPTOR_LABEL, &acceptor_nonce, &nonce),
+            &proof,
+        )?;
+        Ok(Arc::new(keys.record_keys(
+            Role::Initiator,
+            &acceptor_nonce,
+            &nonce,
+        )?))
+    })
+    .await?
+}
+
+/// Authenticates an incoming connection.
+///
+/// `resolve` maps the logical key the initiator claims to have to the peer it belongs to and the
+/// session keys we share with it. The connection is rejected if the key is unknown or the
+/// initiator can't prove it knows the session keys. Otherwise, returns the peer along with the
+/// record keys of the connection.
+///
+/// Since we don't know who's connecting yet, the challenge is sent with `challenge_version`,
+/// which should be the version negotiated with the peer at `source_address` if any.
+pub async fn accept<T>(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    source_address: &SocketAddr,
+    challenge_version: u16,
+    resolve: impl FnOnce(&MaybeInvalidPublicKey) -> Option<(T, Arc<Keys>)>,
+) -> GenericResult<(T, Arc<RecordKeys>)> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let acceptor_nonce = random_nonce()?;
+        write_handshake_message(
+            &mut stream,
+            challenge_version,
+            HandshakeMessage::Challenge {
+                nonce: acceptor_nonce,
+            },
+        )
+        .await?;
+        let (version, buf) =
+            read_binary_message(&mut stream, None, MAX_HANDSHAKE_FRAME_SIZE).await?;
+        let HandshakeMessage::Authenticate { key, nonce, proof } = wire::decode(version, &buf)?
+        else {
+            return Err(trivial_error!("Expected a handshake authentication"));
+        };
+        let Some((peer, keys)) = resolve(&key) else {
+            return Err(trivial_error!("Connection with unknown logical key"));
+        };
+        if keys
+            .verify_proof(
+                &proof_context(INITIATOR_LABEL, &acceptor_nonce, &nonce),
+                &proof,
+            )
+            .is_err()
+        {
+            return Err(trivial_error!("Connection failed to prove its session key"));
+        }
+        let proof = keys.prove(&proof_context(ACCEPTOR_LABEL, &acceptor_nonce, &nonce));
+        // Reply in whatever version the initiator used.
+        write_handshake_message(&mut stream, version, HandshakeMessage::Accept { proof }).await?;
+        trace!("Authenticated connection from {source_address:?}");
+        let records = keys.record_keys(Role::Acceptor, &acceptor_nonce, &nonce)?;
+        Ok((peer, Arc::new(records)))
+    })
+    .await?
+}
@@ -1,6 +1,16 @@
 //! Key exchange using ECDH.
+//!
+//! With the `post-quantum` feature, the exchange is a hybrid of X25519 and ML-KEM-768, so that
+//! recorded traffic stays safe even if X25519 is broken later on: the client joining a group sends
+//! an ML-KEM encapsulation key along with its X25519 public key in its `Associate` message, and a
+//! group owner that supports it replies with a ciphertext encapsulating a second shared secret.
+//! Both secrets are then combined via HKDF (see `encryption::Keys::from_shared_secret`). If either
+//! end doesn't support it, the exchange is plain X25519.
 
-use crate::protocol::encryption::Keys;
+use crate::protocol::{
+    encryption::{CipherSuite, Keys},
+    resumption::Ticket,
+};
 use crate::GenericResult;
 use bincode::{Decode, Encode};
 pub use ring::agreement::EphemeralPrivateKey as PrivateKey;
@@ -12,10 +22,25 @@ use std::sync::Arc;
 use self::KeyExchangeState as State;
 use crate::trivial_error;
 
+#[cfg(feature = "post-quantum")]
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate, Kem, KeyExport, TryKeyInit},
+    MlKem768,
+};
+
 pub const PUBLIC_KEY_LEN: usize = 32;
 
-#[derive(Debug, Clone, Encode, Decode)]
-pub struct MaybeInvalidPublicKey([u8; PUBLIC_KEY_LEN]);
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct MaybeInvalidPublicKey(pub [u8; PUBLIC_KEY_LEN]);
+
+/// The post-quantum part of a hybrid key exchange, see the module docs.
+#[derive(Debug, Clone)]
+pub enum PostQuantumShare {
+    /// The ML-KEM-768 encapsulation key of the client.
+    EncapsulationKey(Vec<u8>),
+    /// The ML-KEM-768 ciphertext from the group owner.
+    Ciphertext(Vec<u8>),
+}
 
 #[derive(Debug)]
 enum KeyExchangeState {
@@ -27,6 +52,11 @@ enum KeyExchangeState {
 #[derive(Debug)]
 pub struct KeyExchange {
     public_key: PublicKey,
+    /// The public key of the peer, once the exchange has completed.
+    peer_key: Option<MaybeInvalidPublicKey>,
+    /// Our ML-KEM key pair, in case we're the client.
+    #[cfg(feature = "post-quantum")]
+    post_quantum_key: <MlKem768 as Kem>::DecapsulationKey,
     state: State,
 }
 
@@ -39,6 +69,9 @@ impl KeyExchange {
         let public_key = private.compute_public_key()?;
         Ok(Self {
             public_key,
+            peer_key: None,
+            #[cfg(feature = "post-quantum")]
+            post_quantum_key: MlKem768::generate_keypair().0,
             state: State::InProgress(private),
         })
     }
@@ -47,19 +80,111 @@ impl KeyExchange {
         MaybeInvalidPublicKey(self.public_key.as_ref().try_into().unwrap())
     }
 
-    pub fn finish(&mut self, peer_key: &MaybeInvalidPublicKey) -> GenericResult<()> {
+    /// Returns the public key of the peer, once the exchange has completed.
+    pub fn peer_public_key(&self) -> Option<&MaybeInvalidPublicKey> {
+        self.peer_key.as_ref()
+    }
+
+    /// Returns the post-quantum share to send to the group owner when associating, if we support
+    /// hybrid key exchanges.
+    pub fn export_post_quantum_key(&self) -> Option<PostQuantumShare> {
+        #[cfg(feature = "post-quantum")]
+        {
+            let key = self.post_quantum_key.encapsulation_key().to_bytes();
+            Some(PostQuantumShare::EncapsulationKey(key.to_vec()))
+        }
+        #[cfg(not(feature = "post-quantum"))]
+        {
+            None
+        }
+    }
+
+    /// Computes the post-quantum shared secret given the peer's share, returning the share to
+    /// reply with, if any.
+    #[cfg(feature = "post-quantum")]
+    fn post_quantum_secret(
+        &self,
+        peer_share: &PostQuantumShare,
+    ) -> GenericResult<(Vec<u8>, Option<PostQuantumShare>)> {
+        match *peer_share {
+            PostQuantumShare::EncapsulationKey(ref key) => {
+                let Ok(key) = <MlKem768 as Kem>::EncapsulationKey::new_from_slice(key) else {
+                    return Err(trivial_error!("Invalid ML-KEM encapsulation key"));
+                };
+                let (ciphertext, secret) = key.encapsulate();
+                Ok((
+                    secret.to_vec(),
+                    Some(PostQuantumShare::Ciphertext(ciphertext.to_vec())),
+                ))
+            }
+            PostQuantumShare::Ciphertext(ref ciphertext) => {
+                let Ok(ciphertext) = ml_kem::Ciphertext::<MlKem768>::try_from(&ciphertext[..])
+                else {
+                    return Err(trivial_error!("Invalid ML-KEM ciphertext"));
+                };
+                let secret = self.post_quantum_key.decapsulate(&ciphertext);
+                Ok((secret.to_vec(), None))
+            }
+        }
+    }
+
+    /// Starts over with fresh keys if the exchange has already completed, e.g. to associate again
+    /// with a peer after our previous group with it was torn down.
+    pub fn restart_if_completed(&mut self) -> Result<(), Unspecified> {
+        if matches!(self.state, State::Completed(..)) {
+            *self = Self::new()?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the exchange given the peer's public key and post-quantum share (if any), and the
+    /// ticket of the session we're resuming (if any). Returns the post-quantum share to send back
+    /// to the peer, if any.
+    ///
+    /// If the exchange has already completed with a different peer key, the peer started over, so
+    /// we do too (see `restart_if_completed`).
+    pub fn finish(
+        &mut self,
+        peer_key: &MaybeInvalidPublicKey,
+        peer_post_quantum_share: Option<&PostQuantumShare>,
+        resumption: Option<&Ticket>,
+        cipher_suite: CipherSuite,
+    ) -> GenericResult<Option<PostQuantumShare>> {
+        if self.peer_key.as_ref().is_some_and(|k| k != peer_key) {
+            self.restart_if_completed()?;
+        }
         if !matches!(self.state, KeyExchangeState::InProgress(..)) {
             return Err(trivial_error!("Exchange already completed"));
         }
+        #[cfg(feature = "post-quantum")]
+        let (post_quantum_secret, reply) = match peer_post_quantum_share {
+            Some(share) => {
+                let (secret, reply) = self.post_quantum_secret(share)?;
+                (Some(secret), reply)
+            }
+            None => (None, None),
+        };
+        #[cfg(not(feature = "post-quantum"))]
+        let (post_quantum_secret, reply) = {
+            let _ = peer_post_quantum_share;
+            (None::<Vec<u8>>, None)
+        };
         let result = std::mem::replace(&mut self.state, State::Errored);
         self.state = match result {
             KeyExchangeState::InProgress(private) => {
                 let peer_key = UnparsedPublicKey::new(&X25519, &peer_key.0[..]);
-                State::Completed(Arc::new(Keys::from_shared_secret(private, peer_key)?))
+                State::Completed(Arc::new(Keys::from_shared_secret(
+                    private,
+                    peer_key,
+                    post_quantum_secret.as_deref(),
+                    resumption.map(|ticket| &ticket.secret[..]),
+                    cipher_suite,
+                )?))
             }
             _ => unreachable!(),
         };
-        Ok(())
+        self.peer_key = Some(peer_key.clone());
+        Ok(reply)
     }
 
     /// Returns the encryption keys for this exchange, if the exchange has finished.
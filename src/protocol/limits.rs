@@ -0,0 +1,225 @@
+//! Resource limits, to prevent misbehaving devices in a group from exhausting our memory or
+//! tasks.
+
+use crate::{trivial_error, utils::RateLimiter, GenericResult};
+use parking_lot::Mutex;
+use std::{collections::HashMap, hash::Hash, sync::Arc, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Configurable limits for a session.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum length in bytes of the body of a control message frame.
+    pub max_control_frame_size: u32,
+    /// Maximum length in bytes of the body of a peer message frame, including encryption
+    /// overhead.
+    pub max_peer_frame_size: u32,
+    /// Maximum number of concurrent incoming connections across all groups, for each kind of port
+    /// (control and peer messages). Must not be zero, see `Limits::validate`.
+    pub max_incoming_connections: usize,
+    /// Maximum number of concurrent incoming connections from a single peer (or from a single
+    /// address, for the unauthenticated control port).
+    pub max_incoming_connections_per_peer: usize,
+    /// Maximum number of connections the control port accepts in a burst from a single address.
+    /// Connections from the peers we already know in the group aren't rate limited.
+    pub control_connection_burst: u32,
+    /// Sustained number of connections per second the control port accepts from a single address.
+    pub control_connections_per_second: u32,
+    /// Maximum number of addresses each control port keeps track of for rate limiting. Once
+    /// reached, connections from new addresses are dropped until some of the known ones go quiet.
+    pub max_control_connection_sources: usize,
+    /// Maximum amount of bytes of out-of-order messages we buffer per peer, see the `delivery`
+    /// module.
+    pub max_reorder_buffer_size: usize,
+    /// Maximum amount of bytes of messages of each priority waiting to be sent to a single peer.
+    /// Once reached, `message_peer` waits, and `try_message_peer` fails. Must not be zero, see
+    /// `Limits::validate`.
+    pub max_send_queue_size: u32,
+    /// How long an incoming control connection can go without sending a full message before we
+    /// drop it, so that idle connections don't hold on to their slot forever.
+    pub idle_timeout: Duration,
+    /// Maximum number of streams a peer can have open at once on a multiplexed connection, see the
+    /// `mux` module. Streams opened beyond it are reset.
+    pub max_streams_per_connection: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_control_frame_size: 64 * 1024,
+            max_peer_frame_size: 16 * 1024 * 1024,
+            max_incoming_connections: 64,
+            max_incoming_connections_per_peer: 8,
+            control_connection_burst: 16,
+            control_connections_per_second: 4,
+            max_control_connection_sources: 256,
+            max_reorder_buffer_size: 32 * 1024 * 1024,
+            max_send_queue_size: 8 * 1024 * 1024,
+            idle_timeout: Duration::from_secs(30),
+            max_streams_per_connection: 64,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks that the limits are usable, i.e. that the ones backed by semaphores are neither zero
+    /// nor more than they can hold.
+    pub fn validate(&self) -> GenericResult<()> {
+        let valid = 1..=Semaphore::MAX_PERMITS;
+        if !valid.contains(&self.max_incoming_connections) {
+            return Err(trivial_error!("Invalid max_incoming_connections"));
+        }
+        if !valid.contains(&(self.max_send_queue_size as usize)) {
+            return Err(trivial_error!("Invalid max_send_queue_size"));
+        }
+        Ok(())
+    }
+}
+
+/// Rate limits events from each key (e.g. connections from each address), keeping track of a
+/// bounded number of keys.
+#[derive(Debug)]
+pub struct KeyedRateLimiter<K> {
+    burst: u32,
+    per_second: u32,
+    max_keys: usize,
+    limiters: HashMap<K, RateLimiter>,
+}
+
+impl<K: Hash + Eq> KeyedRateLimiter<K> {
+    pub fn new(burst: u32, per_second: u32, max_keys: usize) -> Self {
+        Self {
+            burst,
+            per_second,
+            max_keys,
+            limiters: Default::default(),
+        }
+    }
+
+    /// Returns whether an event from a given key is allowed right now. Events from new keys aren't
+    /// if we're already tracking too many others that are still active.
+    pub fn try_acquire(&mut self, key: K) -> bool {
+        if !self.limiters.contains_key(&key) && self.limiters.len() >= self.max_keys {
+            // Idle limiters are the same as new ones, so we can forget them.
+            self.limiters.retain(|_, limiter| !limiter.is_idle());
+            if self.limiters.len() >= self.max_keys {
+                return false;
+            }
+        }
+        self.limiters
+            .entry(key)
+            .or_insert_with(|| RateLimiter::new(self.burst, self.per_second))
+            .try_acquire()
+    }
+}
+
+/// Tracks concurrent incoming connections, both globally and per key (a peer or an address).
+#[derive(Debug)]
+pub struct ConnectionLimiter<K> {
+    global: Arc<Semaphore>,
+    per_key_limit: usize,
+    per_key: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Hash + Eq + Clone> ConnectionLimiter<K> {
+    pub fn new(global_limit: usize, per_key_limit: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(global_limit)),
+            per_key_limit,
+            per_key: Default::default(),
+        }
+    }
+
+    /// Tries to reserve a slot for a new connection, returning `None` if we're over the global
+    /// limit. The slot is released when the returned permit is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit<K>> {
+        let global = Arc::clone(&self.global).try_acquire_owned().ok()?;
+        Some(ConnectionPermit {
+            _global: global,
+            key: None,
+            per_key_limit: self.per_key_limit,
+            per_key: Arc::clone(&self.per_key),
+        })
+    }
+}
+
+/// A reserved connection slot, see `ConnectionLimiter`.
+#[derive(Debug)]
+pub struct ConnectionPermit<K: Hash + Eq + Clone> {
+    _global: OwnedSemaphorePermit,
+    key: Option<K>,
+    per_key_limit: usize,
+    per_key: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Hash + Eq + Clone> ConnectionPermit<K> {
+    /// Attributes this connection to a given key once it's known, returning false if that key
+    /// is over its limit.
+    pub fn attribute(&mut self, key: K) -> bool {
+        debug_assert!(self.key.is_none(), "Connection already attributed");
+        let mut per_key = self.per_key.lock();
+        let count = per_key.entry(key.clone()).or_default();
+        if *count >= self.per_key_limit {
+            return false;
+        }
+        *count += 1;
+        self.key = Some(key);
+        true
+    }
+}
+
+impl<K: Hash + Eq + Clone> Drop for ConnectionPermit<K> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut per_key = self.per_key.lock();
+        let std::collections::hash_map::Entry::Occupied(mut entry) = per_key.entry(key) else {
+            debug_assert!(false, "Attributed connection without count?");
+            return;
+        };
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            entry.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_semaphore_limits() {
+        assert!(Limits::default().validate().is_ok());
+        let empty_queue = Limits {
+            max_send_queue_size: 0,
+            ..Default::default()
+        };
+        assert!(empty_queue.validate().is_err());
+        let no_connections = Limits {
+            max_incoming_connections: 0,
+            ..Default::default()
+        };
+        assert!(no_connections.validate().is_err());
+        let too_many_connections = Limits {
+            max_incoming_connections: Semaphore::MAX_PERMITS + 1,
+            ..Default::default()
+        };
+        assert!(too_many_connections.validate().is_err());
+    }
+
+    #[test]
+    fn rate_limits_each_key() {
+        let mut limiter = KeyedRateLimiter::new(2, 0, 2);
+        assert!(limiter.try_acquire(1));
+        assert!(limiter.try_acquire(1));
+        assert!(!limiter.try_acquire(1));
+        // Other keys have their own budget...
+        assert!(limiter.try_acquire(2));
+        // ...unless there are too many of them.
+        assert!(!limiter.try_acquire(3));
+        assert!(limiter.try_acquire(2));
+        assert!(!limiter.try_acquire(3));
+    }
+}
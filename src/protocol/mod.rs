@@ -6,19 +6,22 @@
 //!    version: u16
 //!    len: u32
 //! Followed by `len` bytes.
-use crate::{trivial_error, utils, GenericResult, GroupId, PeerId};
+use crate::{trivial_error, utils, Delivery, GenericResult, GroupId, PeerId};
 use bincode::{Decode, Encode};
+use handy::{Handle, HandleMap};
 use log::{error, trace};
 use macaddr::MacAddr;
+use parking_lot::RwLock;
 use std::{
     collections::HashMap,
+    future::Future,
     net::{IpAddr, SocketAddr, SocketAddrV6},
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
     time::Duration,
 };
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpStream, UdpSocket},
     task::JoinHandle,
 };
 
@@ -28,100 +31,130 @@ use identity::{LogicalPeerIdentity, OwnIdentity};
 pub mod signing;
 use signing::MaybeInvalidSignature;
 
+pub mod beacon;
+pub mod capabilities;
+pub mod compression;
+pub mod connection;
+pub mod datagram;
+pub mod delivery;
 pub mod encryption;
+pub mod handshake;
 pub mod key_exchange;
+pub mod limits;
+pub mod mux;
+pub mod padding;
+pub mod priority;
+pub mod relay;
+pub mod resumption;
+pub mod roster;
+pub mod routing;
+pub mod service;
+pub mod wire;
 
 const MAGIC: u16 = 0xdead;
-const CURRENT_VERSION: u16 = 1;
 
+/// Reads a binary message, refusing messages longer than `max_len` before reading or allocating
+/// anything for the body. Returns the wire version of the message along with its body.
 async fn read_binary_message(
     mut reader: impl AsyncReadExt + Unpin,
     signature: Option<&mut MaybeInvalidSignature>,
-) -> GenericResult<Vec<u8>> {
+    max_len: u32,
+) -> GenericResult<(u16, Vec<u8>)> {
     let magic = reader.read_u16().await?;
     if magic != MAGIC {
         return Err(trivial_error!("Wrong message magic"));
     }
     let version = reader.read_u16().await?;
-    if version != CURRENT_VERSION {
-        return Err(trivial_error!("Wrong message version"));
+    if !(wire::MIN_VERSION..=wire::CURRENT_VERSION).contains(&version) {
+        return Err(trivial_error!("Unsupported message version"));
     }
 
     let len = reader.read_u32().await?;
+    if len > max_len {
+        error!("Refusing to read message of length {len} (max {max_len})");
+        return Err(trivial_error!("Message too long"));
+    }
 
-    // TODO(emilio): If zeroing shows up in the profile, we could use MaybeUninit + unsafe to work
-    // around it.
     if let Some(signature) = signature {
         reader.read_exact(&mut signature.0).await?;
     }
 
+    // Grow the buffer as data actually arrives rather than trusting the advertised length, so
+    // that a peer can't make us allocate a lot of memory without sending the data to back it.
     let mut buf = vec![];
     if len == 0 {
-        return Ok(buf);
+        return Ok((version, buf));
     }
-    if buf.try_reserve(len as usize).is_err() {
-        return Err(trivial_error!("OOM reading message"));
+    let read = reader.take(u64::from(len)).read_to_end(&mut buf).await?;
+    if read != len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
     }
-    unsafe {
-        // SAFETY: We have enough capacity as per the try_reserve call above. u8 doesn't have
-        // invalid representations.
-        // NOTE: We could avoid this unsafe usage by using buf.resize(len, 0), but that zeroes a
-        // potentially large message, which is a bit wasteful.
-        reader
-            .read_exact(std::slice::from_raw_parts_mut(
-                buf.as_mut_ptr(),
-                len as usize,
-            ))
-            .await?;
-        buf.set_len(len as usize);
+    Ok((version, buf))
+}
+
+/// Decodes a whole binary message into `T`.
+fn decode_message<T: Decode<()>>(buf: &[u8]) -> GenericResult<T> {
+    let (message, len) = match bincode::decode_from_slice::<T, _>(buf, bincode::config::standard())
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to decode binary message {buf:?} {e:?}");
+            return Err(e.into());
+        }
+    };
+    if len != buf.len() {
+        error!("Unexpected decoded message length {} vs {}", len, buf.len());
+        return Err(trivial_error!("Invalid message length"));
     }
-    Ok(buf)
+    Ok(message)
 }
 
-/// Control messages are unsigned.
+/// Control messages are unsigned. Returns the wire version the message was sent with along with
+/// the message. Fails if the message doesn't arrive within `Limits::idle_timeout`, so that the
+/// caller drops the connection along with its slot.
 pub async fn read_control_message(
     reader: impl AsyncReadExt + Unpin,
     source_address: &SocketAddr,
-) -> GenericResult<ControlMessage> {
-    let buf = match read_binary_message(reader, None).await {
-        Ok(buf) => buf,
-        Err(e) => {
+    limits: &limits::Limits,
+) -> GenericResult<(u16, ControlMessage)> {
+    let read = read_binary_message(reader, None, limits.max_control_frame_size);
+    let (version, buf) = match tokio::time::timeout(limits.idle_timeout, read).await {
+        Ok(Ok(buf)) => buf,
+        Ok(Err(e)) => {
             log_error(&*e, source_address);
             return Err(e);
         }
+        Err(..) => {
+            trace!("Control connection from {source_address:?} timed out");
+            return Err(trivial_error!("Control connection timed out"));
+        }
     };
-    let (control_message, len) =
-        match bincode::decode_from_slice::<ControlMessage, _>(&buf, bincode::config::standard()) {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Failed to decode binary control message {buf:?} {e:?}");
-                return Err(e.into());
-            }
-        };
-    if len != buf.len() {
-        error!("Unexpected decoded message length {} vs {}", len, buf.len());
-        return Err(trivial_error!("Invalid message length"));
-    }
-    Ok(control_message)
+    Ok((version, wire::decode(version, &buf)?))
 }
 
 // TODO: In the future use OwnIdentity to also decrypt, not only check the signature from the peer.
+///
+/// Depending on the `capabilities` negotiated with the peer, the record is unpadded and
+/// decompressed after decryption (see the `padding` and `compression` modules).
 pub async fn read_peer_message(
     _: &OwnIdentity,
-    encryption_keys: &encryption::Keys,
+    encryption_keys: &encryption::RecordKeys,
     id: &LogicalPeerIdentity,
     reader: impl AsyncReadExt + Unpin,
     source_address: &SocketAddr,
-) -> GenericResult<Vec<u8>> {
+    limits: &limits::Limits,
+    capabilities: &capabilities::Capabilities,
+) -> GenericResult<(u16, Vec<u8>)> {
     // TODO: If zeroing somehow shows up it can be optimized via MaybeUninit + unsafe.
     let mut signature = MaybeInvalidSignature([0; signing::SIGNATURE_LEN]);
-    let mut buf = match read_binary_message(reader, Some(&mut signature)).await {
-        Ok(buf) => buf,
-        Err(e) => {
-            log_error(&*e, source_address);
-            return Err(e);
-        }
-    };
+    let (version, mut buf) =
+        match read_binary_message(reader, Some(&mut signature), limits.max_peer_frame_size).await {
+            Ok(r) => r,
+            Err(e) => {
+                log_error(&*e, source_address);
+                return Err(e);
+            }
+        };
     if let Err(e) = signing::verify(&id.key, &signature, &buf) {
         log_error(&*e, source_address);
         return Err(e);
@@ -136,7 +169,25 @@ pub async fn read_peer_message(
             return Err(e.into());
         }
     }
-    Ok(buf)
+    if capabilities.padding {
+        buf = match padding::unpad(buf) {
+            Ok(buf) => buf,
+            Err(e) => {
+                log_error(&*e, source_address);
+                return Err(e);
+            }
+        };
+    }
+    if capabilities.compression {
+        buf = match compression::decompress(buf, limits.max_peer_frame_size) {
+            Ok(buf) => buf,
+            Err(e) => {
+                log_error(&*e, source_address);
+                return Err(e);
+            }
+        };
+    }
+    Ok((version, buf))
 }
 
 pub fn log_error(e: &(dyn std::error::Error + 'static), source_address: &SocketAddr) {
@@ -150,9 +201,10 @@ pub fn log_error(e: &(dyn std::error::Error + 'static), source_address: &SocketA
 
 async fn write_binary_message(
     mut writer: impl AsyncWriteExt + Unpin,
+    version: u16,
     msg: &[u8],
     signing_key: Option<&signing::KeyPair>,
-    encryption_keys: Option<&encryption::Keys>,
+    encryption_keys: Option<&encryption::RecordKeys>,
 ) -> GenericResult<()> {
     let msg = match encryption_keys {
         Some(k) => {
@@ -172,7 +224,7 @@ async fn write_binary_message(
 
     // Write the header.
     writer.write_u16(MAGIC).await?;
-    writer.write_u16(CURRENT_VERSION).await?;
+    writer.write_u16(version).await?;
     writer.write_u32(len).await?;
     if let Some(k) = signing_key {
         let signature = signing::sign(k, &msg);
@@ -189,6 +241,8 @@ pub struct P2pPorts {
     pub control: u16,
     /// The port for the p2p communication channel.
     pub p2p: u16,
+    /// The UDP port for datagrams, see the `datagram` module.
+    pub datagram: u16,
 }
 
 /// A MacAddr-like type that we can easily binary encode / decode.
@@ -227,18 +281,37 @@ pub struct PhysiscalPeerIdentity {
     /// Device address of the P2P device. Note this is _not_ usable to get a link-local
     /// address.
     pub dev_addr: MacAddr,
+    /// The interface address the device said it would use in a group we negotiated with it, if
+    /// any. Devices that don't know their device address identify themselves by it, see
+    /// `PeerOwnIdentifier`.
+    pub iface_addr: Option<MacAddr>,
 }
 
 impl PhysiscalPeerIdentity {
-    /// Whether this peer matches its self-reported own identifier.
-    pub fn matches(&self, own_id: &PeerOwnIdentifier) -> bool {
+    /// Whether this peer matches its self-reported own identifier. Peers are looked up with
+    /// `PeerOwnIdentifier::find_peer`, which also takes care of ambiguous names.
+    fn matches(&self, own_id: &PeerOwnIdentifier) -> bool {
         match own_id {
             PeerOwnIdentifier::Name(ref n) => self.name == *n,
-            PeerOwnIdentifier::DevAddr(ref a) => self.dev_addr == a.to_mac_addr(),
+            PeerOwnIdentifier::DevAddr(ref a) => {
+                let addr = a.to_mac_addr();
+                self.dev_addr == addr || self.iface_addr == Some(addr)
+            }
         }
     }
 }
 
+/// How to match peers that identify themselves by name, see `PeerOwnIdentifier`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NameMatching {
+    /// Match the first peer with that name.
+    #[default]
+    First,
+    /// Don't match any peer if several of them have that name, since we can't tell which one it
+    /// is.
+    Unique,
+}
+
 impl std::fmt::Display for PhysiscalPeerIdentity {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use std::fmt::Write;
@@ -254,20 +327,48 @@ impl std::fmt::Display for PhysiscalPeerIdentity {
 pub struct PeerIdentity {
     pub physical: PhysiscalPeerIdentity,
     /// The logical identity to be able to verify messages from a given peer. Unknown until
-    /// association.
+    /// association, or provisionally known from the beacon of the peer, see `logical_verified`.
     pub logical: Option<LogicalPeerIdentity>,
+    /// Whether the peer proved `logical` by associating. If not, it comes from a beacon which
+    /// could have been replayed by any device, so it's only good for display, see the `beacon`
+    /// module.
+    pub logical_verified: bool,
+    /// The capabilities the peer advertises in its beacon, if any. Unverified, like the beacon
+    /// itself, the ones in use are only negotiated on association.
+    pub advertised_capabilities: Option<capabilities::Capabilities>,
+    /// The ngn service the peer advertises, if any. Unknown until service discovery finds it, see
+    /// the `service` module.
+    pub service: Option<service::ServiceRecord>,
+}
+
+impl PeerIdentity {
+    /// The logical identity of the peer, if it proved it by associating.
+    pub fn verified_logical(&self) -> Option<&LogicalPeerIdentity> {
+        self.logical.as_ref().filter(|_| self.logical_verified)
+    }
+
+    /// Records what the peer advertises in its beacon, without overriding a logical identity it
+    /// already proved.
+    pub fn set_beacon(&mut self, beacon: &beacon::Beacon) {
+        self.advertised_capabilities = Some(beacon.capabilities);
+        if !self.logical_verified {
+            self.logical = Some(beacon.identity.clone());
+        }
+    }
 }
 
 /// A single self-reported identifier for a peer. Note that ideally this should always be the mac
 /// address, but:
 ///
-///   * The DBUS backend doesn't easily expose it (yet[1]).
+///   * The DBUS backend only exposes it since wpa_supplicant 2.11[1], or once we own a group.
 ///   * Android restricts it to non-privileged apps because it's considered a persistent identifier
 ///     (though realistically it could be randomized and exposed I guess?).
 ///
-/// So for now we allow to self-report the name instead. This is fine because it's not intended to
-/// be a hard security boundary, that is expected to be implemented at a different layer (either by
-/// not connecting physically to this device, or by authenticating to this device).
+/// So for now we allow to self-report the name instead, or the interface address of the group when
+/// the peer negotiated it with us (see `PhysiscalPeerIdentity::iface_addr`). This is fine because
+/// it's not intended to be a hard security boundary, that is expected to be implemented at a
+/// different layer (either by not connecting physically to this device, or by authenticating to
+/// this device). Names can collide though, see `NameMatching`.
 ///
 /// [1]: https://lists.infradead.org/pipermail/hostap/2025-May/043428.html
 #[derive(Encode, Decode, Debug, Clone)]
@@ -276,6 +377,30 @@ pub enum PeerOwnIdentifier {
     DevAddr(DecodableMacAddr),
 }
 
+impl PeerOwnIdentifier {
+    /// Finds the peer this identifier refers to, given the physical identity of each of `peers`.
+    /// Fails if it's a name several of them have and `name_matching` is `NameMatching::Unique`,
+    /// since we can't tell which one it is.
+    pub fn find_peer<T>(
+        &self,
+        peers: impl IntoIterator<Item = T>,
+        physical: impl Fn(&T) -> &PhysiscalPeerIdentity,
+        name_matching: NameMatching,
+    ) -> GenericResult<Option<T>> {
+        let mut matching = peers.into_iter().filter(|p| physical(p).matches(self));
+        let Some(peer) = matching.next() else {
+            return Ok(None);
+        };
+        if name_matching == NameMatching::Unique
+            && matches!(self, Self::Name(..))
+            && matching.next().is_some()
+        {
+            return Err(trivial_error!("Several peers have that name"));
+        }
+        Ok(Some(peer))
+    }
+}
+
 /// Information from a peer (other than ourselves).
 #[derive(Debug)]
 pub struct PeerInfo<BackendData> {
@@ -283,12 +408,49 @@ pub struct PeerInfo<BackendData> {
     pub identity: PeerIdentity,
     /// Key exchange information for this peer.
     pub key_exchange: key_exchange::KeyExchange,
+    /// Our end-to-end key exchange with this peer for relayed messages, started on demand, see
+    /// the `relay` module.
+    pub relay_key_exchange: Option<key_exchange::KeyExchange>,
     /// Current list of groups the peer is connected to.
     pub groups: Vec<GroupId>,
+    /// The features agreed upon with this peer, known after association.
+    pub capabilities: Option<capabilities::Capabilities>,
+    /// The wire version negotiated with this peer, see the `wire` module.
+    pub wire_version: u16,
     /// Back-end specific data.
     pub data: BackendData,
 }
 
+impl<B> PeerInfo<B> {
+    /// Returns how we're talking to this peer, once associated.
+    pub fn diagnostics(&self) -> Option<PeerDiagnostics> {
+        let keys = self.key_exchange.encryption_keys()?;
+        Some(PeerDiagnostics {
+            wire_version: self.wire_version,
+            capabilities: self.capabilities?,
+            cipher_suite: keys.cipher_suite(),
+            post_quantum: keys.post_quantum(),
+            resumed: keys.resumed(),
+        })
+    }
+}
+
+/// Details about how we're talking to a peer, mostly useful for debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerDiagnostics {
+    /// The wire version negotiated with the peer, see the `wire` module.
+    pub wire_version: u16,
+    /// The features agreed upon with the peer.
+    pub capabilities: capabilities::Capabilities,
+    /// The cipher suite used to encrypt our traffic with the peer.
+    pub cipher_suite: encryption::CipherSuite,
+    /// Whether our keys come from a hybrid post-quantum key exchange, see the `key_exchange`
+    /// module.
+    pub post_quantum: bool,
+    /// Whether our keys resume a previous session, see the `resumption` module.
+    pub resumed: bool,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PeerAddress {
     pub address: IpAddr,
@@ -306,10 +468,14 @@ pub fn peer_to_socket_addr(addr: IpAddr, scope_id: u32, port: u16) -> SocketAddr
 }
 
 /// Send a signed (if with own identity) or unsigned (otherwise) message to a given peer address.
+///
+/// If encryption keys are provided, the connection is authenticated first (see the `handshake`
+/// module), which requires our own identity too.
 pub async fn send_message(
     from: Option<&OwnIdentity>,
     encryption_keys: Option<&encryption::Keys>,
     to: &SocketAddr,
+    version: u16,
     message: &[u8],
 ) -> GenericResult<()> {
     trace!("send_message_to({to:?}, {})", message.len());
@@ -318,8 +484,59 @@ pub async fn send_message(
             Ok(stream) => stream,
             Err(e) => return Err(io::Error::new(io::ErrorKind::TimedOut, e).into()),
         };
+    let mut records = None;
+    if let Some(keys) = encryption_keys {
+        let Some(from) = from else {
+            return Err(trivial_error!(
+                "Need an identity to authenticate the connection"
+            ));
+        };
+        records = Some(handshake::initiate(&mut stream, from, keys, version).await?);
+    }
     let key_pair = from.map(|f| &f.key_pair);
-    write_binary_message(&mut stream, message, key_pair, encryption_keys).await
+    write_binary_message(&mut stream, version, message, key_pair, records.as_deref()).await
+}
+
+/// Writes a signed and encrypted message to an already authenticated peer connection, compressing
+/// and padding it first according to the `capabilities` negotiated with the peer. Padded messages
+/// are never compressed, see the `padding` module.
+pub async fn write_peer_message(
+    from: &OwnIdentity,
+    encryption_keys: &encryption::RecordKeys,
+    writer: impl AsyncWriteExt + Unpin,
+    version: u16,
+    message: &[u8],
+    capabilities: &capabilities::Capabilities,
+    padding: padding::PaddingPolicy,
+) -> GenericResult<()> {
+    let pads = capabilities.padding && padding.pads();
+    let compressed;
+    let message = if capabilities.compression {
+        compressed = if pads {
+            compression::store(message)
+        } else {
+            compression::compress(message)
+        };
+        &compressed[..]
+    } else {
+        message
+    };
+    let padded;
+    let message = if capabilities.padding {
+        let max_len = (capabilities.max_frame_size as usize).saturating_sub(encryption::TAG_LEN);
+        padded = padding::pad(message, padding, max_len)?;
+        &padded[..]
+    } else {
+        message
+    };
+    write_binary_message(
+        writer,
+        version,
+        message,
+        Some(&from.key_pair),
+        Some(encryption_keys),
+    )
+    .await
 }
 
 /// Per group association for a given peer.
@@ -341,16 +558,28 @@ pub struct GroupInfo<BackendData> {
     pub scope_id: u32,
     /// Whether we're the group owner.
     pub is_go: bool,
-    /// The current peers we have. Only around if we're a GO
-    ///
-    /// TODO(emilio): Broadcast these to non-GO members?
+    /// The current peers we have. If we're not the GO, these are the GO and the members it told
+    /// us about, see the `roster` module.
     pub peers: HashMap<PeerId, PeerGroupInfo>,
+    /// The membership roster of this group.
+    pub roster: roster::RosterState,
     /// Task handle to our connection loop. Canceled and awaited on drop.
     pub group_task: OnceLock<JoinHandle<GenericResult<()>>>,
+    /// Our socket for datagrams in this group, once bound by the group task.
+    pub datagram_socket: OnceLock<Arc<UdpSocket>>,
     /// Back-end specific data for this group.
     pub data: BackendData,
 }
 
+impl<B> GroupInfo<B> {
+    /// Returns whether one of the peers we know in this group has a given address.
+    pub fn has_peer_at(&self, address: IpAddr) -> bool {
+        self.peers
+            .values()
+            .any(|info| info.address.address == address)
+    }
+}
+
 impl<B> Drop for GroupInfo<B> {
     fn drop(&mut self) {
         if let Some(task) = self.group_task.take() {
@@ -359,15 +588,86 @@ impl<B> Drop for GroupInfo<B> {
     }
 }
 
+/// The peers or groups of a session, by handle.
+pub trait Store<T> {
+    fn get(&self, handle: Handle) -> Option<&T>;
+    fn get_mut(&mut self, handle: Handle) -> Option<&mut T>;
+    fn iter_with_handles<'a>(&'a self) -> impl Iterator<Item = (Handle, &'a T)>
+    where
+        T: 'a;
+    fn iter_mut_with_handles<'a>(&'a mut self) -> impl Iterator<Item = (Handle, &'a mut T)>
+    where
+        T: 'a;
+}
+
+impl<T> Store<T> for HandleMap<T> {
+    fn get(&self, handle: Handle) -> Option<&T> {
+        HandleMap::get(self, handle)
+    }
+
+    fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        HandleMap::get_mut(self, handle)
+    }
+
+    fn iter_with_handles<'a>(&'a self) -> impl Iterator<Item = (Handle, &'a T)>
+    where
+        T: 'a,
+    {
+        HandleMap::iter_with_handles(self)
+    }
+
+    fn iter_mut_with_handles<'a>(&'a mut self) -> impl Iterator<Item = (Handle, &'a mut T)>
+    where
+        T: 'a,
+    {
+        HandleMap::iter_mut_with_handles(self)
+    }
+}
+
+/// The session-side state and hooks that the platform-independent group logic (see the `roster`,
+/// `relay` and `routing` modules) needs.
+pub trait GroupDelegate: connection::ConnectionDelegate + Sized {
+    type PeerData: Send + Sync;
+    type GroupData: Send + Sync;
+    type Peers: Store<PeerInfo<Self::PeerData>> + Send + Sync;
+    type Groups: Store<GroupInfo<Self::GroupData>> + Send + Sync;
+
+    /// The peers we know about.
+    fn peers(&self) -> &RwLock<Self::Peers>;
+    /// The groups we're in.
+    fn groups(&self) -> &RwLock<Self::Groups>;
+    /// How to match peers that identify themselves by name.
+    fn name_matching(&self) -> NameMatching;
+    /// Returns the group owner of a group we're a client of.
+    fn group_owner<'a>(
+        peers: &'a Self::Peers,
+        group: &GroupInfo<Self::GroupData>,
+    ) -> Option<&'a PeerInfo<Self::PeerData>>;
+    /// Our routes to peers in other groups.
+    fn routing(&self) -> &RwLock<routing::RoutingTable>;
+    /// Hands a message from a peer to the application.
+    fn peer_messaged(&self, peer_id: PeerId, group_id: GroupId, message: &[u8], delivery: Delivery);
+    /// Hands a message from a peer we only know through other groups to the application.
+    fn remote_peer_messaged(&self, from: &identity::LogicalPeerIdentity, message: &[u8]);
+    /// Sends a control message to a member of a group.
+    fn send_control_message(
+        &self,
+        address: IpAddr,
+        control_port: u16,
+        scope_id: u32,
+        version: u16,
+        message: ControlMessage,
+    ) -> impl Future<Output = GenericResult<()>> + Send;
+}
+
 /// The port the GO of the group listens to.
 pub const GO_CONTROL_PORT: u16 = 9001;
 
 /// Control messages defined for the IPv6-based protocol. Note this must be independent of the
 /// underlying platform (e.g. dbus vs. android).
 ///
-/// TODO(emilio): The binary message should probably be stable. Maybe use protobuf or (god forbid)
-/// json or something?
-#[derive(Encode, Decode, Debug)]
+/// See the `wire` module for how these are encoded.
+#[derive(Debug, Clone)]
 pub enum ControlMessage {
     /// Associate this sender with a pre-existing WifiP2P peer, communicating the ports we're
     /// listening to.
@@ -380,5 +680,100 @@ pub enum ControlMessage {
         ports: P2pPorts,
         /// The public ECDH key.
         key_exchange_public_key: key_exchange::MaybeInvalidPublicKey,
+        /// The features we support, see the `capabilities` module.
+        capabilities: capabilities::Capabilities,
+        /// The post-quantum part of the key exchange, if supported, see the `key_exchange`
+        /// module.
+        post_quantum_share: Option<key_exchange::PostQuantumShare>,
+        /// The ticket of the session to resume, if any, see the `resumption` module.
+        resumption_ticket: Option<[u8; resumption::TICKET_ID_LEN]>,
+    },
+    /// The members of the group, sent by the GO to its clients, see the `roster` module.
+    Roster {
+        update: roster::RosterUpdate,
+        /// The signature of the GO over the update.
+        signature: MaybeInvalidSignature,
+    },
+    /// A message between two clients of the group, sent through the GO, see the `relay` module.
+    Relay {
+        envelope: relay::RelayEnvelope,
+        /// The signature of the sender over the envelope.
+        signature: MaybeInvalidSignature,
+    },
+    /// The destinations the sender can reach, see the `routing` module.
+    Routes {
+        announcement: routing::RouteAnnouncement,
+        /// The signature of the sender over the announcement.
+        signature: MaybeInvalidSignature,
+    },
+    /// A message to a peer several hops away, see the `routing` module.
+    Routed {
+        envelope: relay::RelayEnvelope,
+        /// The signature of the original sender over the envelope.
+        signature: MaybeInvalidSignature,
+        /// How many more hops the message can take.
+        ttl: u8,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(name: &str, dev_addr: &str) -> PhysiscalPeerIdentity {
+        PhysiscalPeerIdentity {
+            name: name.to_owned(),
+            dev_addr: dev_addr.parse().unwrap(),
+            iface_addr: None,
+        }
+    }
+
+    fn find(
+        id: &PeerOwnIdentifier,
+        peers: &[PhysiscalPeerIdentity],
+        name_matching: NameMatching,
+    ) -> GenericResult<Option<usize>> {
+        let found = id.find_peer(peers.iter().enumerate(), |(_, p)| p, name_matching)?;
+        Ok(found.map(|(i, _)| i))
+    }
+
+    #[test]
+    fn find_peer_by_name() {
+        let peers = [
+            peer("phone", "02:00:00:00:00:01"),
+            peer("laptop", "02:00:00:00:00:02"),
+            peer("laptop", "02:00:00:00:00:03"),
+        ];
+        let phone = PeerOwnIdentifier::Name("phone".to_owned());
+        let laptop = PeerOwnIdentifier::Name("laptop".to_owned());
+        let tv = PeerOwnIdentifier::Name("tv".to_owned());
+        for name_matching in [NameMatching::First, NameMatching::Unique] {
+            assert_eq!(find(&phone, &peers, name_matching).unwrap(), Some(0));
+            assert_eq!(find(&tv, &peers, name_matching).unwrap(), None);
+        }
+        assert_eq!(find(&laptop, &peers, NameMatching::First).unwrap(), Some(1));
+        assert!(find(&laptop, &peers, NameMatching::Unique).is_err());
+    }
+
+    #[test]
+    fn find_peer_by_address() {
+        let mut peers = [
+            peer("laptop", "02:00:00:00:00:01"),
+            peer("laptop", "02:00:00:00:00:02"),
+        ];
+        peers[1].iface_addr = Some("02:00:00:00:01:02".parse().unwrap());
+        let addr = |a: &str| PeerOwnIdentifier::DevAddr(a.parse::<MacAddr>().unwrap().into());
+        // Addresses are never ambiguous, even if the names are.
+        let by_dev_addr = addr("02:00:00:00:00:02");
+        let by_iface_addr = addr("02:00:00:00:01:02");
+        for name_matching in [NameMatching::First, NameMatching::Unique] {
+            assert_eq!(find(&by_dev_addr, &peers, name_matching).unwrap(), Some(1));
+            assert_eq!(
+                find(&by_iface_addr, &peers, name_matching).unwrap(),
+                Some(1)
+            );
+            let unknown = addr("02:00:00:00:00:03");
+            assert_eq!(find(&unknown, &peers, name_matching).unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! Stream multiplexing over a single peer connection.
+//!
+//! When both peers support it (see `ControlMessage::Associate`), each record sent over a peer
+//! connection contains a `Frame` rather than a raw message. Frames belong to a logical stream, so
+//! that multiple streams (messages, application streams...) can share the connection without a
+//! large transfer in one of them blocking the rest: messages are split in chunks of at most
+//! `MAX_CHUNK_LEN` bytes, and writers take turns to write them. Since chunks don't say which
+//! message they belong to, only one message is sent at a time on each stream, the most urgent one
+//! first (see `Mux::lock_stream`). Regular messages get an implicit stream per priority class (see
+//! `messages_stream`), so that an urgent message never waits for a large bulk one to be sent whole.
+//!
+//! Each stream has its own flow control window: a sender can't have more than the window size of
+//! unacknowledged data in flight, and the receiver grants more credit via `WindowUpdate` frames as
+//! the application consumes data (i.e., once a message has been delivered to the listener, or read
+//! from a `PeerStream`), so that a slow receiver makes senders wait rather than queueing an
+//! unbounded amount of messages. Messages larger than half the window are acknowledged as they're
+//! received instead, since they couldn't be completed otherwise.
+//!
+//! Stream ids are picked by the side opening the stream: odd for the side that initiated the
+//! underlying connection, even for the other, so that they never collide, starting after the
+//! messages streams, which are implicitly open on every connection and carry the messages sent via
+//! `message_peer`. Streams a peer opens beyond `Limits::max_streams_per_connection` are reset right
+//! away.
+use super::{
+    priority::{OwnedTurn, Priority, WriteScheduler},
+    wire::{self, Fields, WireMessage, Writer},
+};
+use crate::{trivial_error, GenericResult};
+use log::trace;
+use parking_lot::Mutex;
+use std::{collections::HashMap, future::Future, sync::Arc};
+use tokio::sync::{mpsc, Notify};
+
+pub type StreamId = u32;
+
+/// Returns the stream used for regular peer messages with a given priority.
+pub fn messages_stream(priority: Priority) -> StreamId {
+    1 + StreamId::from(priority.index())
+}
+
+pub fn is_messages_stream(stream: StreamId) -> bool {
+    (1..=Priority::ALL.len() as StreamId).contains(&stream)
+}
+
+/// The initial flow control window of every stream, in bytes.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The maximum amount of payload bytes in a single data frame.
+pub const MAX_CHUNK_LEN: u32 = 16 * 1024;
+
+#[derive(Debug)]
+pub enum Frame {
+    /// Opens a new stream.
+    Open { stream: StreamId },
+    /// A chunk of a message in a given stream.
+    Data {
+        stream: StreamId,
+        /// Whether this is the last chunk of the current message.
+        end_of_message: bool,
+        payload: Vec<u8>,
+    },
+    /// Grants the peer more credit to send data on a given stream.
+    WindowUpdate { stream: StreamId, increment: u32 },
+    /// Signals that no more data will be sent from this end on a given stream.
+    Close { stream: StreamId },
+    /// Abruptly terminates a stream in both directions.
+    Reset { stream: StreamId },
+}
+
+impl WireMessage for Frame {
+    type Legacy = wire::legacy::Unsupported;
+
+    fn message_type(&self) -> u64 {
+        match *self {
+            Self::Open { .. } => 32,
+            Self::Data { .. } => 33,
+            Self::WindowUpdate { .. } => 34,
+            Self::Close { .. } => 35,
+            Self::Reset { .. } => 36,
+        }
+    }
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        match *self {
+            Self::Open { stream } | Self::Close { stream } | Self::Reset { stream } => {
+                writer.uint(1, stream.into())
+            }
+            Self::Data {
+                stream,
+                end_of_message,
+                ref payload,
+            } => {
+                writer.uint(1, stream.into());
+                writer.bool(2, end_of_message);
+                writer.bytes(3, payload);
+            }
+            Self::WindowUpdate { stream, increment } => {
+                writer.uint(1, stream.into());
+                writer.uint(2, increment.into());
+            }
+        }
+    }
+
+    fn decode_fields(message_type: u64, fields: &Fields) -> GenericResult<Self> {
+        let stream = fields.u32(1)?;
+        Ok(match message_type {
+            32 => Self::Open { stream },
+            33 => Self::Data {
+                stream,
+                end_of_message: fields.bool(2)?,
+                payload: fields.bytes(3)?.to_vec(),
+            },
+            34 => Self::WindowUpdate {
+                stream,
+                increment: fields.u32(2)?,
+            },
+            35 => Self::Close { stream },
+            36 => Self::Reset { stream },
+            _ => return Err(trivial_error!("Unknown multiplexer frame type")),
+        })
+    }
+
+    fn to_legacy(&self) -> GenericResult<Self::Legacy> {
+        Err(trivial_error!("No multiplexing in wire version 1"))
+    }
+
+    fn from_legacy(_: Self::Legacy) -> GenericResult<Self> {
+        Err(trivial_error!("No multiplexing in wire version 1"))
+    }
+}
+
+/// A complete message received on a stream, along with the credit to grant back to the peer via
+/// `Mux::consumed` once it has been consumed.
+pub type IncomingMessage = (Vec<u8>, u32);
+
+/// Something the connection needs to act upon after handling a frame.
+#[derive(Debug)]
+pub enum MuxEvent {
+    /// A full message was received in one of the messages streams. Messages in other streams are
+    /// sent directly to the stream.
+    Message(StreamId, IncomingMessage),
+    /// The peer opened a new stream.
+    Opened(StreamId, mpsc::UnboundedReceiver<IncomingMessage>),
+    /// We need to send a frame back to the peer.
+    Reply(Frame),
+}
+
+#[derive(Debug)]
+struct StreamState {
+    /// How many bytes we can still send before we need more credit.
+    send_window: u32,
+    /// Notified when more credit is granted or the stream is reset.
+    send_window_changed: Arc<Notify>,
+    /// Hands the stream to one message at a time, so that the chunks of different messages don't
+    /// interleave.
+    send_scheduler: Arc<WriteScheduler>,
+    /// How many bytes the peer can still send before we grant more credit.
+    recv_window: u32,
+    /// How many bytes have been consumed since the last window update.
+    recv_unacknowledged: u32,
+    /// The message being currently reassembled.
+    recv_buffer: Vec<u8>,
+    /// How many bytes of `recv_buffer` haven't been acknowledged yet.
+    recv_buffer_unacknowledged: u32,
+    /// Where to send complete messages. `None` for the messages streams, or if the remote end
+    /// closed the stream.
+    incoming: Option<mpsc::UnboundedSender<IncomingMessage>>,
+    local_closed: bool,
+    remote_closed: bool,
+}
+
+impl StreamState {
+    fn new(incoming: Option<mpsc::UnboundedSender<IncomingMessage>>) -> Self {
+        Self {
+            send_window: INITIAL_WINDOW,
+            send_window_changed: Default::default(),
+            send_scheduler: Default::default(),
+            recv_window: INITIAL_WINDOW,
+            recv_unacknowledged: 0,
+            recv_buffer: vec![],
+            recv_buffer_unacknowledged: 0,
+            incoming,
+            local_closed: false,
+            remote_closed: false,
+        }
+    }
+}
+
+/// The right to send messages on a stream, see `Mux::lock_stream`.
+#[derive(Debug)]
+pub struct StreamSender<'a> {
+    mux: &'a Mux,
+    stream: StreamId,
+    _turn: OwnedTurn,
+}
+
+impl StreamSender<'_> {
+    /// Sends a full message, split in data frames which are written with `write`.
+    pub async fn send<F, Fut>(&mut self, message: &[u8], mut write: F) -> GenericResult<()>
+    where
+        F: FnMut(Frame) -> Fut,
+        Fut: Future<Output = GenericResult<()>>,
+    {
+        let mut remaining = message;
+        loop {
+            let wanted = remaining.len().min(MAX_CHUNK_LEN as usize) as u32;
+            let len = self.mux.acquire_send_window(self.stream, wanted).await? as usize;
+            let (chunk, rest) = remaining.split_at(len);
+            remaining = rest;
+            let end_of_message = remaining.is_empty();
+            write(Frame::Data {
+                stream: self.stream,
+                end_of_message,
+                payload: chunk.to_vec(),
+            })
+            .await?;
+            if end_of_message {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The multiplexing state of a connection.
+#[derive(Debug)]
+pub struct Mux {
+    streams: Mutex<HashMap<StreamId, StreamState>>,
+    next_stream_id: Mutex<StreamId>,
+    /// Whether the remote end opens odd-numbered streams.
+    remote_opens_odd: bool,
+    /// Maximum length of a reassembled message.
+    max_message_len: u32,
+    /// Maximum number of streams the remote end can have open at once.
+    max_remote_streams: usize,
+}
+
+impl Mux {
+    pub fn new(initiated_by_us: bool, max_message_len: u32, max_remote_streams: usize) -> Self {
+        let streams = Priority::ALL
+            .into_iter()
+            .map(|priority| (messages_stream(priority), StreamState::new(None)))
+            .collect();
+        Self {
+            streams: Mutex::new(streams),
+            // The first ids after the messages streams.
+            next_stream_id: Mutex::new(if initiated_by_us { 5 } else { 6 }),
+            remote_opens_odd: !initiated_by_us,
+            max_message_len,
+            max_remote_streams,
+        }
+    }
+
+    /// Registers a new locally-opened stream, returning its id and the receiver of its incoming
+    /// messages. The caller is responsible for sending the `Open` frame.
+    pub fn open(&self) -> (StreamId, mpsc::UnboundedReceiver<IncomingMessage>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = {
+            let mut next = self.next_stream_id.lock();
+            let id = *next;
+            *next += 2;
+            id
+        };
+        self.streams.lock().insert(id, StreamState::new(Some(tx)));
+        (id, rx)
+    }
+
+    /// Waits until no other message is being sent on a stream, returning the right to send the
+    /// next one. Waiting messages are handed the stream in priority order.
+    pub async fn lock_stream(
+        &self,
+        stream: StreamId,
+        priority: Priority,
+    ) -> GenericResult<StreamSender<'_>> {
+        let scheduler = match self.streams.lock().get(&stream) {
+            Some(state) => Arc::clone(&state.send_scheduler),
+            None => return Err(trivial_error!("Stream was reset")),
+        };
+        Ok(StreamSender {
+            mux: self,
+            stream,
+            _turn: scheduler.acquire_owned(priority).await,
+        })
+    }
+
+    /// Waits until we can send some data on the given stream, returning how many bytes (up to
+    /// `wanted`) we're allowed to send.
+    async fn acquire_send_window(&self, stream: StreamId, wanted: u32) -> GenericResult<u32> {
+        loop {
+            let changed = {
+                let mut streams = self.streams.lock();
+                let Some(state) = streams.get_mut(&stream) else {
+                    return Err(trivial_error!("Stream was reset"));
+                };
+                if state.local_closed {
+                    return Err(trivial_error!("Stream was closed"));
+                }
+                if wanted == 0 {
+                    return Ok(0);
+                }
+                if state.send_window > 0 {
+                    let granted = state.send_window.min(wanted);
+                    state.send_window -= granted;
+                    return Ok(granted);
+                }
+                Arc::clone(&state.send_window_changed)
+            };
+            let notified = changed.notified();
+            // Check again in case credit arrived while we weren't holding the lock.
+            if self
+                .streams
+                .lock()
+                .get(&stream)
+                .is_none_or(|s| s.send_window > 0)
+            {
+                continue;
+            }
+            notified.await;
+        }
+    }
+
+    /// Marks the local end of a stream as closed, returning whether a `Close` frame needs to be
+    /// sent.
+    pub fn close(&self, stream: StreamId) -> bool {
+        let mut streams = self.streams.lock();
+        let Some(state) = streams.get_mut(&stream) else {
+            return false;
+        };
+        if state.local_closed {
+            return false;
+        }
+        state.local_closed = true;
+        if state.remote_closed {
+            streams.remove(&stream);
+        }
+        true
+    }
+
+    /// Records that a message received on a stream has been consumed, returning the window update
+    /// to send to the peer, if any.
+    pub fn consumed(&self, stream: StreamId, credit: u32) -> Option<Frame> {
+        let mut streams = self.streams.lock();
+        let state = streams.get_mut(&stream)?;
+        state.recv_unacknowledged += credit;
+        if state.recv_unacknowledged < INITIAL_WINDOW / 2 {
+            return None;
+        }
+        let increment = std::mem::take(&mut state.recv_unacknowledged);
+        state.recv_window += increment;
+        Some(Frame::WindowUpdate { stream, increment })
+    }
+
+    /// Forgets about a stream, returning whether a `Reset` frame needs to be sent.
+    pub fn reset(&self, stream: StreamId) -> bool {
+        let Some(state) = self.streams.lock().remove(&stream) else {
+            return false;
+        };
+        state.send_window_changed.notify_waiters();
+        true
+    }
+
+    /// Handles a frame from the remote end. Errors are protocol violations, after which the
+    /// connection should be dropped.
+    pub fn handle_frame(&self, frame: Frame) -> GenericResult<Vec<MuxEvent>> {
+        let mut events = vec![];
+        let mut streams = self.streams.lock();
+        match frame {
+            Frame::Open { stream } => {
+                if (stream % 2 == 1) != self.remote_opens_odd || streams.contains_key(&stream) {
+                    return Err(trivial_error!("Invalid stream id opened"));
+                }
+                let remote_streams = streams
+                    .keys()
+                    .filter(|&&id| {
+                        !is_messages_stream(id) && (id % 2 == 1) == self.remote_opens_odd
+                    })
+                    .count();
+                if remote_streams >= self.max_remote_streams {
+                    trace!("Resetting stream {stream}, too many open streams");
+                    events.push(MuxEvent::Reply(Frame::Reset { stream }));
+                    return Ok(events);
+                }
+                trace!("Remote opened stream {stream}");
+                let (tx, rx) = mpsc::unbounded_channel();
+                streams.insert(stream, StreamState::new(Some(tx)));
+                events.push(MuxEvent::Opened(stream, rx));
+            }
+            Frame::Data {
+                stream,
+                end_of_message,
+                payload,
+            } => {
+                let Some(state) = streams.get_mut(&stream) else {
+                    // Data racing with a reset, just ignore it.
+                    trace!("Data for unknown stream {stream}");
+                    return Ok(events);
+                };
+                if state.remote_closed {
+                    return Err(trivial_error!("Data after stream close"));
+                }
+                let len = payload.len() as u32;
+                if len > state.recv_window {
+                    return Err(trivial_error!("Peer exceeded flow control window"));
+                }
+                if state.recv_buffer.len() + payload.len() > self.max_message_len as usize {
+                    return Err(trivial_error!("Multiplexed message too long"));
+                }
+                state.recv_window -= len;
+                state.recv_buffer.extend_from_slice(&payload);
+                state.recv_buffer_unacknowledged += len;
+                if state.recv_buffer_unacknowledged >= INITIAL_WINDOW / 2 {
+                    // This message is too large to wait for it to be consumed.
+                    let increment = std::mem::take(&mut state.recv_buffer_unacknowledged);
+                    state.recv_window += increment;
+                    events.push(MuxEvent::Reply(Frame::WindowUpdate { stream, increment }));
+                }
+                if end_of_message {
+                    let message = std::mem::take(&mut state.recv_buffer);
+                    let credit = std::mem::take(&mut state.recv_buffer_unacknowledged);
+                    if is_messages_stream(stream) {
+                        events.push(MuxEvent::Message(stream, (message, credit)));
+                    } else if let Some(ref incoming) = state.incoming {
+                        let _ = incoming.send((message, credit));
+                    }
+                }
+            }
+            Frame::WindowUpdate { stream, increment } => {
+                if let Some(state) = streams.get_mut(&stream) {
+                    let Some(window) = state.send_window.checked_add(increment) else {
+                        return Err(trivial_error!("Flow control window overflow"));
+                    };
+                    state.send_window = window;
+                    state.send_window_changed.notify_waiters();
+                }
+            }
+            Frame::Close { stream } => {
+                if is_messages_stream(stream) {
+                    return Err(trivial_error!("Can't close a messages stream"));
+                }
+                if let Some(state) = streams.get_mut(&stream) {
+                    state.remote_closed = true;
+                    state.incoming = None;
+                    if state.local_closed {
+                        streams.remove(&stream);
+                    }
+                }
+            }
+            Frame::Reset { stream } => {
+                if is_messages_stream(stream) {
+                    return Err(trivial_error!("Can't reset a messages stream"));
+                }
+                if let Some(state) = streams.remove(&stream) {
+                    state.send_window_changed.notify_waiters();
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    const LEN: usize = 3 * MAX_CHUNK_LEN as usize - 1;
+
+    /// Sends a multi-chunk message filled with a given byte, yielding between chunks so that
+    /// other messages can be sent meanwhile if they could.
+    async fn send(
+        mux: &Mux,
+        frames: &mpsc::UnboundedSender<Frame>,
+        stream: StreamId,
+        fill: u8,
+        priority: Priority,
+    ) -> GenericResult<()> {
+        let mut stream = mux.lock_stream(stream, priority).await?;
+        stream
+            .send(&[fill; LEN], |frame| {
+                let frames = frames.clone();
+                async move {
+                    tokio::task::yield_now().await;
+                    frames.send(frame)?;
+                    Ok(())
+                }
+            })
+            .await
+    }
+
+    /// Reassembles the messages sent by `send`, returning the byte each one is filled with, in
+    /// the order they were completed.
+    async fn receive(mut frames: mpsc::UnboundedReceiver<Frame>) -> Vec<u8> {
+        let mux = Mux::new(/* initiated_by_us = */ false, 1 << 20, 1);
+        let mut messages = vec![];
+        let mut incoming = None;
+        let mut push = |message: Vec<u8>| {
+            assert_eq!(message.len(), LEN);
+            assert!(message.iter().all(|&b| b == message[0]));
+            messages.push(message[0]);
+        };
+        while let Some(frame) = frames.recv().await {
+            for event in mux.handle_frame(frame).unwrap() {
+                match event {
+                    MuxEvent::Message(_, (message, _)) => push(message),
+                    MuxEvent::Opened(_, rx) => incoming = Some(rx),
+                    MuxEvent::Reply(_) => {}
+                }
+            }
+            while let Some(Ok((message, _))) = incoming.as_mut().map(|rx| rx.try_recv()) {
+                push(message);
+            }
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn concurrent_messages_dont_interleave() {
+        let mux = Mux::new(/* initiated_by_us = */ true, 1 << 20, 0);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stream = messages_stream(Priority::Bulk);
+        let (a, b) = tokio::join!(
+            send(&mux, &tx, stream, b'a', Priority::Bulk),
+            send(&mux, &tx, stream, b'b', Priority::Bulk),
+        );
+        a.unwrap();
+        b.unwrap();
+        drop(tx);
+        assert_eq!(receive(rx).await, b"ab");
+    }
+
+    #[tokio::test]
+    async fn urgent_messages_go_first() {
+        let mux = Mux::new(/* initiated_by_us = */ true, 1 << 20, 0);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (stream, _) = mux.open();
+        tx.send(Frame::Open { stream }).unwrap();
+        let (a, b, c) = tokio::join!(
+            send(&mux, &tx, stream, b'a', Priority::Bulk),
+            send(&mux, &tx, stream, b'b', Priority::Bulk),
+            send(&mux, &tx, stream, b'c', Priority::Realtime),
+        );
+        a.unwrap();
+        b.unwrap();
+        c.unwrap();
+        drop(tx);
+        assert_eq!(receive(rx).await, b"acb");
+    }
+
+    #[tokio::test]
+    async fn urgent_messages_dont_wait_for_bulk_ones() {
+        let mux = Mux::new(/* initiated_by_us = */ true, 1 << 20, 0);
+        let (tx, rx) = mpsc::unbounded_channel();
+        // The bulk message stalls after its first chunk until the realtime one has been sent.
+        let realtime_sent = Semaphore::new(0);
+        let bulk = async {
+            let mut stream = mux
+                .lock_stream(messages_stream(Priority::Bulk), Priority::Bulk)
+                .await?;
+            stream
+                .send(&[b'a'; LEN], |frame| {
+                    let result = tx.send(frame);
+                    let realtime_sent = &realtime_sent;
+                    async move {
+                        result?;
+                        let _ = realtime_sent.acquire().await;
+                        Ok(())
+                    }
+                })
+                .await
+        };
+        let realtime = async {
+            let result = send(
+                &mux,
+                &tx,
+                messages_stream(Priority::Realtime),
+                b'c',
+                Priority::Realtime,
+            )
+            .await;
+            realtime_sent.add_permits(1);
+            result
+        };
+        let (a, c) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(bulk, realtime)
+        })
+        .await
+        .expect("realtime message waited for the bulk one");
+        a.unwrap();
+        c.unwrap();
+        drop(tx);
+        assert_eq!(receive(rx).await, b"ca");
+    }
+
+    #[test]
+    fn resets_streams_beyond_the_limit() {
+        let mux = Mux::new(/* initiated_by_us = */ false, 1 << 20, 2);
+        let open = |stream| mux.handle_frame(Frame::Open { stream }).unwrap();
+        assert!(matches!(open(5)[..], [MuxEvent::Opened(5, _)]));
+        assert!(matches!(open(7)[..], [MuxEvent::Opened(7, _)]));
+        assert!(matches!(
+            open(9)[..],
+            [MuxEvent::Reply(Frame::Reset { stream: 9 })]
+        ));
+        // Streams we open don't count.
+        mux.open();
+        mux.handle_frame(Frame::Reset { stream: 5 }).unwrap();
+        assert!(matches!(open(9)[..], [MuxEvent::Opened(9, _)]));
+    }
+}
@@ -0,0 +1,84 @@
+//! Optional padding of peer records, to hide the length of the messages we send.
+//!
+//! Records are encrypted, but their length is not, so an observer of the link could otherwise
+//! tell a keystroke from a photo. When both peers support it (see `Capabilities::padding`), the
+//! plaintext of every peer record (after compression, see the `compression` module) is wrapped
+//! as:
+//!
+//! ```text
+//!   payload length: u32 | payload | zeros
+//! ```
+//!
+//! How many zeros are added is up to the sender's `PaddingPolicy`, the receiver just strips them.
+//! Records are never padded past the peer frame limit, so the largest records might still leak
+//! some information. Datagrams aren't padded.
+//!
+//! Compressing before padding would leak how compressible the payload is, which can say more
+//! about its contents than its length does (e.g. when it mixes secrets with data an attacker
+//! controls). So records are never compressed when the policy adds padding: they still get the
+//! compression header if negotiated, but are always stored uncompressed.
+use crate::{trivial_error, GenericResult};
+
+/// The length of the header of a padded record.
+pub const HEADER_LEN: usize = 4;
+
+/// How much to pad outgoing peer records. Any policy but `None` also disables compression of the
+/// records, see the module docs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Don't add any padding. Records still get the padding header if negotiated.
+    #[default]
+    None,
+    /// Pad records to the next power of two, which bounds the overhead to 2x but only leaks the
+    /// order of magnitude of the length.
+    PowersOfTwo,
+    /// Pad records to a multiple of the given cell size.
+    FixedCells(u32),
+}
+
+impl PaddingPolicy {
+    /// Whether the policy adds any padding.
+    pub fn pads(self) -> bool {
+        !matches!(self, Self::None | Self::FixedCells(0))
+    }
+
+    /// Returns the length to pad a record of `len` bytes to, up to `max_len`.
+    fn padded_len(self, len: usize, max_len: usize) -> usize {
+        let padded = match self {
+            Self::None | Self::FixedCells(0) => len,
+            Self::PowersOfTwo => len.checked_next_power_of_two().unwrap_or(len),
+            Self::FixedCells(cell) => len.next_multiple_of(cell as usize),
+        };
+        padded.min(max_len).max(len)
+    }
+}
+
+/// Wraps a record, padding it to at most `max_len` bytes according to `policy`.
+pub fn pad(payload: &[u8], policy: PaddingPolicy, max_len: usize) -> GenericResult<Vec<u8>> {
+    let Ok(len) = u32::try_from(payload.len()) else {
+        return Err(trivial_error!("Record too long to pad"));
+    };
+    let padded_len = policy.padded_len(HEADER_LEN + payload.len(), max_len);
+    let mut record = Vec::with_capacity(padded_len);
+    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(payload);
+    record.resize(padded_len, 0);
+    Ok(record)
+}
+
+/// Strips the padding of a record.
+pub fn unpad(mut record: Vec<u8>) -> GenericResult<Vec<u8>> {
+    if record.len() < HEADER_LEN {
+        return Err(trivial_error!("Truncated padded record"));
+    }
+    let len = u32::from_be_bytes(record[..HEADER_LEN].try_into().unwrap()) as usize;
+    if len > record.len() - HEADER_LEN {
+        return Err(trivial_error!("Wrong padded record length"));
+    }
+    if record[HEADER_LEN + len..].iter().any(|&b| b != 0) {
+        return Err(trivial_error!("Non-zero padding"));
+    }
+    record.truncate(HEADER_LEN + len);
+    record.drain(..HEADER_LEN);
+    Ok(record)
+}
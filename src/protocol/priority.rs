@@ -0,0 +1,209 @@
+//! Priority classes for outgoing messages, and the scheduling of writes according to them.
+//!
+//! Every connection has a `WriteScheduler`: whenever the connection is free, the pending write with
+//! the highest priority goes next. With multiplexing, large messages are written in chunks (see
+//! the `mux` module), and regular messages of each priority class go in a stream of their own, so
+//! a bulk transfer only delays more urgent messages by one chunk. Messages in the same stream
+//! can't be interleaved, so each stream has a `WriteScheduler` too, which picks the most urgent
+//! message to send whole once the previous one is done.
+//!
+//! Connections to different peers are independent, but they share the same radio, so the session
+//! also has a `PriorityGate`: before writing, lower priority traffic waits for higher priority
+//! traffic to any peer to finish, for up to `MAX_DEFERRAL` each time so that it can't be starved
+//! by a slow peer.
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{oneshot, Notify};
+
+/// The priority class of an outgoing message, from most to least urgent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Protocol and application control traffic.
+    Control = 0,
+    /// Latency-sensitive updates, like positions in a game.
+    Realtime = 1,
+    /// User-facing traffic, like chat messages.
+    #[default]
+    Interactive = 2,
+    /// Large transfers, like file chunks.
+    Bulk = 3,
+}
+
+impl Priority {
+    const COUNT: usize = 4;
+
+    pub const ALL: [Self; Self::COUNT] =
+        [Self::Control, Self::Realtime, Self::Interactive, Self::Bulk];
+
+    /// Returns the priority with a given index, as used in the wire format.
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(usize::from(index)).copied()
+    }
+
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    busy: bool,
+    waiting: [VecDeque<oneshot::Sender<()>>; Priority::COUNT],
+}
+
+/// Hands turns to write to a connection, highest priority first.
+#[derive(Debug, Default)]
+pub struct WriteScheduler {
+    state: Mutex<SchedulerState>,
+}
+
+/// The right to write to a connection, released on drop.
+#[derive(Debug)]
+pub struct Turn<'a> {
+    scheduler: &'a WriteScheduler,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// Like `Turn`, for a shared scheduler.
+#[derive(Debug)]
+pub struct OwnedTurn {
+    scheduler: Arc<WriteScheduler>,
+}
+
+impl Drop for OwnedTurn {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// Releases a turn that was handed to a waiter which went away before taking it.
+struct Waiter<'a> {
+    scheduler: &'a WriteScheduler,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if receiver.try_recv().is_ok() {
+                self.scheduler.release();
+            }
+        }
+    }
+}
+
+impl WriteScheduler {
+    /// Waits for our turn to write.
+    pub async fn acquire(&self, priority: Priority) -> Turn<'_> {
+        self.wait(priority).await;
+        Turn { scheduler: self }
+    }
+
+    /// Like `acquire`, for a shared scheduler.
+    pub async fn acquire_owned(self: Arc<Self>, priority: Priority) -> OwnedTurn {
+        self.wait(priority).await;
+        OwnedTurn { scheduler: self }
+    }
+
+    /// Waits until the turn is handed to us.
+    async fn wait(&self, priority: Priority) {
+        let receiver = {
+            let mut state = self.state.lock();
+            if !state.busy {
+                state.busy = true;
+                return;
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting[priority as usize].push_back(sender);
+            receiver
+        };
+        let mut waiter = Waiter {
+            scheduler: self,
+            receiver: Some(receiver),
+        };
+        // The sender is only dropped after sending, see release().
+        let _ = waiter.receiver.as_mut().unwrap().await;
+        waiter.receiver = None;
+    }
+
+    /// Hands the turn to the next waiter, if any.
+    fn release(&self) {
+        let mut state = self.state.lock();
+        for queue in &mut state.waiting {
+            while let Some(sender) = queue.pop_front() {
+                if sender.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.busy = false;
+    }
+}
+
+/// How long lower priority traffic waits at most for higher priority traffic to other peers.
+pub const MAX_DEFERRAL: Duration = Duration::from_millis(50);
+
+/// Tracks the priorities being sent across a whole session, see the module docs.
+#[derive(Debug, Default)]
+pub struct PriorityGate {
+    active: [AtomicUsize; Priority::COUNT],
+    changed: Notify,
+}
+
+/// Marks a message as being sent, see `PriorityGate::enter`.
+#[derive(Debug)]
+pub struct GateEntry<'a> {
+    gate: &'a PriorityGate,
+    priority: Priority,
+}
+
+impl Drop for GateEntry<'_> {
+    fn drop(&mut self) {
+        self.gate.active[self.priority as usize].fetch_sub(1, Ordering::Relaxed);
+        self.gate.changed.notify_waiters();
+    }
+}
+
+impl PriorityGate {
+    /// Registers a message of the given priority as being sent until the entry is dropped.
+    pub fn enter(&self, priority: Priority) -> GateEntry<'_> {
+        self.active[priority as usize].fetch_add(1, Ordering::Relaxed);
+        GateEntry {
+            gate: self,
+            priority,
+        }
+    }
+
+    fn higher_priority_active(&self, priority: Priority) -> bool {
+        self.active[..priority as usize]
+            .iter()
+            .any(|count| count.load(Ordering::Relaxed) != 0)
+    }
+
+    /// Waits (for up to `MAX_DEFERRAL`) until no traffic with higher priority is being sent.
+    pub async fn wait_turn(&self, priority: Priority) {
+        let _ = tokio::time::timeout(MAX_DEFERRAL, async {
+            loop {
+                let changed = self.changed.notified();
+                if !self.higher_priority_active(priority) {
+                    return;
+                }
+                changed.await;
+            }
+        })
+        .await;
+    }
+}
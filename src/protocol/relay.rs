@@ -0,0 +1,358 @@
+//! Relaying of messages between clients of a group through the group owner.
+//!
+//! Clients of a Wi-Fi Direct group can't always reach each other directly, and don't share keys,
+//! so when messaging another member of the group they learned about from the roster (see the
+//! `roster` module), they send `ControlMessage::Relay` envelopes to the group owner instead, which
+//! forwards them to the recipient. This requires all three to support it (see
+//! `Capabilities::relay`).
+//!
+//! Envelopes carry the logical keys of the sender and recipient, which is all the group owner
+//! gets to see, and are signed by the sender. The payload is either:
+//!
+//!  * A key exchange public key: the first time a client relays a message to another one, it sends
+//!    it the public key of its key exchange with it, the recipient replies with its own, and both
+//!    derive end-to-end keys from them as for an association (always using AES-256-GCM, since they
+//!    don't know each other's capabilities). This exchange is separate from the one of a direct
+//!    association between them, if any.
+//!  * A message, encrypted with the end-to-end keys with an explicit sequence number as the nonce,
+//!    like datagrams, since envelopes can be lost or duplicated on the way.
+//!
+//! Relayed messages go over the control channel, so they're subject to its rate and frame size
+//! limits, and are best suited for light traffic. They don't have priorities, and there's no
+//! acknowledgement from the recipient: if it lost our keys, it starts a new key exchange and the
+//! message is dropped.
+use super::{
+    encryption::{CipherSuite, Keys},
+    identity::OwnIdentity,
+    key_exchange::{self, KeyExchange},
+    signing::{self, MaybeInvalidPublicKey, MaybeInvalidSignature},
+    wire, ControlMessage, GroupDelegate, PeerInfo, Store, GO_CONTROL_PORT,
+};
+use crate::{trivial_error, Delivery, GenericResult, GroupId, PeerId};
+use log::trace;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+/// Domain separation for envelope signatures.
+const SIGNATURE_CONTEXT: &[u8] = b"ngn relay";
+
+/// The cipher suite of end-to-end keys, see the module docs.
+pub const CIPHER_SUITE: CipherSuite = CipherSuite::Aes256Gcm;
+
+/// How long we wait for the recipient to reply to our end-to-end key exchange.
+pub const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest message we relay, so that envelopes stay below the default control frame limit.
+pub const MAX_MESSAGE_LEN: usize = 60 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum RelayPayload {
+    /// The public key of our end-to-end key exchange with the recipient.
+    KeyExchange {
+        public_key: key_exchange::MaybeInvalidPublicKey,
+        /// Whether this answers a key exchange from the recipient.
+        reply: bool,
+    },
+    /// A message encrypted with the end-to-end keys.
+    Message { sequence: u64, ciphertext: Vec<u8> },
+}
+
+/// A relayed payload, along with its routing information.
+#[derive(Debug, Clone)]
+pub struct RelayEnvelope {
+    /// The logical key of the sender.
+    pub from: MaybeInvalidPublicKey,
+    /// The logical key of the recipient.
+    pub to: MaybeInvalidPublicKey,
+    pub payload: RelayPayload,
+}
+
+impl RelayEnvelope {
+    fn signed_payload(&self) -> Vec<u8> {
+        let mut payload = SIGNATURE_CONTEXT.to_vec();
+        payload.extend_from_slice(&wire::encode_fields(self));
+        payload
+    }
+
+    /// Signs the envelope, which must be from us.
+    pub fn sign(&self, from: &OwnIdentity) -> MaybeInvalidSignature {
+        debug_assert_eq!(
+            self.from,
+            from.to_public().key,
+            "Signing someone else's envelope"
+        );
+        let signature = signing::sign(&from.key_pair, &self.signed_payload());
+        MaybeInvalidSignature(signature.as_ref().try_into().unwrap())
+    }
+
+    /// Checks that the envelope comes from its sender.
+    pub fn verify(&self, signature: &MaybeInvalidSignature) -> GenericResult<()> {
+        signing::verify(&self.from, signature, &self.signed_payload())
+    }
+}
+
+/// How to relay envelopes to a peer, through the group owner of a group we're a client of.
+#[derive(Debug, Clone)]
+pub struct RelayRoute {
+    pub group_id: GroupId,
+    /// The address of the group owner.
+    pub go_address: IpAddr,
+    /// The scope id of the group interface.
+    pub scope_id: u32,
+    /// The wire version negotiated with the group owner.
+    pub wire_version: u16,
+    /// The logical key of the recipient.
+    pub recipient: MaybeInvalidPublicKey,
+}
+
+/// Encrypts a message with the end-to-end keys shared with the recipient.
+pub fn seal(keys: &Keys, message: &[u8]) -> GenericResult<RelayPayload> {
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(trivial_error!("Message too long to relay"));
+    }
+    let mut ciphertext = message.to_vec();
+    let sequence = keys.seal_datagram(&mut ciphertext)?;
+    Ok(RelayPayload::Message {
+        sequence,
+        ciphertext,
+    })
+}
+
+/// Decrypts a relayed message, rejecting it if it fails to authenticate or was already received.
+pub fn open(keys: &Keys, sequence: u64, mut ciphertext: Vec<u8>) -> GenericResult<Vec<u8>> {
+    let len = keys.open_datagram(sequence, &mut ciphertext)?.len();
+    ciphertext.truncate(len);
+    Ok(ciphertext)
+}
+
+/// Returns how to relay messages to a peer we can't message directly, if it's a member of a group
+/// we're a client of.
+pub fn route<D: GroupDelegate>(session: &D, id: PeerId) -> Option<RelayRoute> {
+    let peers = session.peers().read();
+    let groups = session.groups().read();
+    let peer = peers.get(id.0)?;
+    let recipient = peer.identity.verified_logical()?.key.clone();
+    peer.groups.iter().find_map(|group_id| {
+        let group = groups.get(group_id.0)?;
+        if group.is_go || !group.roster.members.contains_key(&id) {
+            return None;
+        }
+        let go = D::group_owner(&peers, group)?;
+        if !go.capabilities?.relay {
+            return None;
+        }
+        Some(RelayRoute {
+            group_id: *group_id,
+            go_address: group.go_ip_address,
+            scope_id: group.scope_id,
+            wire_version: go.wire_version,
+            recipient: recipient.clone(),
+        })
+    })
+}
+
+/// Returns the end-to-end keys we share with a peer, if any.
+fn keys<D: GroupDelegate>(session: &D, id: PeerId) -> Option<Arc<Keys>> {
+    let peers = session.peers().read();
+    let key_exchange = peers.get(id.0)?.relay_key_exchange.as_ref()?;
+    key_exchange.encryption_keys().cloned()
+}
+
+/// Returns our end-to-end key exchange with a peer, starting one if needed.
+fn key_exchange<P>(peer: &mut PeerInfo<P>) -> GenericResult<&mut KeyExchange> {
+    if peer.relay_key_exchange.is_none() {
+        peer.relay_key_exchange = Some(KeyExchange::new()?);
+    }
+    Ok(peer.relay_key_exchange.as_mut().unwrap())
+}
+
+async fn send_envelope<D: GroupDelegate>(
+    session: &D,
+    route: &RelayRoute,
+    payload: RelayPayload,
+) -> GenericResult<()> {
+    let envelope = RelayEnvelope {
+        from: session.identity().to_public().key,
+        to: route.recipient.clone(),
+        payload,
+    };
+    let signature = envelope.sign(session.identity());
+    session
+        .send_control_message(
+            route.go_address,
+            GO_CONTROL_PORT,
+            route.scope_id,
+            route.wire_version,
+            ControlMessage::Relay {
+                envelope,
+                signature,
+            },
+        )
+        .await
+}
+
+/// Starts (or answers) an end-to-end key exchange with a peer through the GO.
+async fn send_key_exchange<D: GroupDelegate>(
+    session: &D,
+    id: PeerId,
+    route: &RelayRoute,
+    reply: bool,
+) -> GenericResult<()> {
+    let public_key = match session.peers().write().get_mut(id.0) {
+        Some(peer) => key_exchange(peer)?.export_public_key(),
+        None => return Err(trivial_error!("Peer was lost (stale handle?)")),
+    };
+    send_envelope(
+        session,
+        route,
+        RelayPayload::KeyExchange { public_key, reply },
+    )
+    .await
+}
+
+/// Sends a message to a peer through the GO, exchanging end-to-end keys with it first if needed.
+pub async fn send<D: GroupDelegate>(
+    session: &D,
+    id: PeerId,
+    route: RelayRoute,
+    message: &[u8],
+) -> GenericResult<()> {
+    let keys = match keys(session, id) {
+        Some(keys) => keys,
+        None => {
+            send_key_exchange(session, id, &route, /* reply = */ false).await?;
+            let keys = tokio::time::timeout(KEY_EXCHANGE_TIMEOUT, async {
+                loop {
+                    if let Some(keys) = keys(session, id) {
+                        return keys;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await;
+            match keys {
+                Ok(keys) => keys,
+                Err(..) => return Err(trivial_error!("Relayed key exchange timed out")),
+            }
+        }
+    };
+    let payload = seal(&keys, message)?;
+    send_envelope(session, &route, payload).await
+}
+
+/// As the GO of a group, forwards an envelope between two of its clients.
+pub async fn forward<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    source: IpAddr,
+    envelope: RelayEnvelope,
+    signature: MaybeInvalidSignature,
+) -> GenericResult<()> {
+    envelope.verify(&signature)?;
+    let (recipient, scope_id, version) = {
+        let peers = session.peers().read();
+        let groups = session.groups().read();
+        let Some(group) = groups.get(group_id.0) else {
+            return Err(trivial_error!("Group not found"));
+        };
+        if !group.is_go {
+            return Err(trivial_error!("Only the GO relays messages"));
+        }
+        let member = |key: &MaybeInvalidPublicKey| {
+            group.peers.iter().find_map(|(peer_id, info)| {
+                let peer = peers.get(peer_id.0)?;
+                let matches =
+                    peer.identity.verified_logical()?.key == *key && peer.capabilities?.relay;
+                matches.then(|| (info.address.clone(), peer.wire_version))
+            })
+        };
+        let Some((sender, _)) = member(&envelope.from) else {
+            return Err(trivial_error!("Relayed envelope from unknown member"));
+        };
+        if sender.address != source {
+            return Err(trivial_error!("Relayed envelope from the wrong address"));
+        }
+        let Some((recipient, version)) = member(&envelope.to) else {
+            return Err(trivial_error!("Relayed envelope to unknown member"));
+        };
+        (recipient, group.scope_id, version)
+    };
+    trace!(
+        "Relaying envelope to {:?} in {group_id:?}",
+        recipient.address
+    );
+    session
+        .send_control_message(
+            recipient.address,
+            recipient.ports.control,
+            scope_id,
+            version,
+            ControlMessage::Relay {
+                envelope,
+                signature,
+            },
+        )
+        .await
+}
+
+/// As a client of a group, handles an envelope relayed by the GO.
+pub async fn receive<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    envelope: RelayEnvelope,
+    signature: MaybeInvalidSignature,
+) -> GenericResult<()> {
+    envelope.verify(&signature)?;
+    if envelope.to != session.identity().to_public().key {
+        return Err(trivial_error!("Relayed envelope isn't for us"));
+    }
+    let peer_id = {
+        let groups = session.groups().read();
+        let Some(group) = groups.get(group_id.0) else {
+            return Err(trivial_error!("Group not found"));
+        };
+        let sender = group
+            .roster
+            .members
+            .iter()
+            .find(|(_, logical)| logical.key == envelope.from);
+        match sender {
+            Some((peer_id, _)) => *peer_id,
+            None => return Err(trivial_error!("Relayed envelope from unknown member")),
+        }
+    };
+    let Some(route) = route(session, peer_id) else {
+        return Err(trivial_error!("Can't relay back to the sender"));
+    };
+    match envelope.payload {
+        RelayPayload::KeyExchange { public_key, reply } => {
+            {
+                let mut peers = session.peers().write();
+                let Some(peer) = peers.get_mut(peer_id.0) else {
+                    return Err(trivial_error!("Peer was lost (stale handle?)"));
+                };
+                // Retries and simultaneous exchanges send the same key more than once.
+                let key_exchange = key_exchange(peer)?;
+                if key_exchange.peer_public_key() != Some(&public_key) {
+                    key_exchange.finish(&public_key, None, None, CIPHER_SUITE)?;
+                }
+            }
+            if !reply {
+                send_key_exchange(session, peer_id, &route, /* reply = */ true).await?;
+            }
+        }
+        RelayPayload::Message {
+            sequence,
+            ciphertext,
+        } => {
+            let Some(keys) = keys(session, peer_id) else {
+                // The sender has keys we don't (e.g. we lost and rediscovered it), start over so
+                // that its next messages get through.
+                send_key_exchange(session, peer_id, &route, /* reply = */ false).await?;
+                return Err(trivial_error!("No end-to-end keys for relayed message"));
+            };
+            let message = open(&keys, sequence, ciphertext)?;
+            session.peer_messaged(peer_id, group_id, &message, Delivery::Relayed);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Session resumption, for peers that come back shortly after being lost (e.g. when the Wi-Fi
+//! Direct group is re-formed).
+//!
+//! Every completed key exchange yields a ticket (see `Keys::resumption_ticket`): an identifier and
+//! a secret both ends derive from the shared secret, and remember for `window`. When a client
+//! re-associates with a group owner it has a ticket for, it offers the ticket id in its
+//! `Associate`. If the group owner still has the ticket (and it was issued for the same logical
+//! identity), it echoes the id back, and both ends mix the ticket secret into the new keys. This
+//! binds the new session to the previous one, so that an attacker that wasn't around for the
+//! first exchange can't impersonate either end. The exchange itself still uses fresh X25519 keys,
+//! preserving forward secrecy.
+//!
+//! Tickets are single use: each resumed session yields a new one. If either end doesn't have the
+//! ticket anymore, the exchange just proceeds as a full one.
+//!
+//! Resumption doesn't make re-associating any faster: the group still needs to be re-formed, and
+//! the keys are still agreed upon with the usual `Associate` round trip. Nor does anything else
+//! carry over from the previous session: messages that were being sent to the peer when it was
+//! lost fail as usual, and it's up to the application to send them again once it's back.
+use super::{identity::LogicalPeerIdentity, signing::MaybeInvalidPublicKey};
+use macaddr::MacAddr;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// The length of a ticket id.
+pub const TICKET_ID_LEN: usize = 16;
+
+/// The length of a ticket secret.
+pub const SECRET_LEN: usize = 32;
+
+/// A reasonable default for how long tickets are kept around.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// What we need to resume a session with a peer.
+#[derive(Clone)]
+pub struct Ticket {
+    pub id: [u8; TICKET_ID_LEN],
+    pub secret: [u8; SECRET_LEN],
+}
+
+impl std::fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ticket").field("id", &self.id).finish()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    ticket: Ticket,
+    /// The device address of the peer, to find the ticket to offer when associating.
+    dev_addr: MacAddr,
+    /// The logical key of the peer, so that a ticket can't be redeemed by anyone else.
+    logical_key: MaybeInvalidPublicKey,
+    issued: Instant,
+}
+
+/// The tickets for the peers we've recently talked to.
+#[derive(Debug)]
+pub struct ResumptionCache {
+    window: Duration,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl ResumptionCache {
+    /// Creates a cache that keeps tickets for `window`. A zero window disables resumption.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Default::default(),
+        }
+    }
+
+    fn expire(&self, entries: &mut Vec<Entry>) {
+        entries.retain(|e| e.issued.elapsed() < self.window);
+    }
+
+    /// Remembers the ticket of the session with a peer, replacing any previous one.
+    pub fn store(&self, dev_addr: MacAddr, logical: &LogicalPeerIdentity, ticket: Ticket) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        self.expire(&mut entries);
+        entries.retain(|e| e.logical_key != logical.key);
+        entries.push(Entry {
+            ticket,
+            dev_addr,
+            logical_key: logical.key.clone(),
+            issued: Instant::now(),
+        });
+    }
+
+    /// Returns the id of the ticket to offer when associating with a given device, if any.
+    pub fn offer(&self, dev_addr: MacAddr) -> Option<[u8; TICKET_ID_LEN]> {
+        let mut entries = self.entries.lock();
+        self.expire(&mut entries);
+        entries
+            .iter()
+            .rev()
+            .find(|e| e.dev_addr == dev_addr)
+            .map(|e| e.ticket.id)
+    }
+
+    /// Takes the ticket with a given id, if it's still valid and was issued for the given peer.
+    pub fn redeem(
+        &self,
+        id: &[u8; TICKET_ID_LEN],
+        logical: &LogicalPeerIdentity,
+    ) -> Option<Ticket> {
+        let mut entries = self.entries.lock();
+        self.expire(&mut entries);
+        let index = entries
+            .iter()
+            .position(|e| e.ticket.id == *id && e.logical_key == logical.key)?;
+        Some(entries.swap_remove(index).ticket)
+    }
+}
@@ -0,0 +1,358 @@
+//! Group membership rosters.
+//!
+//! Wi-Fi Direct groups are stars: clients only associate with the group owner, so on their own
+//! they don't know who else is in the group. When both ends support it (see
+//! `Capabilities::roster`), the group owner tells its clients about the other associated members
+//! whenever membership changes:
+//!
+//!  * Clients that just associated (or that missed an update) get a snapshot of all the members.
+//!  * Other clients get a delta with the members that joined and left since the previous update.
+//!
+//! Updates are numbered. Clients ignore snapshots that aren't newer than the last update they
+//! applied, and deltas that don't directly follow it. Every update is signed with the logical key
+//! of the group owner, over the key exchange public key of the client it's sent to as well, so
+//! that it can't be replayed to another client, or in a later association.
+//!
+//! Clients can only track members they have discovered themselves, the rest are ignored. They
+//! don't share keys with the other members, so they can't message them directly.
+use super::{
+    identity::{LogicalPeerIdentity, OwnIdentity},
+    key_exchange,
+    signing::{self, MaybeInvalidSignature},
+    wire, ControlMessage, GroupDelegate, P2pPorts, PeerAddress, PeerGroupInfo, PeerOwnIdentifier,
+    Store,
+};
+use crate::{trivial_error, GenericResult, GroupId, PeerId};
+use log::{error, trace, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+/// Domain separation for roster signatures, so that they can't be confused with anything else we
+/// sign.
+const SIGNATURE_CONTEXT: &[u8] = b"ngn roster";
+
+/// A member of a group, as seen by the group owner.
+#[derive(Debug, Clone)]
+pub struct RosterMember {
+    /// The physical identifier of the member.
+    pub physical_id: PeerOwnIdentifier,
+    /// The logical identity of the member.
+    pub logical_id: LogicalPeerIdentity,
+    /// The link-local address of the member.
+    pub address: IpAddr,
+    /// The ports the member listens to.
+    pub ports: P2pPorts,
+}
+
+#[derive(Debug, Clone)]
+pub enum RosterChange {
+    /// All the members of the group.
+    Snapshot(Vec<RosterMember>),
+    /// The members that joined and left since the previous update.
+    Delta {
+        joined: Vec<RosterMember>,
+        left: Vec<LogicalPeerIdentity>,
+    },
+}
+
+/// An update of the members of a group, see the module docs.
+#[derive(Debug, Clone)]
+pub struct RosterUpdate {
+    pub sequence: u64,
+    pub change: RosterChange,
+}
+
+impl RosterUpdate {
+    fn signed_payload(&self, recipient: &key_exchange::MaybeInvalidPublicKey) -> Vec<u8> {
+        let mut payload = SIGNATURE_CONTEXT.to_vec();
+        payload.extend_from_slice(&recipient.0);
+        payload.extend_from_slice(&wire::encode_fields(self));
+        payload
+    }
+
+    /// Signs the update for the client with the given key exchange public key.
+    pub fn sign(
+        &self,
+        from: &OwnIdentity,
+        recipient: &key_exchange::MaybeInvalidPublicKey,
+    ) -> MaybeInvalidSignature {
+        let signature = signing::sign(&from.key_pair, &self.signed_payload(recipient));
+        MaybeInvalidSignature(signature.as_ref().try_into().unwrap())
+    }
+
+    /// Checks that the update comes from the group owner, and was meant for us given our key
+    /// exchange public key.
+    pub fn verify(
+        &self,
+        group_owner: &LogicalPeerIdentity,
+        recipient: &key_exchange::MaybeInvalidPublicKey,
+        signature: &MaybeInvalidSignature,
+    ) -> GenericResult<()> {
+        signing::verify(&group_owner.key, signature, &self.signed_payload(recipient))
+    }
+}
+
+/// The roster of a group.
+#[derive(Debug, Default)]
+pub struct RosterState {
+    /// The number of the last update we sent (as the group owner) or applied (as a client).
+    pub sequence: u64,
+    /// The members as of the last update, excluding the group owner.
+    pub members: HashMap<PeerId, LogicalPeerIdentity>,
+    /// As the group owner, the clients that got all the updates so far, which get deltas rather
+    /// than snapshots.
+    pub synced: HashSet<PeerId>,
+    /// As the group owner, serializes our updates, so that clients get them in order.
+    pub sending: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl RosterState {
+    /// As the group owner, records the current members of the group, returning the delta since
+    /// the previous update if anything changed.
+    pub fn advance(&mut self, current: &[(PeerId, RosterMember)]) -> Option<RosterChange> {
+        let joined: Vec<_> = current
+            .iter()
+            .filter(|(id, _)| !self.members.contains_key(id))
+            .map(|(_, member)| member.clone())
+            .collect();
+        let left: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(id, _)| !current.iter().any(|(current, _)| current == *id))
+            .map(|(_, logical)| logical.clone())
+            .collect();
+        if joined.is_empty() && left.is_empty() {
+            return None;
+        }
+        self.sequence += 1;
+        self.members = current
+            .iter()
+            .map(|(id, member)| (*id, member.logical_id.clone()))
+            .collect();
+        let members = &self.members;
+        self.synced.retain(|id| members.contains_key(id));
+        Some(RosterChange::Delta { joined, left })
+    }
+
+    /// As a client, returns whether the update should be applied, in which case it becomes our
+    /// last one.
+    pub fn accept(&mut self, update: &RosterUpdate) -> bool {
+        let in_order = match update.change {
+            RosterChange::Snapshot(..) => update.sequence > self.sequence,
+            RosterChange::Delta { .. } => update.sequence == self.sequence + 1,
+        };
+        if in_order {
+            self.sequence = update.sequence;
+        }
+        in_order
+    }
+}
+
+/// As the group owner of a group, tells its clients about its current members if they changed.
+pub async fn broadcast<D: GroupDelegate>(session: Arc<D>, group_id: GroupId) {
+    let sending = match session.groups().read().get(group_id.0) {
+        Some(group) if group.is_go => Arc::clone(&group.roster.sending),
+        _ => return,
+    };
+    let _sending = sending.lock().await;
+    let (scope_id, messages) = {
+        let peers = session.peers().read();
+        let mut groups = session.groups().write();
+        let Some(group) = groups.get_mut(group_id.0) else {
+            return;
+        };
+        let members: Vec<_> = group
+            .peers
+            .iter()
+            .filter_map(|(peer_id, info)| {
+                let peer = peers.get(peer_id.0)?;
+                let member = RosterMember {
+                    physical_id: PeerOwnIdentifier::DevAddr(peer.identity.physical.dev_addr.into()),
+                    logical_id: peer.identity.verified_logical().cloned()?,
+                    address: info.address.address,
+                    ports: info.address.ports,
+                };
+                Some((*peer_id, member))
+            })
+            .collect();
+        let Some(delta) = group.roster.advance(&members) else {
+            return;
+        };
+        let sequence = group.roster.sequence;
+        let messages: Vec<_> = members
+            .iter()
+            .filter_map(|(peer_id, member)| {
+                let peer = peers.get(peer_id.0)?;
+                if !peer.capabilities.is_some_and(|c| c.roster) {
+                    return None;
+                }
+                let change = if group.roster.synced.contains(peer_id) {
+                    delta.clone()
+                } else {
+                    RosterChange::Snapshot(members.iter().map(|(_, m)| m.clone()).collect())
+                };
+                let update = RosterUpdate { sequence, change };
+                let signature =
+                    update.sign(session.identity(), peer.key_exchange.peer_public_key()?);
+                let message = ControlMessage::Roster { update, signature };
+                Some((
+                    *peer_id,
+                    member.address,
+                    member.ports.control,
+                    peer.wire_version,
+                    message,
+                ))
+            })
+            .collect();
+        (group.scope_id, messages)
+    };
+    for (peer_id, address, control_port, version, message) in messages {
+        let result = session
+            .send_control_message(address, control_port, scope_id, version, message)
+            .await;
+        let mut groups = session.groups().write();
+        let Some(group) = groups.get_mut(group_id.0) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                group.roster.synced.insert(peer_id);
+            }
+            Err(e) => {
+                // It'll get a snapshot next time.
+                error!("Failed to send roster to {peer_id:?} in {group_id:?}: {e}");
+                group.roster.synced.remove(&peer_id);
+            }
+        }
+    }
+}
+
+/// As a client of a group, applies a roster update from the group owner, returning the members
+/// that joined and left.
+pub fn apply<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    update: RosterUpdate,
+    signature: &MaybeInvalidSignature,
+) -> GenericResult<(Vec<PeerId>, Vec<PeerId>)> {
+    let mut peers = session.peers().write();
+    let mut groups = session.groups().write();
+    let Some(group) = groups.get_mut(group_id.0) else {
+        return Err(trivial_error!("Group was torn down?"));
+    };
+    if group.is_go {
+        return Err(trivial_error!("Only the GO sends rosters"));
+    }
+    let Some(go) = D::group_owner(&peers, group) else {
+        return Err(trivial_error!("Couldn't find the GO in the peer list"));
+    };
+    let Some(go_identity) = go.identity.verified_logical() else {
+        return Err(trivial_error!("GO hasn't associated yet?"));
+    };
+    update.verify(go_identity, &go.key_exchange.export_public_key(), signature)?;
+    if !group.roster.accept(&update) {
+        warn!(
+            "Ignoring out of order roster update {} in {group_id:?}",
+            update.sequence
+        );
+        return Ok(Default::default());
+    }
+
+    let own_key = session.identity().to_public().key;
+    let (members, left, is_snapshot) = match update.change {
+        RosterChange::Snapshot(members) => (members, vec![], true),
+        RosterChange::Delta { joined, left } => (joined, left, false),
+    };
+    let mut joined = vec![];
+    let mut present = HashSet::new();
+    for member in members {
+        if member.logical_id.key == own_key {
+            continue;
+        }
+        let found = member.physical_id.find_peer(
+            peers.iter_mut_with_handles(),
+            |(_, p)| &p.identity.physical,
+            session.name_matching(),
+        );
+        let (id, peer) = match found {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                trace!(
+                    "Roster member {} isn't a peer we know about",
+                    member.logical_id
+                );
+                continue;
+            }
+            Err(e) => {
+                error!(
+                    "Can't tell which peer roster member {} is: {e}",
+                    member.logical_id
+                );
+                continue;
+            }
+        };
+        let peer_id = PeerId(id);
+        if peer
+            .identity
+            .verified_logical()
+            .is_some_and(|i| *i != member.logical_id)
+        {
+            error!(
+                "Roster member {} doesn't match the logical identity of {peer_id:?}",
+                member.logical_id
+            );
+            continue;
+        }
+        if peer.groups.contains(&group_id) && !group.roster.members.contains_key(&peer_id) {
+            // We're associated with it directly, i.e. it's the GO.
+            continue;
+        }
+        peer.identity.logical = Some(member.logical_id.clone());
+        peer.identity.logical_verified = true;
+        present.insert(peer_id);
+        let address = PeerAddress {
+            address: member.address,
+            ports: member.ports,
+        };
+        group.peers.insert(peer_id, PeerGroupInfo { address });
+        if group
+            .roster
+            .members
+            .insert(peer_id, member.logical_id)
+            .is_none()
+        {
+            peer.groups.push(group_id);
+            joined.push(peer_id);
+        }
+    }
+
+    let gone: Vec<_> = group
+        .roster
+        .members
+        .iter()
+        .filter(|(peer_id, logical)| {
+            if is_snapshot {
+                !present.contains(*peer_id)
+            } else {
+                left.contains(logical)
+            }
+        })
+        .map(|(peer_id, _)| *peer_id)
+        .collect();
+    let mut removed = vec![];
+    for peer_id in gone {
+        group.roster.members.remove(&peer_id);
+        if group.peers.remove(&peer_id).is_none() {
+            // Lost in the meantime.
+            continue;
+        }
+        if let Some(peer) = peers.get_mut(peer_id.0) {
+            peer.groups.retain(|g| *g != group_id);
+        }
+        removed.push(peer_id);
+    }
+    Ok((joined, removed))
+}
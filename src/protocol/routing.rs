@@ -0,0 +1,679 @@
+//! Multi-hop routing across overlapping groups.
+//!
+//! A device can be in several Wi-Fi Direct groups at once, and then bridges them: when all the
+//! devices on the way support it (see `Capabilities::routing`), peers can message each other
+//! through any number of groups, knowing only each other's logical identity.
+//!
+//! Routes are exchanged with a distance-vector protocol between neighbours, that is, between the
+//! group owner and each of its clients (clients of the same group aren't neighbours of each
+//! other, they reach each other through the group owner). Every `ANNOUNCE_INTERVAL`, and whenever
+//! its routes change, each device sends its neighbours a `ControlMessage::Routes` announcement
+//! with the destinations it can reach and their distance in hops. A neighbour is always one hop
+//! away, and a destination announced at `n` hops by a neighbour is `n + 1` hops away through it.
+//! We keep the shortest route to each destination, or the latest one from the neighbour we're
+//! already routing through.
+//!
+//! To prevent loops:
+//!
+//!  * Routes are announced back to the neighbour we learned them from as unreachable (i.e. at
+//!    `MAX_HOPS`, "poisoned reverse"), so two neighbours never route through each other.
+//!  * Routes longer than `MAX_HOPS` are dropped, so that longer loops count up to it and go away
+//!    ("count to infinity").
+//!  * Routed messages carry a TTL, decremented on every hop, and are dropped when it runs out.
+//!
+//! Routes expire if they aren't announced again within `ROUTE_TIMEOUT`, and are removed right away
+//! when the neighbour they go through leaves the group.
+//!
+//! Announcements are numbered, and signed with the logical key of the neighbour over the logical
+//! key of the recipient, so that they can't be replayed, or sent to another device. We only accept
+//! announcements newer than the last one from each neighbour, so that an old announcement can't
+//! bring back a route that was withdrawn since, and send them one at a time so that they arrive in
+//! order.
+//!
+//! Messages are sent as `ControlMessage::Routed` envelopes, which are the same as the ones used to
+//! relay messages through a group owner (see the `relay` module), with end-to-end keys exchanged
+//! in the same way, and a TTL. Intermediate devices only see the logical keys of the sender and
+//! recipient. Like relayed messages, routed ones go over the control channel, so they're best
+//! suited for light traffic.
+use super::{
+    encryption::Keys,
+    identity::{LogicalPeerIdentity, OwnIdentity},
+    key_exchange::KeyExchange,
+    relay::{self, RelayEnvelope, RelayPayload},
+    signing::{self, MaybeInvalidPublicKey, MaybeInvalidSignature},
+    wire, ControlMessage, GroupDelegate, GroupInfo, PeerGroupInfo, PeerInfo, Store,
+};
+use crate::{trivial_error, Delivery, GenericResult, GroupId, PeerId};
+use log::warn;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Domain separation for announcement signatures.
+const SIGNATURE_CONTEXT: &[u8] = b"ngn routes";
+
+/// The longest route we keep, and the distance of unreachable destinations in announcements.
+pub const MAX_HOPS: u8 = 16;
+
+/// The TTL of the messages we send.
+pub const DEFAULT_TTL: u8 = MAX_HOPS;
+
+/// How often we announce our routes to our neighbours.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long routes last unless they're announced again.
+pub const ROUTE_TIMEOUT: Duration = Duration::from_secs(35);
+
+/// The most destinations we keep routes to, so that a neighbour can't make us use unbounded
+/// memory.
+pub const MAX_ROUTES: usize = 1024;
+
+/// A destination in an announcement.
+#[derive(Debug, Clone)]
+pub struct RouteEntry {
+    pub destination: LogicalPeerIdentity,
+    /// The distance to the destination from the announcing device, `MAX_HOPS` if unreachable.
+    pub hops: u8,
+}
+
+/// The destinations a device can reach, sent to its neighbours, see the module docs.
+#[derive(Debug, Clone)]
+pub struct RouteAnnouncement {
+    pub sequence: u64,
+    pub routes: Vec<RouteEntry>,
+}
+
+impl RouteAnnouncement {
+    fn signed_payload(&self, recipient: &MaybeInvalidPublicKey) -> Vec<u8> {
+        let mut payload = SIGNATURE_CONTEXT.to_vec();
+        payload.extend_from_slice(&recipient.0);
+        payload.extend_from_slice(&wire::encode_fields(self));
+        payload
+    }
+
+    /// Signs the announcement for the neighbour with the given logical key.
+    pub fn sign(
+        &self,
+        from: &OwnIdentity,
+        recipient: &MaybeInvalidPublicKey,
+    ) -> MaybeInvalidSignature {
+        let signature = signing::sign(&from.key_pair, &self.signed_payload(recipient));
+        MaybeInvalidSignature(signature.as_ref().try_into().unwrap())
+    }
+
+    /// Checks that the announcement comes from the given neighbour, and was meant for us.
+    pub fn verify(
+        &self,
+        neighbour: &LogicalPeerIdentity,
+        recipient: &MaybeInvalidPublicKey,
+        signature: &MaybeInvalidSignature,
+    ) -> GenericResult<()> {
+        signing::verify(&neighbour.key, signature, &self.signed_payload(recipient))
+    }
+}
+
+/// How to reach a destination.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub destination: LogicalPeerIdentity,
+    /// The neighbour to send messages to.
+    pub next_hop: PeerId,
+    /// The group we share with the neighbour.
+    pub group_id: GroupId,
+    /// The distance to the destination, 1 if it's the neighbour itself.
+    pub hops: u8,
+    /// When the route goes away unless it's announced again.
+    pub expires: Instant,
+}
+
+/// Our routes to other devices, and the end-to-end keys we share with them.
+#[derive(Debug)]
+pub struct RoutingTable {
+    /// The number of the last announcement we sent.
+    sequence: u64,
+    /// Our routes, by logical key of the destination.
+    routes: HashMap<MaybeInvalidPublicKey, Route>,
+    /// The number of the last announcement we got from each neighbour, by logical key.
+    announcements: HashMap<MaybeInvalidPublicKey, u64>,
+    /// End-to-end key exchanges with the destinations we message, by logical key.
+    key_exchanges: HashMap<MaybeInvalidPublicKey, KeyExchange>,
+    /// Serializes the announcements we send, so that neighbours get them in order.
+    pub sending: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        // Start from the current time, so that our numbers keep increasing across restarts.
+        let sequence = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Self {
+            sequence,
+            routes: Default::default(),
+            announcements: Default::default(),
+            key_exchanges: Default::default(),
+            sending: Default::default(),
+        }
+    }
+}
+
+impl RoutingTable {
+    /// Returns the route to a destination, if any.
+    pub fn route(&self, destination: &MaybeInvalidPublicKey) -> Option<&Route> {
+        self.routes.get(destination)
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.values()
+    }
+
+    /// Returns our end-to-end key exchange with a destination, starting one if needed.
+    pub fn key_exchange(
+        &mut self,
+        destination: &MaybeInvalidPublicKey,
+    ) -> GenericResult<&mut KeyExchange> {
+        if !self.routes.contains_key(destination) {
+            return Err(trivial_error!("No route to peer"));
+        }
+        Ok(match self.key_exchanges.entry(destination.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(KeyExchange::new()?),
+        })
+    }
+
+    /// Returns the end-to-end keys we share with a destination, if any.
+    pub fn keys(&self, destination: &MaybeInvalidPublicKey) -> Option<Arc<Keys>> {
+        self.key_exchanges
+            .get(destination)?
+            .encryption_keys()
+            .cloned()
+    }
+
+    /// Returns the next announcement for a neighbour.
+    pub fn announcement(&mut self, neighbour: PeerId) -> RouteAnnouncement {
+        self.sequence += 1;
+        let routes = self
+            .routes
+            .values()
+            .map(|route| RouteEntry {
+                destination: route.destination.clone(),
+                hops: if route.next_hop == neighbour {
+                    MAX_HOPS
+                } else {
+                    route.hops
+                },
+            })
+            .collect();
+        RouteAnnouncement {
+            sequence: self.sequence,
+            routes,
+        }
+    }
+
+    /// Updates our routes given an announcement from a neighbour, whose signature has already
+    /// been checked. Returns whether our routes changed.
+    pub fn apply(
+        &mut self,
+        own_key: &MaybeInvalidPublicKey,
+        neighbour_id: PeerId,
+        group_id: GroupId,
+        neighbour: &LogicalPeerIdentity,
+        announcement: &RouteAnnouncement,
+    ) -> GenericResult<bool> {
+        let last = self.announcements.entry(neighbour.key.clone()).or_default();
+        if announcement.sequence <= *last {
+            return Err(trivial_error!("Stale route announcement"));
+        }
+        *last = announcement.sequence;
+        let expires = Instant::now() + ROUTE_TIMEOUT;
+        let direct = RouteEntry {
+            destination: neighbour.clone(),
+            hops: 0,
+        };
+        let mut changed = false;
+        for entry in std::iter::once(&direct).chain(&announcement.routes) {
+            let key = &entry.destination.key;
+            if key == own_key || (key == &neighbour.key && entry.hops != 0) {
+                continue;
+            }
+            let hops = entry.hops.saturating_add(1);
+            let through_neighbour = self
+                .routes
+                .get(key)
+                .is_some_and(|r| r.next_hop == neighbour_id && r.group_id == group_id);
+            if hops >= MAX_HOPS {
+                if through_neighbour {
+                    self.remove(key);
+                    changed = true;
+                }
+                continue;
+            }
+            let better = match self.routes.get(key) {
+                Some(route) => through_neighbour || hops < route.hops,
+                None => self.routes.len() < MAX_ROUTES,
+            };
+            if !better {
+                continue;
+            }
+            let route = Route {
+                destination: entry.destination.clone(),
+                next_hop: neighbour_id,
+                group_id,
+                hops,
+                expires,
+            };
+            let old = self.routes.insert(key.clone(), route);
+            changed |= old.is_none_or(|old| {
+                !through_neighbour || old.hops != hops || old.destination != entry.destination
+            });
+        }
+        Ok(changed)
+    }
+
+    /// Removes the routes through a neighbour in a group, e.g. because it left. Returns whether
+    /// anything was removed.
+    pub fn remove_neighbour(&mut self, neighbour_id: PeerId, group_id: GroupId) -> bool {
+        self.remove_where(|route| route.next_hop == neighbour_id && route.group_id == group_id)
+    }
+
+    /// Removes the routes through a group, e.g. because we left it. Returns whether anything was
+    /// removed.
+    pub fn remove_group(&mut self, group_id: GroupId) -> bool {
+        self.remove_where(|route| route.group_id == group_id)
+    }
+
+    /// Removes the routes that weren't announced again in time. Returns whether anything was
+    /// removed.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        self.remove_where(|route| route.expires <= now)
+    }
+
+    fn remove_where(&mut self, condition: impl Fn(&Route) -> bool) -> bool {
+        let removed: Vec<_> = self
+            .routes
+            .iter()
+            .filter(|(_, route)| condition(route))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &removed {
+            self.remove(key);
+        }
+        !removed.is_empty()
+    }
+
+    fn remove(&mut self, destination: &MaybeInvalidPublicKey) {
+        self.routes.remove(destination);
+        // We'd need to exchange keys again anyway if the destination rebooted meanwhile.
+        self.key_exchanges.remove(destination);
+    }
+}
+
+/// Returns whether we exchange routes with a member of a group: the GO does with all its clients,
+/// and clients only with the GO.
+fn is_neighbour<G, P>(group: &GroupInfo<G>, info: &PeerGroupInfo, peer: &PeerInfo<P>) -> bool {
+    (group.is_go || info.address.address == group.go_ip_address)
+        && peer.capabilities.is_some_and(|c| c.routing)
+}
+
+/// Returns the neighbour with the given address in a group.
+fn neighbour<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    address: IpAddr,
+) -> Option<(PeerId, LogicalPeerIdentity)> {
+    let peers = session.peers().read();
+    let groups = session.groups().read();
+    let group = groups.get(group_id.0)?;
+    group.peers.iter().find_map(|(peer_id, info)| {
+        let peer = peers.get(peer_id.0)?;
+        if info.address.address != address || !is_neighbour(group, info, peer) {
+            return None;
+        }
+        Some((*peer_id, peer.identity.verified_logical().cloned()?))
+    })
+}
+
+/// Tells our neighbours in a group about the peers we can reach.
+pub async fn announce<D: GroupDelegate>(session: Arc<D>, group_id: GroupId) {
+    let sending = Arc::clone(&session.routing().read().sending);
+    let _sending = sending.lock().await;
+    let (scope_id, announcements) = {
+        let peers = session.peers().read();
+        let groups = session.groups().read();
+        let Some(group) = groups.get(group_id.0) else {
+            return;
+        };
+        let mut routing = session.routing().write();
+        let announcements: Vec<_> = group
+            .peers
+            .iter()
+            .filter_map(|(peer_id, info)| {
+                let peer = peers.get(peer_id.0)?;
+                if !is_neighbour(group, info, peer) {
+                    return None;
+                }
+                let recipient = &peer.identity.verified_logical()?.key;
+                let announcement = routing.announcement(*peer_id);
+                let signature = announcement.sign(session.identity(), recipient);
+                let message = ControlMessage::Routes {
+                    announcement,
+                    signature,
+                };
+                Some((info.address.clone(), peer.wire_version, message))
+            })
+            .collect();
+        (group.scope_id, announcements)
+    };
+    for (address, version, message) in announcements {
+        let result = session
+            .send_control_message(
+                address.address,
+                address.ports.control,
+                scope_id,
+                version,
+                message,
+            )
+            .await;
+        if let Err(e) = result {
+            warn!(
+                "Failed to announce routes to {:?} in {group_id:?}: {e}",
+                address.address
+            );
+        }
+    }
+}
+
+/// Announces our routes in all our groups after they changed.
+pub fn routes_changed<D: GroupDelegate>(session: &Arc<D>) {
+    let group_ids: Vec<_> = session
+        .groups()
+        .read()
+        .iter_with_handles()
+        .map(|(id, _)| GroupId(id))
+        .collect();
+    for group_id in group_ids {
+        tokio::spawn(announce(Arc::clone(session), group_id));
+    }
+}
+
+/// Expires stale routes and announces ours in a group periodically, for as long as the group
+/// lasts.
+pub async fn maintain<D: GroupDelegate>(session: Arc<D>, group_id: GroupId) -> GenericResult<()> {
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if session.routing().write().expire(Instant::now()) {
+            routes_changed(&session);
+        }
+        announce(Arc::clone(&session), group_id).await;
+    }
+}
+
+/// Updates our routes given an announcement from a neighbour, returning whether they changed.
+pub fn apply<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    source: IpAddr,
+    announcement: &RouteAnnouncement,
+    signature: &MaybeInvalidSignature,
+) -> GenericResult<bool> {
+    let Some((peer_id, neighbour)) = neighbour(session, group_id, source) else {
+        return Err(trivial_error!("Routes from unknown neighbour"));
+    };
+    let own_key = session.identity().to_public().key;
+    announcement.verify(&neighbour, &own_key, signature)?;
+    session
+        .routing()
+        .write()
+        .apply(&own_key, peer_id, group_id, &neighbour, announcement)
+}
+
+/// Sends a message to a peer we can't message directly, through the GO of a group we share with
+/// it (see the `relay` module) or through other groups.
+pub async fn message_indirectly<D: GroupDelegate>(
+    session: &D,
+    id: PeerId,
+    message: &[u8],
+    direct_error: Box<dyn std::error::Error + Send + Sync>,
+) -> GenericResult<()> {
+    if let Some(route) = relay::route(session, id) {
+        return relay::send(session, id, route, message).await;
+    }
+    let logical = session
+        .peers()
+        .read()
+        .get(id.0)
+        .and_then(|peer| peer.identity.verified_logical().cloned())
+        .filter(|logical| session.routing().read().route(&logical.key).is_some());
+    match logical {
+        Some(logical) => send(session, &logical.key, message).await,
+        None => Err(direct_error),
+    }
+}
+
+/// Sends a message to a peer through other groups, exchanging end-to-end keys with it first if
+/// needed.
+pub async fn send<D: GroupDelegate>(
+    session: &D,
+    to: &MaybeInvalidPublicKey,
+    message: &[u8],
+) -> GenericResult<()> {
+    let keys = session.routing().read().keys(to);
+    let keys = match keys {
+        Some(keys) => keys,
+        None => {
+            send_key_exchange(session, to, /* reply = */ false).await?;
+            let keys = tokio::time::timeout(relay::KEY_EXCHANGE_TIMEOUT, async {
+                loop {
+                    if let Some(keys) = session.routing().read().keys(to) {
+                        return keys;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await;
+            match keys {
+                Ok(keys) => keys,
+                Err(..) => return Err(trivial_error!("Routed key exchange timed out")),
+            }
+        }
+    };
+    let payload = relay::seal(&keys, message)?;
+    send_envelope(session, to, payload).await
+}
+
+/// Starts (or answers) an end-to-end key exchange with a peer through other groups.
+async fn send_key_exchange<D: GroupDelegate>(
+    session: &D,
+    to: &MaybeInvalidPublicKey,
+    reply: bool,
+) -> GenericResult<()> {
+    let public_key = session
+        .routing()
+        .write()
+        .key_exchange(to)?
+        .export_public_key();
+    send_envelope(session, to, RelayPayload::KeyExchange { public_key, reply }).await
+}
+
+async fn send_envelope<D: GroupDelegate>(
+    session: &D,
+    to: &MaybeInvalidPublicKey,
+    payload: RelayPayload,
+) -> GenericResult<()> {
+    let envelope = RelayEnvelope {
+        from: session.identity().to_public().key,
+        to: to.clone(),
+        payload,
+    };
+    let signature = envelope.sign(session.identity());
+    route_envelope(session, envelope, signature, DEFAULT_TTL, None).await
+}
+
+/// Sends a routed envelope on to the next hop towards its recipient.
+async fn route_envelope<D: GroupDelegate>(
+    session: &D,
+    envelope: RelayEnvelope,
+    signature: MaybeInvalidSignature,
+    ttl: u8,
+    previous_hop: Option<PeerId>,
+) -> GenericResult<()> {
+    let (address, scope_id, version) = {
+        let peers = session.peers().read();
+        let groups = session.groups().read();
+        let routing = session.routing().read();
+        let Some(route) = routing.route(&envelope.to) else {
+            return Err(trivial_error!("No route to peer"));
+        };
+        if previous_hop == Some(route.next_hop) {
+            return Err(trivial_error!("Routing loop"));
+        }
+        let next_hop = groups.get(route.group_id.0).and_then(|group| {
+            let info = group.peers.get(&route.next_hop)?;
+            let peer = peers.get(route.next_hop.0)?;
+            Some((info.address.clone(), group.scope_id, peer.wire_version))
+        });
+        match next_hop {
+            Some(next_hop) => next_hop,
+            None => return Err(trivial_error!("Next hop is gone")),
+        }
+    };
+    session
+        .send_control_message(
+            address.address,
+            address.ports.control,
+            scope_id,
+            version,
+            ControlMessage::Routed {
+                envelope,
+                signature,
+                ttl,
+            },
+        )
+        .await
+}
+
+/// Handles an envelope routed to us by a neighbour, delivering it or passing it on.
+pub async fn handle<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    source: IpAddr,
+    envelope: RelayEnvelope,
+    signature: MaybeInvalidSignature,
+    ttl: u8,
+) -> GenericResult<()> {
+    let Some((previous_hop, _)) = neighbour(session, group_id, source) else {
+        return Err(trivial_error!("Routed envelope from unknown neighbour"));
+    };
+    envelope.verify(&signature)?;
+    if envelope.to == session.identity().to_public().key {
+        return receive(session, group_id, envelope).await;
+    }
+    if ttl <= 1 {
+        return Err(trivial_error!("Routed envelope TTL expired"));
+    }
+    route_envelope(session, envelope, signature, ttl - 1, Some(previous_hop)).await
+}
+
+/// Handles an envelope routed to us.
+async fn receive<D: GroupDelegate>(
+    session: &D,
+    group_id: GroupId,
+    envelope: RelayEnvelope,
+) -> GenericResult<()> {
+    let sender = match session.routing().read().route(&envelope.from) {
+        Some(route) => route.destination.clone(),
+        None => return Err(trivial_error!("Routed envelope from unknown peer")),
+    };
+    match envelope.payload {
+        RelayPayload::KeyExchange { public_key, reply } => {
+            {
+                let mut routing = session.routing().write();
+                let key_exchange = routing.key_exchange(&sender.key)?;
+                // Retries and simultaneous exchanges send the same key more than once.
+                if key_exchange.peer_public_key() != Some(&public_key) {
+                    key_exchange.finish(&public_key, None, None, relay::CIPHER_SUITE)?;
+                }
+            }
+            if !reply {
+                send_key_exchange(session, &sender.key, /* reply = */ true).await?;
+            }
+        }
+        RelayPayload::Message {
+            sequence,
+            ciphertext,
+        } => {
+            let keys = session.routing().read().keys(&sender.key);
+            let Some(keys) = keys else {
+                // The sender has keys we don't (e.g. we restarted), start over so that its next
+                // messages get through.
+                send_key_exchange(session, &sender.key, /* reply = */ false).await?;
+                return Err(trivial_error!("No end-to-end keys for routed message"));
+            };
+            let message = relay::open(&keys, sequence, ciphertext)?;
+            let peer_id = session
+                .peers()
+                .read()
+                .iter_with_handles()
+                .find(|(_, peer)| {
+                    peer.identity
+                        .verified_logical()
+                        .is_some_and(|logical| logical.key == sender.key)
+                })
+                .map(|(id, _)| PeerId(id));
+            match peer_id {
+                Some(peer_id) => {
+                    session.peer_messaged(peer_id, group_id, &message, Delivery::Routed)
+                }
+                None => session.remote_peer_messaged(&sender, &message),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::identity;
+    use handy::HandleMap;
+
+    fn logical_id(nickname: &str) -> LogicalPeerIdentity {
+        identity::new_own_id(nickname.to_owned())
+            .unwrap()
+            .to_public()
+    }
+
+    #[test]
+    fn stale_announcements_cant_undo_withdrawals() {
+        let own = logical_id("own");
+        let neighbour = logical_id("neighbour");
+        let destination = logical_id("destination");
+        let mut handles = HandleMap::new();
+        let neighbour_id = PeerId(handles.insert(()));
+        let group_id = GroupId(handles.insert(()));
+        let announcement = |sequence, hops| RouteAnnouncement {
+            sequence,
+            routes: vec![RouteEntry {
+                destination: destination.clone(),
+                hops,
+            }],
+        };
+        let reachable = announcement(1, 1);
+        let withdrawn = announcement(2, MAX_HOPS);
+        let mut table = RoutingTable::default();
+        let mut apply = |announcement: &RouteAnnouncement| {
+            table.apply(&own.key, neighbour_id, group_id, &neighbour, announcement)
+        };
+        assert!(apply(&reachable).unwrap());
+        assert!(apply(&withdrawn).unwrap());
+        // E.g. replayed, or sent before the withdrawal but delivered after it.
+        assert!(apply(&reachable).is_err());
+        assert!(apply(&withdrawn).is_err());
+        assert_eq!(table.route(&neighbour.key).unwrap().hops, 1);
+        assert!(table.route(&destination.key).is_none());
+    }
+}
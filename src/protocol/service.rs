@@ -0,0 +1,335 @@
+//! Pre-association service discovery.
+//!
+//! Wi-Fi Direct devices can answer DNS-SD (Bonjour) queries before forming any group, so every
+//! session advertises a `SERVICE_TYPE` service, named after the fingerprint of its logical key,
+//! with a TXT record describing it (see `ServiceRecord`). Discovery then queries the devices
+//! around for their services, and only reports the ones the session is interested in (see
+//! `DiscoveryFilter`), so that printers, TVs and devices running other apps don't show up as
+//! peers.
+//!
+//! The records are laid out like Android's `WifiP2pDnsSdServiceInfo` does, so that both back-ends
+//! see each other: a PTR record from the service type to the instance, and a TXT record for the
+//! instance, with the names compressed using the same well-known offsets (there's no actual packet
+//! for the pointers to point into, they just need to match byte for byte).
+//!
+//! The TXT record is only a hint of what to expect from the peer: it isn't signed, and the peer
+//! still needs to associate to prove its logical identity.
+use super::{identity::OwnIdentity, signing::MaybeInvalidPublicKey, wire};
+use crate::{trivial_error, GenericResult};
+
+/// The DNS-SD service type we advertise.
+pub const SERVICE_TYPE: &str = "_ngn._udp";
+
+/// The longest app id we advertise, so that it fits in a TXT record entry.
+pub const MAX_APP_ID_LEN: usize = 200;
+
+/// The number of bytes of the logical key hash in a fingerprint.
+const FINGERPRINT_LEN: usize = 8;
+
+/// Service protocol type of Bonjour in Wi-Fi Direct service discovery TLVs.
+const PROTOCOL_BONJOUR: u8 = 1;
+
+/// Transaction id of our service discovery requests. We only have one outstanding at a time.
+const TRANSACTION_ID: u8 = 1;
+
+/// Status code of successful service discovery responses.
+const STATUS_SUCCESS: u8 = 0;
+
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_TYPE_TXT: u16 = 16;
+
+/// Version of the Bonjour records, as defined by the Wi-Fi Direct spec.
+const BONJOUR_VERSION: u8 = 1;
+
+/// Name suffixes Android replaces with pointers into a packet that doesn't exist.
+const COMPRESSED_SUFFIXES: [(&str, [u8; 2]); 3] = [
+    ("_tcp.local.", [0xc0, 0x0c]),
+    ("local.", [0xc0, 0x11]),
+    ("_udp.local.", [0xc0, 0x1c]),
+];
+
+/// The pointer Android terminates PTR responses with, standing for the name in the query.
+const QUERY_NAME_POINTER: [u8; 2] = [0xc0, 0x27];
+
+/// Returns a short, printable digest of a logical key, to advertise it in service records.
+pub fn fingerprint(key: &MaybeInvalidPublicKey) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, &key.0);
+    digest.as_ref()[..FINGERPRINT_LEN]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// What a device advertises about itself before association.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceRecord {
+    /// The newest wire version the device speaks, see the `wire` module.
+    pub version: u16,
+    /// The fingerprint of the logical key of the device, see `fingerprint`.
+    pub fingerprint: String,
+    /// The app the device runs ngn for.
+    pub app_id: String,
+}
+
+impl ServiceRecord {
+    /// Returns the record we advertise.
+    pub fn ours(identity: &OwnIdentity, app_id: &str) -> GenericResult<Self> {
+        if app_id.len() > MAX_APP_ID_LEN {
+            return Err(trivial_error!("App id too long"));
+        }
+        Ok(Self {
+            version: wire::CURRENT_VERSION,
+            fingerprint: fingerprint(&identity.to_public().key),
+            app_id: app_id.to_owned(),
+        })
+    }
+
+    /// Whether we can talk to the device.
+    pub fn is_compatible(&self) -> bool {
+        self.version >= wire::MIN_VERSION
+    }
+
+    /// The DNS-SD instance name of the service.
+    pub fn instance_name(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Returns the key-value pairs of the TXT record.
+    pub fn to_txt(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("v", self.version.to_string()),
+            ("fp", self.fingerprint.clone()),
+            ("app", self.app_id.clone()),
+        ]
+    }
+
+    /// Parses the key-value pairs of a TXT record. Unknown keys are ignored, so that newer builds
+    /// can advertise more.
+    pub fn from_txt<'a>(txt: impl IntoIterator<Item = (&'a str, &'a str)>) -> GenericResult<Self> {
+        let (mut version, mut fingerprint, mut app_id) = (None, None, None);
+        for (key, value) in txt {
+            match key {
+                "v" => version = Some(value.parse()?),
+                "fp" => fingerprint = Some(value.to_owned()),
+                "app" => app_id = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        match (version, fingerprint, app_id) {
+            (Some(version), Some(fingerprint), Some(app_id)) => Ok(Self {
+                version,
+                fingerprint,
+                app_id,
+            }),
+            _ => Err(trivial_error!("Incomplete service record")),
+        }
+    }
+
+    /// Returns the Bonjour records to register with the Wi-Fi Direct stack, as (query, response)
+    /// pairs: the PTR record from the service type to the instance, and the TXT record of the
+    /// instance.
+    pub fn bonjour_records(&self) -> GenericResult<[(Vec<u8>, Vec<u8>); 2]> {
+        let instance = self.instance_name();
+        let ptr_query = bonjour_query(&format!("{SERVICE_TYPE}.local."), DNS_TYPE_PTR);
+        let mut ptr_response = vec![instance.len() as u8];
+        ptr_response.extend_from_slice(instance.as_bytes());
+        ptr_response.extend_from_slice(&QUERY_NAME_POINTER);
+
+        let txt_query = bonjour_query(
+            &format!("{instance}.{SERVICE_TYPE}.local.").to_lowercase(),
+            DNS_TYPE_TXT,
+        );
+        let mut txt_response = vec![];
+        for (key, value) in self.to_txt() {
+            let entry = format!("{key}={value}");
+            let Ok(len) = u8::try_from(entry.len()) else {
+                return Err(trivial_error!("TXT record entry too long"));
+            };
+            txt_response.push(len);
+            txt_response.extend_from_slice(entry.as_bytes());
+        }
+        Ok([(ptr_query, ptr_response), (txt_query, txt_response)])
+    }
+}
+
+/// Which discovered devices a session reports as peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DiscoveryFilter {
+    /// All of them, whether they run ngn or not.
+    #[default]
+    All,
+    /// The ones that run a compatible version of ngn, for any app.
+    Ngn,
+    /// The ones that run a compatible version of ngn for one of these apps.
+    Apps(Vec<String>),
+}
+
+impl DiscoveryFilter {
+    /// Whether to report a device advertising the given service, if any.
+    pub fn accepts(&self, service: Option<&ServiceRecord>) -> bool {
+        match self {
+            Self::All => true,
+            Self::Ngn => service.is_some_and(ServiceRecord::is_compatible),
+            Self::Apps(apps) => {
+                service.is_some_and(|s| s.is_compatible() && apps.contains(&s.app_id))
+            }
+        }
+    }
+}
+
+/// Encodes a DNS name the way Android does, see the module docs.
+fn encode_dns_name(mut name: &str, out: &mut Vec<u8>) {
+    loop {
+        if let Some((_, pointer)) = COMPRESSED_SUFFIXES.iter().find(|(s, _)| *s == name) {
+            out.extend_from_slice(pointer);
+            return;
+        }
+        let Some((label, rest)) = name.split_once('.') else {
+            if !name.is_empty() {
+                out.push(name.len() as u8);
+                out.extend_from_slice(name.as_bytes());
+            }
+            out.push(0);
+            return;
+        };
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+        name = rest;
+    }
+}
+
+fn bonjour_query(name: &str, dns_type: u16) -> Vec<u8> {
+    let mut query = vec![];
+    encode_dns_name(name, &mut query);
+    query.extend_from_slice(&dns_type.to_be_bytes());
+    query.push(BONJOUR_VERSION);
+    query
+}
+
+/// Returns the service discovery request TLV for all the Bonjour services of a device. We can't
+/// query the TXT record directly without knowing the instance name, so we filter the responses
+/// instead, see `parse_bonjour_response`.
+pub fn bonjour_request() -> Vec<u8> {
+    let mut tlv = 2u16.to_le_bytes().to_vec();
+    tlv.extend_from_slice(&[PROTOCOL_BONJOUR, TRANSACTION_ID]);
+    tlv
+}
+
+/// Decodes a DNS name encoded like `encode_dns_name` does, returning it and the rest of the data.
+fn decode_dns_name(mut data: &[u8]) -> Option<(String, &[u8])> {
+    let mut name = String::new();
+    loop {
+        let (&len, rest) = data.split_first()?;
+        if len == 0 {
+            return Some((name, rest));
+        }
+        if len & 0xc0 == 0xc0 {
+            let pointer = [len, *rest.first()?];
+            let (suffix, _) = COMPRESSED_SUFFIXES.iter().find(|(_, p)| *p == pointer)?;
+            name.push_str(suffix);
+            return Some((name, &rest[1..]));
+        }
+        let label = rest.get(..len as usize)?;
+        name.push_str(std::str::from_utf8(label).ok()?);
+        name.push('.');
+        data = &rest[len as usize..];
+    }
+}
+
+/// Parses the record of a Bonjour response, returning our service record if it's ours.
+fn parse_bonjour_record(data: &[u8]) -> Option<ServiceRecord> {
+    let (name, rest) = decode_dns_name(data)?;
+    let suffix = format!(".{SERVICE_TYPE}.local.");
+    if !name.ends_with(&suffix) {
+        return None;
+    }
+    let (header, mut txt) = rest.split_at_checked(3)?;
+    if u16::from_be_bytes([header[0], header[1]]) != DNS_TYPE_TXT || header[2] != BONJOUR_VERSION {
+        return None;
+    }
+    let mut entries = vec![];
+    while let Some((&len, rest)) = txt.split_first() {
+        let entry = std::str::from_utf8(rest.get(..len as usize)?).ok()?;
+        entries.push(entry.split_once('=').unwrap_or((entry, "")));
+        txt = &rest[len as usize..];
+    }
+    ServiceRecord::from_txt(entries).ok()
+}
+
+/// Finds our service record in the TLVs of a service discovery response, if the device
+/// advertises it.
+pub fn parse_bonjour_response(mut tlvs: &[u8]) -> Option<ServiceRecord> {
+    while let Some((header, rest)) = tlvs.split_at_checked(2) {
+        let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let tlv = rest.get(..len)?;
+        tlvs = &rest[len..];
+        let Some((&[protocol, _transaction_id, status], data)) = tlv.split_first_chunk::<3>()
+        else {
+            continue;
+        };
+        if protocol != PROTOCOL_BONJOUR || status != STATUS_SUCCESS {
+            continue;
+        }
+        if let Some(record) = parse_bonjour_record(data) {
+            return Some(record);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> ServiceRecord {
+        ServiceRecord {
+            version: 1,
+            fingerprint: "0123456789abcdef".to_owned(),
+            app_id: "chat".to_owned(),
+        }
+    }
+
+    /// Wraps the records like a successful response from a device would.
+    fn response(records: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut tlvs = vec![];
+        for (query, data) in records {
+            let len = 3 + query.len() + data.len();
+            tlvs.extend_from_slice(&(len as u16).to_le_bytes());
+            tlvs.extend_from_slice(&[PROTOCOL_BONJOUR, TRANSACTION_ID, STATUS_SUCCESS]);
+            tlvs.extend_from_slice(query);
+            tlvs.extend_from_slice(data);
+        }
+        tlvs
+    }
+
+    #[test]
+    fn bonjour_round_trip() {
+        let record = record();
+        let records = record.bonjour_records().unwrap();
+        assert_eq!(parse_bonjour_response(&response(&records)), Some(record));
+    }
+
+    #[test]
+    fn bonjour_records_vector() {
+        let [(ptr_query, ptr_response), (txt_query, txt_response)] =
+            record().bonjour_records().unwrap();
+        assert_eq!(ptr_query, b"\x04_ngn\xc0\x1c\x00\x0c\x01");
+        assert_eq!(ptr_response, b"\x100123456789abcdef\xc0\x27");
+        assert_eq!(
+            txt_query,
+            b"\x100123456789abcdef\x04_ngn\xc0\x1c\x00\x10\x01"
+        );
+        assert_eq!(txt_response, b"\x03v=1\x13fp=0123456789abcdef\x08app=chat");
+    }
+
+    #[test]
+    fn bonjour_response_ignores_others() {
+        let other = (
+            b"\x07printer\x04_ipp\xc0\x0c\x00\x10\x01".to_vec(),
+            b"\x03v=1".to_vec(),
+        );
+        let [ptr, _] = record().bonjour_records().unwrap();
+        assert_eq!(parse_bonjour_response(&response(&[other, ptr])), None);
+        assert_eq!(parse_bonjour_response(&[0xff, 0x00]), None);
+    }
+}
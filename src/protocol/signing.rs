@@ -14,7 +14,7 @@ pub const PUBLIC_KEY_LEN: usize = signature::ED25519_PUBLIC_KEY_LEN;
 /// TODO(emilio): This is not exposed by ring, seems unfortunate.
 pub const SIGNATURE_LEN: usize = 64;
 
-#[derive(Encode, Decode, Debug, Eq, PartialEq, Clone)]
+#[derive(Encode, Decode, Debug, Eq, PartialEq, Hash, Clone)]
 pub struct MaybeInvalidPublicKey(pub [u8; PUBLIC_KEY_LEN]);
 
 #[derive(Debug, Eq, PartialEq, Clone)]
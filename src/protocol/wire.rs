@@ -0,0 +1,1266 @@
+//! The ngn wire format.
+//!
+//! # Frames
+//!
+//! Everything ngn sends over TCP (control messages, connection handshakes and peer records) is
+//! framed the same way, with all integers in big endian:
+//!
+//! ```text
+//!   magic: u16 = 0xdead
+//!   version: u16
+//!   length: u32
+//!   signature: [u8; 64]   (peer records only, Ed25519 over the body)
+//!   body: [u8; length]
+//! ```
+//!
+//! The version describes how the body of that frame is encoded, and receivers accept any version
+//! between `MIN_VERSION` and `CURRENT_VERSION`, so every frame can be decoded on its own:
+//!
+//!  * Version 1 is the legacy encoding: the body is the `bincode` (standard configuration)
+//!    serialization of the Rust message types of the builds that predate version 2, which the
+//!    `legacy` module keeps a frozen copy of. It is only kept for compatibility with those builds:
+//!    it depends on the declaration order of fields and variants, and can only express `Associate`
+//!    control messages, without any of the fields added since. Those builds don't authenticate
+//!    their connections, multiplex them, nor send datagrams, so we don't either with peers that
+//!    associated using version 1 (see `Capabilities::version_1` and the `handshake` module).
+//!  * Version 2 is the TLV encoding described below.
+//!
+//! Peer record bodies are encrypted (AES-256-GCM or ChaCha20-Poly1305, as negotiated during
+//! association, see `Capabilities::cipher_suite` and the `encryption` module), and the plaintext
+//! is either an application message, or a multiplexer frame if both peers support multiplexing.
+//! If both peers support compression and padding, the plaintext is wrapped as described in the
+//! `compression` and `padding` modules, in that order.
+//!
+//! # Version negotiation
+//!
+//! A client joining a group sends its `Associate` message to the group owner using
+//! `CURRENT_VERSION`. The group owner replies with its own `Associate` using the version the
+//! client's message was encoded with, and both ends remember it as the version to use for that
+//! peer from then on. Older group owners can't decode the message and drop it, so if the client
+//! doesn't hear back in `ASSOCIATION_TIMEOUT` it retries with `MIN_VERSION`.
+//!
+//! # TLV encoding (version 2)
+//!
+//! All integers are unsigned LEB128 varints (at most 10 bytes, i.e. 64 bits). A message is its
+//! type followed by a list of fields until the end of the body:
+//!
+//! ```text
+//!   message = type:varint field*
+//!   field   = tag:varint length:varint value:[u8; length]
+//! ```
+//!
+//! Field values are either:
+//!
+//!  * integers: a varint, which must take exactly `length` bytes,
+//!  * booleans: an integer that must be 0 or 1,
+//!  * bytes / strings: raw bytes / UTF-8,
+//!  * nested structures: a list of fields (without a type),
+//!  * lists: a sequence of `length:varint value` entries, each of them a nested structure.
+//!
+//! Receivers ignore fields with unknown tags, so new optional fields can be added without bumping
+//! the version. Missing required fields, repeated tags, and unknown message types are errors.
+//!
+//! ## Message types
+//!
+//! | Type | Message                                | Fields                                   |
+//! |------|----------------------------------------|------------------------------------------|
+//! | 1    | `ControlMessage::Associate`            | 1: physical id (nested), 2: logical id (nested), 3: ports (nested), 4: key exchange public key (32 bytes), 5: multiplexing (bool, same as in the capabilities), 6: capabilities (nested, optional), 7: ML-KEM-768 encapsulation key (1184 bytes, optional), 8: ML-KEM-768 ciphertext (1088 bytes, optional, exclusive with 7), 9: resumption ticket (16 bytes, optional) |
+//! | 2    | `ControlMessage::Roster`               | 1: sequence, 2: members (list of members, snapshots only), 3: joined members (list of members, optional), 4: left members (list of logical ids, optional), 5: signature (64 bytes) |
+//! | 3    | `ControlMessage::Relay`                | 1: sender logical key (32 bytes), 2: recipient logical key (32 bytes), 3: key exchange public key (32 bytes), 4: reply (bool, optional, only with 3), 5: sequence (only with 6), 6: ciphertext (exclusive with 3), 7: signature (64 bytes) |
+//! | 4    | `ControlMessage::Routes`               | 1: sequence, 2: routes (list of routes, optional), 3: signature (64 bytes) |
+//! | 5    | `ControlMessage::Routed`               | 1-7: as in `Relay`, 8: TTL               |
+//! | 16   | `HandshakeMessage::Challenge`          | 1: nonce (32 bytes)                      |
+//! | 17   | `HandshakeMessage::Authenticate`       | 1: logical key (32 bytes), 2: nonce (32 bytes), 3: proof (32 bytes) |
+//! | 18   | `HandshakeMessage::Accept`             | 1: proof (32 bytes)                      |
+//! | 32   | `Frame::Open`                          | 1: stream                                |
+//! | 33   | `Frame::Data`                          | 1: stream, 2: end of message (bool), 3: payload |
+//! | 34   | `Frame::WindowUpdate`                  | 1: stream, 2: increment                  |
+//! | 35   | `Frame::Close`                         | 1: stream                                |
+//! | 36   | `Frame::Reset`                         | 1: stream                                |
+//!
+//! Nested structures:
+//!
+//!  * Physical id: exactly one of 1: device name (string), 2: device address (6 or 8 bytes).
+//!  * Logical id: 1: nickname (string), 2: Ed25519 public key (32 bytes).
+//!  * Ports: 1: control, 2: p2p, 3: datagram.
+//!  * Roster member: 1: physical id (nested), 2: logical id (nested), 3: IP address (4 or 16
+//!    bytes), 4: ports (nested).
+//!  * Route: 1: destination logical id (nested), 2: hops.
+//!  * Beacon: 1: logical id (nested), 2: capabilities (nested), 3: signature (64 bytes), see the
+//!    `beacon` module. Beacons aren't messages, they're sent on their own in probe frames.
+//!  * Capabilities: 1: multiplexing, 2: datagrams, 3: compression, 4: relay, 5: file transfer, 9:
+//!    ordered delivery, 10: padding, 11: ChaCha20-Poly1305, 12: hardware AES, 13: roster, 14:
+//!    routing (all optional booleans, false if missing), 6: max frame size, 7: min version, 8: max
+//!    version.
+//!    Peers that don't send capabilities are assumed to support what version 2 builds without
+//!    them did, see `Capabilities::legacy`.
+//!
+//! ## Test vectors
+//!
+//! An `Associate` from a device named `ngn`, with logical identity nickname `alice`, a logical key
+//! of 32 `0x11` bytes, a key exchange public key of 32 `0x22` bytes, ports 1000 (control), 1001
+//! (p2p) and 1002 (datagram), and the default capabilities (multiplexing, datagrams, compression,
+//! ordered delivery, padding, ChaCha20-Poly1305, rosters, relaying and routing, 16MiB frames,
+//! versions 1 to 2) of a device without AES instructions, encoded in version 2 (body only):
+//!
+//! ```text
+//!   01                                              type: Associate
+//!   01 05 01 03 6e676e                              physical id: name "ngn"
+//!   02 29 01 05 616c696365                          logical id: nickname "alice",
+//!         02 20 1111111111111111111111111111111111111111111111111111111111111111  key
+//!   03 0c 01 02 e807 02 02 e907 03 02 ea07          ports: 1000, 1001, 1002
+//!   04 20 2222222222222222222222222222222222222222222222222222222222222222  key exchange key
+//!   05 01 01                                        multiplexing: true
+//!   06 2d 01 01 01 02 01 01                         capabilities: multiplexing, datagrams,
+//!         03 01 01 04 01 01 05 01 00                compression, relaying, no file transfer,
+//!         06 04 80808008 07 01 01 08 01 02          max frame size, versions 1 to 2,
+//!         09 01 01 0a 01 01                         ordered delivery, padding,
+//!         0b 01 01 0c 01 00                         ChaCha20-Poly1305, no hardware AES,
+//!         0d 01 01 0e 01 01                         rosters, routing
+//! ```
+//!
+//! The same `Associate` in version 1, which drops the datagram port and the capabilities:
+//!
+//! ```text
+//!   00                                              variant: Associate
+//!   00 03 6e676e                                    physical id: name "ngn"
+//!   05 616c696365                                   logical id: nickname "alice",
+//!      1111111111111111111111111111111111111111111111111111111111111111  key
+//!   fb e803 fb e903                                 ports: 1000, 1001
+//!   2222222222222222222222222222222222222222222222222222222222222222  key exchange key
+//! ```
+//!
+//! A `Frame::Data` with the message `hello` on the control priority messages stream, ending the
+//! message:
+//!
+//! ```text
+//!   21 01 01 01 02 01 01 03 05 68656c6c6f
+//! ```
+use super::{
+    beacon::{Beacon, SignedBeacon},
+    capabilities::Capabilities,
+    key_exchange::PostQuantumShare,
+    relay::{RelayEnvelope, RelayPayload},
+    roster::{RosterChange, RosterMember, RosterUpdate},
+    routing::{RouteAnnouncement, RouteEntry},
+    signing::MaybeInvalidSignature,
+};
+use crate::{trivial_error, GenericResult};
+use bincode::{Decode, Encode};
+use std::net::IpAddr;
+
+/// The oldest wire version we can still talk.
+pub const MIN_VERSION: u16 = 1;
+
+/// The wire version we prefer, see the module docs.
+pub const CURRENT_VERSION: u16 = 2;
+
+/// How long a client waits for the group owner to associate back before retrying with an older
+/// wire version.
+pub const ASSOCIATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Maximum length of a LEB128-encoded u64.
+const MAX_VARINT_LEN: usize = 10;
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> GenericResult<u64> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let Some((&byte, rest)) = buf.split_first() else {
+            return Err(trivial_error!("Truncated varint"));
+        };
+        *buf = rest;
+        let bits = u64::from(byte & 0x7f);
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(trivial_error!("Varint overflow"));
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(trivial_error!("Varint too long"))
+}
+
+/// Writes the fields of a TLV message or nested structure.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn bytes(&mut self, tag: u64, value: &[u8]) {
+        write_varint(&mut self.buf, tag);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    pub fn uint(&mut self, tag: u64, value: u64) {
+        let mut encoded = Vec::with_capacity(MAX_VARINT_LEN);
+        write_varint(&mut encoded, value);
+        self.bytes(tag, &encoded);
+    }
+
+    pub fn bool(&mut self, tag: u64, value: bool) {
+        self.uint(tag, u64::from(value));
+    }
+
+    pub fn string(&mut self, tag: u64, value: &str) {
+        self.bytes(tag, value.as_bytes());
+    }
+
+    pub fn nested(&mut self, tag: u64, value: &impl WireFields) {
+        self.bytes(tag, &encode_fields(value));
+    }
+
+    pub fn list<T: WireFields>(&mut self, tag: u64, values: &[T]) {
+        let mut encoded = vec![];
+        for value in values {
+            let value = encode_fields(value);
+            write_varint(&mut encoded, value.len() as u64);
+            encoded.extend_from_slice(&value);
+        }
+        self.bytes(tag, &encoded);
+    }
+}
+
+/// Encodes the fields of a nested structure on their own.
+pub fn encode_fields(value: &impl WireFields) -> Vec<u8> {
+    let mut writer = Writer::default();
+    value.encode_fields(&mut writer);
+    writer.buf
+}
+
+/// Decodes the fields of a nested structure on their own.
+pub fn decode_fields<T: WireFields>(buf: &[u8]) -> GenericResult<T> {
+    T::decode_fields(&Fields::parse(buf)?)
+}
+
+/// The fields of a TLV message or nested structure, as read from the wire.
+#[derive(Debug)]
+pub struct Fields<'a> {
+    /// The fields, sorted by tag.
+    fields: Vec<(u64, &'a [u8])>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn parse(mut buf: &'a [u8]) -> GenericResult<Self> {
+        let mut fields: Vec<(u64, &[u8])> = vec![];
+        while !buf.is_empty() {
+            let tag = read_varint(&mut buf)?;
+            let len = read_varint(&mut buf)?;
+            let Some(len) = usize::try_from(len).ok().filter(|len| *len <= buf.len()) else {
+                return Err(trivial_error!("Truncated field"));
+            };
+            let (value, rest) = buf.split_at(len);
+            fields.push((tag, value));
+            buf = rest;
+        }
+        fields.sort_by_key(|(tag, _)| *tag);
+        if fields.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(trivial_error!("Repeated field"));
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn get(&self, tag: u64) -> Option<&'a [u8]> {
+        let index = self.fields.binary_search_by_key(&tag, |(t, _)| *t).ok()?;
+        Some(self.fields[index].1)
+    }
+
+    pub fn has(&self, tag: u64) -> bool {
+        self.get(tag).is_some()
+    }
+
+    pub fn bytes(&self, tag: u64) -> GenericResult<&'a [u8]> {
+        self.get(tag)
+            .ok_or_else(|| trivial_error!("Missing required field"))
+    }
+
+    pub fn array<const N: usize>(&self, tag: u64) -> GenericResult<[u8; N]> {
+        self.bytes(tag)?
+            .try_into()
+            .map_err(|_| trivial_error!("Wrong field length"))
+    }
+
+    pub fn uint(&self, tag: u64) -> GenericResult<u64> {
+        let mut value = self.bytes(tag)?;
+        let result = read_varint(&mut value)?;
+        if !value.is_empty() {
+            return Err(trivial_error!("Trailing data in integer field"));
+        }
+        Ok(result)
+    }
+
+    pub fn u8(&self, tag: u64) -> GenericResult<u8> {
+        u8::try_from(self.uint(tag)?).map_err(|_| trivial_error!("Integer field out of range"))
+    }
+
+    pub fn u16(&self, tag: u64) -> GenericResult<u16> {
+        u16::try_from(self.uint(tag)?).map_err(|_| trivial_error!("Integer field out of range"))
+    }
+
+    pub fn u32(&self, tag: u64) -> GenericResult<u32> {
+        u32::try_from(self.uint(tag)?).map_err(|_| trivial_error!("Integer field out of range"))
+    }
+
+    pub fn bool(&self, tag: u64) -> GenericResult<bool> {
+        match self.uint(tag)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(trivial_error!("Invalid boolean field")),
+        }
+    }
+
+    /// An optional boolean field, which is false if missing.
+    pub fn flag(&self, tag: u64) -> GenericResult<bool> {
+        if !self.has(tag) {
+            return Ok(false);
+        }
+        self.bool(tag)
+    }
+
+    pub fn string(&self, tag: u64) -> GenericResult<String> {
+        Ok(std::str::from_utf8(self.bytes(tag)?)?.to_owned())
+    }
+
+    pub fn nested<T: WireFields>(&self, tag: u64) -> GenericResult<T> {
+        T::decode_fields(&Fields::parse(self.bytes(tag)?)?)
+    }
+
+    /// An optional list field, which is empty if missing.
+    pub fn list<T: WireFields>(&self, tag: u64) -> GenericResult<Vec<T>> {
+        let mut buf = self.get(tag).unwrap_or_default();
+        let mut values = vec![];
+        while !buf.is_empty() {
+            let len = read_varint(&mut buf)?;
+            let Some(len) = usize::try_from(len).ok().filter(|len| *len <= buf.len()) else {
+                return Err(trivial_error!("Truncated list entry"));
+            };
+            let (value, rest) = buf.split_at(len);
+            values.push(T::decode_fields(&Fields::parse(value)?)?);
+            buf = rest;
+        }
+        Ok(values)
+    }
+
+    pub fn ip_addr(&self, tag: u64) -> GenericResult<IpAddr> {
+        let addr = self.bytes(tag)?;
+        if let Ok(addr) = <[u8; 4]>::try_from(addr) {
+            return Ok(addr.into());
+        }
+        if let Ok(addr) = <[u8; 16]>::try_from(addr) {
+            return Ok(addr.into());
+        }
+        Err(trivial_error!("Wrong IP address length"))
+    }
+}
+
+/// A structure that can be encoded as a list of TLV fields.
+pub trait WireFields: Sized {
+    fn encode_fields(&self, writer: &mut Writer);
+    fn decode_fields(fields: &Fields) -> GenericResult<Self>;
+}
+
+/// A top-level message that can be sent in any supported wire version.
+pub trait WireMessage: Sized {
+    /// The version 1 layout of the message, see the `legacy` module.
+    type Legacy: Encode + Decode<()>;
+    /// The TLV message type of this message.
+    fn message_type(&self) -> u64;
+    fn encode_fields(&self, writer: &mut Writer);
+    fn decode_fields(message_type: u64, fields: &Fields) -> GenericResult<Self>;
+    /// Converts the message to its version 1 layout, failing if it can't be expressed in it.
+    fn to_legacy(&self) -> GenericResult<Self::Legacy>;
+    fn from_legacy(legacy: Self::Legacy) -> GenericResult<Self>;
+}
+
+/// Encodes a message body for the given wire version.
+pub fn encode<T: WireMessage>(version: u16, message: &T) -> GenericResult<Vec<u8>> {
+    match version {
+        1 => Ok(bincode::encode_to_vec(
+            message.to_legacy()?,
+            bincode::config::standard(),
+        )?),
+        2 => {
+            let mut writer = Writer::default();
+            write_varint(&mut writer.buf, message.message_type());
+            message.encode_fields(&mut writer);
+            Ok(writer.buf)
+        }
+        _ => Err(trivial_error!("Unsupported wire version")),
+    }
+}
+
+/// Decodes a message body encoded with the given wire version.
+pub fn decode<T: WireMessage>(version: u16, mut buf: &[u8]) -> GenericResult<T> {
+    match version {
+        1 => T::from_legacy(super::decode_message(buf)?),
+        2 => {
+            let message_type = read_varint(&mut buf)?;
+            T::decode_fields(message_type, &Fields::parse(buf)?)
+        }
+        _ => Err(trivial_error!("Unsupported wire version")),
+    }
+}
+
+mod tags {
+    pub const ASSOCIATE: u64 = 1;
+    pub const ROSTER: u64 = 2;
+    pub const RELAY: u64 = 3;
+    pub const ROUTES: u64 = 4;
+    pub const ROUTED: u64 = 5;
+
+    pub mod associate {
+        pub const PHYSICAL_ID: u64 = 1;
+        pub const LOGICAL_ID: u64 = 2;
+        pub const PORTS: u64 = 3;
+        pub const KEY_EXCHANGE_PUBLIC_KEY: u64 = 4;
+        pub const MULTIPLEXING: u64 = 5;
+        pub const CAPABILITIES: u64 = 6;
+        pub const ML_KEM_ENCAPSULATION_KEY: u64 = 7;
+        pub const ML_KEM_CIPHERTEXT: u64 = 8;
+        pub const RESUMPTION_TICKET: u64 = 9;
+    }
+
+    pub mod roster {
+        pub const SEQUENCE: u64 = 1;
+        pub const MEMBERS: u64 = 2;
+        pub const JOINED: u64 = 3;
+        pub const LEFT: u64 = 4;
+        pub const SIGNATURE: u64 = 5;
+    }
+
+    pub mod relay {
+        pub const FROM: u64 = 1;
+        pub const TO: u64 = 2;
+        pub const KEY_EXCHANGE_PUBLIC_KEY: u64 = 3;
+        pub const REPLY: u64 = 4;
+        pub const SEQUENCE: u64 = 5;
+        pub const CIPHERTEXT: u64 = 6;
+        pub const SIGNATURE: u64 = 7;
+    }
+
+    pub mod routes {
+        pub const SEQUENCE: u64 = 1;
+        pub const ROUTES: u64 = 2;
+        pub const SIGNATURE: u64 = 3;
+    }
+
+    pub mod routed {
+        // The envelope and signature use the `relay` tags.
+        pub const TTL: u64 = 8;
+    }
+
+    pub mod route {
+        pub const DESTINATION: u64 = 1;
+        pub const HOPS: u64 = 2;
+    }
+
+    pub mod roster_member {
+        pub const PHYSICAL_ID: u64 = 1;
+        pub const LOGICAL_ID: u64 = 2;
+        pub const ADDRESS: u64 = 3;
+        pub const PORTS: u64 = 4;
+    }
+
+    pub mod beacon {
+        pub const LOGICAL_ID: u64 = 1;
+        pub const CAPABILITIES: u64 = 2;
+        pub const SIGNATURE: u64 = 3;
+    }
+
+    pub mod physical_id {
+        pub const NAME: u64 = 1;
+        pub const DEV_ADDR: u64 = 2;
+    }
+
+    pub mod logical_id {
+        pub const NICKNAME: u64 = 1;
+        pub const KEY: u64 = 2;
+    }
+
+    pub mod ports {
+        pub const CONTROL: u64 = 1;
+        pub const P2P: u64 = 2;
+        pub const DATAGRAM: u64 = 3;
+    }
+
+    pub mod capabilities {
+        pub const MULTIPLEXING: u64 = 1;
+        pub const DATAGRAMS: u64 = 2;
+        pub const COMPRESSION: u64 = 3;
+        pub const RELAY: u64 = 4;
+        pub const FILE_TRANSFER: u64 = 5;
+        pub const MAX_FRAME_SIZE: u64 = 6;
+        pub const MIN_VERSION: u64 = 7;
+        pub const MAX_VERSION: u64 = 8;
+        pub const ORDERED_DELIVERY: u64 = 9;
+        pub const PADDING: u64 = 10;
+        pub const CHACHA20_POLY1305: u64 = 11;
+        pub const HARDWARE_AES: u64 = 12;
+        pub const ROSTER: u64 = 13;
+        pub const ROUTING: u64 = 14;
+    }
+}
+
+impl WireFields for super::PeerOwnIdentifier {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::physical_id::*;
+        match *self {
+            Self::Name(ref name) => writer.string(NAME, name),
+            Self::DevAddr(ref addr) => writer.bytes(
+                DEV_ADDR,
+                if addr.is_v8 {
+                    &addr.bytes
+                } else {
+                    &addr.bytes[..6]
+                },
+            ),
+        }
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::physical_id::*;
+        if fields.has(NAME) == fields.has(DEV_ADDR) {
+            return Err(trivial_error!("Expected exactly one physical identifier"));
+        }
+        if fields.has(NAME) {
+            return Ok(Self::Name(fields.string(NAME)?));
+        }
+        let addr = fields.bytes(DEV_ADDR)?;
+        let mut bytes = [0u8; 8];
+        let is_v8 = match addr.len() {
+            6 => false,
+            8 => true,
+            _ => return Err(trivial_error!("Wrong device address length")),
+        };
+        bytes[..addr.len()].copy_from_slice(addr);
+        Ok(Self::DevAddr(super::DecodableMacAddr { is_v8, bytes }))
+    }
+}
+
+impl WireFields for super::identity::LogicalPeerIdentity {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::logical_id::*;
+        writer.string(NICKNAME, &self.nickname);
+        writer.bytes(KEY, &self.key.0);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::logical_id::*;
+        Ok(Self {
+            nickname: fields.string(NICKNAME)?,
+            key: super::signing::MaybeInvalidPublicKey(fields.array(KEY)?),
+        })
+    }
+}
+
+impl WireFields for super::P2pPorts {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::ports::*;
+        writer.uint(CONTROL, self.control.into());
+        writer.uint(P2P, self.p2p.into());
+        writer.uint(DATAGRAM, self.datagram.into());
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::ports::*;
+        Ok(Self {
+            control: fields.u16(CONTROL)?,
+            p2p: fields.u16(P2P)?,
+            datagram: fields.u16(DATAGRAM)?,
+        })
+    }
+}
+
+impl WireFields for super::capabilities::Capabilities {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::capabilities::*;
+        writer.bool(MULTIPLEXING, self.multiplexing);
+        writer.bool(DATAGRAMS, self.datagrams);
+        writer.bool(COMPRESSION, self.compression);
+        writer.bool(RELAY, self.relay);
+        writer.bool(FILE_TRANSFER, self.file_transfer);
+        writer.uint(MAX_FRAME_SIZE, self.max_frame_size.into());
+        writer.uint(MIN_VERSION, self.min_version.into());
+        writer.uint(MAX_VERSION, self.max_version.into());
+        writer.bool(ORDERED_DELIVERY, self.ordered_delivery);
+        writer.bool(PADDING, self.padding);
+        writer.bool(CHACHA20_POLY1305, self.chacha20_poly1305);
+        writer.bool(HARDWARE_AES, self.hardware_aes);
+        writer.bool(ROSTER, self.roster);
+        writer.bool(ROUTING, self.routing);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::capabilities::*;
+        Ok(Self {
+            multiplexing: fields.flag(MULTIPLEXING)?,
+            datagrams: fields.flag(DATAGRAMS)?,
+            compression: fields.flag(COMPRESSION)?,
+            ordered_delivery: fields.flag(ORDERED_DELIVERY)?,
+            padding: fields.flag(PADDING)?,
+            chacha20_poly1305: fields.flag(CHACHA20_POLY1305)?,
+            hardware_aes: fields.flag(HARDWARE_AES)?,
+            roster: fields.flag(ROSTER)?,
+            relay: fields.flag(RELAY)?,
+            routing: fields.flag(ROUTING)?,
+            file_transfer: fields.flag(FILE_TRANSFER)?,
+            max_frame_size: fields.u32(MAX_FRAME_SIZE)?,
+            min_version: fields.u16(MIN_VERSION)?,
+            max_version: fields.u16(MAX_VERSION)?,
+        })
+    }
+}
+
+impl WireFields for RosterMember {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::roster_member::*;
+        writer.nested(PHYSICAL_ID, &self.physical_id);
+        writer.nested(LOGICAL_ID, &self.logical_id);
+        match self.address {
+            IpAddr::V4(addr) => writer.bytes(ADDRESS, &addr.octets()),
+            IpAddr::V6(addr) => writer.bytes(ADDRESS, &addr.octets()),
+        }
+        writer.nested(PORTS, &self.ports);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::roster_member::*;
+        Ok(Self {
+            physical_id: fields.nested(PHYSICAL_ID)?,
+            logical_id: fields.nested(LOGICAL_ID)?,
+            address: fields.ip_addr(ADDRESS)?,
+            ports: fields.nested(PORTS)?,
+        })
+    }
+}
+
+/// Only encodes the update itself, the signature goes along with it in `ControlMessage::Roster`.
+impl WireFields for RosterUpdate {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::roster::*;
+        writer.uint(SEQUENCE, self.sequence);
+        match self.change {
+            RosterChange::Snapshot(ref members) => writer.list(MEMBERS, members),
+            RosterChange::Delta {
+                ref joined,
+                ref left,
+            } => {
+                writer.list(JOINED, joined);
+                writer.list(LEFT, left);
+            }
+        }
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::roster::*;
+        let change = if fields.has(MEMBERS) {
+            if fields.has(JOINED) || fields.has(LEFT) {
+                return Err(trivial_error!("Conflicting roster fields"));
+            }
+            RosterChange::Snapshot(fields.list(MEMBERS)?)
+        } else {
+            RosterChange::Delta {
+                joined: fields.list(JOINED)?,
+                left: fields.list(LEFT)?,
+            }
+        };
+        Ok(Self {
+            sequence: fields.uint(SEQUENCE)?,
+            change,
+        })
+    }
+}
+
+/// Only encodes the envelope itself, the signature goes along with it in `ControlMessage::Relay`.
+impl WireFields for RelayEnvelope {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::relay::*;
+        writer.bytes(FROM, &self.from.0);
+        writer.bytes(TO, &self.to.0);
+        match self.payload {
+            RelayPayload::KeyExchange {
+                ref public_key,
+                reply,
+            } => {
+                writer.bytes(KEY_EXCHANGE_PUBLIC_KEY, &public_key.0);
+                writer.bool(REPLY, reply);
+            }
+            RelayPayload::Message {
+                sequence,
+                ref ciphertext,
+            } => {
+                writer.uint(SEQUENCE, sequence);
+                writer.bytes(CIPHERTEXT, ciphertext);
+            }
+        }
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::relay::*;
+        let payload = match (fields.has(KEY_EXCHANGE_PUBLIC_KEY), fields.has(CIPHERTEXT)) {
+            (true, false) => RelayPayload::KeyExchange {
+                public_key: super::key_exchange::MaybeInvalidPublicKey(
+                    fields.array(KEY_EXCHANGE_PUBLIC_KEY)?,
+                ),
+                reply: fields.flag(REPLY)?,
+            },
+            (false, true) => RelayPayload::Message {
+                sequence: fields.uint(SEQUENCE)?,
+                ciphertext: fields.bytes(CIPHERTEXT)?.to_vec(),
+            },
+            _ => return Err(trivial_error!("Expected exactly one relay payload")),
+        };
+        Ok(Self {
+            from: super::signing::MaybeInvalidPublicKey(fields.array(FROM)?),
+            to: super::signing::MaybeInvalidPublicKey(fields.array(TO)?),
+            payload,
+        })
+    }
+}
+
+impl WireFields for RouteEntry {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::route::*;
+        writer.nested(DESTINATION, &self.destination);
+        writer.uint(HOPS, self.hops.into());
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::route::*;
+        Ok(Self {
+            destination: fields.nested(DESTINATION)?,
+            hops: fields.u8(HOPS)?,
+        })
+    }
+}
+
+/// Only encodes the announcement itself, the signature goes along with it in
+/// `ControlMessage::Routes`.
+impl WireFields for RouteAnnouncement {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::routes::*;
+        writer.uint(SEQUENCE, self.sequence);
+        writer.list(ROUTES, &self.routes);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::routes::*;
+        Ok(Self {
+            sequence: fields.uint(SEQUENCE)?,
+            routes: fields.list(ROUTES)?,
+        })
+    }
+}
+
+/// Only encodes the beacon itself, see `SignedBeacon` for the signature.
+impl WireFields for Beacon {
+    fn encode_fields(&self, writer: &mut Writer) {
+        use tags::beacon::*;
+        writer.nested(LOGICAL_ID, &self.identity);
+        writer.nested(CAPABILITIES, &self.capabilities);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        use tags::beacon::*;
+        Ok(Self {
+            identity: fields.nested(LOGICAL_ID)?,
+            capabilities: fields.nested(CAPABILITIES)?,
+        })
+    }
+}
+
+impl WireFields for SignedBeacon {
+    fn encode_fields(&self, writer: &mut Writer) {
+        self.beacon.encode_fields(writer);
+        writer.bytes(tags::beacon::SIGNATURE, &self.signature.0);
+    }
+
+    fn decode_fields(fields: &Fields) -> GenericResult<Self> {
+        Ok(Self {
+            beacon: Beacon::decode_fields(fields)?,
+            signature: MaybeInvalidSignature(fields.array(tags::beacon::SIGNATURE)?),
+        })
+    }
+}
+
+impl WireMessage for super::ControlMessage {
+    type Legacy = legacy::ControlMessage;
+
+    fn message_type(&self) -> u64 {
+        match *self {
+            Self::Associate { .. } => tags::ASSOCIATE,
+            Self::Roster { .. } => tags::ROSTER,
+            Self::Relay { .. } => tags::RELAY,
+            Self::Routes { .. } => tags::ROUTES,
+            Self::Routed { .. } => tags::ROUTED,
+        }
+    }
+
+    fn encode_fields(&self, writer: &mut Writer) {
+        match *self {
+            Self::Associate {
+                ref physical_id,
+                ref logical_id,
+                ref ports,
+                ref key_exchange_public_key,
+                ref capabilities,
+                ref post_quantum_share,
+                ref resumption_ticket,
+            } => {
+                use tags::associate::*;
+                writer.nested(PHYSICAL_ID, physical_id);
+                writer.nested(LOGICAL_ID, logical_id);
+                writer.nested(PORTS, ports);
+                writer.bytes(KEY_EXCHANGE_PUBLIC_KEY, &key_exchange_public_key.0);
+                // Kept for builds that predate capability negotiation.
+                writer.bool(MULTIPLEXING, capabilities.multiplexing);
+                writer.nested(CAPABILITIES, capabilities);
+                match *post_quantum_share {
+                    Some(PostQuantumShare::EncapsulationKey(ref key)) => {
+                        writer.bytes(ML_KEM_ENCAPSULATION_KEY, key)
+                    }
+                    Some(PostQuantumShare::Ciphertext(ref ciphertext)) => {
+                        writer.bytes(ML_KEM_CIPHERTEXT, ciphertext)
+                    }
+                    None => {}
+                }
+                if let Some(ref ticket) = *resumption_ticket {
+                    writer.bytes(RESUMPTION_TICKET, ticket);
+                }
+            }
+            Self::Roster {
+                ref update,
+                ref signature,
+            } => {
+                update.encode_fields(writer);
+                writer.bytes(tags::roster::SIGNATURE, &signature.0);
+            }
+            Self::Relay {
+                ref envelope,
+                ref signature,
+            } => {
+                envelope.encode_fields(writer);
+                writer.bytes(tags::relay::SIGNATURE, &signature.0);
+            }
+            Self::Routes {
+                ref announcement,
+                ref signature,
+            } => {
+                announcement.encode_fields(writer);
+                writer.bytes(tags::routes::SIGNATURE, &signature.0);
+            }
+            Self::Routed {
+                ref envelope,
+                ref signature,
+                ttl,
+            } => {
+                envelope.encode_fields(writer);
+                writer.bytes(tags::relay::SIGNATURE, &signature.0);
+                writer.uint(tags::routed::TTL, ttl.into());
+            }
+        }
+    }
+
+    fn decode_fields(message_type: u64, fields: &Fields) -> GenericResult<Self> {
+        match message_type {
+            tags::ASSOCIATE => {
+                use tags::associate::*;
+                Ok(Self::Associate {
+                    physical_id: fields.nested(PHYSICAL_ID)?,
+                    logical_id: fields.nested(LOGICAL_ID)?,
+                    ports: fields.nested(PORTS)?,
+                    key_exchange_public_key: super::key_exchange::MaybeInvalidPublicKey(
+                        fields.array(KEY_EXCHANGE_PUBLIC_KEY)?,
+                    ),
+                    capabilities: if fields.has(CAPABILITIES) {
+                        fields.nested(CAPABILITIES)?
+                    } else {
+                        Capabilities::legacy(fields.bool(MULTIPLEXING)?)
+                    },
+                    post_quantum_share: match (
+                        fields.get(ML_KEM_ENCAPSULATION_KEY),
+                        fields.get(ML_KEM_CIPHERTEXT),
+                    ) {
+                        (None, None) => None,
+                        (Some(key), None) => Some(PostQuantumShare::EncapsulationKey(key.to_vec())),
+                        (None, Some(ciphertext)) => {
+                            Some(PostQuantumShare::Ciphertext(ciphertext.to_vec()))
+                        }
+                        (Some(..), Some(..)) => {
+                            return Err(trivial_error!("Conflicting ML-KEM fields"))
+                        }
+                    },
+                    resumption_ticket: if fields.has(RESUMPTION_TICKET) {
+                        Some(fields.array(RESUMPTION_TICKET)?)
+                    } else {
+                        None
+                    },
+                })
+            }
+            tags::ROSTER => Ok(Self::Roster {
+                update: RosterUpdate::decode_fields(fields)?,
+                signature: MaybeInvalidSignature(fields.array(tags::roster::SIGNATURE)?),
+            }),
+            tags::RELAY => Ok(Self::Relay {
+                envelope: RelayEnvelope::decode_fields(fields)?,
+                signature: MaybeInvalidSignature(fields.array(tags::relay::SIGNATURE)?),
+            }),
+            tags::ROUTES => Ok(Self::Routes {
+                announcement: RouteAnnouncement::decode_fields(fields)?,
+                signature: MaybeInvalidSignature(fields.array(tags::routes::SIGNATURE)?),
+            }),
+            tags::ROUTED => Ok(Self::Routed {
+                envelope: RelayEnvelope::decode_fields(fields)?,
+                signature: MaybeInvalidSignature(fields.array(tags::relay::SIGNATURE)?),
+                ttl: fields.u8(tags::routed::TTL)?,
+            }),
+            _ => Err(trivial_error!("Unknown control message type")),
+        }
+    }
+
+    /// Only `Associate` exists in version 1. The fields added since are dropped, as older builds
+    /// wouldn't understand them anyway.
+    fn to_legacy(&self) -> GenericResult<Self::Legacy> {
+        match *self {
+            Self::Associate {
+                ref physical_id,
+                ref logical_id,
+                ref ports,
+                ref key_exchange_public_key,
+                ..
+            } => Ok(legacy::ControlMessage::Associate {
+                physical_id: match *physical_id {
+                    super::PeerOwnIdentifier::Name(ref name) => {
+                        legacy::PhysicalId::Name(name.clone())
+                    }
+                    super::PeerOwnIdentifier::DevAddr(ref addr) => {
+                        legacy::PhysicalId::DevAddr(legacy::DevAddr {
+                            is_v8: addr.is_v8,
+                            bytes: addr.bytes,
+                        })
+                    }
+                },
+                logical_id: legacy::LogicalId {
+                    nickname: logical_id.nickname.clone(),
+                    key: logical_id.key.0,
+                },
+                ports: legacy::Ports {
+                    control: ports.control,
+                    p2p: ports.p2p,
+                },
+                key_exchange_public_key: key_exchange_public_key.0,
+            }),
+            _ => Err(trivial_error!("Message not supported in wire version 1")),
+        }
+    }
+
+    fn from_legacy(legacy: Self::Legacy) -> GenericResult<Self> {
+        let legacy::ControlMessage::Associate {
+            physical_id,
+            logical_id,
+            ports,
+            key_exchange_public_key,
+        } = legacy;
+        Ok(Self::Associate {
+            physical_id: match physical_id {
+                legacy::PhysicalId::Name(name) => super::PeerOwnIdentifier::Name(name),
+                legacy::PhysicalId::DevAddr(addr) => {
+                    super::PeerOwnIdentifier::DevAddr(super::DecodableMacAddr {
+                        is_v8: addr.is_v8,
+                        bytes: addr.bytes,
+                    })
+                }
+            },
+            logical_id: super::identity::LogicalPeerIdentity {
+                nickname: logical_id.nickname,
+                key: super::signing::MaybeInvalidPublicKey(logical_id.key),
+            },
+            ports: super::P2pPorts {
+                control: ports.control,
+                p2p: ports.p2p,
+                // Not used, see `Capabilities::version_1`.
+                datagram: 0,
+            },
+            key_exchange_public_key: super::key_exchange::MaybeInvalidPublicKey(
+                key_exchange_public_key,
+            ),
+            capabilities: Capabilities::version_1(),
+            post_quantum_share: None,
+            resumption_ticket: None,
+        })
+    }
+}
+
+/// The version 1 layout of the messages, see the module docs.
+///
+/// These mirror the Rust types that builds predating version 2 serialized with `bincode`, field by
+/// field and variant by variant, so they must never change, even when the types they mirror do.
+pub mod legacy {
+    use bincode::{Decode, Encode};
+
+    #[derive(Encode, Decode, Debug)]
+    pub struct DevAddr {
+        pub is_v8: bool,
+        pub bytes: [u8; 8],
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    pub enum PhysicalId {
+        Name(String),
+        DevAddr(DevAddr),
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    pub struct LogicalId {
+        pub nickname: String,
+        pub key: [u8; 32],
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    pub struct Ports {
+        pub control: u16,
+        pub p2p: u16,
+    }
+
+    #[derive(Encode, Decode, Debug)]
+    pub enum ControlMessage {
+        Associate {
+            physical_id: PhysicalId,
+            logical_id: LogicalId,
+            ports: Ports,
+            key_exchange_public_key: [u8; 32],
+        },
+    }
+
+    /// Stands in for the messages that don't exist in version 1.
+    #[derive(Encode, Decode, Debug)]
+    pub struct Unsupported;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        identity::LogicalPeerIdentity, key_exchange, mux::Frame, signing::MaybeInvalidPublicKey,
+        ControlMessage, P2pPorts, PeerOwnIdentifier,
+    };
+
+    /// Parses the hex dumps of the module docs, ignoring whitespace.
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|d| u8::from_str_radix(std::str::from_utf8(d).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    /// The `Associate` of the test vectors in the module docs.
+    fn associate() -> ControlMessage {
+        ControlMessage::Associate {
+            physical_id: PeerOwnIdentifier::Name("ngn".to_owned()),
+            logical_id: LogicalPeerIdentity {
+                nickname: "alice".to_owned(),
+                key: MaybeInvalidPublicKey([0x11; 32]),
+            },
+            ports: P2pPorts {
+                control: 1000,
+                p2p: 1001,
+                datagram: 1002,
+            },
+            key_exchange_public_key: key_exchange::MaybeInvalidPublicKey([0x22; 32]),
+            capabilities: Capabilities {
+                multiplexing: true,
+                datagrams: true,
+                compression: true,
+                ordered_delivery: true,
+                padding: true,
+                chacha20_poly1305: true,
+                hardware_aes: false,
+                roster: true,
+                relay: true,
+                routing: true,
+                file_transfer: false,
+                max_frame_size: 16 * 1024 * 1024,
+                min_version: 1,
+                max_version: 2,
+            },
+            post_quantum_share: None,
+            resumption_ticket: None,
+        }
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 300, u32::MAX.into(), u64::MAX] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            let mut slice = &buf[..];
+            assert_eq!(read_varint(&mut slice).unwrap(), value);
+            assert!(slice.is_empty());
+        }
+        let mut buf = vec![];
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn varint_errors() {
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        // 11 bytes.
+        assert!(read_varint(&mut &[0xff; 10][..]).is_err());
+        // More than 64 bits in the last byte.
+        let mut overflow = [0xff; 10];
+        overflow[9] = 0x02;
+        assert!(read_varint(&mut &overflow[..]).is_err());
+    }
+
+    #[test]
+    fn associate_vector() {
+        let expected = hex("01
+             01 05 01 03 6e676e
+             02 29 01 05 616c696365
+                   02 20 1111111111111111111111111111111111111111111111111111111111111111
+             03 0c 01 02 e807 02 02 e907 03 02 ea07
+             04 20 2222222222222222222222222222222222222222222222222222222222222222
+             05 01 01
+             06 2d 01 01 01 02 01 01
+                   03 01 01 04 01 01 05 01 00
+                   06 04 80808008 07 01 01 08 01 02
+                   09 01 01 0a 01 01
+                   0b 01 01 0c 01 00
+                   0d 01 01 0e 01 01");
+        assert_eq!(encode(2, &associate()).unwrap(), expected);
+        let decoded: ControlMessage = decode(2, &expected).unwrap();
+        assert_eq!(encode(2, &decoded).unwrap(), expected);
+    }
+
+    #[test]
+    fn legacy_associate_vector() {
+        // As encoded by builds that predate version 2.
+        let expected = hex("00
+             00 03 6e676e
+             05 616c696365
+             1111111111111111111111111111111111111111111111111111111111111111
+             fb e803 fb e903
+             2222222222222222222222222222222222222222222222222222222222222222");
+        assert_eq!(encode(1, &associate()).unwrap(), expected);
+        let ControlMessage::Associate {
+            physical_id,
+            logical_id,
+            ports,
+            capabilities,
+            post_quantum_share,
+            ..
+        } = decode(1, &expected).unwrap()
+        else {
+            panic!("Expected an Associate");
+        };
+        assert!(matches!(physical_id, PeerOwnIdentifier::Name(ref n) if n == "ngn"));
+        assert_eq!(logical_id.nickname, "alice");
+        assert_eq!((ports.control, ports.p2p), (1000, 1001));
+        assert_eq!(capabilities, Capabilities::version_1());
+        assert!(post_quantum_share.is_none());
+    }
+
+    #[test]
+    fn data_frame_vector() {
+        let expected = hex("21 01 01 01 02 01 01 03 05 68656c6c6f");
+        let frame = Frame::Data {
+            stream: 1,
+            end_of_message: true,
+            payload: b"hello".to_vec(),
+        };
+        assert_eq!(encode(2, &frame).unwrap(), expected);
+        let Frame::Data {
+            stream,
+            end_of_message,
+            payload,
+        } = decode(2, &expected).unwrap()
+        else {
+            panic!("Expected a data frame");
+        };
+        assert_eq!(
+            (stream, end_of_message, &payload[..]),
+            (1, true, &b"hello"[..])
+        );
+    }
+
+    #[test]
+    fn tlv_round_trip() {
+        let mut writer = Writer::default();
+        writer.uint(1, 1234);
+        writer.bool(2, true);
+        writer.string(3, "ngn");
+        writer.bytes(4, &[]);
+        writer.list(
+            5,
+            &[P2pPorts {
+                control: 1,
+                p2p: 2,
+                datagram: 3,
+            }],
+        );
+        let fields = Fields::parse(&writer.buf).unwrap();
+        assert_eq!(fields.u16(1).unwrap(), 1234);
+        assert!(fields.u8(1).is_err());
+        assert!(fields.bool(2).unwrap());
+        assert!(fields.bool(1).is_err());
+        assert_eq!(fields.string(3).unwrap(), "ngn");
+        assert_eq!(fields.bytes(4).unwrap(), b"");
+        let ports: Vec<P2pPorts> = fields.list(5).unwrap();
+        assert_eq!(ports[0].datagram, 3);
+        assert!(!fields.flag(6).unwrap());
+        assert!(fields.bytes(6).is_err());
+        assert!(fields.list::<P2pPorts>(6).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tlv_skips_unknown_fields() {
+        let mut buf = encode(2, &associate()).unwrap();
+        // A field from a newer build, with a multi-byte tag.
+        let mut extra = Writer::default();
+        extra.bytes(1000, b"from the future");
+        buf.extend_from_slice(&extra.buf);
+        let decoded: ControlMessage = decode(2, &buf).unwrap();
+        assert_eq!(
+            encode(2, &decoded).unwrap(),
+            encode(2, &associate()).unwrap()
+        );
+    }
+
+    #[test]
+    fn tlv_errors() {
+        // Repeated tag.
+        assert!(Fields::parse(&hex("01 01 00 01 01 00")).is_err());
+        // Truncated value.
+        assert!(Fields::parse(&hex("01 02 00")).is_err());
+        // Trailing data in an integer.
+        assert!(Fields::parse(&hex("01 02 00 00")).unwrap().uint(1).is_err());
+        // Unknown message type.
+        assert!(decode::<ControlMessage>(2, &hex("7f")).is_err());
+        // Neither frames nor rosters exist in version 1.
+        assert!(encode(1, &Frame::Open { stream: 1 }).is_err());
+        let roster = ControlMessage::Roster {
+            update: crate::protocol::roster::RosterUpdate {
+                sequence: 1,
+                change: crate::protocol::roster::RosterChange::Snapshot(vec![]),
+            },
+            signature: MaybeInvalidSignature([0; 64]),
+        };
+        assert!(encode(1, &roster).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 //! Miscellaneous utilities.
 
+use crate::GenericResult;
 use log::error;
 use macaddr::MacAddr;
 use std::net::Ipv6Addr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[macro_export]
 macro_rules! trivial_error {
@@ -59,6 +60,87 @@ where
     retry_timeout(Duration::from_millis(500), count, thing).await
 }
 
+/// A simple token bucket rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    refill_per_second: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter that allows bursts of up to `burst` events, and `per_second` events
+    /// per second sustained.
+    pub fn new(burst: u32, per_second: u32) -> Self {
+        Self {
+            capacity: f64::from(burst),
+            tokens: f64::from(burst),
+            refill_per_second: f64::from(per_second),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+    }
+
+    /// Returns whether a full burst would be allowed right now, i.e. whether the limiter is in
+    /// the same state as a new one.
+    pub fn is_idle(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    /// Returns whether an event is allowed right now, consuming a token if so.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Returns the WPS checksum digit of the first seven digits of a PIN.
+fn wps_pin_checksum(pin: u32) -> u32 {
+    let mut accum = 0;
+    let mut pin = pin;
+    for weight in [3, 1, 3, 1, 3, 1, 3] {
+        accum += weight * (pin % 10);
+        pin /= 10;
+    }
+    (10 - accum % 10) % 10
+}
+
+/// Generates a random eight-digit WPS PIN, with a valid checksum.
+pub fn new_wps_pin() -> GenericResult<String> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; 4];
+    ring::rand::SystemRandom::new().fill(&mut bytes)?;
+    let pin = u32::from_le_bytes(bytes) % 10_000_000;
+    Ok(format!("{:08}", pin * 10 + wps_pin_checksum(pin)))
+}
+
+/// Whether a WPS PIN is well-formed: four or eight digits, the latter with a valid checksum.
+pub fn is_valid_wps_pin(pin: &str) -> bool {
+    if !pin.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    match pin.len() {
+        4 => true,
+        8 => {
+            let pin: u32 = pin.parse().unwrap();
+            wps_pin_checksum(pin / 10) == pin % 10
+        }
+        _ => false,
+    }
+}
+
 /// Turns a raw buffer into a mac address.
 pub fn to_mac_addr(buff: &[u8]) -> Option<MacAddr> {
     let len = buff.len();